@@ -0,0 +1,107 @@
+//! Allocations and time per message of the OKX decoding pipeline, failing once a message takes
+//! more allocations than its budget.
+//!
+//! Decoding borrows from the frame and reuses the book's buffers, what is left is:
+//! - serde_json skipping a nested value into a `RawValue` with a scratch stack, allocated once per
+//!   deserializer. One for the frame and one for every book of a push.
+//! - the levels of a snapshot, which the event owns.
+//!
+//! `cargo bench -p singular --bench decode`
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::Instant,
+};
+
+use singular::{
+    adapters::{
+        okx::{OkxBooks, OkxProtocol},
+        protocol::Protocol,
+        sequence::FeedStats,
+    },
+    transmute::okx::{parse_into, OkxPush},
+};
+
+/// Counts every allocation made through the global allocator
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const MESSAGES: usize = 100_000;
+
+const TRADE: &str = r#"{"arg":{"channel":"trades","instId":"BTC-USDT"},"data":[{"instId":"BTC-USDT","tradeId":"426790906","px":"30460.1","sz":"0.0010244","side":"sell","ts":"1688085963425"}]}"#;
+
+/// `books5` carries no checksum or sequence, so the same push validates every time
+const BOOK: &str = r#"{"arg":{"channel":"books5","instId":"BTC-USDT"},"data":[{"asks":[["30557.3","0.1","0","1"],["30557.6","0.51065898","0","3"]],"bids":[["30545","0.51069492","0","2"],["30544.9","0.17474","0","1"]],"instId":"BTC-USDT","ts":"1688060541909"}]}"#;
+
+/// Run `f` over every message after a warm up, printing allocations and time per message. Panics
+/// if a message allocates more than `budget` times.
+fn measure(name: &str, budget: usize, mut f: impl FnMut()) {
+    for _ in 0..1_000 {
+        f();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        f();
+    }
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    println!(
+        "{name:<16} {:>6.2} allocations/message {:>8.1} ns/message",
+        allocations as f64 / MESSAGES as f64,
+        elapsed.as_nanos() as f64 / MESSAGES as f64,
+    );
+    assert!(
+        allocations <= budget * MESSAGES,
+        "{name} allocates more than {budget} times per message"
+    );
+}
+
+fn main() {
+    let mut events = Vec::new();
+    // The frame
+    measure("trade", 1, || {
+        parse_into(black_box(TRADE), &mut events).unwrap();
+        events.clear();
+    });
+
+    let mut books = OkxBooks::new(Arc::new(FeedStats::default()));
+    // The frame, the book and the snapshot's levels
+    measure("book validate", 3, || {
+        let push = OkxPush::parse(black_box(BOOK)).unwrap();
+        books.validate(&push, &mut events);
+        events.clear();
+    });
+
+    let mut protocol = OkxProtocol::new("wss://ws.okx.com:8443/ws/v5/public");
+    let mut outputs = Vec::new();
+    // The frame, the outputs land in a reused buffer like the actor's
+    measure("protocol decode", 1, || {
+        protocol.decode(black_box(TRADE), &mut outputs);
+        black_box(&outputs);
+        outputs.clear();
+    });
+}
@@ -1,245 +1,1064 @@
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
-    sync::Arc,
+    collections::{HashMap, HashSet},
+    mem,
+    sync::{Arc, Mutex},
 };
 
+use async_trait::async_trait;
+
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use tokio::sync::{mpsc, oneshot};
-use tokio::{net::TcpStream, sync::mpsc::UnboundedSender};
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::{self, Instant};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
 use url::Url;
 
+#[cfg(test)]
+use crate::mock::MockExchange;
+
 use crate::{
-    event::{self, Event, EventType},
-    models::normal::DataTypes,
-    transmute::{self, okx::TradeRaw},
+    adapters::{
+        backoff::Backoff,
+        capabilities::Capabilities,
+        keepalive::{Keepalive, KeepaliveAction, KeepaliveConfig},
+        okx::{OkxProtocol, OKX_PUBLIC_URL},
+        protocol::{Output, Protocol},
+        sequence::{FeedCounts, FeedStats},
+        subscriptions::{answered, Ack, Acks, Op, SubscribeError, Subscriptions},
+    },
+    event::{now_micros, ConnectionStatus, Event, Timestamps},
+    interfaces::{
+        event_stream, notify_lagged, Adapter, ConnectionState, Subscribing, STREAM_CAPACITY,
+    },
+    models::{normal::DataTypes, Exchange, Symbol},
 };
 type SocketStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-struct MyActor {
+/// A snapshot fetched for a stream, see [`Protocol::snapshot`]
+type Fetched = (SocketRequest, Result<String, String>);
+
+struct MyActor<P> {
     receiver: mpsc::Receiver<ActorMessage>,
+    /// How to talk to the exchange
+    protocol: P,
+    /// `None` while disconnected
+    write: Option<SplitSink<SocketStream, Message>>,
+    read: Option<SplitStream<SocketStream>>,
+    backoff: Backoff,
+    /// When to dial again while disconnected
+    reconnect_at: Instant,
+    /// When to move to a fresh connection, for exchanges that close old ones
+    rotate_at: Option<Instant>,
+    /// The connection a rotation replaced, still read while the new one subscribes
+    retiring: Option<Retiring>,
+    keepalive: Keepalive<SocketRequest>,
+    subscriptions: Subscriptions<SocketRequest, Event>,
+    /// Requests waiting on the exchange's answer
+    acks: Acks<SocketRequest>,
+    fetched_tx: mpsc::Sender<Fetched>,
+    fetched_rx: mpsc::Receiver<Fetched>,
+    state: watch::Sender<ConnectionState>,
+    /// Buffer every frame is decoded into, emptied by [`MyActor::apply`]
+    outputs: Vec<Output>,
+}
+
+/// The old connection of an overlapping rotation, see [`Protocol::overlap`]
+struct Retiring {
     write: SplitSink<SocketStream, Message>,
     read: SplitStream<SocketStream>,
-    subscriptions: HashMap<SocketRequest, Vec<mpsc::Sender<String>>>,
+    /// Replayed subscriptions the new connection has not had acked yet
+    awaiting: HashSet<SocketRequest>,
 }
 
 enum ActorMessage {
     Sub {
         request: SocketRequest,
-        respond_to: mpsc::Sender<String>,
+        respond_to: mpsc::Sender<Event>,
+        /// Answered once the exchange confirms or rejects the subscription
+        ack: Ack,
     },
+    /// Release the reference `respond_to` took with [`ActorMessage::Sub`]
     Unsub {
         request: SocketRequest,
-        respond_to: mpsc::Sender<SocketRequest>,
+        respond_to: mpsc::Sender<Event>,
+        ack: Ack,
     },
+    /// Close the connection and stop, `done` is answered once closed
+    Shutdown { done: oneshot::Sender<()> },
 }
 
-impl MyActor {
-    pub async fn new(receiver: mpsc::Receiver<ActorMessage>, ws_url: &str) -> Self {
-        let (write_stream, _) = connect_async(Url::parse(ws_url).expect("url"))
-            .await
-            .unwrap();
-
-        let (write, read) = write_stream.split();
+impl<P: Protocol> MyActor<P> {
+    /// The actor starts disconnected and dials the protocol's url as soon as it runs
+    pub fn new(
+        receiver: mpsc::Receiver<ActorMessage>,
+        mut protocol: P,
+        stats: Arc<FeedStats>,
+        keepalive: KeepaliveConfig,
+        state: watch::Sender<ConnectionState>,
+    ) -> Self {
+        protocol.attach(stats);
+        let (fetched_tx, fetched_rx) = mpsc::channel(16);
         Self {
-            read,
-            write,
             receiver,
-            subscriptions: HashMap::new(),
+            protocol,
+            write: None,
+            read: None,
+            backoff: Backoff::default(),
+            reconnect_at: Instant::now(),
+            rotate_at: None,
+            retiring: None,
+            keepalive: Keepalive::new(keepalive),
+            subscriptions: Subscriptions::default(),
+            acks: Acks::default(),
+            fetched_tx,
+            fetched_rx,
+            state,
+            outputs: Vec::new(),
+        }
+    }
+
+    /// Dial the exchange once. On success every subscription is replayed upstream and the
+    /// subscribers are told the stream is back, on failure the next attempt is scheduled.
+    async fn connect(&mut self) {
+        let stream = match self.open().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                let delay = self.backoff.next_delay();
+                eprintln!(
+                    "MyActor: failed to connect to {} ({e}), retrying in {delay:?}",
+                    self.protocol.url()
+                );
+                self.reconnect_at = Instant::now() + delay;
+                return;
+            }
+        };
+
+        let reconnected = self.backoff.attempt() > 0;
+        self.backoff.reset();
+        self.established(stream).await;
+        if reconnected {
+            self.notify_connection(true, "Reconnected").await;
+        }
+    }
+
+    /// Move to a fresh connection before the exchange closes this one. The old connection is only
+    /// dropped once the new one is up, so subscribers never see a disconnect. Protocols that
+    /// [`overlap`](Protocol::overlap) keep reading it until every replayed subscription is acked.
+    async fn rotate(&mut self) {
+        match self.open().await {
+            Ok(stream) => {
+                self.backoff.reset();
+                self.retire().await;
+                let old = self.write.take().zip(self.read.take());
+                let awaiting: HashSet<SocketRequest> = self.subscriptions.keys().copied().collect();
+                match old {
+                    Some((write, read)) if self.protocol.overlap() && !awaiting.is_empty() => {
+                        self.retiring = Some(Retiring {
+                            write,
+                            read,
+                            awaiting,
+                        });
+                        self.replace(stream).await;
+                    }
+                    old => {
+                        if let Some((mut write, _)) = old {
+                            let _ = write.close().await;
+                        }
+                        self.established(stream).await;
+                    }
+                }
+            }
+            Err(e) => {
+                let delay = self.backoff.next_delay();
+                eprintln!(
+                    "MyActor: failed to replace the connection to {} ({e}), retrying in {delay:?}",
+                    self.protocol.url()
+                );
+                self.rotate_at = Some(Instant::now() + delay);
+            }
+        }
+    }
+
+    /// Dial the protocol's url, or the endpoint it negotiates for this connection. A negotiated
+    /// endpoint may also change how the connection is kept alive.
+    async fn open(&mut self) -> Result<SocketStream, String> {
+        let Some(endpoint) = self.protocol.endpoint() else {
+            return dial(self.protocol.url()).await;
+        };
+        let endpoint = endpoint.await?;
+        if let Some(ping_interval) = endpoint.ping_interval {
+            self.keepalive.config.ping_interval = ping_interval;
+        }
+        if let Some(pong_timeout) = endpoint.pong_timeout {
+            self.keepalive.config.pong_timeout = pong_timeout;
+        }
+        dial(&endpoint.url).await
+    }
+
+    /// Start streaming over `stream` from a clean protocol
+    async fn established(&mut self, stream: SocketStream) {
+        self.protocol.reset();
+        self.replace(stream).await;
+    }
+
+    /// Stream over `stream` from now on. Nothing sent on the old connection, or while there was
+    /// none, will be answered, so every subscription is replayed and whoever waits on a request
+    /// moves over to the replay.
+    async fn replace(&mut self, stream: SocketStream) {
+        let (write, read) = stream.split();
+        self.write = Some(write);
+        self.read = Some(read);
+        self.keepalive.connected();
+        self.state.send_replace(ConnectionState::Connected);
+        self.rotate_at = self.protocol.max_age().map(|age| Instant::now() + age);
+        for frame in self.protocol.handshake() {
+            self.send_upstream(Message::Text(frame)).await;
+        }
+
+        let in_flight = self.acks.drain();
+        let requests: Vec<SocketRequest> = self.subscriptions.keys().cloned().collect();
+        for request in requests {
+            self.send_request(Op::Subscribe, request, None).await;
+        }
+        for pending in in_flight {
+            for waiter in pending.waiters {
+                if let Err(waiter) = self.acks.join(pending.op, &pending.key, waiter) {
+                    // Unsubscribed meanwhile, the old connection took it with it
+                    let _ = waiter.send(Ok(()));
+                }
+            }
+        }
+    }
+
+    /// The stream ended or errored. Drop the connection, tell every subscriber and schedule a
+    /// reconnect.
+    async fn disconnected(&mut self, reason: String) {
+        eprintln!(
+            "MyActor: lost connection to {}: {reason}",
+            self.protocol.url()
+        );
+        self.write = None;
+        self.read = None;
+        self.retiring = None;
+        self.rotate_at = None;
+        self.protocol.reset();
+        self.reconnect_at = Instant::now() + self.backoff.next_delay();
+        self.state
+            .send_replace(ConnectionState::Disconnected(reason.clone()));
+        self.notify_connection(false, &reason).await;
+    }
+
+    /// Close the connection for good. Dropping the subscriptions ends every client's stream and
+    /// dropping the acks fails whoever still waits on the exchange.
+    async fn shutdown(&mut self) {
+        if let Some(mut write) = self.write.take() {
+            let _ = write.close().await;
+        }
+        self.read = None;
+        self.retire().await;
+        self.subscriptions = Subscriptions::default();
+        self.acks.drain();
+        self.state.send_replace(ConnectionState::Closed);
+    }
+
+    /// Ping the exchange when the connection has been quiet, reconnect when the pong never comes
+    /// and resubscribe a channel that stopped delivering data
+    async fn check_keepalive(&mut self) {
+        match self.keepalive.poll() {
+            KeepaliveAction::Idle => {}
+            KeepaliveAction::Ping => {
+                let ping = match self.keepalive.config.ping.as_str() {
+                    "" => Message::Ping(Vec::new()),
+                    ping => Message::Text(ping.to_string()),
+                };
+                self.send_upstream(ping).await;
+            }
+            KeepaliveAction::Resubscribe(request) => {
+                for op in [Op::Unsubscribe, Op::Subscribe] {
+                    self.send_request(op, request, None).await;
+                }
+            }
+            KeepaliveAction::Reconnect(reason) => self.disconnected(reason).await,
+        }
+    }
+
+    async fn notify_connection(&mut self, connected: bool, reason: &str) {
+        let mut dropped = Vec::new();
+        for (request, subs) in self.subscriptions.iter() {
+            let event = Event::Connection(ConnectionStatus {
+                exchange: self.protocol.exchange(),
+                symbol: request.symbol,
+                data_type: request.data_type,
+                connected,
+                reason: reason.to_string(),
+                ts: Timestamps {
+                    received: now_micros(),
+                    ..Timestamps::default()
+                },
+            });
+            dropped.extend(send_to_clients(subs, event));
+        }
+        self.disconnect(dropped).await;
+    }
+
+    /// Send to the exchange if connected. Anything sent while disconnected is covered by the
+    /// resubscribe on reconnect.
+    async fn send_upstream(&mut self, message: Message) {
+        if let Some(write) = self.write.as_mut() {
+            let _ = write.send(message).await;
+        }
+    }
+
+    /// Ask the exchange to `op` `request`, `ack` is answered once it did
+    async fn send_request(&mut self, op: Op, request: SocketRequest, ack: Option<Ack>) {
+        match self.protocol.request(op, &request) {
+            Ok(frame) => {
+                self.send_upstream(Message::Text(frame)).await;
+                self.acks.sent(op, request, ack);
+            }
+            Err(e) => {
+                if let Some(ack) = ack {
+                    let _ = ack.send(Err(e));
+                }
+            }
         }
     }
+
     async fn handle_message(&mut self, msg: ActorMessage) {
-        let _ = match msg {
+        match msg {
             ActorMessage::Sub {
                 request,
                 respond_to,
-            } => self.subscribe(request, respond_to).await,
+                ack,
+            } => self.subscribe(request, respond_to, ack).await,
             ActorMessage::Unsub {
                 request,
                 respond_to,
-            } => self.unsubscribe(request, respond_to).await,
-        };
+                ack,
+            } => self.unsubscribe(request, respond_to, ack).await,
+            ActorMessage::Shutdown { done } => {
+                self.shutdown().await;
+                let _ = done.send(());
+            }
+        }
     }
 
+    /// Only the first subscriber of a channel subscribes upstream, later ones share its answer
     async fn subscribe(
         &mut self,
         request: SocketRequest,
-        client_tx: mpsc::Sender<String>,
-    ) -> Result<(), ()> {
-        self.subscriptions
-            .entry(request.clone())
-            .or_default()
-            .push(client_tx);
-        let message = match request.data_type {
-            DataTypes::Trade => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "trades",
-                    "instId": request.symbol
-                }]
-            })),
-            DataTypes::Book => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "books",
-                    "instId": request.symbol
-                }]
-            })),
-            // DataTypes::OrderbookSnapshot => Some(serde_json::json!({
-            //     "op": "subscribe",
-            //     "args": [{
-            //         "channel": "books5",
-            //         "instId": "BTC-USDT"
-            //     }]
-            // })),
-        };
-        return if let Some(m) = message {
-            self.write.send(Message::Text(m.to_string())).await.unwrap();
-            Ok(())
-        } else {
-            todo!();
-            // Err(DemoError {
-            //     message: format!(
-            //         "The provided Event Type is not available on this exchange: {}",
-            //         kind
-            //     ),
-            // })
-        };
+        client_tx: mpsc::Sender<Event>,
+        ack: Ack,
+    ) {
+        if self.subscriptions.add(request, client_tx) {
+            let frame = match self.over_limit() {
+                Some(error) => Err(error),
+                None => self.protocol.request(Op::Subscribe, &request),
+            };
+            match frame {
+                Ok(frame) => {
+                    if request.data_type != DataTypes::Trade {
+                        self.keepalive.watch(request);
+                    }
+                    self.send_upstream(Message::Text(frame)).await;
+                    self.acks.sent(Op::Subscribe, request, Some(ack));
+                }
+                Err(e) => {
+                    self.subscriptions.take(&request);
+                    let _ = ack.send(Err(e));
+                }
+            }
+        } else if let Err(ack) = self.acks.join(Op::Subscribe, &request, ack) {
+            let _ = ack.send(Ok(()));
+        }
     }
 
+    /// Whether the channel just added takes the connection past what the exchange allows on one
+    fn over_limit(&self) -> Option<SubscribeError> {
+        let max = self.protocol.capabilities().max_subscriptions?;
+        (self.subscriptions.keys().count() > max).then(|| {
+            SubscribeError::Full(format!(
+                "More than {max} channels on one {} connection",
+                self.protocol.exchange()
+            ))
+        })
+    }
+
+    /// Only the last subscriber of a channel unsubscribes upstream
     async fn unsubscribe(
         &mut self,
         request: SocketRequest,
-        client_tx: mpsc::Sender<SocketRequest>,
-    ) -> Result<(), ()> {
-        let message = match request.data_type {
-            DataTypes::Trade => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "trades",
-                    "instId": request.symbol
-                }]
-            })),
-            DataTypes::Book => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "books",
-                    "instId":  request.symbol
-                }]
-            })),
-            // EventType::OrderbookSnapshot => Some(serde_json::json!({
-            //     "op": "unsubscribe",
-            //     "args": [{
-            //         "channel": "books5",
-            //         "instId": symbol
-            //     }]
-            // })),
-        };
-        return if let Some(m) = message {
-            self.write.send(Message::Text(m.to_string())).await.unwrap();
-            Ok(())
+        client_tx: mpsc::Sender<Event>,
+        ack: Ack,
+    ) {
+        if self.subscriptions.remove(&request, &client_tx) {
+            self.release(request, Some(ack)).await;
         } else {
-            todo!();
-            // Err(DemoError {
-            //     message: format!(
-            //         "The provided Event Type is not available on this exchange: {}",
-            //         kind
-            //     ),
-            // })
+            let _ = ack.send(Ok(()));
+        }
+    }
+
+    /// Unsubscribe every channel whose subscribers all dropped their receiver
+    async fn prune(&mut self) {
+        for request in self.subscriptions.prune() {
+            self.release(request, None).await;
+        }
+    }
+
+    /// Drop clients that went away or fell too far behind to take another event, unsubscribing
+    /// every channel left without subscribers. A lagging client's streams end with an
+    /// [`Event::Lagged`], it never stalls the connection for everyone else.
+    async fn disconnect(&mut self, dropped: Vec<mpsc::Sender<Event>>) {
+        if dropped.is_empty() {
+            return;
+        }
+        let exchange = self.protocol.exchange();
+        for (request, subs) in self.subscriptions.iter() {
+            for client in subs
+                .iter()
+                .filter(|c| dropped.iter().any(|d| d.same_channel(c)))
+            {
+                notify_lagged(client, exchange, request.symbol, request.data_type);
+            }
+        }
+        for request in self.subscriptions.disconnect(&dropped) {
+            self.release(request, None).await;
+        }
+    }
+
+    async fn release(&mut self, request: SocketRequest, ack: Option<Ack>) {
+        self.keepalive.unwatch(&request);
+        self.send_request(Op::Unsubscribe, request, ack).await;
+    }
+
+    /// Decode a text frame unless it is the answer to a keepalive ping. `at` is when it was read
+    /// off the socket.
+    async fn received(&mut self, text: &str, at: u64) {
+        if !self.keepalive.received(text) {
+            let mut outputs = mem::take(&mut self.outputs);
+            self.protocol.decode(text, &mut outputs);
+            stamp_received(&mut outputs, at);
+            self.apply(&mut outputs).await;
+            self.outputs = outputs;
+        }
+    }
+
+    /// Carry out what the protocol decoded. A rejected subscribe drops its subscribers, they never
+    /// got a stream.
+    async fn apply(&mut self, outputs: &mut Vec<Output>) {
+        let mut dropped = Vec::new();
+        for output in outputs.drain(..) {
+            match output {
+                Output::Event(request, event) => {
+                    self.keepalive.data(&request);
+                    if let Some(subs) = self.subscriptions.get(&request) {
+                        dropped.extend(send_to_clients(subs, event));
+                    }
+                }
+                Output::Acked(op, request) => {
+                    self.acks.acked(op, &request);
+                    if op == Op::Subscribe {
+                        self.resubscribed(&request).await;
+                    }
+                }
+                Output::Rejected(request, error) => {
+                    eprintln!("MyActor: {error}");
+                    let rejected = match request {
+                        Some((op, request)) => self.acks.rejected_request(op, &request, error),
+                        None => self.acks.rejected(error),
+                    };
+                    if let Some((Op::Subscribe, request)) = rejected {
+                        self.subscriptions.take(&request);
+                        self.keepalive.unwatch(&request);
+                        self.resubscribed(&request).await;
+                    }
+                }
+                Output::Resubscribe(request) => {
+                    for op in [Op::Unsubscribe, Op::Subscribe] {
+                        self.send_request(op, request, None).await;
+                    }
+                }
+                Output::Fetch(request) => self.fetch(request),
+                Output::Reconnect(reason) => self.disconnected(reason).await,
+                Output::Rotate => self.rotate_at = Some(Instant::now()),
+                Output::Send(frame) => self.send_upstream(Message::Text(frame)).await,
+            }
+        }
+        self.disconnect(dropped).await;
+    }
+
+    /// The new connection of a rotation answered a replayed subscribe. The old one closes once
+    /// none are left.
+    async fn resubscribed(&mut self, request: &SocketRequest) {
+        let Some(retiring) = self.retiring.as_mut() else {
+            return;
         };
+        retiring.awaiting.remove(request);
+        if retiring.awaiting.is_empty() {
+            self.retire().await;
+        }
     }
 
-    pub fn parse(raw_str: &str) -> serde_json::Result<event::Event> {
-        let de_ser: transmute::okx::OkxRaw<TradeRaw> = serde_json::from_str(raw_str)?;
-        Ok(event::Event::Trade(de_ser.into()))
+    /// Close the old connection of a rotation
+    async fn retire(&mut self) {
+        if let Some(mut retiring) = self.retiring.take() {
+            let _ = retiring.write.close().await;
+        }
     }
+
+    /// Fetch a snapshot in the background, it comes back through `fetched_rx`
+    fn fetch(&self, request: SocketRequest) {
+        let Some(snapshot) = self.protocol.snapshot(&request) else {
+            return;
+        };
+        let fetched = self.fetched_tx.clone();
+        tokio::spawn(async move {
+            let body = snapshot.await;
+            let _ = fetched.send((request, body)).await;
+        });
+    }
+}
+
+async fn dial(ws_url: &str) -> Result<SocketStream, String> {
+    let url = Url::parse(ws_url).map_err(|e| e.to_string())?;
+    let (stream, _) = connect_async(url).await.map_err(|e| e.to_string())?;
+    Ok(stream)
 }
 
-async fn run_my_actor(mut actor: MyActor) {
+/// Next frame of the connection, never resolves while disconnected
+async fn next_frame(
+    read: Option<&mut SplitStream<SocketStream>>,
+) -> Option<Result<Message, tungstenite::Error>> {
+    match read {
+        Some(read) => read.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn run_my_actor<P: Protocol>(mut actor: MyActor<P>) {
     loop {
         tokio::select! {
-           Some(Ok(val)) = actor.read.next() => {
-                if let Ok(parse) = MyActor::parse(&val.to_string()) {
-                    let subs = match parse {
-                        Event::Trade(t) => {
-                            dbg!(&t);
-                            let request = SocketRequest { symbol: t.symbol, data_type: DataTypes::Trade};
-                            &actor.subscriptions.get(&request).unwrap()[..]
+           frame = next_frame(actor.read.as_mut()) => match frame {
+                Some(Ok(Message::Close(frame))) => {
+                    let reason = frame.map_or("Closed by exchange".into(), |f| f.reason.to_string());
+                    actor.disconnected(reason).await;
+                }
+                Some(Ok(Message::Text(text))) => actor.received(&text, now_micros()).await,
+                Some(Ok(Message::Binary(frame))) => {
+                    let at = now_micros();
+                    match actor.protocol.inflate(&frame) {
+                        Some(text) => actor.received(&text, at).await,
+                        None => {
+                            actor.keepalive.received("");
                         }
-                        Event::OrderbookUpdate(t) => todo!(),
-                        Event::OrderbookSnapshot(t) => todo!(),
-                    };
-                    send_to_clients(subs, &val.to_string()).await;
+                    }
+                }
+                Some(Ok(_)) => {
+                    actor.keepalive.received("");
+                }
+                Some(Err(e)) => actor.disconnected(e.to_string()).await,
+                None => actor.disconnected("Stream ended".into()).await,
+           },
+           // The old connection of a rotation only delivers, it ending is no disconnect
+           frame = next_frame(actor.retiring.as_mut().map(|r| &mut r.read)) => match frame {
+                Some(Ok(Message::Text(text))) => actor.received(&text, now_micros()).await,
+                Some(Ok(Message::Binary(frame))) => {
+                    let at = now_micros();
+                    if let Some(text) = actor.protocol.inflate(&frame) {
+                        actor.received(&text, at).await;
+                    }
                 }
+                Some(Ok(Message::Close(_)) | Err(_)) | None => actor.retire().await,
+                Some(Ok(_)) => {}
+           },
+           _ = time::sleep_until(actor.reconnect_at), if actor.read.is_none() => {
+               actor.connect().await;
            }
-           Some(msg) = actor.receiver.recv() => {
-               actor.handle_message(msg).await;
+           _ = time::sleep_until(actor.keepalive.deadline()), if actor.read.is_some() => {
+               actor.prune().await;
+               actor.check_keepalive().await;
            }
+           _ = time::sleep_until(actor.rotate_at.unwrap_or_else(Instant::now)), if actor.rotate_at.is_some() => {
+               actor.rotate().await;
+           }
+           Some((request, body)) = actor.fetched_rx.recv() => {
+               let at = now_micros();
+               let mut outputs = Vec::new();
+               actor.protocol.snapshot_fetched(&request, body, &mut outputs);
+               stamp_received(&mut outputs, at);
+               actor.apply(&mut outputs).await;
+           }
+           msg = actor.receiver.recv() => match msg {
+               Some(msg @ ActorMessage::Shutdown { .. }) => {
+                   actor.handle_message(msg).await;
+                   break;
+               }
+               Some(msg) => actor.handle_message(msg).await,
+               // Every handle is gone
+               None => break,
+           },
         }
     }
 }
 
-async fn send_to_clients(clients: &[mpsc::Sender<String>], value: &str) {
-    for tx in clients.iter() {
-        tx.send(value.to_string()).await.unwrap();
+/// Stamp the events among `outputs` with when the frame they were decoded from was received
+fn stamp_received(outputs: &mut [Output], at: u64) {
+    for output in outputs {
+        if let Output::Event(_, event) = output {
+            event.ts_mut().received = at;
+        }
     }
 }
+
+/// Hand `event` to every client without waiting on any of them. Returns the clients that dropped
+/// their receiver or whose buffer is full.
+fn send_to_clients(clients: &[mpsc::Sender<Event>], mut event: Event) -> Vec<mpsc::Sender<Event>> {
+    event.ts_mut().dispatched = now_micros();
+    clients
+        .iter()
+        .filter(|tx| tx.try_send(event.clone()).is_err())
+        .cloned()
+        .collect()
+}
 #[derive(Clone)]
 pub struct MyActorHandle {
     sender: mpsc::Sender<ActorMessage>,
+    stats: Arc<FeedStats>,
+    exchange: Exchange,
+    capabilities: Capabilities,
+    state: watch::Receiver<ConnectionState>,
+    /// Clients of the streams taken through [`Adapter::subscribe`], to release them by request.
+    /// Weak, so a stream ends as soon as the actor drops its client, and its entry goes with the
+    /// next subscription.
+    streams: Arc<Mutex<HashMap<SocketRequest, Vec<mpsc::WeakSender<Event>>>>>,
 }
 
 impl MyActorHandle {
-    pub async fn new() -> Self {
+    pub fn new() -> Self {
+        Self::with_keepalive(KeepaliveConfig::for_exchange(Exchange::Okx))
+    }
+
+    pub fn with_keepalive(keepalive: KeepaliveConfig) -> Self {
+        Self::connect(OKX_PUBLIC_URL, keepalive)
+    }
+
+    /// Run an actor against `ws_url`, the OKX public endpoint or anything speaking its protocol
+    pub fn connect(ws_url: &str, keepalive: KeepaliveConfig) -> Self {
+        Self::with_protocol(OkxProtocol::new(ws_url), keepalive)
+    }
+
+    /// Run an actor for any exchange
+    pub fn with_protocol<P: Protocol>(protocol: P, keepalive: KeepaliveConfig) -> Self {
+        Self::spawn(protocol, keepalive, Backoff::default())
+    }
+
+    fn spawn<P: Protocol>(protocol: P, keepalive: KeepaliveConfig, backoff: Backoff) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let actor = MyActor::new(receiver, "wss://ws.okx.com:8443/ws/v5/public").await;
+        let stats = Arc::new(FeedStats::default());
+        let (state_tx, state) = watch::channel(ConnectionState::Connecting);
+        let exchange = protocol.exchange();
+        let capabilities = protocol.capabilities();
+        let mut actor = MyActor::new(receiver, protocol, stats.clone(), keepalive, state_tx);
+        actor.backoff = backoff;
         tokio::spawn(run_my_actor(actor));
 
-        Self { sender }
+        Self {
+            sender,
+            stats,
+            exchange,
+            capabilities,
+            state,
+            streams: Arc::default(),
+        }
+    }
+
+    /// Stream `request` into `client`. Resolves once the exchange confirms the subscription, or
+    /// with [`SubscribeError::Timeout`] if it doesn't answer in time.
+    pub async fn subscribe(
+        &self,
+        request: SocketRequest,
+        client: mpsc::Sender<Event>,
+    ) -> Result<(), SubscribeError> {
+        answered(self.send_sub(request, client).await?).await
+    }
+
+    /// Hand the subscription to the actor, the receiver gets the exchange's answer
+    async fn send_sub(
+        &self,
+        request: SocketRequest,
+        client: mpsc::Sender<Event>,
+    ) -> Result<oneshot::Receiver<Result<(), SubscribeError>>, SubscribeError> {
+        let (ack, answer) = oneshot::channel();
+        let msg = ActorMessage::Sub {
+            request,
+            respond_to: client,
+            ack,
+        };
+        self.sender
+            .send(msg)
+            .await
+            .map_err(|_| SubscribeError::Closed)?;
+        Ok(answer)
+    }
+
+    /// Stop streaming `request` into `client`, the channel it subscribed with
+    pub async fn unsubscribe(
+        &self,
+        request: SocketRequest,
+        client: mpsc::Sender<Event>,
+    ) -> Result<(), SubscribeError> {
+        let (ack, answer) = oneshot::channel();
+        let msg = ActorMessage::Unsub {
+            request,
+            respond_to: client,
+            ack,
+        };
+        self.sender
+            .send(msg)
+            .await
+            .map_err(|_| SubscribeError::Closed)?;
+        answer.await.unwrap_or(Err(SubscribeError::Closed))
+    }
+
+    /// Sequence and checksum counters of this adapter's feed
+    pub fn stats(&self) -> FeedCounts {
+        self.stats.counts()
     }
 }
 
+/// An actor on the OKX public endpoint, spawned on the current runtime
+impl Default for MyActorHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every clone of a handle shares its streams, and shutting one down stops the actor for all of
+/// them
+#[async_trait]
+impl Adapter for MyActorHandle {
+    fn exchange(&self) -> Exchange {
+        self.exchange
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities.clone()
+    }
+
+    async fn request(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<Subscribing, SubscribeError> {
+        self.capabilities.check(data_type, "")?;
+        let request = SocketRequest { symbol, data_type };
+        let (client, events) = mpsc::channel(STREAM_CAPACITY);
+        let answer = self.send_sub(request, client.clone()).await?;
+        let streams = self.streams.clone();
+        Ok(Box::pin(async move {
+            answered(answer).await?;
+            let mut streams = streams.lock().unwrap();
+            streams.retain(|_, clients| {
+                clients.retain(|client| client.upgrade().is_some());
+                !clients.is_empty()
+            });
+            streams.entry(request).or_default().push(client.downgrade());
+            Ok(event_stream(events))
+        }))
+    }
+
+    async fn unsubscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<(), SubscribeError> {
+        let request = SocketRequest { symbol, data_type };
+        let clients = self.streams.lock().unwrap().remove(&request);
+        // Those the actor dropped already are released
+        for client in clients.into_iter().flatten().filter_map(|c| c.upgrade()) {
+            MyActorHandle::unsubscribe(self, request, client).await?;
+        }
+        Ok(())
+    }
+
+    fn connection(&self) -> watch::Receiver<ConnectionState> {
+        self.state.clone()
+    }
+
+    async fn shutdown(&mut self) {
+        self.streams.lock().unwrap().clear();
+        let (done, closed) = oneshot::channel();
+        if self
+            .sender
+            .send(ActorMessage::Shutdown { done })
+            .await
+            .is_ok()
+        {
+            let _ = closed.await;
+        }
+    }
+}
+
+#[cfg(test)]
+fn trades(symbol: &str) -> SocketRequest {
+    SocketRequest {
+        symbol: symbol.into(),
+        data_type: DataTypes::Trade,
+    }
+}
+
+#[cfg(test)]
+pub(crate) async fn next_event(events: &mut mpsc::Receiver<Event>) -> Event {
+    let event = time::timeout(std::time::Duration::from_secs(5), events.recv()).await;
+    event.expect("no event within 5s").expect("actor stopped")
+}
+
 #[tokio::test]
 async fn actor_test() {
-    let handle = MyActorHandle::new().await;
+    let exchange = MockExchange::start().await;
+    let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+    let handle = MyActorHandle::connect(exchange.url(), keepalive);
 
     let (send, mut recv) = mpsc::channel(400);
-    let request = SocketRequest {
+    for symbol in ["BTC-USDT", "ETH-USDT"] {
+        handle
+            .subscribe(trades(symbol), send.clone())
+            .await
+            .unwrap();
+    }
+
+    exchange.trade("ETH-USDT", "1850.5", "2", "buy");
+    exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
+    for symbol in ["ETH-USDT", "BTC-USDT"] {
+        let event = next_event(&mut recv).await;
+        assert!(
+            matches!(&event, Event::Trade(t) if t.symbol == symbol),
+            "{event:?}"
+        );
+        // Stamped on the way through
+        let ts = event.ts();
+        assert!(ts.exchange.is_some() && ts.received > 0 && ts.dispatched >= ts.received);
+    }
+
+    let book = SocketRequest {
         symbol: "BTC-USDT".into(),
-        data_type: DataTypes::Trade,
+        data_type: DataTypes::Book,
     };
+    handle.subscribe(book, send).await.unwrap();
+    exchange.book("snapshot", "BTC-USDT", &[("30461", "2")], &[("30460", "1")]);
+    exchange.book("update", "BTC-USDT", &[("30461", "0")], &[]);
+    assert!(
+        matches!(next_event(&mut recv).await, Event::OrderbookSnapshot(s) if s.levels.len() == 2)
+    );
+    assert!(
+        matches!(next_event(&mut recv).await, Event::OrderbookUpdate(u) if u.quantity.is_zero())
+    );
+    assert_eq!(handle.stats(), FeedCounts::default());
+}
 
-    let _ = handle
-        .sender
-        .send(ActorMessage::Sub {
-            respond_to: send.clone(),
-            request,
-        })
-        .await;
+#[tokio::test]
+async fn lagging_client_test() {
+    let exchange = MockExchange::start().await;
+    let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+    let handle = MyActorHandle::connect(exchange.url(), keepalive);
 
-    let request = SocketRequest {
-        symbol: "ETH-USDT".into(),
-        data_type: DataTypes::Trade,
-    };
+    let (slow_tx, mut slow_rx) = mpsc::channel(1);
+    let (fast_tx, mut fast_rx) = mpsc::channel(16);
+    handle.subscribe(trades("BTC-USDT"), slow_tx).await.unwrap();
+    handle.subscribe(trades("BTC-USDT"), fast_tx).await.unwrap();
 
-    let _ = handle
-        .sender
-        .send(ActorMessage::Sub {
-            respond_to: send,
-            request,
-        })
-        .await;
+    for price in ["30460.1", "30460.2", "30460.3"] {
+        exchange.trade("BTC-USDT", price, "0.5", "sell");
+    }
+    // The client that doesn't keep up loses its stream, the other one never waits on it
+    for price in ["30460.1", "30460.2", "30460.3"] {
+        let event = next_event(&mut fast_rx).await;
+        assert!(
+            matches!(&event, Event::Trade(t) if t.price.to_string() == price),
+            "{event:?}"
+        );
+    }
+    assert!(matches!(next_event(&mut slow_rx).await, Event::Trade(_)));
+    // and is told so once it made room, rather than going quiet
+    let lagged = next_event(&mut slow_rx).await;
+    assert!(
+        matches!(&lagged, Event::Lagged(l) if l.symbol == "BTC-USDT" && l.data_type == DataTypes::Trade),
+        "{lagged:?}"
+    );
+    assert!(slow_rx.recv().await.is_none());
+}
 
-    while let Some(t) = recv.recv().await {
-        // println!("Received: {}", t);
+#[tokio::test]
+async fn lagging_stream_test() {
+    let exchange = MockExchange::start().await;
+    let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+    let mut handle = MyActorHandle::connect(exchange.url(), keepalive);
+    let mut stream = Adapter::subscribe(&mut handle, DataTypes::Trade, "BTC-USDT".into())
+        .await
+        .unwrap();
+
+    // One push with more trades than the stream buffers
+    let trade = serde_json::json!({
+        "instId": "BTC-USDT",
+        "tradeId": "426790906",
+        "px": "30460.1",
+        "sz": "0.5",
+        "side": "sell",
+        "ts": "1688085963425"
+    });
+    let push = serde_json::json!({
+        "arg": {"channel": "trades", "instId": "BTC-USDT"},
+        "data": vec![trade; STREAM_CAPACITY + 1],
+    });
+    exchange.push(push.to_string());
+    // Once the actor drops the stream's client nothing else keeps it open
+    loop {
+        let event = time::timeout(std::time::Duration::from_secs(5), stream.next()).await;
+        match event.expect("no event within 5s") {
+            Some(Event::Trade(_)) => {}
+            Some(Event::Lagged(_)) => break,
+            other => panic!("{other:?}"),
+        }
     }
+    let end = time::timeout(std::time::Duration::from_secs(5), stream.next()).await;
+    assert!(matches!(end, Ok(None)), "{end:?}");
+
+    // and its entry goes with the next subscription
+    let _eth = Adapter::subscribe(&mut handle, DataTypes::Trade, "ETH-USDT".into())
+        .await
+        .unwrap();
+    let streams = handle.streams.lock().unwrap();
+    assert_eq!(streams.keys().collect::<Vec<_>>(), [&trades("ETH-USDT")]);
+}
+
+#[tokio::test]
+async fn reconnect_test() {
+    use std::time::Duration;
+
+    let exchange = MockExchange::start().await;
+    let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+    let backoff = Backoff::new(Duration::from_millis(10), Duration::from_millis(50));
+    let handle = MyActorHandle::spawn(OkxProtocol::new(exchange.url()), keepalive, backoff);
+
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    handle
+        .subscribe(trades("BTC-USDT"), client_tx)
+        .await
+        .unwrap();
+
+    exchange.disconnect();
+    let status = next_event(&mut client_rx).await;
+    assert!(matches!(status, Event::Connection(c) if !c.connected));
+    let status = next_event(&mut client_rx).await;
+    assert!(matches!(status, Event::Connection(c) if c.connected));
+
+    // The redial replays the subscription
+    let ops = exchange.ops(2).await;
+    assert_eq!(ops[0], ops[1]);
+    assert_eq!(exchange.connections(), 2);
+
+    exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
+    assert!(matches!(next_event(&mut client_rx).await, Event::Trade(_)));
+}
+
+#[tokio::test]
+async fn keepalive_test() {
+    use std::time::Duration;
+
+    let exchange = MockExchange::start().await;
+    exchange.silence();
+    let keepalive = KeepaliveConfig {
+        ping_interval: Duration::from_millis(50),
+        pong_timeout: Duration::from_millis(50),
+        stale_after: None,
+        ..KeepaliveConfig::for_exchange(Exchange::Okx)
+    };
+    let handle = MyActorHandle::connect(exchange.url(), keepalive);
+
+    let (client_tx, mut client_rx) = mpsc::channel(16);
+    handle
+        .subscribe(trades("BTC-USDT"), client_tx)
+        .await
+        .unwrap();
+
+    // Never answering the ping drops the connection
+    let status = next_event(&mut client_rx).await;
+    assert!(matches!(status, Event::Connection(c) if !c.connected));
+}
+
+#[tokio::test]
+async fn ref_count_test() {
+    let exchange = MockExchange::start().await;
+    let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+    let handle = MyActorHandle::connect(exchange.url(), keepalive);
+
+    let (first_tx, _first_rx) = mpsc::channel(16);
+    let (second_tx, second_rx) = mpsc::channel(16);
+    handle
+        .subscribe(trades("BTC-USDT"), first_tx.clone())
+        .await
+        .unwrap();
+    handle
+        .subscribe(trades("BTC-USDT"), second_tx)
+        .await
+        .unwrap();
+    handle
+        .unsubscribe(trades("BTC-USDT"), first_tx)
+        .await
+        .unwrap();
+    // Two subscribers, one upstream subscribe
+    assert_eq!(exchange.recorded().len(), 1);
+
+    // The second subscriber going away releases the channel once a push finds it closed
+    drop(second_rx);
+    exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
+    let ops = exchange.ops(2).await;
+    assert_eq!(ops[1]["op"], "unsubscribe");
+    assert_eq!(ops[1]["args"][0]["instId"], "BTC-USDT");
+}
+
+#[tokio::test]
+async fn ack_test() {
+    use std::time::Duration;
+
+    let exchange = MockExchange::start().await;
+    exchange.reject("NOPE-USDT");
+    exchange.set_delay(Duration::from_millis(20));
+    let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+    let handle = MyActorHandle::connect(exchange.url(), keepalive);
+
+    let (client, _client_rx) = mpsc::channel(16);
+    let subscribe = handle.subscribe(trades("BTC-USDT"), client.clone());
+    let rejected = handle.subscribe(trades("NOPE-USDT"), client.clone());
+    let (subscribe, rejected) = tokio::join!(subscribe, rejected);
+    assert_eq!(subscribe, Ok(()));
+    assert!(
+        matches!(&rejected, Err(SubscribeError::Exchange { code, .. }) if code == "60018"),
+        "{rejected:?}"
+    );
+
+    // A second subscriber shares the confirmed stream, no request goes upstream
+    let (other, _other_rx) = mpsc::channel(16);
+    let shared = handle.subscribe(trades("BTC-USDT"), other.clone()).await;
+    assert_eq!(shared, Ok(()));
+    let released = handle.unsubscribe(trades("BTC-USDT"), other).await;
+    assert_eq!(released, Ok(()));
+    assert_eq!(exchange.recorded().len(), 2);
+
+    let unsubscribe = handle.unsubscribe(trades("BTC-USDT"), client).await;
+    assert_eq!(unsubscribe, Ok(()));
+    assert_eq!(exchange.ops(3).await[2]["op"], "unsubscribe");
 }
 
-#[derive(Hash, Eq, PartialEq, Clone, Debug, Default)]
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug, Default)]
 pub struct SocketRequest {
-    symbol: String,
-    data_type: DataTypes,
+    pub symbol: Symbol,
+    pub data_type: DataTypes,
 }
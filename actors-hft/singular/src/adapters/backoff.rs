@@ -0,0 +1,69 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// Capped exponential backoff with jitter for reconnecting to an exchange
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_millis(500), Duration::from_secs(30))
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Delay before the next attempt. Doubles every attempt up to `max`, the second half of the
+    /// delay is random so adapters that dropped together don't all redial at once.
+    pub fn next_delay(&mut self) -> Duration {
+        let capped = self
+            .base
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = capped / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// Call once a connection succeeds
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1));
+
+        for expected in [100, 200, 400, 800, 1000, 1000] {
+            let delay = backoff.next_delay();
+            let expected = Duration::from_millis(expected);
+            assert!(delay >= expected / 2 && delay <= expected, "{delay:?}");
+        }
+
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,495 @@
+use std::{collections::HashMap, mem, sync::Arc, time::Duration};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol, SnapshotFuture},
+        sequence::{FeedStats, Sequence, SequenceTracker},
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{Event, Resync, Timestamps},
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::binance::{
+        BinanceData, BinanceFrame, BinanceReply, BinanceStream, DepthSnapshotRaw, DepthUpdateRaw,
+    },
+};
+
+/// USDⓈ-M futures combined stream endpoint
+pub const BINANCE_USDM_URL: &str = "wss://fstream.binance.com/stream";
+/// USDⓈ-M futures REST base, the depth snapshot is fetched from `{base}/depth`
+pub const BINANCE_USDM_REST: &str = "https://fapi.binance.com/fapi/v1";
+/// COIN-M futures combined stream endpoint
+pub const BINANCE_COINM_URL: &str = "wss://dstream.binance.com/stream";
+/// COIN-M futures REST base
+pub const BINANCE_COINM_REST: &str = "https://dapi.binance.com/dapi/v1";
+
+/// Binance closes every connection after 24 hours. Moving to a new one well before keeps the
+/// streams going without a disconnect.
+const MAX_AGE: Duration = Duration::from_secs(23 * 60 * 60);
+
+/// Levels asked of the REST depth snapshot
+pub(crate) const SNAPSHOT_DEPTH: usize = 1000;
+
+/// Where a `depth` stream is in Binance's book sync
+#[derive(Debug)]
+enum DepthSync {
+    /// Waiting for the REST snapshot, diffs are kept until it arrives
+    Buffering {
+        diffs: Vec<DepthUpdateRaw>,
+        fetching: bool,
+    },
+    /// The snapshot at `last_update_id` is out, the first diff applied must straddle it
+    Snapshot { last_update_id: i64 },
+    /// Every diff has to follow the last one by `pu`
+    Streaming,
+}
+
+impl Default for DepthSync {
+    fn default() -> Self {
+        DepthSync::Buffering {
+            diffs: Vec::new(),
+            fetching: false,
+        }
+    }
+}
+
+/// The combined stream websocket of Binance USDⓈ-M or COIN-M futures. Trades come from
+/// `aggTrade`, best bid and offer from `bookTicker` and books from `depth@100ms` diffs applied on
+/// top of a REST snapshot.
+pub struct BinanceProtocol {
+    exchange: Exchange,
+    url: String,
+    rest_url: String,
+    max_age: Duration,
+    client: reqwest::Client,
+    next_id: u64,
+    /// Requests waiting on their answer, by id
+    ids: HashMap<u64, (Op, SocketRequest)>,
+    /// Request of every subscribed stream name
+    streams: HashMap<String, SocketRequest>,
+    /// Sync state of every `depth` stream, by symbol
+    books: HashMap<Symbol, DepthSync>,
+    sequences: SequenceTracker<Symbol>,
+    stats: Arc<FeedStats>,
+}
+
+impl BinanceProtocol {
+    pub fn usdm() -> Self {
+        Self::new(Exchange::BinanceUsdm, BINANCE_USDM_URL, BINANCE_USDM_REST)
+    }
+
+    pub fn coinm() -> Self {
+        Self::new(
+            Exchange::BinanceCoinm,
+            BINANCE_COINM_URL,
+            BINANCE_COINM_REST,
+        )
+    }
+
+    /// Stream from `url` and fetch depth snapshots from `rest_url`, the `fapi/v1` or `dapi/v1`
+    /// base of the same market
+    pub fn new(exchange: Exchange, url: &str, rest_url: &str) -> Self {
+        let stats = Arc::new(FeedStats::default());
+        Self {
+            exchange,
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+            max_age: MAX_AGE,
+            client: reqwest::Client::new(),
+            next_id: 0,
+            ids: HashMap::new(),
+            streams: HashMap::new(),
+            books: HashMap::new(),
+            sequences: SequenceTracker::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// Move to a new connection after `max_age` instead of 23 hours
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Stream name of a request, `btcusdt@aggTrade`
+    fn stream(request: &SocketRequest) -> String {
+        let symbol = request.symbol.to_lowercase();
+        match request.data_type {
+            DataTypes::Trade => format!("{symbol}@aggTrade"),
+            DataTypes::Book => format!("{symbol}@depth@100ms"),
+            DataTypes::Bbo => format!("{symbol}@bookTicker"),
+        }
+    }
+
+    fn data(&mut self, raw: BinanceStream, outputs: &mut Vec<Output>) {
+        let Some(request) = self.streams.get(&raw.stream).cloned() else {
+            return;
+        };
+        let event = match raw.data {
+            BinanceData::AggTrade(trade) => Event::Trade(trade.into_trade(self.exchange)),
+            BinanceData::BookTicker(ticker) => ticker.into_event(self.exchange),
+            BinanceData::Depth(diff) => return self.depth(&request, diff, outputs),
+        };
+        outputs.push(Output::Event(request, event));
+    }
+
+    fn answer(&mut self, reply: BinanceReply, outputs: &mut Vec<Output>) {
+        let request = reply.id.and_then(|id| self.ids.remove(&id));
+        match (reply.error(), request) {
+            (None, Some((op, request))) => outputs.push(Output::Acked(op, request)),
+            (Some((code, message)), request) => {
+                let error = SubscribeError::Exchange { code, message };
+                outputs.push(Output::Rejected(request, error));
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// Apply a diff once the book is synced, buffer it until then. Diffs older than the snapshot
+    /// are dropped, one that doesn't follow the last applied diff restarts the sync.
+    fn depth(&mut self, request: &SocketRequest, diff: DepthUpdateRaw, outputs: &mut Vec<Output>) {
+        let Some(sync) = self.books.get_mut(&request.symbol) else {
+            return;
+        };
+
+        let synced = match sync {
+            DepthSync::Buffering { diffs, fetching } => {
+                diffs.push(diff);
+                if !mem::replace(fetching, true) {
+                    outputs.push(Output::Fetch(*request));
+                }
+                return;
+            }
+            DepthSync::Snapshot { last_update_id } => {
+                let last_update_id = *last_update_id;
+                if diff.final_update_id < last_update_id {
+                    return;
+                }
+                if diff.first_update_id > last_update_id {
+                    FeedStats::incr(&self.stats.gaps);
+                    Err(format!(
+                        "Gap between the snapshot at {last_update_id} and the diff from {}",
+                        diff.first_update_id
+                    ))
+                } else {
+                    self.sequences.check(
+                        request.symbol,
+                        diff.final_update_id,
+                        Some(diff.prev_final_update_id),
+                        true,
+                    );
+                    *sync = DepthSync::Streaming;
+                    Ok(())
+                }
+            }
+            DepthSync::Streaming => match self.sequences.check(
+                request.symbol,
+                diff.final_update_id,
+                Some(diff.prev_final_update_id),
+                false,
+            ) {
+                Sequence::InOrder => Ok(()),
+                Sequence::Duplicate | Sequence::OutOfOrder => return,
+                sequence => Err(format!("{sequence:?}")),
+            },
+        };
+
+        match synced {
+            Ok(()) => {
+                let events = diff.into_events(self.exchange);
+                outputs.extend(
+                    events
+                        .into_iter()
+                        .map(|event| Output::Event(*request, event)),
+                );
+            }
+            Err(reason) => self.resync(request, diff, reason, outputs),
+        }
+    }
+
+    /// Drop the book and fetch a new snapshot, starting over with `diff`
+    fn resync(
+        &mut self,
+        request: &SocketRequest,
+        diff: DepthUpdateRaw,
+        reason: String,
+        outputs: &mut Vec<Output>,
+    ) {
+        FeedStats::incr(&self.stats.resyncs);
+        self.sequences.reset(&request.symbol);
+        self.books.insert(
+            request.symbol,
+            DepthSync::Buffering {
+                diffs: vec![diff],
+                fetching: true,
+            },
+        );
+
+        let event = Event::Resync(Resync {
+            exchange: self.exchange,
+            symbol: request.symbol,
+            reason: format!("{reason} on depth"),
+            ts: Timestamps::default(),
+        });
+        outputs.extend([Output::Event(*request, event), Output::Fetch(*request)]);
+    }
+}
+
+impl Protocol for BinanceProtocol {
+    fn exchange(&self) -> Exchange {
+        self.exchange
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.sequences = SequenceTracker::new(stats.clone());
+        self.stats = stats;
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let stream = Self::stream(request);
+        let method = match op {
+            Op::Subscribe => {
+                self.streams.insert(stream.clone(), *request);
+                if request.data_type == DataTypes::Book {
+                    self.books.insert(request.symbol, DepthSync::default());
+                }
+                "SUBSCRIBE"
+            }
+            Op::Unsubscribe => {
+                self.streams.remove(&stream);
+                if request.data_type == DataTypes::Book {
+                    self.books.remove(&request.symbol);
+                    self.sequences.reset(&request.symbol);
+                }
+                "UNSUBSCRIBE"
+            }
+        };
+
+        self.next_id += 1;
+        self.ids.insert(self.next_id, (op, *request));
+        Ok(serde_json::json!({
+            "method": method,
+            "params": [stream],
+            "id": self.next_id,
+        })
+        .to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        match serde_json::from_str::<BinanceFrame>(frame) {
+            Ok(BinanceFrame::Data(data)) => self.data(data, outputs),
+            Ok(BinanceFrame::Reply(reply)) => self.answer(reply, outputs),
+            Err(_) => {}
+        }
+    }
+
+    /// Every book syncs again from a new snapshot, the diffs of two connections don't chain
+    fn reset(&mut self) {
+        self.ids.clear();
+        self.books
+            .values_mut()
+            .for_each(|sync| *sync = DepthSync::default());
+        self.sequences.clear();
+    }
+
+    fn max_age(&self) -> Option<Duration> {
+        Some(self.max_age)
+    }
+
+    fn snapshot(&self, request: &SocketRequest) -> Option<SnapshotFuture> {
+        if request.data_type != DataTypes::Book {
+            return None;
+        }
+
+        let url = format!(
+            "{}/depth?symbol={}&limit={SNAPSHOT_DEPTH}",
+            self.rest_url,
+            request.symbol.to_uppercase()
+        );
+        let client = self.client.clone();
+        Some(Box::pin(async move {
+            let response = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?;
+            response.text().await.map_err(|e| e.to_string())
+        }))
+    }
+
+    /// Publish the snapshot and apply the buffered diffs on top of it. A failed fetch is retried
+    /// with the next diff.
+    fn snapshot_fetched(
+        &mut self,
+        request: &SocketRequest,
+        body: Result<String, String>,
+        outputs: &mut Vec<Output>,
+    ) {
+        let Some(DepthSync::Buffering {
+            diffs,
+            fetching: true,
+        }) = self.books.get_mut(&request.symbol)
+        else {
+            return;
+        };
+        let diffs = mem::take(diffs);
+
+        let snapshot = body.and_then(|body| {
+            serde_json::from_str::<DepthSnapshotRaw>(&body).map_err(|e| e.to_string())
+        });
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!(
+                    "BinanceProtocol: depth snapshot of {} failed: {e}",
+                    request.symbol
+                );
+                self.books.insert(request.symbol, DepthSync::default());
+                return;
+            }
+        };
+
+        let last_update_id = snapshot.last_update_id;
+        self.books
+            .insert(request.symbol, DepthSync::Snapshot { last_update_id });
+        let symbol = Symbol::canonical(self.exchange, &request.symbol, str::to_uppercase);
+        let event = snapshot.into_event(self.exchange, symbol);
+        outputs.push(Output::Event(*request, event));
+        for diff in diffs {
+            self.depth(request, diff, outputs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        event::TradeId,
+        mock::{connect, decoded, request, MockExchange},
+        models::{Decimal, Side},
+    };
+
+    fn usdm(exchange: &MockExchange) -> BinanceProtocol {
+        BinanceProtocol::new(Exchange::BinanceUsdm, exchange.url(), exchange.rest_url())
+    }
+
+    #[test]
+    fn test_answers_by_id() {
+        let mut binance = BinanceProtocol::usdm();
+        let btc = request("BTCUSDT", DataTypes::Trade);
+        let nope = request("NOPEUSDT", DataTypes::Bbo);
+        let subscribe = binance.request(Op::Subscribe, &btc).unwrap();
+        let subscribe: serde_json::Value = serde_json::from_str(&subscribe).unwrap();
+        assert_eq!(subscribe["params"][0], "btcusdt@aggTrade");
+        binance.request(Op::Subscribe, &nope).unwrap();
+
+        // Answered out of order, each by the id of its request
+        let rejected = decoded(
+            &mut binance,
+            r#"{"error":{"code":-1121,"msg":"Invalid symbol."},"id":2}"#,
+        );
+        assert!(
+            matches!(&rejected[..], [Output::Rejected(Some((Op::Subscribe, r)), SubscribeError::Exchange { code, .. })] if *r == nope && code == "-1121"),
+            "{rejected:?}"
+        );
+        let acked = decoded(&mut binance, r#"{"result":null,"id":1}"#);
+        assert!(matches!(acked[..], [Output::Acked(Op::Subscribe, r)] if r == btc));
+        assert!(binance.ids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_agg_trade() {
+        let exchange = MockExchange::binance().await;
+        let handle = connect(usdm(&exchange));
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("BTCUSDT", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+
+        // `m` says the buyer made the market, so the taker sold
+        exchange.agg_trade("BTCUSDT", "30460.1", "0.5", true);
+        exchange.agg_trade("BTCUSDT", "30460.2", "0.1", false);
+        for side in [Side::SELL, Side::BUY] {
+            let trade = next_event(&mut events).await;
+            assert!(
+                matches!(&trade, Event::Trade(t) if t.side as u8 == side as u8 && t.trade_id == TradeId::Number(1861244612)),
+                "{trade:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_book_sync() {
+        let exchange = MockExchange::binance().await;
+        exchange.depth_snapshot("BTCUSDT", 100, &[("30461", "2")], &[("30460", "1")]);
+        let handle = connect(usdm(&exchange));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("BTCUSDT", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        // Older than the snapshot, straddling it, then chained by `pu`
+        exchange.depth("BTCUSDT", (90, 95, 89), &[("30462", "1")], &[]);
+        exchange.depth("BTCUSDT", (96, 104, 95), &[("30461", "0")], &[]);
+        exchange.depth("BTCUSDT", (105, 110, 104), &[], &[("30459", "3")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let ask = next_event(&mut events).await;
+        assert!(
+            matches!(&ask, Event::OrderbookUpdate(u) if u.price == "30461.0".parse::<Decimal>().unwrap() && u.quantity.is_zero())
+        );
+        let bid = next_event(&mut events).await;
+        assert!(
+            matches!(&bid, Event::OrderbookUpdate(u) if u.price == "30459.0".parse::<Decimal>().unwrap())
+        );
+
+        // A diff that doesn't follow the last one syncs from a new snapshot
+        exchange.depth_snapshot("BTCUSDT", 200, &[("30470", "1")], &[("30465", "1")]);
+        exchange.depth("BTCUSDT", (150, 160, 140), &[("30463", "1")], &[]);
+        exchange.depth("BTCUSDT", (195, 205, 160), &[("30470", "4")], &[]);
+        assert!(matches!(next_event(&mut events).await, Event::Resync(_)));
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels[0].price == "30470.0".parse::<Decimal>().unwrap()),
+            "{snapshot:?}"
+        );
+        let ask = next_event(&mut events).await;
+        assert!(
+            matches!(&ask, Event::OrderbookUpdate(u) if u.quantity == "4.0".parse::<Decimal>().unwrap())
+        );
+
+        let stats = handle.stats();
+        assert_eq!((stats.gaps, stats.resyncs), (1, 1));
+        assert_eq!(exchange.fetches(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let exchange = MockExchange::binance().await;
+        let protocol = usdm(&exchange).with_max_age(Duration::from_millis(300));
+        let handle = connect(protocol);
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("BTCUSDT", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+
+        // The replacement connection replays the subscription before the old one is closed
+        exchange.ops(2).await;
+        assert!(exchange.connections() >= 2);
+        exchange.agg_trade("BTCUSDT", "30460.1", "0.5", false);
+        let trade = next_event(&mut events).await;
+        assert!(matches!(&trade, Event::Trade(_)), "{trade:?}");
+    }
+}
@@ -0,0 +1,474 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol},
+        sequence::FeedStats,
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{ConnectionStatus, Event, Resync, Timestamps},
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::bitfinex::{
+        pair, trading_symbol, BitfinexBook, BitfinexEvent, BookRaw, ChannelData, ChannelFrame,
+        TickerRaw,
+    },
+};
+
+/// Bitfinex public websocket, v2
+pub const BITFINEX_URL: &str = "wss://api-pub.bitfinex.com/ws/2";
+
+/// `conf` flag asking for a `cs` checksum frame after every book update
+const FLAG_CHECKSUM: u64 = 131072;
+
+/// Levels per side of every book subscription, enough for the 25 level checksum
+pub(crate) const BOOK_LENGTH: usize = 25;
+
+/// Info codes the exchange sends before going away
+const INFO_RECONNECT: i64 = 20051;
+const INFO_MAINTENANCE_START: i64 = 20060;
+const INFO_MAINTENANCE_END: i64 = 20061;
+
+/// The Bitfinex v2 websocket. Every subscription gets a numeric `chanId` and its data arrives as
+/// `[chanId, ..]`, so channels are routed by that id. Trades come from `trades`, best bid and
+/// offer from `ticker` and books from raw P0 `book` channels, checked against the `cs` frames the
+/// `conf` handshake turns on.
+#[derive(Debug)]
+pub struct BitfinexProtocol {
+    url: String,
+    /// Subscribes waiting on their answer, by channel and trading symbol
+    requests: HashMap<(&'static str, String), SocketRequest>,
+    /// Channel and request of every subscribed `chanId`
+    channels: HashMap<u64, (&'static str, SocketRequest)>,
+    /// Unsubscribes waiting on their answer, by `chanId`
+    closing: HashMap<u64, SocketRequest>,
+    /// Book of every `book` channel
+    books: HashMap<u64, BitfinexBook>,
+    /// Last trade id of every `trades` channel. A trade arrives as `te` and again as `tu`, only
+    /// the first one is passed on.
+    last_trades: HashMap<u64, i64>,
+    stats: Arc<FeedStats>,
+}
+
+impl BitfinexProtocol {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            requests: HashMap::new(),
+            channels: HashMap::new(),
+            closing: HashMap::new(),
+            books: HashMap::new(),
+            last_trades: HashMap::new(),
+            stats: Arc::default(),
+        }
+    }
+
+    fn channel(data_type: DataTypes) -> &'static str {
+        match data_type {
+            DataTypes::Trade => "trades",
+            DataTypes::Book => "book",
+            DataTypes::Bbo => "ticker",
+        }
+    }
+
+    fn channel_named(name: &str) -> Option<&'static str> {
+        ["trades", "book", "ticker"]
+            .into_iter()
+            .find(|channel| *channel == name)
+    }
+
+    /// Answer to a subscribe, an unsubscribe, or a notice from the exchange
+    fn event(&mut self, event: BitfinexEvent, outputs: &mut Vec<Output>) {
+        match event.event.as_str() {
+            "subscribed" => {
+                let (Some(chan_id), Some(channel), Some(symbol)) = (
+                    event.chan_id,
+                    event.channel.as_deref().and_then(Self::channel_named),
+                    event.symbol,
+                ) else {
+                    return;
+                };
+                let Some(request) = self.requests.remove(&(channel, symbol)) else {
+                    return;
+                };
+                if channel == "book" {
+                    self.books.insert(chan_id, BitfinexBook::default());
+                }
+                self.channels.insert(chan_id, (channel, request));
+                outputs.push(Output::Acked(Op::Subscribe, request));
+            }
+            "unsubscribed" => {
+                let request = event
+                    .chan_id
+                    .and_then(|chan_id| self.closing.remove(&chan_id));
+                outputs.extend(request.map(|request| Output::Acked(Op::Unsubscribe, request)));
+            }
+            "error" => {
+                let request = event
+                    .channel
+                    .as_deref()
+                    .and_then(Self::channel_named)
+                    .zip(event.symbol)
+                    .and_then(|key| self.requests.remove(&key))
+                    .map(|request| (Op::Subscribe, request));
+                let error = SubscribeError::Exchange {
+                    code: event.code.unwrap_or_default().to_string(),
+                    message: event.msg.unwrap_or_default(),
+                };
+                outputs.push(Output::Rejected(request, error));
+            }
+            "info" => self.info(event.code, event.msg.unwrap_or_default(), outputs),
+            // `conf` and `pong`
+            _ => {}
+        }
+    }
+
+    /// Bitfinex asks for a reconnect before restarting a server and warns subscribers ahead of
+    /// maintenance, after which every channel has to be subscribed again
+    fn info(&self, code: Option<i64>, msg: String, outputs: &mut Vec<Output>) {
+        match code {
+            Some(INFO_RECONNECT | INFO_MAINTENANCE_END) => outputs.push(Output::Reconnect(msg)),
+            Some(INFO_MAINTENANCE_START) => {
+                outputs.extend(self.channels.values().map(|(_, request)| {
+                    let event = Event::Connection(ConnectionStatus {
+                        exchange: Exchange::Bitfinex,
+                        symbol: request.symbol,
+                        data_type: request.data_type,
+                        connected: false,
+                        reason: msg.clone(),
+                        ts: Timestamps::default(),
+                    });
+                    Output::Event(*request, event)
+                }))
+            }
+            _ => {}
+        }
+    }
+
+    /// Pair events of a request are named by, `BTCUSD` for `tBTCUSD`
+    fn symbol(request: &SocketRequest) -> Symbol {
+        Symbol::canonical(Exchange::Bitfinex, &request.symbol, |symbol| {
+            pair(&trading_symbol(symbol)).to_string()
+        })
+    }
+
+    fn data(&mut self, frame: ChannelFrame, outputs: &mut Vec<Output>) {
+        let chan_id = frame.chan_id;
+        let Some((channel, request)) = self.channels.get(&chan_id).cloned() else {
+            return;
+        };
+        let symbol = Self::symbol(&request);
+
+        let event = match (channel, frame.data) {
+            (_, ChannelData::Heartbeat) => {
+                FeedStats::incr(&self.stats.heartbeats);
+                return;
+            }
+            ("trades", ChannelData::Executed(trade) | ChannelData::Updated(trade)) => {
+                let last = self.last_trades.entry(chan_id).or_default();
+                if trade.id() <= *last {
+                    return;
+                }
+                *last = trade.id();
+                Event::Trade(trade.into_trade(symbol))
+            }
+            ("ticker", ChannelData::Data(data)) => {
+                let Ok(ticker) = serde_json::from_str::<TickerRaw>(data.get()) else {
+                    return;
+                };
+                ticker.into_event(symbol)
+            }
+            ("book", ChannelData::Data(data)) => {
+                let Ok(raw) = serde_json::from_str::<BookRaw>(data.get()) else {
+                    return;
+                };
+                let Some(book) = self.books.get_mut(&chan_id) else {
+                    return;
+                };
+                if !book.valid && matches!(raw, BookRaw::Update(_)) {
+                    return;
+                }
+                book.apply(&raw);
+                raw.into_event(symbol)
+            }
+            ("book", ChannelData::Checksum(checksum)) => {
+                return self.checksum(chan_id, checksum, request, outputs);
+            }
+            // The snapshot of recent trades sent on subscribe
+            _ => return,
+        };
+        outputs.push(Output::Event(request, event));
+    }
+
+    /// Check a book against a `cs` frame. Only the first mismatch asks for a resync, everything
+    /// after it is dropped until the snapshot of the resubscribe arrives.
+    fn checksum(
+        &mut self,
+        chan_id: u64,
+        checksum: i32,
+        request: SocketRequest,
+        outputs: &mut Vec<Output>,
+    ) {
+        let Some(book) = self.books.get_mut(&chan_id) else {
+            return;
+        };
+        if !book.valid || book.verify(checksum) {
+            return;
+        }
+
+        FeedStats::incr(&self.stats.checksum_mismatches);
+        FeedStats::incr(&self.stats.resyncs);
+        let event = Event::Resync(Resync {
+            exchange: Exchange::Bitfinex,
+            symbol: Self::symbol(&request),
+            reason: "Checksum mismatch on book".to_string(),
+            ts: Timestamps::default(),
+        });
+        outputs.extend([Output::Event(request, event), Output::Resubscribe(request)]);
+    }
+}
+
+impl Protocol for BitfinexProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::Bitfinex
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.stats = stats;
+    }
+
+    fn handshake(&self) -> Vec<String> {
+        vec![serde_json::json!({"event": "conf", "flags": FLAG_CHECKSUM}).to_string()]
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let channel = Self::channel(request.data_type);
+        let symbol = trading_symbol(&request.symbol);
+
+        match op {
+            Op::Subscribe => {
+                let mut frame = serde_json::json!({
+                    "event": "subscribe",
+                    "channel": channel,
+                    "symbol": symbol,
+                });
+                if channel == "book" {
+                    frame["prec"] = "P0".into();
+                    frame["freq"] = "F0".into();
+                    frame["len"] = BOOK_LENGTH.to_string().into();
+                }
+                self.requests.insert((channel, symbol), *request);
+                Ok(frame.to_string())
+            }
+            Op::Unsubscribe => {
+                let chan_id = self
+                    .channels
+                    .iter()
+                    .find(|(_, (_, subscribed))| subscribed == request)
+                    .map(|(chan_id, _)| *chan_id)
+                    .ok_or_else(|| {
+                        SubscribeError::Unsupported(format!(
+                            "Unsubscribing {channel} {symbol} before its chanId"
+                        ))
+                    })?;
+                self.channels.remove(&chan_id);
+                self.books.remove(&chan_id);
+                self.last_trades.remove(&chan_id);
+                self.closing.insert(chan_id, *request);
+                Ok(serde_json::json!({"event": "unsubscribe", "chanId": chan_id}).to_string())
+            }
+        }
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        if let Ok(frame) = serde_json::from_str::<ChannelFrame>(frame) {
+            return self.data(frame, outputs);
+        }
+        if let Ok(event) = serde_json::from_str::<BitfinexEvent>(frame) {
+            self.event(event, outputs);
+        }
+    }
+
+    /// Channel ids only live as long as their connection
+    fn reset(&mut self) {
+        self.requests.clear();
+        self.channels.clear();
+        self.closing.clear();
+        self.books.clear();
+        self.last_trades.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        event::TradeId,
+        mock::{connect, decoded, request, MockExchange},
+        models::{Decimal, Side},
+    };
+
+    #[test]
+    fn test_routing() {
+        let mut bitfinex = BitfinexProtocol::new(BITFINEX_URL);
+        let trades = request("BTC-USD", DataTypes::Trade);
+        let frame = bitfinex.request(Op::Subscribe, &trades).unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["channel"], "trades");
+        assert_eq!(frame["symbol"], "tBTCUSD");
+
+        let error = bitfinex.request(Op::Unsubscribe, &trades);
+        assert!(matches!(error, Err(SubscribeError::Unsupported(_))));
+
+        let acked = decoded(
+            &mut bitfinex,
+            r#"{"event":"subscribed","channel":"trades","chanId":17470,"symbol":"tBTCUSD","pair":"BTCUSD"}"#,
+        );
+        assert!(matches!(&acked[..], [Output::Acked(Op::Subscribe, r)] if *r == trades));
+
+        let outputs = decoded(
+            &mut bitfinex,
+            r#"[17470,"te",[401597395,1574694478808,0.005,7245.3]]"#,
+        );
+        assert!(
+            matches!(&outputs[..], [Output::Event(_, Event::Trade(t))] if t.symbol == "BTCUSD"),
+            "{outputs:?}"
+        );
+        let settled = decoded(
+            &mut bitfinex,
+            r#"[17470,"tu",[401597395,1574694478808,0.005,7245.3]]"#,
+        );
+        assert!(settled.is_empty());
+
+        decoded(&mut bitfinex, r#"[17470,"hb"]"#);
+        assert_eq!(bitfinex.stats.heartbeats.load(Ordering::Relaxed), 1);
+
+        let frame = bitfinex.request(Op::Unsubscribe, &trades).unwrap();
+        assert_eq!(frame, r#"{"chanId":17470,"event":"unsubscribe"}"#);
+        assert!(decoded(&mut bitfinex, r#"[17470,"hb"]"#).is_empty());
+        let acked = decoded(
+            &mut bitfinex,
+            r#"{"event":"unsubscribed","status":"OK","chanId":17470}"#,
+        );
+        assert!(matches!(&acked[..], [Output::Acked(Op::Unsubscribe, _)]));
+    }
+
+    #[tokio::test]
+    async fn test_channel_ids() {
+        let exchange = MockExchange::bitfinex().await;
+        let handle = connect(BitfinexProtocol::new(exchange.url()));
+
+        let (btc, mut btc_events) = mpsc::channel(16);
+        let (eth, mut eth_events) = mpsc::channel(16);
+        let subscribe = handle.subscribe(request("BTC-USD", DataTypes::Trade), btc);
+        subscribe.await.unwrap();
+        let subscribe = handle.subscribe(request("ETH-USD", DataTypes::Trade), eth);
+        subscribe.await.unwrap();
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops[0]["event"], "conf");
+        assert_eq!(ops[0]["flags"], FLAG_CHECKSUM);
+        let eth_chan = exchange.chan_id("trades", "tETHUSD");
+        assert_ne!(exchange.chan_id("trades", "tBTCUSD"), eth_chan);
+
+        // Data only carries the `chanId`, a heartbeat on it is counted and not streamed
+        exchange.push(format!(r#"[{eth_chan},"hb"]"#));
+        exchange.bitfinex_trade("tETHUSD", "te", 1, "2", "1850.1");
+        exchange.bitfinex_trade("tETHUSD", "tu", 1, "2", "1850.1");
+        exchange.bitfinex_trade("tETHUSD", "te", 2, "1", "1850.2");
+        exchange.bitfinex_trade("tBTCUSD", "te", 3, "-0.5", "30460.1");
+        let trade = next_event(&mut btc_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "BTCUSD" && matches!(t.side, Side::SELL) && t.quantity == "0.5".parse::<Decimal>().unwrap()),
+            "{trade:?}"
+        );
+        let trade = next_event(&mut eth_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "ETHUSD"),
+            "{trade:?}"
+        );
+        // The `tu` of a trade already executed is not streamed again
+        let trade = next_event(&mut eth_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.trade_id == TradeId::Number(2)),
+            "{trade:?}"
+        );
+        assert_eq!(handle.stats().heartbeats, 1);
+    }
+
+    #[tokio::test]
+    async fn test_book_checksum() {
+        let exchange = MockExchange::bitfinex().await;
+        let handle = connect(BitfinexProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("BTC-USD", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        exchange.bitfinex_book(
+            "tBTCUSD",
+            true,
+            &[("30460", "2", "1.5"), ("30461", "1", "-2")],
+        );
+        exchange.bitfinex_book("tBTCUSD", false, &[("30461", "0", "-1")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.symbol == "BTCUSD" && s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let removed = next_event(&mut events).await;
+        assert!(matches!(&removed, Event::OrderbookUpdate(u) if u.quantity.is_zero()));
+
+        // A checksum that doesn't match the book resubscribes for a fresh snapshot
+        let chan_id = exchange.chan_id("book", "tBTCUSD");
+        exchange.push(format!(r#"[{chan_id},"cs",1]"#));
+        let resync = next_event(&mut events).await;
+        assert!(
+            matches!(&resync, Event::Resync(r) if r.symbol == "BTCUSD"),
+            "{resync:?}"
+        );
+        let ops = exchange.ops(4).await;
+        assert_eq!(ops[2]["event"], "unsubscribe");
+        assert_eq!(ops[2]["chanId"], chan_id);
+        assert_eq!(ops[3]["event"], "subscribe");
+        assert_eq!(ops[3]["prec"], "P0");
+
+        exchange.bitfinex_book("tBTCUSD", true, &[("30462", "1", "1")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels[0].price == "30462.0".parse::<Decimal>().unwrap()),
+            "{snapshot:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_info_reconnect() {
+        let exchange = MockExchange::bitfinex().await;
+        let handle = connect(BitfinexProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("BTC-USD", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+
+        exchange.push(r#"{"event":"info","code":20060,"msg":"Entering in Maintenance mode"}"#);
+        let status = next_event(&mut events).await;
+        assert!(
+            matches!(&status, Event::Connection(c) if !c.connected),
+            "{status:?}"
+        );
+
+        exchange.push(r#"{"event":"info","code":20051,"msg":"Stopping. Please try to reconnect"}"#);
+        let ops = exchange.ops(4).await;
+        assert_eq!(ops[2]["event"], "conf");
+        assert_eq!(ops[3]["event"], "subscribe");
+        assert_eq!(exchange.connections(), 2);
+    }
+}
@@ -0,0 +1,445 @@
+use std::{collections::HashMap, mem, sync::Arc};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol, SnapshotFuture},
+        sequence::FeedStats,
+        subscriptions::{Op, SubscribeError},
+    },
+    event::Event,
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::bitstamp::{BitstampFrame, ErrorRaw, OrderBookRaw, TradeRaw},
+};
+
+/// Bitstamp websocket, v2
+pub const BITSTAMP_URL: &str = "wss://ws.bitstamp.net";
+/// Bitstamp REST base, the order book snapshot is fetched from `{base}/order_book/{pair}/`
+pub const BITSTAMP_REST: &str = "https://www.bitstamp.net/api/v2";
+
+/// Where a `diff_order_book` channel is in its sync with the REST order book
+#[derive(Debug)]
+enum DiffSync {
+    /// Waiting for the REST snapshot, diffs are kept until it arrives
+    Buffering {
+        diffs: Vec<OrderBookRaw>,
+        fetching: bool,
+    },
+    /// Diffs apply when they are newer than the last one applied, or than the snapshot
+    Streaming { microtimestamp: i64 },
+}
+
+impl Default for DiffSync {
+    fn default() -> Self {
+        DiffSync::Buffering {
+            diffs: Vec::new(),
+            fetching: false,
+        }
+    }
+}
+
+/// The Bitstamp websocket. Trades come from `live_trades`, best bid and offer from the top of
+/// `order_book` and books from `diff_order_book` applied on top of a REST snapshot by
+/// `microtimestamp`. A `bts:request_reconnect` moves the streams to a new connection before the
+/// old one goes away, what both deliver meanwhile is dropped by trade id or `microtimestamp`.
+pub struct BitstampProtocol {
+    url: String,
+    rest_url: String,
+    client: reqwest::Client,
+    /// Requests waiting on their answer, by channel
+    pending: HashMap<String, (Op, SocketRequest)>,
+    /// Request of every subscribed channel
+    streams: HashMap<String, SocketRequest>,
+    /// Sync state of every `diff_order_book` channel, by pair
+    books: HashMap<Symbol, DiffSync>,
+    /// Id of the last trade of every `live_trades` channel, `microtimestamp` of the last top of
+    /// every `order_book` channel
+    last: HashMap<String, u64>,
+    stats: Arc<FeedStats>,
+}
+
+impl BitstampProtocol {
+    /// Stream from `url` and fetch order book snapshots from `rest_url`
+    pub fn new(url: &str, rest_url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            rest_url: rest_url.to_string(),
+            client: reqwest::Client::new(),
+            pending: HashMap::new(),
+            streams: HashMap::new(),
+            books: HashMap::new(),
+            last: HashMap::new(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Bitstamp pair of a request, `BTC/USD` becomes `btcusd`
+    pub fn pair(symbol: &str) -> String {
+        symbol.to_lowercase().replace(['-', '/', '_'], "")
+    }
+
+    /// Pair of a request as events name it, interned the first time it is seen
+    fn symbol(request: &SocketRequest) -> Symbol {
+        Symbol::canonical(Exchange::BitStamp, &request.symbol, Self::pair)
+    }
+
+    fn channel(request: &SocketRequest) -> String {
+        let pair = Self::pair(&request.symbol);
+        match request.data_type {
+            DataTypes::Trade => format!("live_trades_{pair}"),
+            DataTypes::Book => format!("diff_order_book_{pair}"),
+            DataTypes::Bbo => format!("order_book_{pair}"),
+        }
+    }
+
+    fn data(&mut self, frame: BitstampFrame, outputs: &mut Vec<Output>) {
+        let Some(request) = self.streams.get(&frame.channel).cloned() else {
+            return;
+        };
+        let pair = Self::symbol(&request);
+        let event = match request.data_type {
+            DataTypes::Trade => frame
+                .data::<TradeRaw>()
+                .filter(|raw| self.newer(&frame.channel, raw.id))
+                .map(|raw| Event::Trade(raw.into_trade(pair))),
+            DataTypes::Bbo => frame
+                .data::<OrderBookRaw>()
+                .filter(|raw| self.newer(&frame.channel, raw.microtimestamp as u64))
+                .map(|raw| raw.into_bbo(pair)),
+            DataTypes::Book => {
+                if let Some(diff) = frame.data::<OrderBookRaw>() {
+                    self.diff(&request, diff, outputs);
+                }
+                return;
+            }
+        };
+        outputs.extend(event.map(|event| Output::Event(request, event)));
+    }
+
+    /// Whether `mark` is past the last one of `channel`, which it then becomes. Both connections of
+    /// a rotation deliver the same trades and tops of book.
+    fn newer(&mut self, channel: &str, mark: u64) -> bool {
+        match self.last.get_mut(channel) {
+            Some(last) if *last >= mark => false,
+            Some(last) => {
+                *last = mark;
+                true
+            }
+            None => {
+                self.last.insert(channel.to_string(), mark);
+                true
+            }
+        }
+    }
+
+    /// Apply a diff once the book is synced, buffer it until then. Diffs no newer than the last
+    /// one applied are dropped, only older ones count as out of order.
+    fn diff(&mut self, request: &SocketRequest, diff: OrderBookRaw, outputs: &mut Vec<Output>) {
+        let pair = Self::symbol(request);
+        let Some(sync) = self.books.get_mut(&pair) else {
+            return;
+        };
+
+        match sync {
+            DiffSync::Buffering { diffs, fetching } => {
+                diffs.push(diff);
+                if !mem::replace(fetching, true) {
+                    outputs.push(Output::Fetch(*request));
+                }
+            }
+            DiffSync::Streaming { microtimestamp } => {
+                if diff.microtimestamp <= *microtimestamp {
+                    // The same diff on both connections of a rotation
+                    if diff.microtimestamp < *microtimestamp {
+                        FeedStats::incr(&self.stats.out_of_order);
+                    }
+                    return;
+                }
+                *microtimestamp = diff.microtimestamp;
+                let events = diff.into_events(pair, false);
+                outputs.extend(
+                    events
+                        .into_iter()
+                        .map(|event| Output::Event(*request, event)),
+                );
+            }
+        }
+    }
+
+    fn answer(&mut self, frame: BitstampFrame, outputs: &mut Vec<Output>) {
+        let request = self.pending.remove(&frame.channel);
+        match frame.event.as_str() {
+            "bts:subscription_succeeded" | "bts:unsubscription_succeeded" => {
+                outputs.extend(request.map(|(op, request)| Output::Acked(op, request)));
+            }
+            "bts:error" => {
+                let Some(error) = frame.data::<ErrorRaw>() else {
+                    return;
+                };
+                let error = SubscribeError::Exchange {
+                    code: error.code.map(|code| code.to_string()).unwrap_or_default(),
+                    message: error.message,
+                };
+                outputs.push(Output::Rejected(request, error));
+            }
+            "bts:request_reconnect" => outputs.push(Output::Rotate),
+            // `bts:heartbeat`
+            _ => {}
+        }
+    }
+}
+
+impl Protocol for BitstampProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::BitStamp
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.stats = stats;
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let channel = Self::channel(request);
+        let pair = Self::symbol(request);
+        let event = match op {
+            Op::Subscribe => {
+                self.streams.insert(channel.clone(), *request);
+                // A book replayed on the new connection of a rotation stays in sync
+                if request.data_type == DataTypes::Book {
+                    self.books.entry(pair).or_default();
+                }
+                "bts:subscribe"
+            }
+            Op::Unsubscribe => {
+                self.streams.remove(&channel);
+                self.last.remove(&channel);
+                if request.data_type == DataTypes::Book {
+                    self.books.remove(&pair);
+                }
+                "bts:unsubscribe"
+            }
+        };
+
+        let frame = serde_json::json!({
+            "event": event,
+            "data": {"channel": channel},
+        });
+        self.pending.insert(channel, (op, *request));
+        Ok(frame.to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(frame) = serde_json::from_str::<BitstampFrame>(frame) else {
+            return;
+        };
+        if frame.event.starts_with("bts:") {
+            self.answer(frame, outputs)
+        } else {
+            self.data(frame, outputs)
+        }
+    }
+
+    /// Every book syncs again from a new snapshot, the diffs of the new connection may have
+    /// skipped some
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.last.clear();
+        self.books
+            .values_mut()
+            .for_each(|sync| *sync = DiffSync::default());
+    }
+
+    fn overlap(&self) -> bool {
+        true
+    }
+
+    fn snapshot(&self, request: &SocketRequest) -> Option<SnapshotFuture> {
+        if request.data_type != DataTypes::Book {
+            return None;
+        }
+
+        let url = format!(
+            "{}/order_book/{}/",
+            self.rest_url,
+            Self::pair(&request.symbol)
+        );
+        let client = self.client.clone();
+        Some(Box::pin(async move {
+            let response = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?;
+            response.text().await.map_err(|e| e.to_string())
+        }))
+    }
+
+    /// Publish the snapshot and apply the buffered diffs that are newer. A failed fetch is retried
+    /// with the next diff.
+    fn snapshot_fetched(
+        &mut self,
+        request: &SocketRequest,
+        body: Result<String, String>,
+        outputs: &mut Vec<Output>,
+    ) {
+        let pair = Self::symbol(request);
+        let Some(DiffSync::Buffering {
+            diffs,
+            fetching: true,
+        }) = self.books.get_mut(&pair)
+        else {
+            return;
+        };
+        let diffs = mem::take(diffs);
+
+        let snapshot = body.and_then(|body| {
+            serde_json::from_str::<OrderBookRaw>(&body).map_err(|e| e.to_string())
+        });
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("BitstampProtocol: order book of {pair} failed: {e}");
+                self.books.insert(pair, DiffSync::default());
+                return;
+            }
+        };
+
+        let microtimestamp = snapshot.microtimestamp;
+        self.books
+            .insert(pair, DiffSync::Streaming { microtimestamp });
+        let events = snapshot.into_events(pair, true);
+        outputs.extend(
+            events
+                .into_iter()
+                .map(|event| Output::Event(*request, event)),
+        );
+        for diff in diffs
+            .into_iter()
+            .filter(|diff| diff.microtimestamp > microtimestamp)
+        {
+            self.diff(request, diff, outputs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{sync::mpsc, time};
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[tokio::test]
+    async fn test_channels() {
+        let exchange = MockExchange::bitstamp().await;
+        exchange.reject("nopeusd");
+        let handle = connect(BitstampProtocol::new(exchange.url(), exchange.rest_url()));
+
+        // Answers carry no id, only the channel they are about
+        let (client, mut events) = mpsc::channel(16);
+        let bbo = request("BTC/USD", DataTypes::Bbo);
+        handle.subscribe(bbo, client.clone()).await.unwrap();
+        let rejected = handle
+            .subscribe(request("nopeusd", DataTypes::Trade), client)
+            .await;
+        assert!(
+            matches!(&rejected, Err(SubscribeError::Exchange { message, .. }) if message.contains("live_trades_nopeusd")),
+            "{rejected:?}"
+        );
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[0]["data"]["channel"], "order_book_btcusd");
+
+        // Only the top of the order book is streamed
+        exchange.bitstamp_order_book(
+            "btcusd",
+            &[("30461", "1"), ("30462", "2")],
+            &[("30460", "3"), ("30459", "4")],
+        );
+        let Event::OrderbookSnapshot(bbo) = next_event(&mut events).await else {
+            panic!("expected the top of book");
+        };
+        assert!(bbo.symbol == "btcusd" && bbo.exchange == Exchange::BitStamp);
+        assert_eq!(bbo.levels.len(), 2);
+        assert_eq!(bbo.levels[1].price, "30460.0".parse::<Decimal>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_book_sync() {
+        let exchange = MockExchange::bitstamp().await;
+        exchange.bitstamp_snapshot("btcusd", 1000, &[("30461", "2")], &[("30460", "1")]);
+        let handle = connect(BitstampProtocol::new(exchange.url(), exchange.rest_url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("btcusd", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        // Diffs older than the snapshot are dropped, newer ones applied in order
+        exchange.bitstamp_diff("btcusd", 990, &[("30462", "1")], &[]);
+        exchange.bitstamp_diff("btcusd", 1010, &[("30461", "0")], &[]);
+        exchange.bitstamp_diff("btcusd", 1005, &[], &[("30458", "1")]);
+        exchange.bitstamp_diff("btcusd", 1020, &[], &[("30459", "3")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let ask = next_event(&mut events).await;
+        assert!(
+            matches!(&ask, Event::OrderbookUpdate(u) if u.price == "30461.0".parse::<Decimal>().unwrap() && u.quantity.is_zero()),
+            "{ask:?}"
+        );
+        let bid = next_event(&mut events).await;
+        assert!(
+            matches!(&bid, Event::OrderbookUpdate(u) if u.price == "30459.0".parse::<Decimal>().unwrap()),
+            "{bid:?}"
+        );
+        assert_eq!(exchange.fetches(), 1);
+        assert_eq!(handle.stats().out_of_order, 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_reconnect() {
+        let exchange = MockExchange::bitstamp().await;
+        let handle = connect(BitstampProtocol::new(exchange.url(), exchange.rest_url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("btcusd", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+
+        // The streams move to a new connection without subscribers seeing a disconnect. The old
+        // one is read until the new one acks the replay, trades both deliver arrive once.
+        exchange.set_delay(Duration::from_millis(200));
+        exchange.push(r#"{"event":"bts:request_reconnect","channel":"","data":""}"#);
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[1]["event"], "bts:subscribe");
+        assert_eq!(exchange.connections(), 2);
+
+        for amount in ["0.5", "0.6"] {
+            exchange.bitstamp_trade("btcusd", "30460", amount, 0);
+            let trade = next_event(&mut events).await;
+            assert!(
+                matches!(&trade, Event::Trade(t) if t.quantity == amount.parse::<Decimal>().unwrap()),
+                "{trade:?}"
+            );
+        }
+        time::sleep(Duration::from_millis(300)).await;
+        exchange.bitstamp_trade("btcusd", "30460", "0.7", 0);
+        let trade = next_event(&mut events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.quantity == "0.7".parse::<Decimal>().unwrap()),
+            "{trade:?}"
+        );
+        assert!(events.try_recv().is_err());
+    }
+}
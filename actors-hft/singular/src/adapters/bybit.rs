@@ -0,0 +1,470 @@
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        capabilities::Capabilities,
+        protocol::{Output, Protocol},
+        sequence::{FeedStats, Sequence, SequenceTracker},
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{Event, Resync, Trade},
+    models::{normal::DataTypes, Exchange},
+    transmute::bybit::{BybitPush, BybitReply, OrderbookRaw, TickerRaw, TradeRaw},
+};
+
+/// Spot public stream
+pub const BYBIT_SPOT_URL: &str = "wss://stream.bybit.com/v5/public/spot";
+/// USDT and USDC perpetuals and futures public stream
+pub const BYBIT_LINEAR_URL: &str = "wss://stream.bybit.com/v5/public/linear";
+
+/// Book depth subscribed when none is given
+const DEFAULT_DEPTH: usize = 50;
+
+/// Characters the topics of one public connection may add up to, on every category. Spot also
+/// takes at most 10 args per subscribe, every request here carries a single topic.
+const TOPIC_CHARS: usize = 21_000;
+
+/// Market of a Bybit v5 public stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    Spot,
+    Linear,
+}
+
+impl Category {
+    /// `orderbook` depths the category offers
+    pub fn depths(self) -> &'static [usize] {
+        match self {
+            Category::Spot => &[1, 50, 200],
+            Category::Linear => &[1, 50, 200, 500],
+        }
+    }
+}
+
+impl Display for Category {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Category::Spot => write!(f, "spot"),
+            Category::Linear => write!(f, "linear"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TopicLimit {
+    Count(usize),
+    Chars(usize),
+}
+
+/// The Bybit v5 public stream of one category. Trades come from `publicTrade` and books from
+/// `orderbook` snapshots and deltas, followed by `u` and resubscribed on a gap. Best bid and offer
+/// come from `tickers` on linear and from `orderbook.1` on spot, whose tickers carry no quotes.
+#[derive(Debug)]
+pub struct BybitProtocol {
+    category: Category,
+    url: String,
+    /// Levels per side of every `orderbook` subscription
+    depth: usize,
+    limit: TopicLimit,
+    next_id: u64,
+    /// Requests waiting on their answer, by `req_id`
+    ids: HashMap<String, (Op, SocketRequest)>,
+    /// Request of every subscribed topic
+    streams: HashMap<String, SocketRequest>,
+    /// Last full ticker of every linear `tickers` topic, deltas apply on top
+    tickers: HashMap<String, TickerRaw>,
+    sequences: SequenceTracker<String>,
+    stats: Arc<FeedStats>,
+}
+
+impl BybitProtocol {
+    pub fn spot() -> Self {
+        Self::new(Category::Spot, BYBIT_SPOT_URL)
+    }
+
+    pub fn linear() -> Self {
+        Self::new(Category::Linear, BYBIT_LINEAR_URL)
+    }
+
+    pub fn new(category: Category, url: &str) -> Self {
+        let stats = Arc::new(FeedStats::default());
+        Self {
+            category,
+            url: url.to_string(),
+            depth: DEFAULT_DEPTH,
+            limit: TopicLimit::Chars(TOPIC_CHARS),
+            next_id: 0,
+            ids: HashMap::new(),
+            streams: HashMap::new(),
+            tickers: HashMap::new(),
+            sequences: SequenceTracker::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// Subscribe books `depth` levels deep instead of 50, one of [`Category::depths`]
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Carry at most `topics` topics on the connection
+    pub fn with_topic_limit(mut self, topics: usize) -> Self {
+        self.limit = TopicLimit::Count(topics);
+        self
+    }
+
+    /// Bybit symbol of a request, `btc-usdt` becomes `BTCUSDT`
+    pub fn symbol(symbol: &str) -> String {
+        symbol.to_uppercase().replace(['-', '/', '_'], "")
+    }
+
+    fn topic(&self, request: &SocketRequest) -> String {
+        let symbol = Self::symbol(&request.symbol);
+        match (request.data_type, self.category) {
+            (DataTypes::Trade, _) => format!("publicTrade.{symbol}"),
+            (DataTypes::Book, _) => format!("orderbook.{}.{symbol}", self.depth),
+            (DataTypes::Bbo, Category::Spot) => format!("orderbook.1.{symbol}"),
+            (DataTypes::Bbo, Category::Linear) => format!("tickers.{symbol}"),
+        }
+    }
+
+    /// Whether subscribing `topic` too takes the connection over its limit
+    fn over_limit(&self, topic: &str) -> Option<String> {
+        let (used, needed, limit, unit) = match self.limit {
+            TopicLimit::Count(limit) => (self.streams.len(), 1, limit, "topics"),
+            TopicLimit::Chars(limit) => {
+                let used = self.streams.keys().map(String::len).sum();
+                (used, topic.len(), limit, "characters of topics")
+            }
+        };
+        (used + needed > limit).then(|| {
+            format!(
+                "More than {limit} {unit} on one Bybit {} connection",
+                self.category
+            )
+        })
+    }
+
+    /// Apply an `orderbook` push. Deltas have to follow the last push by `u`, a gap drops the book
+    /// and resubscribes for a fresh snapshot.
+    fn orderbook(&mut self, request: SocketRequest, push: &BybitPush, outputs: &mut Vec<Output>) {
+        let Some(raw) = push.data::<OrderbookRaw>() else {
+            return;
+        };
+        // `u` of 1 means Bybit restarted the book and sends it whole
+        let is_snapshot = push.is_snapshot() || raw.update_id == 1;
+        let sequence = self.sequences.check(
+            push.topic.clone(),
+            raw.update_id,
+            Some(raw.update_id - 1),
+            is_snapshot,
+        );
+
+        match sequence {
+            Sequence::Snapshot | Sequence::InOrder => {
+                let events = raw.into_events(is_snapshot, push.timestamps());
+                outputs.extend(
+                    events
+                        .into_iter()
+                        .map(|event| Output::Event(request, event)),
+                );
+            }
+            Sequence::Gap { .. } => {
+                self.sequences.reset(&push.topic);
+                FeedStats::incr(&self.stats.resyncs);
+                let event = Event::Resync(Resync {
+                    exchange: Exchange::ByBit,
+                    symbol: raw.symbol,
+                    reason: format!("{sequence:?} on {}", push.topic),
+                    ts: push.timestamps(),
+                });
+                outputs.extend([Output::Event(request, event), Output::Resubscribe(request)]);
+            }
+            _ => {}
+        }
+    }
+
+    /// Apply a linear `tickers` push, an event goes out whenever the best bid or offer moved
+    fn ticker(&mut self, request: SocketRequest, push: &BybitPush, outputs: &mut Vec<Output>) {
+        let Some(raw) = push.data::<TickerRaw>() else {
+            return;
+        };
+        let ticker = self.tickers.entry(push.topic.clone()).or_default();
+        if push.is_snapshot() {
+            *ticker = TickerRaw::default();
+        }
+        if !ticker.merge(raw) {
+            return;
+        }
+        let event = ticker.to_event(push.timestamps());
+        outputs.extend(event.map(|event| Output::Event(request, event)));
+    }
+
+    fn answer(&mut self, reply: BybitReply, outputs: &mut Vec<Output>) {
+        let request = reply.req_id.and_then(|id| self.ids.remove(&id));
+        match (reply.success, request) {
+            (Some(true), Some((op, request))) => outputs.push(Output::Acked(op, request)),
+            (Some(false), request) => {
+                let error = SubscribeError::Exchange {
+                    code: reply.op,
+                    message: reply.ret_msg,
+                };
+                outputs.push(Output::Rejected(request, error));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Protocol for BybitProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::ByBit
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Only the depths and asset class of this connection's category
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            depths: self.category.depths(),
+            asset_classes: match self.category {
+                Category::Spot => &["spot"],
+                Category::Linear => &["linear"],
+            },
+            max_subscriptions: match self.limit {
+                TopicLimit::Count(topics) => Some(topics),
+                TopicLimit::Chars(_) => None,
+            },
+            ..Capabilities::for_exchange(Exchange::ByBit)
+        }
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.sequences = SequenceTracker::new(stats.clone());
+        self.stats = stats;
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        self.capabilities().check_depth(self.depth)?;
+        let topic = self.topic(request);
+        match op {
+            Op::Subscribe if !self.streams.contains_key(&topic) => {
+                if let Some(limit) = self.over_limit(&topic) {
+                    return Err(SubscribeError::Full(limit));
+                }
+                self.streams.insert(topic.clone(), *request);
+            }
+            Op::Subscribe => {}
+            Op::Unsubscribe => {
+                self.streams.remove(&topic);
+                self.tickers.remove(&topic);
+                self.sequences.reset(&topic);
+            }
+        }
+
+        self.next_id += 1;
+        let id = self.next_id.to_string();
+        self.ids.insert(id.clone(), (op, *request));
+        Ok(serde_json::json!({
+            "req_id": id,
+            "op": op.as_str(),
+            "args": [topic],
+        })
+        .to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(push) = serde_json::from_str::<BybitPush>(frame) else {
+            if let Ok(reply) = serde_json::from_str::<BybitReply>(frame) {
+                self.answer(reply, outputs);
+            }
+            return;
+        };
+        let Some(request) = self.streams.get(&push.topic).cloned() else {
+            return;
+        };
+
+        match push.topic.split('.').next() {
+            Some("publicTrade") => {
+                let trades = push.data::<Vec<TradeRaw>>().unwrap_or_default();
+                outputs.extend(
+                    trades
+                        .into_iter()
+                        .map(|raw| Output::Event(request, Event::Trade(Trade::from(raw)))),
+                );
+            }
+            Some("orderbook") => self.orderbook(request, &push, outputs),
+            Some("tickers") => self.ticker(request, &push, outputs),
+            _ => {}
+        }
+    }
+
+    /// Bybit sends every book and ticker whole again on resubscribe
+    fn reset(&mut self) {
+        self.ids.clear();
+        self.tickers.clear();
+        self.sequences.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[test]
+    fn test_topics() {
+        let spot = BybitProtocol::spot();
+        assert_eq!(
+            spot.topic(&request("btc-usdt", DataTypes::Book)),
+            "orderbook.50.BTCUSDT"
+        );
+        assert_eq!(
+            spot.topic(&request("BTCUSDT", DataTypes::Bbo)),
+            "orderbook.1.BTCUSDT"
+        );
+        let linear = BybitProtocol::linear().with_depth(500);
+        assert_eq!(
+            linear.topic(&request("BTCUSDT", DataTypes::Bbo)),
+            "tickers.BTCUSDT"
+        );
+        assert_eq!(
+            linear.topic(&request("BTCUSDT", DataTypes::Book)),
+            "orderbook.500.BTCUSDT"
+        );
+
+        let mut spot = BybitProtocol::spot().with_depth(500);
+        let error = spot.request(Op::Subscribe, &request("BTCUSDT", DataTypes::Trade));
+        assert!(
+            matches!(&error, Err(SubscribeError::NotOffered { offered, .. }) if offered.len() == 3),
+            "{error:?}"
+        );
+    }
+
+    #[test]
+    fn test_topic_limit() {
+        let mut spot = BybitProtocol::spot().with_topic_limit(2);
+        for symbol in ["BTCUSDT", "ETHUSDT"] {
+            let trade = request(symbol, DataTypes::Trade);
+            assert!(spot.request(Op::Subscribe, &trade).is_ok());
+        }
+        // Replaying a topic on reconnect doesn't count twice
+        let replay = spot.request(Op::Subscribe, &request("BTCUSDT", DataTypes::Trade));
+        assert!(replay.is_ok());
+        let third = spot.request(Op::Subscribe, &request("SOLUSDT", DataTypes::Trade));
+        assert!(
+            matches!(&third, Err(SubscribeError::Full(limit)) if limit.contains("spot")),
+            "{third:?}"
+        );
+
+        let unsubscribe = spot.request(Op::Unsubscribe, &request("ETHUSDT", DataTypes::Trade));
+        assert!(unsubscribe.is_ok());
+        let third = spot.request(Op::Subscribe, &request("SOLUSDT", DataTypes::Trade));
+        assert!(third.is_ok());
+        // The 10 args of spot are per subscribe, not per connection
+        let mut spot = BybitProtocol::spot();
+        for symbol in [
+            "BTC", "ETH", "SOL", "XRP", "ADA", "DOT", "TRX", "LTC", "BCH", "LINK", "AVAX",
+        ] {
+            let trade = request(&format!("{symbol}USDT"), DataTypes::Trade);
+            assert!(spot.request(Op::Subscribe, &trade).is_ok(), "{symbol}");
+        }
+        assert_eq!(spot.capabilities().max_subscriptions, None);
+    }
+
+    #[tokio::test]
+    async fn test_bbo_by_category() {
+        // Spot tickers carry no quotes, the best bid and offer is the top of `orderbook.1`
+        let exchange = MockExchange::bybit().await;
+        let handle = connect(BybitProtocol::new(Category::Spot, exchange.url()));
+        let (client, mut events) = mpsc::channel(16);
+        handle
+            .subscribe(request("BTCUSDT", DataTypes::Bbo), client)
+            .await
+            .unwrap();
+        assert_eq!(exchange.ops(1).await[0]["args"][0], "orderbook.1.BTCUSDT");
+
+        let topic = "orderbook.1.BTCUSDT";
+        exchange.orderbook(topic, "snapshot", 1, &[("30461", "2")], &[("30460", "1")]);
+        let bbo = next_event(&mut events).await;
+        assert!(
+            matches!(&bbo, Event::OrderbookSnapshot(s) if s.levels.len() == 2 && s.exchange == Exchange::ByBit),
+            "{bbo:?}"
+        );
+
+        // Linear has it in `tickers`, whose deltas only carry what changed
+        let exchange = MockExchange::bybit().await;
+        let handle = connect(BybitProtocol::new(Category::Linear, exchange.url()));
+        let (client, mut events) = mpsc::channel(16);
+        handle
+            .subscribe(request("BTCUSDT", DataTypes::Bbo), client)
+            .await
+            .unwrap();
+        assert_eq!(exchange.ops(1).await[0]["args"][0], "tickers.BTCUSDT");
+
+        exchange.bybit_ticker("snapshot", "BTCUSDT", ("30460", "1"), ("30461", "2"));
+        exchange.bybit_ticker("delta", "BTCUSDT", ("30460", "3"), ("", ""));
+        let bbo = next_event(&mut events).await;
+        assert!(
+            matches!(&bbo, Event::OrderbookSnapshot(s) if s.levels.len() == 2),
+            "{bbo:?}"
+        );
+        let Event::OrderbookSnapshot(bbo) = next_event(&mut events).await else {
+            panic!("expected the top of book");
+        };
+        assert_eq!(bbo.levels[1].quantity, "3.0".parse::<Decimal>().unwrap());
+        assert_eq!(bbo.levels[0].price, "30461.0".parse::<Decimal>().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_orderbook_gap() {
+        let exchange = MockExchange::bybit().await;
+        let handle = connect(BybitProtocol::new(Category::Spot, exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("BTCUSDT", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        let topic = "orderbook.50.BTCUSDT";
+        exchange.orderbook(topic, "snapshot", 10, &[("30461", "2")], &[("30460", "1")]);
+        exchange.orderbook(topic, "delta", 11, &[("30461", "0")], &[]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let removed = next_event(&mut events).await;
+        assert!(matches!(&removed, Event::OrderbookUpdate(u) if u.quantity.is_zero()));
+
+        // Update 12 never came
+        exchange.orderbook(topic, "delta", 13, &[], &[("30459", "1")]);
+        let resync = next_event(&mut events).await;
+        assert!(
+            matches!(&resync, Event::Resync(r) if r.symbol == "BTCUSDT"),
+            "{resync:?}"
+        );
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops[1]["op"], "unsubscribe");
+        assert_eq!(ops[2]["op"], "subscribe");
+        assert_eq!(ops[2]["args"][0], topic);
+
+        // Deltas are dropped until the new snapshot
+        exchange.orderbook(topic, "delta", 14, &[], &[("30458", "1")]);
+        exchange.orderbook(topic, "snapshot", 20, &[("30462", "1")], &[]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels[0].price == "30462.0".parse::<Decimal>().unwrap()),
+            "{snapshot:?}"
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+use crate::{
+    adapters::{binance, bitfinex, huobi, kraken, subscriptions::SubscribeError},
+    models::{normal::DataTypes, Exchange},
+};
+
+/// Data types every adapter streams
+const ALL_TYPES: &[DataTypes] = &[DataTypes::Trade, DataTypes::Book, DataTypes::Bbo];
+
+/// Requests one connection may send upstream within `per`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+/// What an exchange's adapter can stream, checked before a request goes upstream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub exchange: Exchange,
+    pub data_types: &'static [DataTypes],
+    /// Book depths the adapter subscribes, empty when books stream whole
+    pub depths: &'static [usize],
+    /// Asset classes of the instruments the connection carries, the first one is the default
+    pub asset_classes: &'static [&'static str],
+    /// Most channels one connection may carry, `None` when the exchange doesn't say
+    pub max_subscriptions: Option<usize>,
+    /// Limit on subscribe and unsubscribe requests, `None` when the exchange doesn't say
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Capabilities {
+    /// Descriptor of an exchange. Bybit streams each category on its own connection, its
+    /// adapters narrow this down to theirs.
+    pub fn for_exchange(exchange: Exchange) -> Self {
+        let base = Self {
+            exchange,
+            data_types: ALL_TYPES,
+            depths: &[],
+            asset_classes: &["spot"],
+            max_subscriptions: None,
+            rate_limit: None,
+        };
+        match exchange {
+            Exchange::Okx => Self {
+                depths: &[400],
+                asset_classes: &["spot", "linear", "inverse", "option"],
+                rate_limit: Some(RateLimit {
+                    requests: 480,
+                    per: Duration::from_secs(60 * 60),
+                }),
+                ..base
+            },
+            Exchange::BinanceUsdm | Exchange::BinanceCoinm => Self {
+                depths: &[binance::SNAPSHOT_DEPTH],
+                asset_classes: if exchange == Exchange::BinanceUsdm {
+                    &["linear"]
+                } else {
+                    &["inverse"]
+                },
+                max_subscriptions: Some(200),
+                rate_limit: Some(RateLimit {
+                    requests: 10,
+                    per: Duration::from_secs(1),
+                }),
+                ..base
+            },
+            Exchange::Coinbase => Self {
+                rate_limit: Some(RateLimit {
+                    requests: 8,
+                    per: Duration::from_secs(1),
+                }),
+                ..base
+            },
+            Exchange::Kraken => Self {
+                depths: &kraken::DEPTHS,
+                ..base
+            },
+            Exchange::ByBit => Self {
+                depths: &[1, 50, 200, 500],
+                asset_classes: &["spot", "linear"],
+                ..base
+            },
+            Exchange::Bitfinex => Self {
+                depths: &[bitfinex::BOOK_LENGTH],
+                max_subscriptions: Some(30),
+                ..base
+            },
+            Exchange::Huobi => Self {
+                depths: &huobi::DEPTHS,
+                ..base
+            },
+            Exchange::Deribit => Self {
+                asset_classes: &["inverse", "linear", "option"],
+                ..base
+            },
+            Exchange::BitStamp => base,
+            Exchange::KuCoin => Self {
+                max_subscriptions: Some(400),
+                rate_limit: Some(RateLimit {
+                    requests: 100,
+                    per: Duration::from_secs(10),
+                }),
+                ..base
+            },
+        }
+    }
+
+    /// Whether `data_type` can be streamed for an instrument of `asset_class`. An empty asset
+    /// class stands for the default one.
+    pub fn check(&self, data_type: DataTypes, asset_class: &str) -> Result<(), SubscribeError> {
+        if !self.data_types.contains(&data_type) {
+            return Err(SubscribeError::NotOffered {
+                what: format!("{data_type} on {}", self.exchange),
+                offered: self.data_types.iter().map(ToString::to_string).collect(),
+            });
+        }
+        if !asset_class.is_empty() && !self.asset_classes.contains(&asset_class) {
+            return Err(SubscribeError::NotOffered {
+                what: format!("{asset_class} on {}", self.exchange),
+                offered: self.asset_classes.iter().map(ToString::to_string).collect(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether a book of `depth` levels can be subscribed
+    pub fn check_depth(&self, depth: usize) -> Result<(), SubscribeError> {
+        if self.depths.is_empty() || self.depths.contains(&depth) {
+            return Ok(());
+        }
+        Err(SubscribeError::NotOffered {
+            what: format!("Book depth {depth} on {}", self.exchange),
+            offered: self.depths.iter().map(ToString::to_string).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let bybit = Capabilities::for_exchange(Exchange::ByBit);
+        assert!(bybit.check(DataTypes::Bbo, "linear").is_ok());
+        assert!(bybit.check(DataTypes::Trade, "").is_ok());
+
+        let error = bybit.check(DataTypes::Trade, "option").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "option on bybit is not offered, choose from: spot, linear"
+        );
+
+        let kraken = Capabilities::for_exchange(Exchange::Kraken);
+        assert!(kraken.check_depth(25).is_ok());
+        assert!(matches!(
+            kraken.check_depth(50),
+            Err(SubscribeError::NotOffered { offered, .. }) if offered.len() == kraken::DEPTHS.len()
+        ));
+        // Books that stream whole take any depth
+        assert!(Capabilities::for_exchange(Exchange::Coinbase)
+            .check_depth(50)
+            .is_ok());
+    }
+}
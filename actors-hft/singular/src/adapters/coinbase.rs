@@ -0,0 +1,251 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol},
+        subscriptions::{Op, SubscribeError},
+    },
+    event::Event,
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::coinbase::CoinbaseMessage,
+};
+
+/// Coinbase Exchange public websocket feed
+pub const COINBASE_URL: &str = "wss://ws-feed.exchange.coinbase.com";
+
+/// The Coinbase Exchange websocket feed. Trades come from `matches`, books from `level2_batch`
+/// snapshots and `l2update`s and best bid and offer from `ticker`.
+#[derive(Debug)]
+pub struct CoinbaseProtocol {
+    url: String,
+    /// Requests waiting on their answer, oldest first. Coinbase answers in order and neither its
+    /// `subscriptions` nor its `error` messages say which request they answer.
+    pending: VecDeque<(Op, SocketRequest)>,
+    /// Request of every channel and product id Coinbase confirmed
+    streams: HashMap<(&'static str, Symbol), SocketRequest>,
+}
+
+impl CoinbaseProtocol {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            pending: VecDeque::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    fn channel(data_type: DataTypes) -> &'static str {
+        match data_type {
+            DataTypes::Trade => "matches",
+            DataTypes::Book => "level2_batch",
+            DataTypes::Bbo => "ticker",
+        }
+    }
+
+    /// Coinbase product id of a symbol, `btc/usd` becomes `BTC-USD`
+    pub fn product_id(symbol: &str) -> String {
+        symbol.to_uppercase().replace(['/', '_'], "-")
+    }
+
+    /// Channel and product id streaming `request`
+    fn key(request: &SocketRequest) -> (&'static str, Symbol) {
+        let product_id = Symbol::canonical(Exchange::Coinbase, &request.symbol, Self::product_id);
+        (Self::channel(request.data_type), product_id)
+    }
+
+    /// Tag events with the request streaming `channel` for `product_id`
+    fn route(
+        &self,
+        channel: &'static str,
+        product_id: Symbol,
+        events: impl IntoIterator<Item = Event>,
+        outputs: &mut Vec<Output>,
+    ) {
+        if let Some(request) = self.streams.get(&(channel, product_id)) {
+            outputs.extend(
+                events
+                    .into_iter()
+                    .map(|event| Output::Event(*request, event)),
+            );
+        }
+    }
+}
+
+impl Protocol for CoinbaseProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::Coinbase
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let (channel, product_id) = Self::key(request);
+        // Routed once confirmed, a rejected product never is. Nothing is routed after
+        // unsubscribing.
+        if op == Op::Unsubscribe {
+            self.streams.remove(&(channel, product_id));
+        }
+
+        self.pending.push_back((op, *request));
+        Ok(serde_json::json!({
+            "type": op.as_str(),
+            "product_ids": [product_id],
+            "channels": [channel],
+        })
+        .to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(message) = serde_json::from_str::<CoinbaseMessage>(frame) else {
+            return;
+        };
+
+        match message {
+            CoinbaseMessage::Match(raw) => {
+                let product_id = raw.product_id;
+                self.route("matches", product_id, [Event::Trade(raw.into())], outputs)
+            }
+            CoinbaseMessage::Snapshot(raw) => {
+                let product_id = raw.product_id;
+                self.route("level2_batch", product_id, [raw.into_event()], outputs)
+            }
+            CoinbaseMessage::L2update(raw) => {
+                let product_id = raw.product_id;
+                self.route("level2_batch", product_id, raw.into_events(), outputs)
+            }
+            CoinbaseMessage::Ticker(raw) => {
+                let product_id = raw.product_id;
+                self.route("ticker", product_id, [raw.into_event()], outputs)
+            }
+            CoinbaseMessage::Subscriptions => {
+                let Some((op, request)) = self.pending.pop_front() else {
+                    return;
+                };
+                if op == Op::Subscribe {
+                    self.streams.insert(Self::key(&request), request);
+                }
+                outputs.push(Output::Acked(op, request));
+            }
+            CoinbaseMessage::Error(raw) => {
+                let error = SubscribeError::Exchange {
+                    code: raw.message,
+                    message: raw.reason,
+                };
+                outputs.push(Output::Rejected(self.pending.pop_front(), error));
+            }
+            CoinbaseMessage::Other => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, decoded, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[test]
+    fn test_product_id() {
+        assert_eq!(CoinbaseProtocol::product_id("BTC-USD"), "BTC-USD");
+        assert_eq!(CoinbaseProtocol::product_id("eth/usd"), "ETH-USD");
+    }
+
+    #[test]
+    fn test_routes_confirmed() {
+        let mut protocol = CoinbaseProtocol::new(COINBASE_URL);
+        let (btc, nope) = (
+            request("BTC-USD", DataTypes::Trade),
+            request("NOPE-USD", DataTypes::Trade),
+        );
+        protocol.request(Op::Subscribe, &btc).unwrap();
+        protocol.request(Op::Subscribe, &nope).unwrap();
+        assert!(protocol.streams.is_empty());
+
+        let subscriptions = r#"{"type":"subscriptions","channels":[]}"#;
+        let error = r#"{"type":"error","message":"Failed to subscribe","reason":"NOPE-USD is not a valid product"}"#;
+        assert!(
+            matches!(decoded(&mut protocol, subscriptions)[..], [Output::Acked(Op::Subscribe, r)] if r == btc)
+        );
+        assert!(
+            matches!(decoded(&mut protocol, error)[..], [Output::Rejected(Some((Op::Subscribe, r)), _)] if r == nope)
+        );
+        assert_eq!(protocol.streams.len(), 1);
+        assert_eq!(
+            protocol.streams.get(&("matches", "BTC-USD".into())),
+            Some(&btc)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_product_ids() {
+        let exchange = MockExchange::coinbase().await;
+        let handle = connect(CoinbaseProtocol::new(exchange.url()));
+
+        let (btc, mut btc_events) = mpsc::channel(16);
+        let (eth, mut eth_events) = mpsc::channel(16);
+        let subscribe = handle.subscribe(request("btc/usd", DataTypes::Trade), btc);
+        subscribe.await.unwrap();
+        let subscribe = handle.subscribe(request("ETH-USD", DataTypes::Trade), eth);
+        subscribe.await.unwrap();
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[0]["product_ids"][0], "BTC-USD");
+        assert_eq!(ops[0]["channels"][0], "matches");
+
+        // Matches are routed by product id, whatever the symbol was subscribed as
+        exchange.coinbase_match("ETH-USD", "1850.1", "2", "buy");
+        exchange.coinbase_match("BTC-USD", "30460.1", "0.5", "sell");
+        let trade = next_event(&mut btc_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "BTC-USD" && t.trade_id == "10"),
+            "{trade:?}"
+        );
+        let trade = next_event(&mut eth_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "ETH-USD"),
+            "{trade:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_level2() {
+        let exchange = MockExchange::coinbase().await;
+        let handle = connect(CoinbaseProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("BTC-USD", DataTypes::Book);
+        handle.subscribe(book, client.clone()).await.unwrap();
+
+        exchange.level2_snapshot("BTC-USD", &[("30461", "2")], &[("30460", "1")]);
+        exchange.level2_update("BTC-USD", &[("sell", "30461", "0"), ("buy", "30459", "3")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let ask = next_event(&mut events).await;
+        assert!(
+            matches!(&ask, Event::OrderbookUpdate(u) if u.price == "30461.0".parse::<Decimal>().unwrap() && u.quantity.is_zero())
+        );
+        let bid = next_event(&mut events).await;
+        assert!(
+            matches!(&bid, Event::OrderbookUpdate(u) if u.price == "30459.0".parse::<Decimal>().unwrap())
+        );
+
+        handle.unsubscribe(book, client).await.unwrap();
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[1]["type"], "unsubscribe");
+        assert_eq!(ops[1]["channels"][0], "level2_batch");
+    }
+}
@@ -0,0 +1,389 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol},
+        sequence::{FeedStats, Sequence, SequenceTracker},
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{Event, Resync, Timestamps, Trade},
+    models::{normal::DataTypes, Exchange},
+    transmute::deribit::{
+        BookRaw, DeribitFrame, HeartbeatParams, QuoteRaw, SubscriptionParams, TradeRaw,
+    },
+};
+
+/// Deribit production websocket, JSON-RPC 2.0
+pub const DERIBIT_URL: &str = "wss://www.deribit.com/ws/api/v2";
+
+/// Seconds between the heartbeats asked for with `public/set_heartbeat`
+const HEARTBEAT_INTERVAL: u64 = 30;
+
+/// Id of the calls that keep the connection alive, `public/set_heartbeat` and `public/test`.
+/// Requests are numbered from 1 so their answers are never mistaken for these.
+const HEARTBEAT_ID: u64 = 0;
+
+/// Code of a subscribe answered without its channel, JSON-RPC's invalid params
+const NOT_SUBSCRIBED: i64 = -32602;
+
+/// The Deribit websocket. Every call is a JSON-RPC request answered by `id`, every stream a
+/// `subscription` notification. Trades come from `trades`, best bid and offer from `quote` and
+/// books from `book` changes chained by `change_id`, for futures and options alike.
+#[derive(Debug)]
+pub struct DeribitProtocol {
+    url: String,
+    /// Seconds between heartbeats
+    heartbeat: u64,
+    next_id: u64,
+    /// Requests waiting on their answer and the channel they name, by `id`
+    ids: HashMap<u64, (Op, SocketRequest, String)>,
+    /// Request of every subscribed channel
+    streams: HashMap<String, SocketRequest>,
+    sequences: SequenceTracker<String>,
+    stats: Arc<FeedStats>,
+}
+
+impl DeribitProtocol {
+    pub fn new(url: &str) -> Self {
+        let stats = Arc::new(FeedStats::default());
+        Self {
+            url: url.to_string(),
+            heartbeat: HEARTBEAT_INTERVAL,
+            next_id: 0,
+            ids: HashMap::new(),
+            streams: HashMap::new(),
+            sequences: SequenceTracker::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// Ask for a heartbeat every `seconds` instead of 30, Deribit takes no less than 10
+    pub fn with_heartbeat(mut self, seconds: u64) -> Self {
+        self.heartbeat = seconds.max(10);
+        self
+    }
+
+    /// Channel of a request. Instrument names are used as Deribit spells them, an option such as
+    /// `BTC-29SEP23-30000-C` keeps its dashes.
+    fn channel(request: &SocketRequest) -> String {
+        let instrument = request.symbol.to_uppercase();
+        match request.data_type {
+            DataTypes::Trade => format!("trades.{instrument}.100ms"),
+            DataTypes::Book => format!("book.{instrument}.100ms"),
+            DataTypes::Bbo => format!("quote.{instrument}"),
+        }
+    }
+
+    fn call(id: u64, method: &str, params: serde_json::Value) -> String {
+        serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        })
+        .to_string()
+    }
+
+    /// Answer to a call. A subscribe that comes back without its channel was refused, Deribit
+    /// doesn't send an error for instruments it doesn't know.
+    fn answer(&mut self, frame: DeribitFrame, outputs: &mut Vec<Output>) {
+        let Some(id) = frame.id.filter(|id| *id != HEARTBEAT_ID) else {
+            return;
+        };
+        let request = self.ids.remove(&id);
+        if let Some(error) = frame.error {
+            let error = SubscribeError::Exchange {
+                code: error.code.to_string(),
+                message: error.message,
+            };
+            let request = request.map(|(op, request, _)| (op, request));
+            return outputs.push(Output::Rejected(request, error));
+        }
+
+        let Some((op, request, channel)) = request else {
+            return;
+        };
+        let channels = frame.result::<Vec<String>>().unwrap_or_default();
+        if op == Op::Subscribe && !channels.contains(&channel) {
+            self.streams.remove(&channel);
+            let error = SubscribeError::Exchange {
+                code: NOT_SUBSCRIBED.to_string(),
+                message: format!("Deribit didn't subscribe {channel}"),
+            };
+            return outputs.push(Output::Rejected(Some((op, request)), error));
+        }
+        outputs.push(Output::Acked(op, request));
+    }
+
+    fn notification(&mut self, method: &str, frame: &DeribitFrame, outputs: &mut Vec<Output>) {
+        match method {
+            "subscription" => {
+                if let Some(params) = frame.params::<SubscriptionParams>() {
+                    self.subscription(params, outputs);
+                }
+            }
+            "heartbeat" => {
+                FeedStats::incr(&self.stats.heartbeats);
+                if let Some(params) = frame.params::<HeartbeatParams>() {
+                    if params.is_test_request() {
+                        let test = Self::call(HEARTBEAT_ID, "public/test", serde_json::json!({}));
+                        outputs.push(Output::Send(test));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn subscription(&mut self, params: SubscriptionParams, outputs: &mut Vec<Output>) {
+        let Some(request) = self.streams.get(&params.channel).cloned() else {
+            return;
+        };
+        match params.channel.split('.').next() {
+            Some("trades") => {
+                let trades = params.data::<Vec<TradeRaw>>().unwrap_or_default();
+                outputs.extend(
+                    trades
+                        .into_iter()
+                        .map(|raw| Output::Event(request, Event::Trade(Trade::from(raw)))),
+                );
+            }
+            Some("quote") => {
+                if let Some(quote) = params.data::<QuoteRaw>() {
+                    outputs.push(Output::Event(request, quote.into_event()));
+                }
+            }
+            Some("book") => {
+                if let Some(book) = params.data::<BookRaw>() {
+                    self.book(request, params.channel, book, outputs);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Follow the `change_id` chain of a book. A break resubscribes, which brings a new snapshot.
+    fn book(
+        &mut self,
+        request: SocketRequest,
+        channel: String,
+        book: BookRaw,
+        outputs: &mut Vec<Output>,
+    ) {
+        let is_snapshot = book.is_snapshot();
+        let prev = book.prev_change_id.unwrap_or_default();
+        match self
+            .sequences
+            .check(channel, book.change_id, Some(prev), is_snapshot)
+        {
+            Sequence::Snapshot | Sequence::InOrder => {
+                let events = book.into_events();
+                outputs.extend(
+                    events
+                        .into_iter()
+                        .map(|event| Output::Event(request, event)),
+                );
+            }
+            Sequence::Gap { expected, received } => {
+                FeedStats::incr(&self.stats.resyncs);
+                let event = Event::Resync(Resync {
+                    exchange: Exchange::Deribit,
+                    symbol: book.instrument_name,
+                    reason: format!("Change {received} doesn't follow {expected} on book"),
+                    ts: Timestamps::default(),
+                });
+                outputs.extend([Output::Event(request, event), Output::Resubscribe(request)]);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Protocol for DeribitProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::Deribit
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.sequences = SequenceTracker::new(stats.clone());
+        self.stats = stats;
+    }
+
+    /// Deribit sends a `test_request` once a heartbeat interval passes without traffic and
+    /// closes the connection if it isn't answered
+    fn handshake(&self) -> Vec<String> {
+        vec![Self::call(
+            HEARTBEAT_ID,
+            "public/set_heartbeat",
+            serde_json::json!({"interval": self.heartbeat}),
+        )]
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let channel = Self::channel(request);
+        let method = match op {
+            Op::Subscribe => {
+                self.streams.insert(channel.clone(), *request);
+                "public/subscribe"
+            }
+            Op::Unsubscribe => {
+                self.streams.remove(&channel);
+                self.sequences.reset(&channel);
+                "public/unsubscribe"
+            }
+        };
+
+        self.next_id += 1;
+        let frame = Self::call(
+            self.next_id,
+            method,
+            serde_json::json!({"channels": [channel]}),
+        );
+        self.ids.insert(self.next_id, (op, *request, channel));
+        Ok(frame)
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(frame) = serde_json::from_str::<DeribitFrame>(frame) else {
+            return;
+        };
+        match frame.method.clone() {
+            Some(method) => self.notification(&method, &frame, outputs),
+            None => self.answer(frame, outputs),
+        }
+    }
+
+    /// Deribit sends every book whole again on resubscribe
+    fn reset(&mut self) {
+        self.ids.clear();
+        self.sequences.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, decoded, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[test]
+    fn test_rpc_ids() {
+        let mut deribit = DeribitProtocol::new(DERIBIT_URL);
+        let trades = request("btc-perpetual", DataTypes::Trade);
+        let frame = deribit.request(Op::Subscribe, &trades).unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["id"], 1);
+        assert_eq!(frame["method"], "public/subscribe");
+        assert_eq!(frame["params"]["channels"][0], "trades.BTC-PERPETUAL.100ms");
+
+        // Answers to heartbeat calls don't touch the requests in flight
+        assert!(decoded(&mut deribit, r#"{"jsonrpc":"2.0","id":0,"result":"ok"}"#).is_empty());
+        let acked = decoded(
+            &mut deribit,
+            r#"{"jsonrpc":"2.0","id":1,"result":["trades.BTC-PERPETUAL.100ms"]}"#,
+        );
+        assert!(matches!(&acked[..], [Output::Acked(Op::Subscribe, r)] if *r == trades));
+
+        let test = decoded(
+            &mut deribit,
+            r#"{"jsonrpc":"2.0","method":"heartbeat","params":{"type":"test_request"}}"#,
+        );
+        let [Output::Send(frame)] = &test[..] else {
+            panic!("Expected a public/test call, got {test:?}");
+        };
+        let frame: serde_json::Value = serde_json::from_str(frame).unwrap();
+        assert_eq!(frame["method"], "public/test");
+        assert_eq!(frame["id"], HEARTBEAT_ID);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeats() {
+        let exchange = MockExchange::deribit().await;
+        exchange.reject("NOPE-PERPETUAL");
+        let handle = connect(DeribitProtocol::new(exchange.url()).with_heartbeat(5));
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("BTC-PERPETUAL", DataTypes::Trade);
+        handle.subscribe(trades, client.clone()).await.unwrap();
+        // Deribit takes no interval under 10 seconds
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[0]["method"], "public/set_heartbeat");
+        assert_eq!(ops[0]["params"]["interval"], 10);
+
+        // A `test_request` has to be answered with a `public/test` call
+        exchange.test_request();
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops[2]["method"], "public/test");
+        assert_eq!(ops[2]["id"], HEARTBEAT_ID);
+        exchange.deribit_trade("BTC-PERPETUAL", "30460.5", "100", "sell");
+        let trade = next_event(&mut events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.quantity == "100.0".parse::<Decimal>().unwrap()),
+            "{trade:?}"
+        );
+        assert_eq!(handle.stats().heartbeats, 1);
+
+        // The answer to an unknown instrument just leaves its channel out
+        let rejected = handle
+            .subscribe(request("NOPE-PERPETUAL", DataTypes::Trade), client)
+            .await;
+        assert!(
+            matches!(&rejected, Err(SubscribeError::Exchange { code, message }) if code == "-32602" && message.contains("NOPE")),
+            "{rejected:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_option_book() {
+        let exchange = MockExchange::deribit().await;
+        let handle = connect(DeribitProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("BTC-29SEP23-30000-C", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        let option = "BTC-29SEP23-30000-C";
+        exchange.deribit_book(
+            option,
+            (100, None),
+            &[("new", "0.026", "12.5")],
+            &[("new", "0.0245", "3")],
+        );
+        exchange.deribit_book(option, (101, Some(100)), &[], &[("delete", "0.0245", "0")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.symbol == option && s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let removed = next_event(&mut events).await;
+        assert!(
+            matches!(&removed, Event::OrderbookUpdate(u) if u.quantity.is_zero()),
+            "{removed:?}"
+        );
+
+        // A change that doesn't follow the last one resubscribes for a fresh snapshot
+        exchange.deribit_book(option, (104, Some(103)), &[("new", "0.027", "1")], &[]);
+        let resync = next_event(&mut events).await;
+        assert!(
+            matches!(&resync, Event::Resync(r) if r.symbol == option),
+            "{resync:?}"
+        );
+        let ops = exchange.ops(4).await;
+        assert_eq!(ops[2]["method"], "public/unsubscribe");
+        assert_eq!(ops[3]["method"], "public/subscribe");
+        assert_eq!(
+            ops[3]["params"]["channels"][0],
+            "book.BTC-29SEP23-30000-C.100ms"
+        );
+    }
+}
@@ -0,0 +1,479 @@
+use std::{collections::HashMap, mem, sync::Arc};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol},
+        sequence::{FeedStats, Sequence, SequenceTracker},
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{Event, Resync, Timestamps},
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::huobi::{inflate, BboRaw, HuobiPing, HuobiPush, HuobiReply, MbpRaw, TradeDetailRaw},
+};
+
+/// Huobi spot market websocket
+pub const HUOBI_URL: &str = "wss://api.huobi.pro/ws";
+
+/// `mbp` depths Huobi offers. 400 levels are only served from `wss://api.huobi.pro/feed`.
+pub const DEPTHS: [usize; 4] = [5, 20, 150, 400];
+
+/// Book depth subscribed when none is given
+const DEFAULT_DEPTH: usize = 150;
+
+/// Where an `mbp` topic is in Huobi's book sync
+#[derive(Debug)]
+enum MbpSync {
+    /// Waiting for the snapshot asked for with a `req`, updates are kept until it arrives
+    Buffering {
+        updates: Vec<MbpRaw>,
+        requested: bool,
+    },
+    /// The snapshot at `seq_num` is out, the first update applied must follow it
+    Snapshot { seq_num: i64 },
+    /// Every update has to follow the last one by `prevSeqNum`
+    Streaming,
+}
+
+impl Default for MbpSync {
+    fn default() -> Self {
+        MbpSync::Buffering {
+            updates: Vec::new(),
+            requested: false,
+        }
+    }
+}
+
+/// The Huobi market websocket. Every frame arrives gzip-compressed and the exchange's `ping`s
+/// have to be echoed as `pong`s. Trades come from `trade.detail`, best bid and offer from `bbo`
+/// and books from `mbp` incremental updates applied on top of a snapshot asked for with `req`.
+#[derive(Debug)]
+pub struct HuobiProtocol {
+    url: String,
+    /// Levels per side of every `mbp` subscription
+    depth: usize,
+    next_id: u64,
+    /// Requests waiting on their answer, by `id`
+    ids: HashMap<String, (Op, SocketRequest)>,
+    /// Snapshot `req`s waiting on their answer, topic by `id`
+    snapshots: HashMap<String, String>,
+    /// Request of every subscribed topic
+    streams: HashMap<String, SocketRequest>,
+    /// Sync state of every `mbp` topic
+    books: HashMap<String, MbpSync>,
+    sequences: SequenceTracker<String>,
+    stats: Arc<FeedStats>,
+}
+
+impl HuobiProtocol {
+    pub fn new(url: &str) -> Self {
+        let stats = Arc::new(FeedStats::default());
+        Self {
+            url: url.to_string(),
+            depth: DEFAULT_DEPTH,
+            next_id: 0,
+            ids: HashMap::new(),
+            snapshots: HashMap::new(),
+            streams: HashMap::new(),
+            books: HashMap::new(),
+            sequences: SequenceTracker::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// Subscribe books `depth` levels deep instead of 150, one of [`DEPTHS`]
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Huobi symbol of a request, `BTC-USDT` becomes `btcusdt`
+    pub fn symbol(symbol: &str) -> String {
+        symbol.to_lowercase().replace(['-', '/', '_'], "")
+    }
+
+    /// Symbol events of a request are named by, interned the first time it is seen
+    fn event_symbol(request: &SocketRequest) -> Symbol {
+        Symbol::canonical(Exchange::Huobi, &request.symbol, Self::symbol)
+    }
+
+    fn topic(&self, request: &SocketRequest) -> String {
+        let symbol = Self::symbol(&request.symbol);
+        match request.data_type {
+            DataTypes::Trade => format!("market.{symbol}.trade.detail"),
+            DataTypes::Book => format!("market.{symbol}.mbp.{}", self.depth),
+            DataTypes::Bbo => format!("market.{symbol}.bbo"),
+        }
+    }
+
+    fn id(&mut self) -> String {
+        self.next_id += 1;
+        self.next_id.to_string()
+    }
+
+    /// `req` frame asking for the snapshot of an `mbp` topic
+    fn request_snapshot(&mut self, topic: &str) -> Output {
+        let id = self.id();
+        self.snapshots.insert(id.clone(), topic.to_string());
+        Output::Send(serde_json::json!({"req": topic, "id": id}).to_string())
+    }
+
+    fn push(&mut self, push: HuobiPush, outputs: &mut Vec<Output>) {
+        let Some(request) = self.streams.get(&push.ch).cloned() else {
+            return;
+        };
+        let symbol = Self::event_symbol(&request);
+
+        match push.ch.rsplit('.').next() {
+            Some("detail") => {
+                if let Some(detail) = push.tick::<TradeDetailRaw>() {
+                    outputs.extend(detail.data.into_iter().map(|trade| {
+                        Output::Event(request, Event::Trade(trade.into_trade(symbol)))
+                    }));
+                }
+            }
+            Some("bbo") => {
+                if let Some(bbo) = push.tick::<BboRaw>() {
+                    outputs.push(Output::Event(request, bbo.into_event(symbol)));
+                }
+            }
+            _ => {
+                if let Some(mut update) = push.tick::<MbpRaw>() {
+                    update.ts = push.ts;
+                    self.mbp(&request, push.ch, update, outputs);
+                }
+            }
+        }
+    }
+
+    /// Apply an update once the book is synced, buffer it until then. Updates older than the
+    /// snapshot are dropped, one that doesn't follow the last applied update restarts the sync.
+    fn mbp(
+        &mut self,
+        request: &SocketRequest,
+        topic: String,
+        update: MbpRaw,
+        outputs: &mut Vec<Output>,
+    ) {
+        let Some(sync) = self.books.get_mut(&topic) else {
+            return;
+        };
+        let prev = update.prev_seq_num.unwrap_or_default();
+
+        let synced = match sync {
+            MbpSync::Buffering { updates, requested } => {
+                updates.push(update);
+                if !mem::replace(requested, true) {
+                    outputs.push(self.request_snapshot(&topic));
+                }
+                return;
+            }
+            MbpSync::Snapshot { seq_num } => {
+                let seq_num = *seq_num;
+                if update.seq_num <= seq_num {
+                    return;
+                }
+                if prev > seq_num {
+                    FeedStats::incr(&self.stats.gaps);
+                    Err(format!(
+                        "Gap between the snapshot at {seq_num} and the update after {prev}"
+                    ))
+                } else {
+                    self.sequences
+                        .check(topic.clone(), update.seq_num, Some(prev), true);
+                    *sync = MbpSync::Streaming;
+                    Ok(())
+                }
+            }
+            MbpSync::Streaming => {
+                match self
+                    .sequences
+                    .check(topic.clone(), update.seq_num, Some(prev), false)
+                {
+                    Sequence::InOrder => Ok(()),
+                    Sequence::Duplicate | Sequence::OutOfOrder => return,
+                    sequence => Err(format!("{sequence:?}")),
+                }
+            }
+        };
+
+        match synced {
+            Ok(()) => {
+                let events = update.into_events(Self::event_symbol(request), false);
+                outputs.extend(
+                    events
+                        .into_iter()
+                        .map(|event| Output::Event(*request, event)),
+                );
+            }
+            Err(reason) => self.resync(request, topic, update, reason, outputs),
+        }
+    }
+
+    /// Drop the book and ask for a new snapshot, starting over with `update`
+    fn resync(
+        &mut self,
+        request: &SocketRequest,
+        topic: String,
+        update: MbpRaw,
+        reason: String,
+        outputs: &mut Vec<Output>,
+    ) {
+        FeedStats::incr(&self.stats.resyncs);
+        self.sequences.reset(&topic);
+        self.books.insert(
+            topic.clone(),
+            MbpSync::Buffering {
+                updates: vec![update],
+                requested: true,
+            },
+        );
+
+        let event = Event::Resync(Resync {
+            exchange: Exchange::Huobi,
+            symbol: Self::event_symbol(request),
+            reason: format!("{reason} on mbp"),
+            ts: Timestamps::default(),
+        });
+        outputs.push(Output::Event(*request, event));
+        outputs.push(self.request_snapshot(&topic));
+    }
+
+    /// Publish the snapshot and apply the buffered updates on top of it. A failed `req` is
+    /// retried with the next update.
+    fn snapshot(&mut self, topic: String, reply: HuobiReply, outputs: &mut Vec<Output>) {
+        let Some(request) = self.streams.get(&topic).cloned() else {
+            return;
+        };
+        let Some(MbpSync::Buffering {
+            updates,
+            requested: true,
+        }) = self.books.get_mut(&topic)
+        else {
+            return;
+        };
+        let updates = mem::take(updates);
+
+        let snapshot = reply
+            .data
+            .filter(|_| reply.status == "ok")
+            .and_then(|data| serde_json::from_str::<MbpRaw>(data.get()).ok());
+        let Some(mut snapshot) = snapshot else {
+            eprintln!(
+                "HuobiProtocol: snapshot of {topic} failed: {}",
+                reply.err_msg
+            );
+            self.books.insert(topic, MbpSync::default());
+            return;
+        };
+
+        snapshot.ts = reply.ts;
+        let seq_num = snapshot.seq_num;
+        self.books
+            .insert(topic.clone(), MbpSync::Snapshot { seq_num });
+        let symbol = Self::event_symbol(&request);
+        let events = snapshot.into_events(symbol, true);
+        outputs.extend(
+            events
+                .into_iter()
+                .map(|event| Output::Event(request, event)),
+        );
+        for update in updates {
+            self.mbp(&request, topic.clone(), update, outputs);
+        }
+    }
+
+    fn answer(&mut self, reply: HuobiReply, outputs: &mut Vec<Output>) {
+        if let Some(topic) = reply.id.as_ref().and_then(|id| self.snapshots.remove(id)) {
+            return self.snapshot(topic, reply, outputs);
+        }
+
+        let request = reply.id.as_ref().and_then(|id| self.ids.remove(id));
+        match (reply.is_ok(), request) {
+            (true, Some((op, request))) => outputs.push(Output::Acked(op, request)),
+            (false, request) => {
+                let error = SubscribeError::Exchange {
+                    code: reply.err_code,
+                    message: reply.err_msg,
+                };
+                outputs.push(Output::Rejected(request, error));
+            }
+            (true, None) => {}
+        }
+    }
+}
+
+impl Protocol for HuobiProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::Huobi
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.sequences = SequenceTracker::new(stats.clone());
+        self.stats = stats;
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        self.capabilities().check_depth(self.depth)?;
+        let topic = self.topic(request);
+        let key = match op {
+            Op::Subscribe => {
+                self.streams.insert(topic.clone(), *request);
+                if request.data_type == DataTypes::Book {
+                    self.books.insert(topic.clone(), MbpSync::default());
+                }
+                "sub"
+            }
+            Op::Unsubscribe => {
+                self.streams.remove(&topic);
+                if request.data_type == DataTypes::Book {
+                    self.books.remove(&topic);
+                    self.sequences.reset(&topic);
+                }
+                "unsub"
+            }
+        };
+
+        let id = self.id();
+        self.ids.insert(id.clone(), (op, *request));
+        Ok(serde_json::json!({ key: topic, "id": id }).to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        if let Ok(push) = serde_json::from_str::<HuobiPush>(frame) {
+            return self.push(push, outputs);
+        }
+        if let Ok(ping) = serde_json::from_str::<HuobiPing>(frame) {
+            let pong = serde_json::json!({"pong": ping.ping}).to_string();
+            return outputs.push(Output::Send(pong));
+        }
+        if let Ok(reply) = serde_json::from_str::<HuobiReply>(frame) {
+            self.answer(reply, outputs);
+        }
+    }
+
+    fn inflate(&self, frame: &[u8]) -> Option<String> {
+        inflate(frame)
+    }
+
+    /// Every book syncs again from a new snapshot, the updates of two connections don't chain
+    fn reset(&mut self) {
+        self.ids.clear();
+        self.snapshots.clear();
+        self.books
+            .values_mut()
+            .for_each(|sync| *sync = MbpSync::default());
+        self.sequences.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, decoded, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[test]
+    fn test_topics() {
+        let mut huobi = HuobiProtocol::new(HUOBI_URL).with_depth(20);
+        let frame = huobi
+            .request(Op::Subscribe, &request("BTC-USDT", DataTypes::Book))
+            .unwrap();
+        assert_eq!(frame, r#"{"id":"1","sub":"market.btcusdt.mbp.20"}"#);
+        let frame = huobi
+            .request(Op::Unsubscribe, &request("btcusdt", DataTypes::Trade))
+            .unwrap();
+        assert_eq!(frame, r#"{"id":"2","unsub":"market.btcusdt.trade.detail"}"#);
+
+        let pong = decoded(&mut huobi, r#"{"ping":1492420473027}"#);
+        assert!(
+            matches!(&pong[..], [Output::Send(frame)] if frame == r#"{"pong":1492420473027}"#),
+            "{pong:?}"
+        );
+
+        let mut huobi = HuobiProtocol::new(HUOBI_URL).with_depth(50);
+        let error = huobi.request(Op::Subscribe, &request("btcusdt", DataTypes::Book));
+        assert!(matches!(error, Err(SubscribeError::NotOffered { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_gzip_ping() {
+        let exchange = MockExchange::huobi().await;
+        let handle = connect(HuobiProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("btcusdt", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+
+        // Pings arrive gzip-compressed like everything else, each is echoed as its own pong
+        exchange.push(r#"{"ping":1492420473027}"#);
+        exchange.push(r#"{"ping":1492420473028}"#);
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops[1], serde_json::json!({"pong": 1492420473027_i64}));
+        assert_eq!(ops[2], serde_json::json!({"pong": 1492420473028_i64}));
+
+        exchange.huobi_trade("btcusdt", "52648.62", "0.006754", "sell");
+        let trade = next_event(&mut events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "btcusdt" && t.exchange == Exchange::Huobi),
+            "{trade:?}"
+        );
+        assert_eq!(exchange.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mbp_sync() {
+        let exchange = MockExchange::huobi().await;
+        let handle = connect(HuobiProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("btcusdt", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        // The first update asks for the snapshot, which drops anything it already covers
+        let topic = "market.btcusdt.mbp.150";
+        exchange.mbp_snapshot(topic, 10, &[("645.14", "26.7")], &[("645.1", "3")]);
+        exchange.mbp(topic, (10, 9), &[("645.14", "20")], &[]);
+        exchange.mbp(topic, (11, 10), &[("645.14", "0")], &[]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.symbol == "btcusdt" && s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let removed = next_event(&mut events).await;
+        assert!(
+            matches!(&removed, Event::OrderbookUpdate(u) if u.quantity.is_zero()),
+            "{removed:?}"
+        );
+        assert_eq!(exchange.fetches(), 1);
+
+        // An update that skips one asks for a new snapshot
+        exchange.mbp_snapshot(topic, 20, &[("645.2", "1")], &[]);
+        exchange.mbp(topic, (21, 20), &[("645.3", "1")], &[]);
+        let resync = next_event(&mut events).await;
+        assert!(
+            matches!(&resync, Event::Resync(r) if r.symbol == "btcusdt"),
+            "{resync:?}"
+        );
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels[0].price == "645.2".parse::<Decimal>().unwrap()),
+            "{snapshot:?}"
+        );
+        let update = next_event(&mut events).await;
+        assert!(
+            matches!(&update, Event::OrderbookUpdate(u) if u.price == "645.3".parse::<Decimal>().unwrap()),
+            "{update:?}"
+        );
+        assert_eq!(exchange.fetches(), 2);
+    }
+}
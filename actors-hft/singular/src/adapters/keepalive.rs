@@ -0,0 +1,268 @@
+use std::{collections::HashMap, hash::Hash, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::models::Exchange;
+
+/// How an adapter keeps its exchange connection alive and decides it has gone quiet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    /// Send `ping` after this long without receiving anything
+    pub ping_interval: Duration,
+    /// Reconnect if the `pong` doesn't arrive within this long
+    pub pong_timeout: Duration,
+    /// Application level ping frame the exchange expects, empty to send a websocket ping
+    pub ping: String,
+    /// Frame the exchange answers a ping with, empty for a websocket pong or a reply the protocol
+    /// decodes itself
+    pub pong: String,
+    /// Resubscribe a watched channel when it delivers no data for this long. `None` disables the
+    /// watchdog.
+    pub stale_after: Option<Duration>,
+}
+
+impl KeepaliveConfig {
+    /// Defaults for an exchange. OKX drops connections that are silent for 30 seconds unless a
+    /// text `ping` is sent. Binance and Coinbase reject text they can't parse and Kraken pushes a
+    /// heartbeat every second, so they only get websocket pings. Bybit wants an `op` ping every 20
+    /// seconds.
+    pub fn for_exchange(exchange: Exchange) -> Self {
+        match exchange {
+            Exchange::Okx => Self {
+                ping_interval: Duration::from_secs(20),
+                pong_timeout: Duration::from_secs(10),
+                ping: "ping".into(),
+                pong: "pong".into(),
+                stale_after: Some(Duration::from_secs(60)),
+            },
+            Exchange::BinanceUsdm
+            | Exchange::BinanceCoinm
+            | Exchange::Coinbase
+            | Exchange::Kraken
+            | Exchange::Deribit => Self {
+                ping_interval: Duration::from_secs(30),
+                pong_timeout: Duration::from_secs(10),
+                ping: String::new(),
+                pong: String::new(),
+                stale_after: None,
+            },
+            Exchange::ByBit => Self {
+                ping_interval: Duration::from_secs(20),
+                pong_timeout: Duration::from_secs(10),
+                ping: r#"{"op":"ping"}"#.into(),
+                pong: String::new(),
+                stale_after: None,
+            },
+            // Huobi pings every 5 seconds on its own, the protocol answers those
+            Exchange::Huobi => Self {
+                ping_interval: Duration::from_secs(30),
+                pong_timeout: Duration::from_secs(10),
+                ping: String::new(),
+                pong: String::new(),
+                stale_after: None,
+            },
+            // Quiet channels get an `hb` every 15 seconds
+            Exchange::Bitfinex => Self {
+                ping_interval: Duration::from_secs(30),
+                pong_timeout: Duration::from_secs(10),
+                ping: r#"{"event":"ping"}"#.into(),
+                pong: String::new(),
+                stale_after: None,
+            },
+            // Bitstamp answers `bts:heartbeat` in kind
+            Exchange::BitStamp => Self {
+                ping_interval: Duration::from_secs(30),
+                pong_timeout: Duration::from_secs(10),
+                ping: r#"{"event":"bts:heartbeat"}"#.into(),
+                pong: String::new(),
+                stale_after: None,
+            },
+            // The server a connection is handed out with names its own interval and timeout
+            Exchange::KuCoin => Self {
+                ping_interval: Duration::from_secs(18),
+                pong_timeout: Duration::from_secs(10),
+                ping: r#"{"id":"ping","type":"ping"}"#.into(),
+                pong: String::new(),
+                stale_after: None,
+            },
+        }
+    }
+}
+
+/// What the adapter should do when the keepalive deadline passes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepaliveAction<K> {
+    /// Nothing is due yet
+    Idle,
+    /// Send [`KeepaliveConfig::ping`]
+    Ping,
+    /// A watched channel went stale, unsubscribe and subscribe it again. The rest of the
+    /// connection carries on.
+    Resubscribe(K),
+    /// The connection is dead, reconnect
+    Reconnect(String),
+}
+
+/// Keepalive and per-channel inactivity state of one connection
+#[derive(Debug)]
+pub struct Keepalive<K> {
+    pub config: KeepaliveConfig,
+    last_received: Instant,
+    ping_sent: Option<Instant>,
+    channels: HashMap<K, Instant>,
+}
+
+impl<K: Hash + Eq + Clone + std::fmt::Debug> Keepalive<K> {
+    pub fn new(config: KeepaliveConfig) -> Self {
+        Self {
+            config,
+            last_received: Instant::now(),
+            ping_sent: None,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Restart every timer, used once a connection is (re)established
+    pub fn connected(&mut self) {
+        let now = Instant::now();
+        self.last_received = now;
+        self.ping_sent = None;
+        self.channels.values_mut().for_each(|seen| *seen = now);
+    }
+
+    /// Any frame arrived. Returns `true` if it was the pong and needs no further handling.
+    pub fn received(&mut self, text: &str) -> bool {
+        self.last_received = Instant::now();
+        self.ping_sent = None;
+        text == self.config.pong
+    }
+
+    /// Start watching a channel for inactivity. Only channels that push steadily, books and
+    /// tickers, are worth watching, the trades of an illiquid instrument can be quiet for minutes.
+    pub fn watch(&mut self, channel: K) {
+        self.channels.entry(channel).or_insert_with(Instant::now);
+    }
+
+    pub fn unwatch(&mut self, channel: &K) {
+        self.channels.remove(channel);
+    }
+
+    /// Every watched channel
+    pub fn channels(&self) -> impl Iterator<Item = &K> {
+        self.channels.keys()
+    }
+
+    /// Data arrived on a channel
+    pub fn data(&mut self, channel: &K) {
+        if let Some(seen) = self.channels.get_mut(channel) {
+            *seen = Instant::now();
+        }
+    }
+
+    /// When [`Keepalive::poll`] next has something to do
+    pub fn deadline(&self) -> Instant {
+        let ping = match self.ping_sent {
+            Some(sent) => sent + self.config.pong_timeout,
+            None => self.last_received + self.config.ping_interval,
+        };
+
+        match (self.config.stale_after, self.channels.values().min()) {
+            (Some(stale_after), Some(oldest)) => ping.min(*oldest + stale_after),
+            _ => ping,
+        }
+    }
+
+    pub fn poll(&mut self) -> KeepaliveAction<K> {
+        let now = Instant::now();
+
+        if let Some(stale_after) = self.config.stale_after {
+            if let Some((channel, seen)) = self
+                .channels
+                .iter_mut()
+                .find(|(_, seen)| now.duration_since(**seen) >= stale_after)
+            {
+                eprintln!("Keepalive: no data on {channel:?} for {stale_after:?}, resubscribing");
+                // Given another `stale_after` to come back
+                *seen = now;
+                return KeepaliveAction::Resubscribe(channel.clone());
+            }
+        }
+
+        match self.ping_sent {
+            Some(sent) if now.duration_since(sent) >= self.config.pong_timeout => {
+                KeepaliveAction::Reconnect(format!("No pong within {:?}", self.config.pong_timeout))
+            }
+            None if now.duration_since(self.last_received) >= self.config.ping_interval => {
+                self.ping_sent = Some(now);
+                KeepaliveAction::Ping
+            }
+            _ => KeepaliveAction::Idle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> KeepaliveConfig {
+        KeepaliveConfig {
+            ping_interval: Duration::from_secs(20),
+            pong_timeout: Duration::from_secs(10),
+            stale_after: Some(Duration::from_secs(60)),
+            ..KeepaliveConfig::for_exchange(Exchange::Okx)
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ping_pong() {
+        let mut keepalive: Keepalive<&str> = Keepalive::new(config());
+        assert_eq!(keepalive.poll(), KeepaliveAction::Idle);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(keepalive.poll(), KeepaliveAction::Ping);
+        assert_eq!(
+            keepalive.deadline(),
+            Instant::now() + Duration::from_secs(10)
+        );
+
+        assert!(keepalive.received("pong"));
+        assert_eq!(keepalive.poll(), KeepaliveAction::Idle);
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert_eq!(keepalive.poll(), KeepaliveAction::Ping);
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(matches!(keepalive.poll(), KeepaliveAction::Reconnect(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stale_channel() {
+        let mut keepalive = Keepalive::new(config());
+        keepalive.watch("trades");
+        keepalive.watch("books");
+
+        // Pongs keep the connection alive but not the channels
+        for _ in 0..5 {
+            tokio::time::advance(Duration::from_secs(12)).await;
+            assert!(keepalive.received("pong"));
+            keepalive.data(&"books");
+        }
+        assert_eq!(keepalive.poll(), KeepaliveAction::Resubscribe("trades"));
+        // Only the stale channel is resubscribed, and only once per `stale_after`, the next thing
+        // due is the ping
+        assert_eq!(keepalive.poll(), KeepaliveAction::Idle);
+        assert_eq!(
+            keepalive.deadline(),
+            Instant::now() + Duration::from_secs(20)
+        );
+
+        keepalive.unwatch(&"trades");
+        assert_eq!(keepalive.poll(), KeepaliveAction::Idle);
+
+        keepalive.connected();
+        assert_eq!(
+            keepalive.deadline(),
+            Instant::now() + Duration::from_secs(20)
+        );
+    }
+}
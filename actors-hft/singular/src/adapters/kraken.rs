@@ -0,0 +1,346 @@
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Output, Protocol},
+        sequence::FeedStats,
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{Event, Resync, Timestamps, Trade},
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::kraken::{
+        symbol, BookRaw, KrakenBook, KrakenPush, KrakenReply, TickerRaw, TradeRaw, DEPTH_LIMIT,
+    },
+};
+
+/// Kraken spot public websocket, v2
+pub const KRAKEN_URL: &str = "wss://ws.kraken.com/v2";
+
+/// Book depths Kraken offers
+pub const DEPTHS: [usize; 5] = [10, 25, 100, 500, DEPTH_LIMIT];
+
+/// The Kraken v2 websocket. Trades come from `trade`, best bid and offer from `ticker` and books
+/// from `book`, validated by checksum and resubscribed when they break.
+#[derive(Debug)]
+pub struct KrakenProtocol {
+    url: String,
+    /// Levels per side of every `book` subscription
+    depth: usize,
+    next_id: u64,
+    /// Requests waiting on their answer, by `req_id`
+    ids: HashMap<u64, (Op, SocketRequest)>,
+    /// Request of every subscribed channel and canonical pair
+    streams: HashMap<(&'static str, Symbol), SocketRequest>,
+    /// Book of every `book` subscription, by canonical pair
+    books: HashMap<Symbol, KrakenBook>,
+    stats: Arc<FeedStats>,
+}
+
+impl KrakenProtocol {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            depth: DEPTHS[0],
+            next_id: 0,
+            ids: HashMap::new(),
+            streams: HashMap::new(),
+            books: HashMap::new(),
+            stats: Arc::default(),
+        }
+    }
+
+    /// Subscribe books `depth` levels deep instead of 10, one of [`DEPTHS`]
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    fn channel(data_type: DataTypes) -> &'static str {
+        match data_type {
+            DataTypes::Trade => "trade",
+            DataTypes::Book => "book",
+            DataTypes::Bbo => "ticker",
+        }
+    }
+
+    /// `params` of a request. Trades skip the snapshot of recent trades, the ticker only pushes
+    /// when the best bid or offer changes.
+    fn params(&self, channel: &str, pair: &str) -> serde_json::Value {
+        let mut params = serde_json::json!({
+            "channel": channel,
+            "symbol": [pair],
+        });
+        match channel {
+            "trade" => params["snapshot"] = false.into(),
+            "book" => params["depth"] = self.depth.into(),
+            "ticker" => params["event_trigger"] = "bbo".into(),
+            _ => {}
+        }
+        params
+    }
+
+    /// Tag events with the request streaming `channel` for `pair`
+    fn route(
+        &self,
+        channel: &'static str,
+        pair: Symbol,
+        events: impl IntoIterator<Item = Event>,
+        outputs: &mut Vec<Output>,
+    ) {
+        if let Some(request) = self.streams.get(&(channel, pair)) {
+            outputs.extend(
+                events
+                    .into_iter()
+                    .map(|event| Output::Event(*request, event)),
+            );
+        }
+    }
+
+    /// Apply a `book` push and check it against its checksum. Only the first mismatch asks for a
+    /// resync, everything after it is dropped until the snapshot of the resubscribe arrives.
+    fn book(&mut self, raw: BookRaw, is_snapshot: bool, outputs: &mut Vec<Output>) {
+        let pair = symbol(&raw.symbol);
+        let Some(request) = self.streams.get(&("book", pair)).cloned() else {
+            return;
+        };
+        let Some(book) = self.books.get_mut(&pair) else {
+            return;
+        };
+
+        let was_valid = book.valid;
+        if book.verify(&raw, is_snapshot) {
+            let events = raw.into_events(is_snapshot).into_iter();
+            outputs.extend(events.map(|event| Output::Event(request, event)));
+            return;
+        }
+        if !was_valid && !is_snapshot {
+            return;
+        }
+
+        FeedStats::incr(&self.stats.checksum_mismatches);
+        FeedStats::incr(&self.stats.resyncs);
+        let event = Event::Resync(Resync {
+            exchange: Exchange::Kraken,
+            symbol: pair,
+            reason: "Checksum mismatch on book".to_string(),
+            ts: Timestamps::default(),
+        });
+        outputs.extend([Output::Event(request, event), Output::Resubscribe(request)]);
+    }
+
+    fn answer(&mut self, reply: KrakenReply, outputs: &mut Vec<Output>) {
+        let request = reply.req_id.and_then(|id| self.ids.remove(&id));
+        match (reply.success, request) {
+            (Some(true), Some((op, request))) => outputs.push(Output::Acked(op, request)),
+            (Some(false), request) => {
+                let error = SubscribeError::Exchange {
+                    code: reply.method,
+                    message: reply.error.unwrap_or_default(),
+                };
+                outputs.push(Output::Rejected(request, error));
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Protocol for KrakenProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::Kraken
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.stats = stats;
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        self.capabilities().check_depth(self.depth)?;
+        let channel = Self::channel(request.data_type);
+        let pair = symbol(&request.symbol);
+        let params = self.params(channel, &pair);
+
+        let key = (channel, pair);
+        match op {
+            Op::Subscribe => {
+                self.streams.insert(key, *request);
+                if request.data_type == DataTypes::Book {
+                    self.books.insert(pair, KrakenBook::new(self.depth));
+                }
+            }
+            Op::Unsubscribe => {
+                self.streams.remove(&key);
+                if request.data_type == DataTypes::Book {
+                    self.books.remove(&pair);
+                }
+            }
+        }
+
+        self.next_id += 1;
+        self.ids.insert(self.next_id, (op, *request));
+        Ok(serde_json::json!({
+            "method": op.as_str(),
+            "params": params,
+            "req_id": self.next_id,
+        })
+        .to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(push) = serde_json::from_str::<KrakenPush>(frame) else {
+            if let Ok(reply) = serde_json::from_str::<KrakenReply>(frame) {
+                self.answer(reply, outputs);
+            }
+            return;
+        };
+
+        match push.channel.as_str() {
+            "trade" => {
+                for raw in push.data::<TradeRaw>() {
+                    let trade: Trade = raw.into();
+                    self.route("trade", trade.symbol, [Event::Trade(trade)], outputs);
+                }
+            }
+            "ticker" => {
+                for raw in push.data::<TickerRaw>() {
+                    let pair = symbol(&raw.symbol);
+                    self.route("ticker", pair, [raw.into_event()], outputs);
+                }
+            }
+            "book" => {
+                let is_snapshot = push.is_snapshot();
+                for raw in push.data::<BookRaw>() {
+                    self.book(raw, is_snapshot, outputs);
+                }
+            }
+            // `heartbeat` and `status`
+            _ => {}
+        }
+    }
+
+    /// Every book waits for the snapshot the resubscribe brings
+    fn reset(&mut self) {
+        self.ids.clear();
+        for book in self.books.values_mut() {
+            *book = KrakenBook::new(self.depth);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[test]
+    fn test_params() {
+        let mut kraken = KrakenProtocol::new(KRAKEN_URL).with_depth(25);
+        let frame = kraken
+            .request(Op::Subscribe, &request("XBT/USD", DataTypes::Book))
+            .unwrap();
+        let frame: serde_json::Value = serde_json::from_str(&frame).unwrap();
+        assert_eq!(frame["params"]["symbol"][0], "BTC/USD");
+        assert_eq!(frame["params"]["depth"], 25);
+        assert_eq!(frame["req_id"], 1);
+
+        let mut kraken = KrakenProtocol::new(KRAKEN_URL).with_depth(20);
+        let error = kraken.request(Op::Subscribe, &request("XBT/USD", DataTypes::Book));
+        assert!(matches!(error, Err(SubscribeError::NotOffered { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_aliases() {
+        let exchange = MockExchange::kraken().await;
+        exchange.reject("NOPE/USD");
+        let handle = connect(KrakenProtocol::new(exchange.url()));
+
+        // `XBT` and `XDG` go out as `BTC` and `DOGE`, and their pushes come back to the client
+        // that asked for the Kraken name
+        let (btc, mut btc_events) = mpsc::channel(16);
+        let (doge, mut doge_events) = mpsc::channel(16);
+        let subscribe = handle.subscribe(request("XBT/USD", DataTypes::Trade), btc);
+        subscribe.await.unwrap();
+        let subscribe = handle.subscribe(request("xdg-usd", DataTypes::Trade), doge.clone());
+        subscribe.await.unwrap();
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[0]["params"]["symbol"][0], "BTC/USD");
+        assert_eq!(ops[1]["params"]["symbol"][0], "DOGE/USD");
+
+        exchange.kraken_trade("DOGE/USD", "0.0612", "1500", "sell");
+        exchange.kraken_trade("BTC/USD", "30460.1", "0.5", "buy");
+        let trade = next_event(&mut btc_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "BTC/USD" && t.exchange == Exchange::Kraken),
+            "{trade:?}"
+        );
+        let trade = next_event(&mut doge_events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "DOGE/USD"),
+            "{trade:?}"
+        );
+
+        let rejected = handle
+            .subscribe(request("NOPE/USD", DataTypes::Trade), doge)
+            .await;
+        assert!(
+            matches!(&rejected, Err(SubscribeError::Exchange { message, .. }) if message.contains("NOPE/USD")),
+            "{rejected:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_book_checksum() {
+        let exchange = MockExchange::kraken().await;
+        let handle = connect(KrakenProtocol::new(exchange.url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("XBT/USD", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        exchange.kraken_book(
+            "snapshot",
+            "BTC/USD",
+            &[("30461.0", "2.00")],
+            &[("30460.0", "1.50")],
+        );
+        exchange.kraken_book("update", "BTC/USD", &[("30461.0", "0.00")], &[]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.symbol == "BTC/USD" && s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let removed = next_event(&mut events).await;
+        assert!(matches!(&removed, Event::OrderbookUpdate(u) if u.quantity.is_zero()));
+
+        // A push that doesn't match its checksum resubscribes for a fresh snapshot
+        exchange.push(
+            r#"{"channel":"book","type":"update","data":[{"symbol":"BTC/USD","bids":[{"price":30459.0,"qty":1.00}],"asks":[],"checksum":1}]}"#,
+        );
+        let resync = next_event(&mut events).await;
+        assert!(
+            matches!(&resync, Event::Resync(r) if r.symbol == "BTC/USD"),
+            "{resync:?}"
+        );
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops[1]["method"], "unsubscribe");
+        assert_eq!(ops[2]["method"], "subscribe");
+        assert_eq!(ops[2]["params"]["depth"], 10);
+
+        exchange.kraken_book("snapshot", "BTC/USD", &[("30462.0", "1.00")], &[]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels[0].price == "30462.0".parse::<Decimal>().unwrap()),
+            "{snapshot:?}"
+        );
+    }
+}
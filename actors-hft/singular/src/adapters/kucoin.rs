@@ -0,0 +1,557 @@
+use std::{
+    collections::HashMap,
+    mem,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        protocol::{Endpoint, EndpointFuture, Output, Protocol, SnapshotFuture},
+        sequence::{FeedStats, Sequence, SequenceTracker},
+        subscriptions::{Op, SubscribeError},
+    },
+    event::{Event, Resync, Timestamps},
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::kucoin::{
+        BulletRaw, KucoinFrame, KucoinRest, Level2Raw, MatchRaw, OrderBookRaw, TickerRaw,
+    },
+};
+
+/// KuCoin spot REST base. There is no fixed websocket url, every connection asks
+/// `{base}/bullet-public` for a token and the server to dial.
+pub const KUCOIN_REST: &str = "https://api.kucoin.com/api/v1";
+
+/// Where a `/market/level2` topic is in KuCoin's book sync
+#[derive(Debug)]
+enum Level2Sync {
+    /// Waiting for the REST order book, updates are kept until it arrives
+    Buffering {
+        updates: Vec<Level2Raw>,
+        fetching: bool,
+    },
+    /// The order book at `sequence` is out, the first update applied must straddle it
+    Snapshot { sequence: i64 },
+    /// Every update has to start where the last one ended
+    Streaming,
+}
+
+impl Default for Level2Sync {
+    fn default() -> Self {
+        Level2Sync::Buffering {
+            updates: Vec::new(),
+            fetching: false,
+        }
+    }
+}
+
+/// The KuCoin spot websocket. Every connection first negotiates a token and a server, along with
+/// the ping interval that server expects, over REST. Trades come from `/market/match`, best bid
+/// and offer from `/market/ticker` and books from `/market/level2` updates applied on top of a
+/// REST order book.
+pub struct KucoinProtocol {
+    rest_url: String,
+    client: reqwest::Client,
+    next_id: u64,
+    /// Requests waiting on their answer, by `id`
+    ids: HashMap<String, (Op, SocketRequest)>,
+    /// Request of every subscribed topic
+    streams: HashMap<String, SocketRequest>,
+    /// Sync state of every `/market/level2` topic, by symbol
+    books: HashMap<Symbol, Level2Sync>,
+    sequences: SequenceTracker<Symbol>,
+    stats: Arc<FeedStats>,
+}
+
+impl KucoinProtocol {
+    /// Negotiate connections and fetch order books from `rest_url`
+    pub fn new(rest_url: &str) -> Self {
+        let stats = Arc::new(FeedStats::default());
+        Self {
+            rest_url: rest_url.to_string(),
+            client: reqwest::Client::new(),
+            next_id: 0,
+            ids: HashMap::new(),
+            streams: HashMap::new(),
+            books: HashMap::new(),
+            sequences: SequenceTracker::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// KuCoin symbol of a request, `btc/usdt` becomes `BTC-USDT`
+    pub fn symbol(symbol: &str) -> String {
+        symbol.to_uppercase().replace(['/', '_'], "-")
+    }
+
+    /// Symbol events of a request are named by, interned the first time it is seen
+    fn event_symbol(request: &SocketRequest) -> Symbol {
+        Symbol::canonical(Exchange::KuCoin, &request.symbol, Self::symbol)
+    }
+
+    fn topic(request: &SocketRequest) -> String {
+        let symbol = Self::symbol(&request.symbol);
+        match request.data_type {
+            DataTypes::Trade => format!("/market/match:{symbol}"),
+            DataTypes::Book => format!("/market/level2:{symbol}"),
+            DataTypes::Bbo => format!("/market/ticker:{symbol}"),
+        }
+    }
+
+    fn id(&mut self) -> String {
+        self.next_id += 1;
+        self.next_id.to_string()
+    }
+
+    fn message(&mut self, frame: KucoinFrame, outputs: &mut Vec<Output>) {
+        let Some(request) = frame
+            .topic
+            .as_ref()
+            .and_then(|topic| self.streams.get(topic))
+            .cloned()
+        else {
+            return;
+        };
+
+        let event = match request.data_type {
+            DataTypes::Trade => frame.data::<MatchRaw>().map(|raw| Event::Trade(raw.into())),
+            DataTypes::Bbo => frame
+                .data::<TickerRaw>()
+                .map(|raw| raw.into_event(Self::event_symbol(&request))),
+            DataTypes::Book => {
+                if let Some(update) = frame.data::<Level2Raw>() {
+                    self.level2(&request, update, outputs);
+                }
+                return;
+            }
+        };
+        outputs.extend(event.map(|event| Output::Event(request, event)));
+    }
+
+    /// Apply an update once the book is synced, buffer it until then. Updates the order book
+    /// already covers are dropped, one that doesn't start where the last ended restarts the sync.
+    fn level2(&mut self, request: &SocketRequest, update: Level2Raw, outputs: &mut Vec<Output>) {
+        let symbol = Self::event_symbol(request);
+        let Some(sync) = self.books.get_mut(&symbol) else {
+            return;
+        };
+        let prev = update.sequence_start - 1;
+
+        let synced = match sync {
+            Level2Sync::Buffering { updates, fetching } => {
+                updates.push(update);
+                if !mem::replace(fetching, true) {
+                    outputs.push(Output::Fetch(*request));
+                }
+                return;
+            }
+            Level2Sync::Snapshot { sequence } => {
+                let sequence = *sequence;
+                if update.sequence_end <= sequence {
+                    return;
+                }
+                if prev > sequence {
+                    FeedStats::incr(&self.stats.gaps);
+                    Err(format!(
+                        "Gap between the order book at {sequence} and the update from {}",
+                        update.sequence_start
+                    ))
+                } else {
+                    self.sequences
+                        .check(symbol, update.sequence_end, Some(prev), true);
+                    *sync = Level2Sync::Streaming;
+                    Ok(sequence)
+                }
+            }
+            Level2Sync::Streaming => {
+                match self
+                    .sequences
+                    .check(symbol, update.sequence_end, Some(prev), false)
+                {
+                    Sequence::InOrder => Ok(prev),
+                    Sequence::Duplicate | Sequence::OutOfOrder => return,
+                    sequence => Err(format!("{sequence:?}")),
+                }
+            }
+        };
+
+        match synced {
+            Ok(after) => {
+                let events = update.into_events(after);
+                outputs.extend(
+                    events
+                        .into_iter()
+                        .map(|event| Output::Event(*request, event)),
+                );
+            }
+            Err(reason) => self.resync(request, update, reason, outputs),
+        }
+    }
+
+    /// Drop the book and fetch a new order book, starting over with `update`
+    fn resync(
+        &mut self,
+        request: &SocketRequest,
+        update: Level2Raw,
+        reason: String,
+        outputs: &mut Vec<Output>,
+    ) {
+        let symbol = Self::event_symbol(request);
+        FeedStats::incr(&self.stats.resyncs);
+        self.sequences.reset(&symbol);
+        self.books.insert(
+            symbol,
+            Level2Sync::Buffering {
+                updates: vec![update],
+                fetching: true,
+            },
+        );
+
+        let event = Event::Resync(Resync {
+            exchange: Exchange::KuCoin,
+            symbol,
+            reason: format!("{reason} on level2"),
+            ts: Timestamps::default(),
+        });
+        outputs.extend([Output::Event(*request, event), Output::Fetch(*request)]);
+    }
+
+    fn answer(&mut self, frame: KucoinFrame, outputs: &mut Vec<Output>) {
+        let request = frame.id.as_ref().and_then(|id| self.ids.remove(id));
+        match (frame.kind.as_str(), request) {
+            ("ack", Some((op, request))) => outputs.push(Output::Acked(op, request)),
+            ("error", request) => {
+                let error = SubscribeError::Exchange {
+                    code: frame.code.map(|code| code.to_string()).unwrap_or_default(),
+                    message: frame.data::<String>().unwrap_or_default(),
+                };
+                outputs.push(Output::Rejected(request, error));
+            }
+            // `welcome` and `pong`
+            _ => {}
+        }
+    }
+}
+
+impl Protocol for KucoinProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::KuCoin
+    }
+
+    fn url(&self) -> &str {
+        &self.rest_url
+    }
+
+    /// A fresh token for every connection, the server to use it on and how often to ping there
+    fn endpoint(&self) -> Option<EndpointFuture> {
+        let url = format!("{}/bullet-public", self.rest_url);
+        let client = self.client.clone();
+        Some(Box::pin(async move {
+            let response = client
+                .post(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?;
+            let bullet = response
+                .json::<KucoinRest<BulletRaw>>()
+                .await
+                .map_err(|e| e.to_string())?
+                .into_result()?;
+            let server = bullet
+                .instance_servers
+                .into_iter()
+                .next()
+                .ok_or("bullet-public returned no servers")?;
+
+            let connect_id = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+            Ok(Endpoint {
+                url: format!(
+                    "{}?token={}&connectId={connect_id}",
+                    server.endpoint, bullet.token
+                ),
+                ping_interval: Some(Duration::from_millis(server.ping_interval)),
+                pong_timeout: Some(Duration::from_millis(server.ping_timeout)),
+            })
+        }))
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.sequences = SequenceTracker::new(stats.clone());
+        self.stats = stats;
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let topic = Self::topic(request);
+        let symbol = Self::event_symbol(request);
+        let kind = match op {
+            Op::Subscribe => {
+                self.streams.insert(topic.clone(), *request);
+                if request.data_type == DataTypes::Book {
+                    self.books.insert(symbol, Level2Sync::default());
+                }
+                "subscribe"
+            }
+            Op::Unsubscribe => {
+                self.streams.remove(&topic);
+                if request.data_type == DataTypes::Book {
+                    self.books.remove(&symbol);
+                    self.sequences.reset(&symbol);
+                }
+                "unsubscribe"
+            }
+        };
+
+        let id = self.id();
+        self.ids.insert(id.clone(), (op, *request));
+        let frame = serde_json::json!({
+            "id": id,
+            "type": kind,
+            "topic": topic,
+            "privateChannel": false,
+            "response": true,
+        });
+        Ok(frame.to_string())
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(frame) = serde_json::from_str::<KucoinFrame>(frame) else {
+            return;
+        };
+        match frame.kind.as_str() {
+            "message" => self.message(frame, outputs),
+            _ => self.answer(frame, outputs),
+        }
+    }
+
+    /// Every book syncs again from a new order book, the updates of two connections don't chain
+    fn reset(&mut self) {
+        self.ids.clear();
+        self.books
+            .values_mut()
+            .for_each(|sync| *sync = Level2Sync::default());
+        self.sequences.clear();
+    }
+
+    fn snapshot(&self, request: &SocketRequest) -> Option<SnapshotFuture> {
+        if request.data_type != DataTypes::Book {
+            return None;
+        }
+
+        let url = format!(
+            "{}/market/orderbook/level2_100?symbol={}",
+            self.rest_url,
+            Self::symbol(&request.symbol)
+        );
+        let client = self.client.clone();
+        Some(Box::pin(async move {
+            let response = client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?;
+            response.text().await.map_err(|e| e.to_string())
+        }))
+    }
+
+    /// Publish the order book and apply the buffered updates on top of it. A failed fetch is
+    /// retried with the next update.
+    fn snapshot_fetched(
+        &mut self,
+        request: &SocketRequest,
+        body: Result<String, String>,
+        outputs: &mut Vec<Output>,
+    ) {
+        let symbol = Self::event_symbol(request);
+        let Some(Level2Sync::Buffering {
+            updates,
+            fetching: true,
+        }) = self.books.get_mut(&symbol)
+        else {
+            return;
+        };
+        let updates = mem::take(updates);
+
+        let snapshot = body.and_then(|body| {
+            serde_json::from_str::<KucoinRest<OrderBookRaw>>(&body)
+                .map_err(|e| e.to_string())?
+                .into_result()
+        });
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                eprintln!("KucoinProtocol: order book of {symbol} failed: {e}");
+                self.books.insert(symbol, Level2Sync::default());
+                return;
+            }
+        };
+
+        let sequence = snapshot.sequence;
+        self.books.insert(symbol, Level2Sync::Snapshot { sequence });
+        outputs.push(Output::Event(*request, snapshot.into_event(symbol)));
+        for update in updates {
+            self.level2(request, update, outputs);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        adapters::actor::next_event,
+        mock::{connect, decoded, request, MockExchange},
+        models::Decimal,
+    };
+
+    #[test]
+    fn test_topics() {
+        let mut kucoin = KucoinProtocol::new(KUCOIN_REST);
+        let frame = kucoin
+            .request(Op::Subscribe, &request("btc/usdt", DataTypes::Book))
+            .unwrap();
+        assert_eq!(
+            frame,
+            r#"{"id":"1","privateChannel":false,"response":true,"topic":"/market/level2:BTC-USDT","type":"subscribe"}"#
+        );
+        let frame = kucoin
+            .request(Op::Unsubscribe, &request("ETH-USDT", DataTypes::Bbo))
+            .unwrap();
+        assert!(frame.contains(r#""topic":"/market/ticker:ETH-USDT","type":"unsubscribe""#));
+
+        let rejected = decoded(
+            &mut kucoin,
+            r#"{"id":"1","type":"error","code":404,"data":"topic /market/level2:BTC-USDT is not found"}"#,
+        );
+        assert!(
+            matches!(&rejected[..], [Output::Rejected(Some((Op::Subscribe, _)), SubscribeError::Exchange { code, .. })] if code == "404"),
+            "{rejected:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bullet() {
+        let exchange = MockExchange::kucoin().await;
+        let kucoin = KucoinProtocol::new(exchange.rest_url());
+
+        // The token, server and ping interval all come from `bullet-public`
+        let endpoint = kucoin.endpoint().unwrap().await.unwrap();
+        let dial = format!("{}?token=token-1&connectId=", exchange.url());
+        assert!(endpoint.url.starts_with(&dial), "{}", endpoint.url);
+        assert_eq!(endpoint.ping_interval, Some(Duration::from_secs(18)));
+        assert_eq!(endpoint.pong_timeout, Some(Duration::from_secs(10)));
+        let endpoint = kucoin.endpoint().unwrap().await.unwrap();
+        assert!(endpoint.url.contains("token=token-2&"), "{}", endpoint.url);
+
+        // The actor dials the url it negotiated, the only one the mock listens on
+        let handle = connect(kucoin);
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("BTC-USDT", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+        assert_eq!(exchange.ops(1).await[0]["topic"], "/market/match:BTC-USDT");
+        exchange.kucoin_match("BTC-USDT", "26715.4", "0.0021", "sell");
+        let trade = next_event(&mut events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.symbol == "BTC-USDT" && t.exchange == Exchange::KuCoin),
+            "{trade:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_level2_sync() {
+        let exchange = MockExchange::kucoin().await;
+        exchange.kucoin_snapshot("BTC-USDT", 100, &[("26716", "1")], &[("26715", "2")]);
+        let handle = connect(KucoinProtocol::new(exchange.rest_url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let book = request("BTC-USDT", DataTypes::Book);
+        handle.subscribe(book, client).await.unwrap();
+
+        // Changes the order book already has are skipped, even within an update straddling it
+        exchange.kucoin_level2("BTC-USDT", (98, 99), &[("26717", "1")], &[]);
+        exchange.kucoin_level2(
+            "BTC-USDT",
+            (100, 101),
+            &[("26716", "3"), ("26716", "0")],
+            &[],
+        );
+        exchange.kucoin_level2("BTC-USDT", (102, 102), &[], &[("26714", "4")]);
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.symbol == "BTC-USDT" && s.levels.len() == 2),
+            "{snapshot:?}"
+        );
+        let removed = next_event(&mut events).await;
+        assert!(
+            matches!(&removed, Event::OrderbookUpdate(u) if u.price == "26716.0".parse::<Decimal>().unwrap() && u.quantity.is_zero()),
+            "{removed:?}"
+        );
+        let bid = next_event(&mut events).await;
+        assert!(
+            matches!(&bid, Event::OrderbookUpdate(u) if u.price == "26714.0".parse::<Decimal>().unwrap()),
+            "{bid:?}"
+        );
+        assert_eq!(exchange.fetches(), 1);
+
+        // An update that skips some fetches the order book again
+        exchange.kucoin_snapshot("BTC-USDT", 110, &[("26720", "1")], &[]);
+        exchange.kucoin_level2("BTC-USDT", (111, 111), &[("26721", "1")], &[]);
+        let resync = next_event(&mut events).await;
+        assert!(
+            matches!(&resync, Event::Resync(r) if r.symbol == "BTC-USDT"),
+            "{resync:?}"
+        );
+        let snapshot = next_event(&mut events).await;
+        assert!(
+            matches!(&snapshot, Event::OrderbookSnapshot(s) if s.levels[0].price == "26720.0".parse::<Decimal>().unwrap()),
+            "{snapshot:?}"
+        );
+        let update = next_event(&mut events).await;
+        assert!(
+            matches!(&update, Event::OrderbookUpdate(u) if u.price == "26721.0".parse::<Decimal>().unwrap()),
+            "{update:?}"
+        );
+        assert_eq!(handle.stats().resyncs, 1);
+    }
+
+    #[tokio::test]
+    async fn test_token_refresh() {
+        let exchange = MockExchange::kucoin().await;
+        let handle = connect(KucoinProtocol::new(exchange.rest_url()));
+
+        let (client, mut events) = mpsc::channel(16);
+        let trades = request("BTC-USDT", DataTypes::Trade);
+        handle.subscribe(trades, client).await.unwrap();
+
+        // Every connection dials with a token of its own
+        exchange.disconnect();
+        let disconnected = next_event(&mut events).await;
+        assert!(
+            matches!(&disconnected, Event::Connection(c) if !c.connected),
+            "{disconnected:?}"
+        );
+        let reconnected = next_event(&mut events).await;
+        assert!(
+            matches!(&reconnected, Event::Connection(c) if c.connected),
+            "{reconnected:?}"
+        );
+        let dialled = exchange.dialled();
+        assert_eq!(dialled.len(), 2);
+        assert!(dialled[0].contains("token=token-1&connectId="));
+        assert!(dialled[1].contains("token=token-2&connectId="));
+
+        exchange.kucoin_match("BTC-USDT", "26715.4", "0.0021", "buy");
+        let trade = next_event(&mut events).await;
+        assert!(
+            matches!(&trade, Event::Trade(t) if t.price == "26715.4".parse::<Decimal>().unwrap()),
+            "{trade:?}"
+        );
+    }
+}
@@ -1,16 +1,27 @@
+use std::{collections::HashMap, sync::Arc};
+
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::{net::TcpStream, sync::mpsc::UnboundedSender};
+use tokio::{
+    net::TcpStream,
+    sync::mpsc::UnboundedSender,
+    time::{self, Instant},
+};
 use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 use url::Url;
 
 use crate::{
+    adapters::{
+        backoff::Backoff,
+        keepalive::{Keepalive, KeepaliveAction, KeepaliveConfig},
+        okx::{op_message, OkxBooks, Validated},
+        sequence::FeedStats,
+    },
     event::{self, EventType},
-    models::normal::DataTypes,
-    transmute::{self, okx::TradeRaw},
+    models::Exchange,
+    transmute::{self, okx::OkxPush},
 };
 type SocketStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
@@ -25,9 +36,18 @@ pub enum DispatchEvents {
 
 #[derive(Debug)]
 pub struct Demo {
+    url: String,
     write: SplitSink<SocketStream, Message>,
     read: SplitStream<SocketStream>,
-    // state:
+    books: OkxBooks,
+    stats: Arc<FeedStats>,
+    /// Watches every subscribed book
+    keepalive: Keepalive<(String, String)>,
+    /// Number of [`DemoCmd::Sub`] holding each (channel, instId)
+    subscriptions: HashMap<(String, String), usize>,
+    backoff: Backoff,
+    /// When to dial again, set once the keepalive gave up on the connection
+    reconnect_at: Option<Instant>,
 }
 
 impl Demo {
@@ -37,12 +57,55 @@ impl Demo {
             .unwrap();
 
         let (write, read) = write_stream.split();
-        Self { read, write }
+        let stats = Arc::new(FeedStats::default());
+        Self {
+            url: ws_url.to_string(),
+            read,
+            write,
+            books: OkxBooks::new(stats.clone()),
+            stats,
+            keepalive: Keepalive::new(KeepaliveConfig::for_exchange(Exchange::Okx)),
+            subscriptions: HashMap::new(),
+            backoff: Backoff::default(),
+            reconnect_at: None,
+        }
     }
 
-    pub fn parse(raw_str: &str) -> serde_json::Result<event::Event> {
-        let de_ser: transmute::okx::OkxRaw<TradeRaw> = serde_json::from_str(raw_str)?;
-        Ok(event::Event::Trade(de_ser.into()))
+    pub fn with_keepalive(mut self, config: KeepaliveConfig) -> Self {
+        self.keepalive = Keepalive::new(config);
+        self
+    }
+
+    pub fn parse(raw_str: &str) -> serde_json::Result<Vec<event::Event>> {
+        transmute::okx::parse(raw_str)
+    }
+
+    /// Decode a frame into `out`, validating book pushes and resubscribing any book that broke
+    async fn handle_frame(&mut self, raw_str: &str, out: &mut Vec<event::Event>) {
+        let Ok(push) = OkxPush::parse(raw_str) else {
+            return;
+        };
+        self.keepalive
+            .data(&(push.arg.channel.to_string(), push.arg.inst_id.to_string()));
+
+        if !push.is_book() {
+            if push.events(out).is_err() {
+                out.clear();
+            }
+            return;
+        }
+        if let Validated::Resync {
+            event,
+            channel,
+            inst_id,
+        } = self.books.validate(&push, out)
+        {
+            for op in ["unsubscribe", "subscribe"] {
+                let message = op_message(op, &channel, &inst_id);
+                let _ = self.write.send(Message::Text(message)).await;
+            }
+            out.push(event);
+        }
     }
 }
 
@@ -50,6 +113,8 @@ impl Demo {
 pub struct DemoHandler {
     pub dispatch_sender: UnboundedSender<DemoCmd>,
     pub dispatch_receiver: crossbeam::channel::Receiver<DispatchEvents>,
+    /// Sequence and checksum counters of the adapter's feed
+    pub stats: Arc<FeedStats>,
 }
 #[derive(Debug)]
 pub struct DemoError {
@@ -60,23 +125,58 @@ impl Demo {
     pub async fn run(mut self) -> DemoHandler {
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
         let (sync_tx, sync_rx) = channel::bounded(10);
+        let stats = self.stats.clone();
         tokio::spawn(async move {
+            // Reused for the events of every frame
+            let mut events = Vec::new();
             loop {
                 tokio::select! {
                     Some(Ok(val)) = self.read.next() => {
-                        if let Ok(t) = Self::parse(val.to_string().as_str()) {
-                        sync_tx.send(DispatchEvents::DataReal(t)).unwrap();
+                        let received = event::now_micros();
+                        let Ok(text) = val.into_text() else { continue };
+                        if self.keepalive.received(&text) {
+                            continue;
+                        }
+                        self.handle_frame(&text, &mut events).await;
+                        for mut t in events.drain(..) {
+                            let ts = t.ts_mut();
+                            ts.received = received;
+                            ts.dispatched = event::now_micros();
+                            sync_tx.send(DispatchEvents::DataReal(t)).unwrap();
+                        }
+                        sync_tx.send(DispatchEvents::Data(text)).unwrap();
+                    }
+                    _ = time::sleep_until(self.keepalive.deadline()), if self.reconnect_at.is_none() => {
+                        match self.keepalive.poll() {
+                            KeepaliveAction::Idle => {}
+                            KeepaliveAction::Ping => {
+                                let ping = self.keepalive.config.ping.clone();
+                                let _ = self.write.send(Message::Text(ping)).await;
+                            }
+                            KeepaliveAction::Resubscribe(key) => {
+                                for op in ["unsubscribe", "subscribe"] {
+                                    if let Err(e) = self.send_op(op, &key).await {
+                                        sync_tx.send(DispatchEvents::Error(e)).unwrap();
+                                    }
+                                }
+                            }
+                            KeepaliveAction::Reconnect(reason) => {
+                                sync_tx.send(DispatchEvents::Info(reason)).unwrap();
+                                self.reconnect_at = Some(Instant::now());
+                            }
+                        }
+                    }
+                    _ = time::sleep_until(self.reconnect_at.unwrap_or_else(Instant::now)), if self.reconnect_at.is_some() => {
+                        let url = self.url.clone();
+                        if let Err(e) = self.reconnect(&url).await {
+                            sync_tx.send(DispatchEvents::Error(e)).unwrap();
                         }
-                        sync_tx.send(DispatchEvents::Data(val.to_string())).unwrap();
                     }
                     Some( cmd) = rx.recv() => {
                         let res = match cmd {
                            DemoCmd::Sub { ref symbol, event_kind } => self.subscribe(event_kind, symbol).await,
                            DemoCmd::Unsub { ref symbol, event_kind } => self.unsubscribe(event_kind, symbol).await,
-                           DemoCmd::Reconnect {ref url}=> {
-                                self.reconnect(url).await;
-                                Ok(())
-                           }
+                           DemoCmd::Reconnect { ref url } => self.reconnect(url).await,
                         };
 
                         if let Err(e) = res {
@@ -90,96 +190,109 @@ impl Demo {
         DemoHandler {
             dispatch_sender: tx,
             dispatch_receiver: sync_rx,
+            stats,
         }
     }
 
     // impl Demo<Running> {
+    /// Only the first subscribe of a channel goes upstream
     async fn subscribe(&mut self, kind: EventType, symbol: &str) -> Result<(), DemoError> {
-        let message = match kind {
-            EventType::Trade => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "trades",
-                    "instId": symbol
-                }]
-            })),
-            EventType::OrderbookUpdate => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "books",
-                    "instId": "BTC-USDT"
-                }]
-            })),
-            EventType::AdapterDisconnect => None,
-            EventType::OrderbookSnapshot => Some(serde_json::json!({
-                "op": "subscribe",
-                "args": [{
-                    "channel": "books5",
-                    "instId": "BTC-USDT"
-                }]
-            })),
-        };
-        return if let Some(m) = message {
-            self.write.send(Message::Text(m.to_string())).await.unwrap();
-            Ok(())
-        } else {
-            Err(DemoError {
-                message: format!(
-                    "The provided Event Type is not available on this exchange: {}",
-                    kind
-                ),
-            })
-        };
+        let key = (okx_channel(kind)?.to_string(), symbol.to_string());
+        let refs = self.subscriptions.entry(key.clone()).or_default();
+        *refs += 1;
+        if *refs == 1 {
+            self.send_op("subscribe", &key).await?;
+            // Trades can be quiet for minutes, only books are watched
+            if !matches!(kind, EventType::Trade) {
+                self.keepalive.watch(key);
+            }
+        }
+        Ok(())
     }
 
+    /// Only the last unsubscribe of a channel goes upstream
     async fn unsubscribe(&mut self, kind: EventType, symbol: &str) -> Result<(), DemoError> {
-        let message = match kind {
-            EventType::Trade => Some(serde_json::json!({
-                "op": "unsubscribe",
-                "args": [{
-                    "channel": "trades",
-                    "instId": symbol
-                }]
-            })),
-            EventType::OrderbookUpdate => Some(serde_json::json!({
-                "op": "unsubscribe",
-                "args": [{
-                    "channel": "books",
-                    "instId": "BTC-USDT"
-                }]
-            })),
-            EventType::AdapterDisconnect => None,
-            EventType::OrderbookSnapshot => Some(serde_json::json!({
-                "op": "unsubscribe",
-                "args": [{
-                    "channel": "books5",
-                    "instId": "BTC-USDT"
-                }]
-            })),
-        };
-        return if let Some(m) = message {
-            self.write.send(Message::Text(m.to_string())).await.unwrap();
-            Ok(())
-        } else {
-            Err(DemoError {
-                message: format!(
-                    "The provided Event Type is not available on this exchange: {}",
-                    kind
-                ),
-            })
+        let key = (okx_channel(kind)?.to_string(), symbol.to_string());
+        let Some(refs) = self.subscriptions.get_mut(&key) else {
+            return Err(DemoError {
+                message: format!("Not subscribed to {kind} for {symbol}"),
+            });
         };
+        *refs -= 1;
+        if *refs == 0 {
+            self.subscriptions.remove(&key);
+            self.keepalive.unwatch(&key);
+            self.send_op("unsubscribe", &key).await?;
+        }
+        Ok(())
     }
-    async fn reconnect(&mut self, ws_url: &str) {
-        let (write_stream, _) = connect_async(Url::parse(ws_url).expect("url"))
+
+    async fn send_op(
+        &mut self,
+        op: &str,
+        (channel, inst_id): &(String, String),
+    ) -> Result<(), DemoError> {
+        let message = op_message(op, channel, inst_id);
+        self.write
+            .send(Message::Text(message))
             .await
-            .unwrap();
+            .map_err(|e| DemoError {
+                message: format!("Failed to {op} {channel} for {inst_id}: {e}"),
+            })
+    }
+
+    /// Dial `ws_url` and resubscribe every channel the old connection streamed. A failed attempt
+    /// schedules the next one after backing off.
+    async fn reconnect(&mut self, ws_url: &str) -> Result<(), DemoError> {
+        let dialed = self.dial(ws_url).await;
+        match dialed {
+            Ok(()) => {
+                self.backoff.reset();
+                self.reconnect_at = None;
+            }
+            Err(_) => self.reconnect_at = Some(Instant::now() + self.backoff.next_delay()),
+        }
+        dialed
+    }
+
+    async fn dial(&mut self, ws_url: &str) -> Result<(), DemoError> {
+        let url = Url::parse(ws_url).map_err(|e| DemoError {
+            message: format!("Invalid url {ws_url}: {e}"),
+        })?;
+        let (write_stream, _) = connect_async(url).await.map_err(|e| DemoError {
+            message: format!("Failed to reconnect to {ws_url}: {e}"),
+        })?;
 
         let (write, read) = write_stream.split();
+        self.url = ws_url.to_string();
         self.write = write;
         self.read = read;
+        self.books.reset();
+        self.keepalive.connected();
+
+        let channels: Vec<(String, String)> = self.subscriptions.keys().cloned().collect();
+        for key in channels.iter() {
+            self.send_op("subscribe", key).await?;
+        }
+        Ok(())
     }
     // pub appendj
 }
+/// OKX channel streaming an [`EventType`]
+fn okx_channel(kind: EventType) -> Result<&'static str, DemoError> {
+    match kind {
+        EventType::Trade => Ok("trades"),
+        EventType::OrderbookUpdate => Ok("books"),
+        EventType::OrderbookSnapshot => Ok("books5"),
+        EventType::AdapterDisconnect => Err(DemoError {
+            message: format!(
+                "The provided Event Type is not available on this exchange: {}",
+                kind
+            ),
+        }),
+    }
+}
+
 #[derive(Debug)]
 pub enum DemoCmd {
     Sub {
@@ -196,39 +309,81 @@ pub enum DemoCmd {
 }
 #[cfg(test)]
 mod test {
-    use tokio::time;
+    use std::time::Duration;
 
     use super::*;
+    use crate::mock::MockExchange;
+
+    /// Next decoded event, skipping raw frames and info
+    async fn next_event(rx: &channel::Receiver<DispatchEvents>) -> event::Event {
+        let rx = rx.clone();
+        tokio::task::spawn_blocking(move || loop {
+            match rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(DispatchEvents::DataReal(event)) => return event,
+                Ok(_) => continue,
+                Err(e) => panic!("no event: {e}"),
+            }
+        })
+        .await
+        .unwrap()
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn example() {
+        let exchange = MockExchange::start().await;
         let DemoHandler {
             dispatch_sender: tx,
             dispatch_receiver,
-        } = Demo::new("wss://ws.okx.com:8443/ws/v5/public")
-            .await
-            .run()
-            .await;
+            ..
+        } = Demo::new(exchange.url()).await.run().await;
 
-        tx.send(DemoCmd::Sub {
-            symbol: "BTC-USDT".into(),
-            event_kind: EventType::Trade,
-        })
-        .unwrap();
+        for symbol in ["BTC-USDT", "BTC-USDT", "ETH-USDT"] {
+            tx.send(DemoCmd::Sub {
+                symbol: symbol.into(),
+                event_kind: EventType::OrderbookUpdate,
+            })
+            .unwrap();
+        }
+        // The second BTC-USDT subscribe shares the first
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[0]["args"][0]["instId"], "BTC-USDT");
+        assert_eq!(ops[1]["args"][0]["instId"], "ETH-USDT");
 
-        tokio::spawn(async move {
-            while let Ok(t) = dispatch_receiver.recv() {
-                if let DispatchEvents::Data(r) = t {
-                    println!("{}", r);
-                }
-            }
-        });
+        exchange.book("snapshot", "ETH-USDT", &[("1851", "3")], &[("1850", "2")]);
+        let event = next_event(&dispatch_receiver).await;
+        assert!(matches!(event, event::Event::OrderbookSnapshot(s) if s.symbol == "ETH-USDT"));
 
-        time::sleep(time::Duration::from_secs(2)).await;
+        for _ in 0..2 {
+            tx.send(DemoCmd::Unsub {
+                symbol: "BTC-USDT".into(),
+                event_kind: EventType::OrderbookUpdate,
+            })
+            .unwrap();
+        }
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops.len(), 3);
+        assert_eq!(ops[2]["op"], "unsubscribe");
+        assert_eq!(ops[2]["args"][0]["instId"], "BTC-USDT");
+    }
 
-        tx.send(DemoCmd::Unsub {
-            symbol: "BTC-USDT".into(),
-            event_kind: EventType::Trade,
-        })
-        .unwrap();
+    #[tokio::test]
+    async fn test_reconnect_backoff() {
+        let exchange = MockExchange::start().await;
+        let mut demo = Demo::new(exchange.url()).await;
+
+        // Nothing listens there, every failed attempt waits longer before the next
+        let mut last = Instant::now();
+        for attempt in 1..=3 {
+            assert!(demo.reconnect("ws://127.0.0.1:1").await.is_err());
+            let next = demo.reconnect_at.expect("next attempt scheduled");
+            assert!(next > last);
+            assert_eq!(demo.backoff.attempt(), attempt);
+            last = next;
+        }
+
+        let url = exchange.url().to_string();
+        demo.reconnect(&url).await.unwrap();
+        assert!(demo.reconnect_at.is_none());
+        assert_eq!(demo.backoff.attempt(), 0);
     }
 }
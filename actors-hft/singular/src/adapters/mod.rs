@@ -1,3 +1,19 @@
 pub mod actor;
+pub mod backoff;
+pub mod binance;
+pub mod bitfinex;
+pub mod bitstamp;
+pub mod bybit;
+pub mod capabilities;
+pub mod coinbase;
+pub mod deribit;
+pub mod huobi;
+pub mod keepalive;
+pub mod kraken;
+pub mod kucoin;
 pub mod less;
 pub mod okx;
+pub mod protocol;
+pub mod ring;
+pub mod sequence;
+pub mod subscriptions;
@@ -1,83 +1,424 @@
 use std::{
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::{Arc, Mutex},
 };
 
 use crate::{
-    event::{Event, Trade},
-    interfaces::Adapter,
-    models::Symbol,
+    adapters::{
+        actor::SocketRequest,
+        backoff::Backoff,
+        capabilities::Capabilities,
+        keepalive::{Keepalive, KeepaliveAction, KeepaliveConfig},
+        protocol::{Output, Protocol},
+        ring::{EventRing, Overflow, RingCounts},
+        sequence::{FeedCounts, FeedStats, SequenceTracker},
+        subscriptions::{answered, Ack, Acks, Op, SubscribeError},
+    },
+    event::{now_micros, Event, Resync, Timestamps},
+    interfaces::{
+        event_stream, notify_lagged, Adapter, ConnectionState, EventStream, Subscribing,
+        STREAM_CAPACITY,
+    },
+    models::{normal::DataTypes, Exchange, Symbol},
+    transmute::okx::{ArgRef, OkxBook, OkxEvent, OkxPush},
 };
 use async_trait::async_trait;
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt as _, StreamExt as _,
-};
+use futures_util::{stream::SplitSink, SinkExt as _, StreamExt as _};
 use tokio::net::TcpStream;
-use tokio::sync::watch;
-use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
-use url::Url;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{self, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+/// OKX public market data endpoint
+pub const OKX_PUBLIC_URL: &str = "wss://ws.okx.com:8443/ws/v5/public";
+
+/// Events the connection task may queue ahead of the dispatch task by default
+const RING_CAPACITY: usize = 4096;
+
+/// Requests from [`Okx`] to its connection task
+#[derive(Debug)]
+enum Control {
+    /// Subscribe upstream, answering the [`Ack`] once OKX does
+    Subscribe(Channel, Option<Ack>),
+    Unsubscribe(Channel),
+    Close,
+}
+
 type SocketStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type SocketSink = SplitSink<SocketStream, Message>;
+
+/// (channel, instId) of a push
+type Channel = (&'static str, Symbol);
 
+/// An event on its way to the dispatch task, tagged with the channel of its push
+type Tagged = (Channel, Event);
+
+/// Changes to where the dispatch task delivers events
 #[derive(Debug)]
-pub struct Okx {
-    connected: bool,
-    write: Option<SplitSink<SocketStream, Message>>,
-    /// By default this HeapAllocated Ring buffer will have a capacity of `1024`
-    /// This buffer will be used for data only
-    data_buffer: Arc<Mutex<VecDeque<(String, String)>>>,
-    /// This buffer will only be for non-data messages Eg: Hb, status, infor & warn messages
-    message_buffer: Arc<Mutex<VecDeque<String>>>,
-    subscriptions: BTreeMap<String, watch::Receiver<String>>,
-    senders: Arc<Mutex<BTreeMap<String, watch::Sender<String>>>>,
+enum Route {
+    /// Keep the symbol's receiver at its latest event
+    Watch(Symbol, watch::Sender<Option<Event>>),
+    Unwatch(Symbol),
+    Stream(Channel, mpsc::Sender<Event>),
+    Release(Channel),
+    Clear,
 }
 
-struct Connected;
-struct Unconnected;
+/// What [`Okx`] holds on its channels. Shared with the dispatch task, which lets go of the
+/// streams of clients that fall behind.
+#[derive(Debug, Default)]
+struct Refs {
+    /// References held on every subscribed (channel, instId)
+    channels: BTreeMap<Channel, usize>,
+    /// Streams taken through [`Adapter::subscribe`], by (channel, instId)
+    streams: HashMap<Channel, usize>,
+}
+
+impl Refs {
+    /// Take a reference on a channel, `true` if it is the first one
+    fn add(&mut self, key: Channel) -> bool {
+        let refs = self.channels.entry(key).or_default();
+        *refs += 1;
+        *refs == 1
+    }
+
+    /// Release a reference on a channel, `true` if it was the last one
+    fn release(&mut self, key: Channel) -> bool {
+        let Some(refs) = self.channels.get_mut(&key) else {
+            return false;
+        };
+        *refs -= 1;
+        if *refs > 0 {
+            return false;
+        }
+        self.channels.remove(&key);
+        true
+    }
+
+    /// Whether any channel of `symbol` is still referenced
+    fn watches(&self, symbol: Symbol) -> bool {
+        self.channels.keys().any(|(_, s)| *s == symbol)
+    }
+}
+
+#[derive(Debug)]
+pub struct Okx {
+    url: String,
+    state: Arc<watch::Sender<ConnectionState>>,
+    control: Option<mpsc::UnboundedSender<Control>>,
+    keepalive: KeepaliveConfig,
+    refs: Arc<Mutex<Refs>>,
+    /// Parsed events of every data push, from the connection task to the dispatch task
+    events: Arc<EventRing<Tagged>>,
+    /// Sequence and checksum counters of every connection's books
+    stats: Arc<FeedStats>,
+    subscriptions: BTreeMap<Symbol, watch::Receiver<Option<Event>>>,
+    routes: mpsc::UnboundedSender<Route>,
+    /// Taken by the dispatch task once connected
+    dispatch: Option<mpsc::UnboundedReceiver<Route>>,
+}
 
 impl Okx {
-    /// Connect to provided exchange and start reading the stream into buffer
-    pub async fn connect(mut self, url: &str) -> Self {
-        let (ws_stream, _) = connect_async(Url::parse(url).unwrap())
-            .await
-            .expect("Failed to connect");
-
-        let (write, mut read) = ws_stream.split();
-        self.write = Some(write);
-        self.connected = true;
-        let write_to_data_buffer = self.data_buffer.clone();
-        // let write_to_message_buffer = self.message_buffer.clone();
-        let _ = tokio::spawn(async move {
-            while let Some(Ok(msg)) = read.next().await {
-                let mut data_buff = write_to_data_buffer.lock().unwrap();
-                // let string = msg.to_string();
-                data_buff.push_back(("BTC-USDT".into(), msg.to_string()));
-                // if string.contains("tradeId") {
-                //     data_buff.push_front(msg.to_string());
-                // } else if string.contains("orderbookId") {
-                //     data_buff.push_front(msg.to_string());
-                // } else {
-                //     let mut mess_buff = write_to_message_buffer.lock().unwrap();
-                //     mess_buff.push_front(string);
-                // }
+    /// Connect to provided exchange and start reading the stream into the ring, and dispatching
+    /// out of it. Fails if the first dial does, after that the connection task redials on its own.
+    pub async fn connect(mut self, url: &str) -> Result<Self, tungstenite::Error> {
+        self.url = url.to_string();
+        let (socket, _) = connect_async(url).await?;
+
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        self.control = Some(control_tx.clone());
+        let task = Connection {
+            url: self.url.clone(),
+            state: self.state.clone(),
+            control: control_rx,
+            events: self.events.clone(),
+            stats: self.stats.clone(),
+            keepalive: Keepalive::new(self.keepalive.clone()),
+            backoff: Backoff::default(),
+            channels: BTreeSet::new(),
+            acks: Acks::default(),
+            ids: HashMap::new(),
+            next_id: 0,
+        };
+        tokio::spawn(task.run(socket));
+        if let Some(routes) = self.dispatch.take() {
+            let released = Released {
+                refs: self.refs.clone(),
+                control: control_tx,
+            };
+            tokio::spawn(dispatch(self.events.clone(), routes, released));
+        }
+        Ok(self)
+    }
+}
+
+/// The connection task. It owns the socket, keeps it alive with pings and resubscribes channels
+/// that go quiet. Once the pong is late or the socket fails it redials with backoff and replays
+/// every channel, until [`Control::Close`].
+struct Connection {
+    url: String,
+    state: Arc<watch::Sender<ConnectionState>>,
+    control: mpsc::UnboundedReceiver<Control>,
+    events: Arc<EventRing<Tagged>>,
+    stats: Arc<FeedStats>,
+    keepalive: Keepalive<Channel>,
+    backoff: Backoff,
+    /// Every subscribed channel, replayed on each new connection
+    channels: BTreeSet<Channel>,
+    /// Ops OKX has not answered yet
+    acks: Acks<Channel>,
+    /// Op and channel of every op in `acks`, by the `id` it was sent with
+    ids: HashMap<String, (Op, Channel)>,
+    next_id: u64,
+}
+
+impl Connection {
+    async fn run(mut self, socket: SocketStream) {
+        let mut socket = Some(socket);
+        loop {
+            let Some(connected) = socket.take() else {
+                self.state.send_replace(ConnectionState::Closed);
+                return;
+            };
+            let reason = match self.serve(connected).await {
+                Some(reason) => reason,
+                None => {
+                    self.state.send_replace(ConnectionState::Closed);
+                    return;
+                }
+            };
+            eprintln!("Okx: lost connection to {}: {reason}", self.url);
+            self.state
+                .send_replace(ConnectionState::Disconnected(reason));
+            socket = self.redial().await;
+        }
+    }
+
+    /// Dial until it works, backing off between attempts. `None` if closed meanwhile, channels
+    /// taken or released in between are replayed once connected.
+    async fn redial(&mut self) -> Option<SocketStream> {
+        loop {
+            let delay = self.backoff.next_delay();
+            let retry = time::sleep(delay);
+            tokio::pin!(retry);
+            loop {
+                tokio::select! {
+                    _ = &mut retry => break,
+                    control = self.control.recv() => match control {
+                        Some(Control::Subscribe(channel, ack)) => {
+                            self.track(channel);
+                            self.acks.sent(Op::Subscribe, channel, ack);
+                        }
+                        Some(Control::Unsubscribe(channel)) => {
+                            self.untrack(&channel);
+                        }
+                        Some(Control::Close) | None => return None,
+                    },
+                }
             }
-        });
 
-        let buff: Arc<Mutex<VecDeque<(String, String)>>> = self.data_buffer.clone();
-        let sends: Arc<Mutex<BTreeMap<String, watch::Sender<String>>>> = self.senders.clone();
-
-        let _ = tokio::spawn(async move {
-            while !buff.lock().unwrap().is_empty() {
-                println!("Hello");
-                // for (sym, tx) in sends.lock().unwrap().iter() {
-                //     let mut read_buff = buff.lock().unwrap();
-                //     if !read_buff.is_empty() {
-                //         tx.send(read_buff.pop_back().unwrap().1).unwrap();
-                //     }
-                // }
+            self.state.send_replace(ConnectionState::Connecting);
+            match connect_async(self.url.as_str()).await {
+                Ok((socket, _)) => {
+                    self.backoff.reset();
+                    return Some(socket);
+                }
+                Err(e) => eprintln!("Okx: failed to connect to {}: {e}", self.url),
+            }
+        }
+    }
+
+    fn track(&mut self, channel: Channel) {
+        self.channels.insert(channel);
+        if watched(channel.0) {
+            self.keepalive.watch(channel);
+        }
+    }
+
+    /// Forget a channel, `false` if it wasn't subscribed
+    fn untrack(&mut self, channel: &Channel) -> bool {
+        self.keepalive.unwatch(channel);
+        self.channels.remove(channel)
+    }
+
+    /// Send an op upstream and wait for its answer, `false` if the socket failed
+    async fn send_op(
+        &mut self,
+        write: &mut SocketSink,
+        op: Op,
+        channel: Channel,
+        ack: Option<Ack>,
+    ) -> bool {
+        let id = self.next_id();
+        self.ids.insert(id.clone(), (op, channel));
+        self.acks.sent(op, channel, ack);
+        let message = op_message_with_id(&id, op.as_str(), channel.0, &channel.1);
+        write.send(Message::Text(message)).await.is_ok()
+    }
+
+    /// Subscribe to a channel again, for a fresh snapshot. Nobody waits on these ops, so they stay
+    /// out of `acks` and an error answering them is only logged.
+    async fn resubscribe(&mut self, write: &mut SocketSink, channel: Channel) {
+        for op in [Op::Unsubscribe, Op::Subscribe] {
+            let id = self.next_id();
+            let message = op_message_with_id(&id, op.as_str(), channel.0, &channel.1);
+            if write.send(Message::Text(message)).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.next_id += 1;
+        self.next_id.to_string()
+    }
+
+    /// OKX answered an op. Errors say which by the op's `id`, one without a known `id` answered
+    /// nothing anyone waits on.
+    fn answered(&mut self, event: OkxEvent) {
+        let sent = event.id.as_ref().and_then(|id| self.ids.remove(id));
+        let op = match event.event.as_str() {
+            "subscribe" => Op::Subscribe,
+            "unsubscribe" => Op::Unsubscribe,
+            "error" => {
+                let error = SubscribeError::Exchange {
+                    code: event.code.unwrap_or_default(),
+                    message: event.msg.unwrap_or_default(),
+                };
+                eprintln!("Okx: {error}");
+                let Some((op, channel)) = sent else { return };
+                if let Some((Op::Subscribe, channel)) =
+                    self.acks.rejected_request(op, &channel, error)
+                {
+                    self.untrack(&channel);
+                }
+                return;
             }
+            _ => return,
+        };
+        let channel = event.arg.and_then(|arg| {
+            Some((
+                OkxProtocol::channel_name(&arg.channel)?,
+                Symbol::get(&arg.inst_id)?,
+            ))
         });
-        self
+        if let Some(channel) = channel {
+            self.acks.acked(op, &channel);
+        }
+    }
+
+    /// Stream `socket` until it fails, returning why, or until closed on purpose
+    async fn serve(&mut self, socket: SocketStream) -> Option<String> {
+        let (mut write, mut read) = socket.split();
+        self.state.send_replace(ConnectionState::Connected);
+        self.keepalive.connected();
+        // Nothing sent on the old connection will be answered, its waiters wait on the replay
+        let in_flight = self.acks.drain();
+        self.ids.clear();
+        let channels: Vec<Channel> = self.channels.iter().copied().collect();
+        for channel in channels {
+            if !self.send_op(&mut write, Op::Subscribe, channel, None).await {
+                return Some("Failed to write to the socket".to_string());
+            }
+        }
+        for pending in in_flight {
+            for waiter in pending.waiters {
+                if let Err(waiter) = self.acks.join(Op::Subscribe, &pending.key, waiter) {
+                    // Unsubscribed meanwhile
+                    let _ = waiter.send(Ok(()));
+                }
+            }
+        }
+        // OKX sends fresh snapshots on every connection, so the books start over with it
+        let mut books = OkxBooks::new(self.stats.clone());
+        // Reused for the events of every push
+        let mut parsed = Vec::new();
+
+        // `None` once closed on purpose
+        let reason = loop {
+            tokio::select! {
+                msg = read.next() => {
+                    let Some(Ok(msg)) = msg else {
+                        break Some("Connection closed".to_string());
+                    };
+                    let received = now_micros();
+                    let Ok(text) = msg.into_text() else { continue };
+                    if self.keepalive.received(&text) {
+                        continue;
+                    }
+                    let Ok(push) = OkxPush::parse(&text) else {
+                        if let Ok(event) = serde_json::from_str::<OkxEvent>(&text) {
+                            self.answered(event);
+                        }
+                        continue;
+                    };
+                    let Some(channel) = OkxProtocol::channel_name(push.arg.channel) else {
+                        continue;
+                    };
+                    let key = (channel, push.arg.inst_id);
+                    self.keepalive.data(&key);
+
+                    let mut resync = false;
+                    if push.is_book() {
+                        if let Validated::Resync { event, .. } = books.validate(&push, &mut parsed) {
+                            parsed.push(event);
+                            resync = true;
+                        }
+                    } else if push.events(&mut parsed).is_err() {
+                        parsed.clear();
+                    }
+                    if resync {
+                        self.resubscribe(&mut write, key).await;
+                    }
+                    for mut event in parsed.drain(..) {
+                        event.ts_mut().received = received;
+                        self.events.push((key, event)).await;
+                    }
+                }
+                control = self.control.recv() => {
+                    let sent = match control {
+                        Some(Control::Subscribe(channel, ack)) => {
+                            self.track(channel);
+                            self.send_op(&mut write, Op::Subscribe, channel, ack).await
+                        }
+                        Some(Control::Unsubscribe(channel)) => {
+                            // Never subscribed if OKX rejected it
+                            !self.untrack(&channel)
+                                || self.send_op(&mut write, Op::Unsubscribe, channel, None).await
+                        }
+                        Some(Control::Close) | None => break None,
+                    };
+                    if !sent {
+                        break Some("Failed to write to the socket".to_string());
+                    }
+                }
+                _ = time::sleep_until(self.keepalive.deadline()) => match self.keepalive.poll() {
+                    KeepaliveAction::Idle => {}
+                    KeepaliveAction::Ping => {
+                        let ping = self.keepalive.config.ping.clone();
+                        if write.send(Message::Text(ping)).await.is_err() {
+                            break Some("Failed to write to the socket".to_string());
+                        }
+                    }
+                    KeepaliveAction::Resubscribe(channel) => {
+                        self.resubscribe(&mut write, channel).await;
+                    }
+                    KeepaliveAction::Reconnect(reason) => break Some(reason),
+                },
+            }
+        };
+        let _ = write.close().await;
+        reason
+    }
+}
+
+impl Default for Okx {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -87,187 +428,946 @@ impl Okx {
     where
         Self: Sized,
     {
+        let (routes, dispatch) = mpsc::unbounded_channel();
         Self {
-            write: None,
-            connected: true,
-            data_buffer: Arc::new(Mutex::new(VecDeque::with_capacity(4000))),
-            message_buffer: Arc::new(Mutex::new(VecDeque::new())),
+            url: String::new(),
+            state: Arc::new(watch::channel(ConnectionState::Connecting).0),
+            control: None,
+            keepalive: KeepaliveConfig::for_exchange(Exchange::Okx),
+            refs: Arc::default(),
+            events: Arc::new(EventRing::new(RING_CAPACITY, Overflow::default())),
+            stats: Arc::default(),
             subscriptions: BTreeMap::new(),
-            senders: Arc::new(Mutex::new(BTreeMap::new())),
+            routes,
+            dispatch: Some(dispatch),
         }
     }
-    async fn send_message(&mut self, message: String) {
-        self.write
-            .as_mut()
-            .expect("Writer is not None")
-            .send(Message::Text(message))
-            .await
-            .unwrap();
+
+    pub fn with_keepalive(mut self, config: KeepaliveConfig) -> Self {
+        self.keepalive = config;
+        self
     }
-    pub async fn subscribe_orderbook(&mut self, symbol: Symbol) {
-        let message = serde_json::json!({
-            "op": "subscribe",
-            "args": [{
-                "channel": "books",
-                "instId": symbol.to_string()
 
-            }]
-        });
+    /// Queue at most `capacity` events between the socket and the dispatch task, and what to do
+    /// once that many wait. Set before connecting.
+    pub fn with_ring(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.events = Arc::new(EventRing::new(capacity, overflow));
+        self
+    }
 
-        self.send_message(message.to_string()).await;
-        let (tx, rx) = watch::channel("Hello".to_string());
-        self.subscriptions.insert(symbol.clone(), rx);
-        let sends = self.senders.clone();
-        let mut txs = sends.lock().unwrap();
-        txs.insert(symbol.clone(), tx);
+    /// Counters of the ring between the socket and the dispatch task
+    pub fn ring_counts(&self) -> RingCounts {
+        self.events.counts()
+    }
 
-        // dbg!(&self.subscriptions, &txs);
+    /// Gaps, checksum mismatches and resyncs of the books, across every connection so far
+    pub fn stats(&self) -> FeedCounts {
+        self.stats.counts()
     }
 
-    pub async fn subscribe_trade(&mut self, symbol: Symbol) {
-        let message = serde_json::json!({
-            "op": "subscribe",
-            "args": [{
-                "channel": "trades",
-                "instId": symbol
-            }]
-        });
+    pub fn is_connected(&self) -> bool {
+        *self.state.borrow() == ConnectionState::Connected
+    }
 
-        self.send_message(message.to_string()).await;
-        let (tx, rx) = watch::channel("Hello".to_string());
-        self.subscriptions.insert(symbol.clone(), rx);
+    /// Take a reference on a channel. Only the first one subscribes upstream, answering `ack`
+    /// once OKX does, and opens the symbol's receiver.
+    async fn add(&mut self, channel: &'static str, symbol: Symbol, ack: Option<Ack>) {
+        let key = (channel, symbol);
+        if !self.refs.lock().unwrap().add(key) {
+            if let Some(ack) = ack {
+                let _ = ack.send(Ok(()));
+            }
+            return;
+        }
 
-        let sends = self.senders.clone();
-        let mut txs = sends.lock().unwrap();
-        txs.insert(symbol.clone(), tx);
-        println!("Complete");
+        self.control(Control::Subscribe(key, ack));
+        // Closed if the dispatch task released the symbol's last channel
+        let watched = self
+            .subscriptions
+            .get(&symbol)
+            .is_some_and(|latest| latest.has_changed().is_ok());
+        if !watched {
+            let (tx, rx) = watch::channel(None);
+            self.subscriptions.insert(symbol, rx);
+            self.route(Route::Watch(symbol, tx));
+        }
     }
 
-    pub async fn subscribe_orderbook_snapshot(&mut self, symbol: crate::models::Symbol) {
-        let message = serde_json::json!({
-            "op": "subscribe",
-            "args": [{
-                "channel": "books5",
-                "instId": symbol
-            }]
-        });
+    /// Release a reference on a channel. Only the last one unsubscribes upstream, and the symbol's
+    /// receiver closes once none of its channels are left.
+    async fn release(&mut self, channel: &'static str, symbol: Symbol) {
+        let key = (channel, symbol);
+        let watched = {
+            let mut refs = self.refs.lock().unwrap();
+            if !refs.release(key) {
+                return;
+            }
+            refs.watches(symbol)
+        };
 
-        self.send_message(message.to_string()).await;
+        self.control(Control::Unsubscribe(key));
+        if !watched {
+            self.subscriptions.remove(&symbol);
+            self.route(Route::Unwatch(symbol));
+        }
+    }
 
-        let (tx, rx) = watch::channel("Hello".to_string());
+    fn control(&self, control: Control) {
+        if let Some(tx) = self.control.as_ref() {
+            let _ = tx.send(control);
+        }
+    }
 
-        let sends = self.senders.clone();
-        let mut txs = sends.lock().unwrap();
-        txs.insert(symbol.clone(), tx);
+    fn route(&self, route: Route) {
+        let _ = self.routes.send(route);
+    }
 
-        self.subscriptions.insert(symbol.clone(), rx);
+    pub async fn subscribe_orderbook(&mut self, symbol: Symbol) {
+        self.add("books", symbol, None).await;
     }
-    pub async fn unsubscribe_orderbook(&mut self, symbol: &str) {
-        let message = serde_json::json!({
-            "op": "subscribe",
-            "args": [{
-                "channel": "books",
-                "instId": symbol
-            }]
-        });
 
-        self.send_message(message.to_string()).await;
-        let (tx, rx) = watch::channel("Hello".to_string());
-        self.subscriptions.insert(symbol.to_string(), rx);
-        let sends = self.senders.clone();
-        let mut txs = sends.lock().unwrap();
-        txs.insert(symbol.to_string(), tx);
+    pub async fn subscribe_trade(&mut self, symbol: Symbol) {
+        self.add("trades", symbol, None).await;
     }
 
-    pub async fn unsubscribe_trade(&mut self, symbol: &str) {
-        let message = serde_json::json!({
-            "op": "subscribe",
-            "args": [{
-                "channel": "trades",
-                "instId": symbol
-            }]
-        });
+    pub async fn subscribe_orderbook_snapshot(&mut self, symbol: Symbol) {
+        self.add("books5", symbol, None).await;
+    }
 
-        self.send_message(message.to_string()).await;
-        let (tx, rx) = watch::channel("Hello".to_string());
-        self.subscriptions.insert(symbol.to_string(), rx);
+    /// Tick-by-tick top 50 levels, only streamed to VIP accounts
+    pub async fn subscribe_orderbook_l2_tbt(&mut self, symbol: Symbol) {
+        self.add("books50-l2-tbt", symbol, None).await;
+    }
 
-        let sends = self.senders.clone();
-        let mut txs = sends.lock().unwrap();
-        txs.insert(symbol.to_string(), tx);
-        println!("Complete");
+    pub async fn unsubscribe_orderbook(&mut self, symbol: Symbol) {
+        self.release("books", symbol).await;
     }
 
-    pub async fn unsubscribe_orderbook_snapshot(&mut self, symbol: &str) {
-        let message = serde_json::json!({
-            "op": "subscribe",
-            "args": [{
-                "channel": "books5",
-                "instId": symbol
-            }]
-        });
+    pub async fn unsubscribe_trade(&mut self, symbol: Symbol) {
+        self.release("trades", symbol).await;
+    }
 
-        self.send_message(message.to_string()).await;
+    pub async fn unsubscribe_orderbook_snapshot(&mut self, symbol: Symbol) {
+        self.release("books5", symbol).await;
+    }
 
-        let (tx, rx) = watch::channel("Hello".to_string());
+    pub async fn unsubscribe_orderbook_l2_tbt(&mut self, symbol: Symbol) {
+        self.release("books50-l2-tbt", symbol).await;
+    }
 
-        let sends = self.senders.clone();
-        let mut txs = sends.lock().unwrap();
-        txs.insert(symbol.to_string(), tx);
+    /// Latest event of a subscribed symbol
+    pub fn get_receiver(&self, symbol: &str) -> Option<&watch::Receiver<Option<Event>>> {
+        self.subscriptions
+            .get(&Symbol::get(symbol)?)
+            .filter(|latest| latest.has_changed().is_ok())
+    }
+}
+
+/// How the dispatch task lets go of the streams of clients that fell behind
+struct Released {
+    refs: Arc<Mutex<Refs>>,
+    control: mpsc::UnboundedSender<Control>,
+}
 
-        self.subscriptions.insert(symbol.to_string(), rx);
+impl Released {
+    /// Release the references `dropped` streams of `key` held. Once they were the last the channel
+    /// is unsubscribed and, if it was the symbol's last, its receiver closed, like
+    /// [`Okx::release`] does.
+    fn streams(
+        &self,
+        key: Channel,
+        dropped: usize,
+        watchers: &mut HashMap<Symbol, watch::Sender<Option<Event>>>,
+    ) {
+        let mut guard = self.refs.lock().unwrap();
+        let refs = &mut *guard;
+        for _ in 0..dropped {
+            // Released through the handle meanwhile
+            let Some(streams) = refs.streams.get_mut(&key) else {
+                return;
+            };
+            *streams -= 1;
+            if *streams == 0 {
+                refs.streams.remove(&key);
+            }
+            if refs.release(key) {
+                let _ = self.control.send(Control::Unsubscribe(key));
+                if !refs.watches(key.1) {
+                    watchers.remove(&key.1);
+                }
+            }
+        }
     }
-    pub fn get_receiver(&self, symbol: &str) -> Option<&watch::Receiver<String>> {
-        self.subscriptions.get(symbol)
+}
+
+/// Deliver what the connection task queued to the receiver of its symbol and the streams of its
+/// channel, until the [`Okx`] is dropped. Routes are applied first so a stream is in place before
+/// the data it subscribed.
+async fn dispatch(
+    events: Arc<EventRing<Tagged>>,
+    mut routes: mpsc::UnboundedReceiver<Route>,
+    released: Released,
+) {
+    let mut watchers: HashMap<Symbol, watch::Sender<Option<Event>>> = HashMap::new();
+    let mut streams: HashMap<Channel, Vec<mpsc::Sender<Event>>> = HashMap::new();
+    loop {
+        tokio::select! {
+            biased;
+            route = routes.recv() => match route {
+                Some(Route::Watch(symbol, watcher)) => {
+                    watchers.insert(symbol, watcher);
+                }
+                Some(Route::Unwatch(symbol)) => {
+                    watchers.remove(&symbol);
+                }
+                Some(Route::Stream(key, client)) => streams.entry(key).or_default().push(client),
+                Some(Route::Release(key)) => {
+                    streams.remove(&key);
+                }
+                Some(Route::Clear) => streams.clear(),
+                None => break,
+            },
+            (key, mut event) = events.pop() => {
+                event.ts_mut().dispatched = now_micros();
+                if let Some(watcher) = watchers.get(&key.1) {
+                    watcher.send_replace(Some(event.clone()));
+                }
+                // A client that fell behind loses its stream rather than stalling every other one,
+                // and is told so. Its references go with it.
+                if let Some(clients) = streams.get_mut(&key) {
+                    let before = clients.len();
+                    clients.retain(|client| match client.try_send(event.clone()) {
+                        Ok(()) => true,
+                        Err(_) => {
+                            notify_lagged(client, Exchange::Okx, key.1, event.data_type());
+                            false
+                        }
+                    });
+                    let dropped = before - clients.len();
+                    if dropped > 0 {
+                        released.streams(key, dropped, &mut watchers);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Streams are fed by the dispatch task as pushes arrive. Subscribing resolves on OKX's answer, or
+/// fails with [`SubscribeError::Timeout`] when there is none. A rejected channel fails with OKX's
+/// code and message, and takes every stream of it along.
+#[async_trait]
+impl Adapter for Okx {
+    fn exchange(&self) -> Exchange {
+        Exchange::Okx
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::for_exchange(Exchange::Okx)
     }
 
-    pub async fn reconnect(&mut self) -> Result<(), ()> {
-        if self.connected {
-            return Ok(());
+    async fn request(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<Subscribing, SubscribeError> {
+        if self.control.is_none() {
+            return Err(SubscribeError::Closed);
         }
-        let (ws_stream, _) =
-            connect_async(Url::parse("wss://ws.okx.com:8443/ws/v5/public").unwrap())
-                .await
-                .expect("Failed to connect");
+        self.capabilities().check(data_type, "")?;
+        let channel = OkxProtocol::channel(data_type);
+        let (client, events) = mpsc::channel(STREAM_CAPACITY);
+        let key = (channel, symbol);
+        *self.refs.lock().unwrap().streams.entry(key).or_default() += 1;
+        self.route(Route::Stream(key, client));
+        let (ack, answer) = oneshot::channel();
+        self.add(channel, symbol, Some(ack)).await;
+        Ok(Box::pin(async move {
+            answered(answer).await?;
+            Ok(event_stream(events))
+        }))
+    }
 
-        let (write, read) = ws_stream.split();
+    async fn subscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<EventStream, SubscribeError> {
+        match self.request(data_type, symbol).await?.await {
+            Ok(stream) => Ok(stream),
+            Err(e) => {
+                // OKX never streams a rejected channel, so every stream of it goes
+                self.unsubscribe(data_type, symbol).await?;
+                Err(e)
+            }
+        }
+    }
 
-        self.write = Some(write);
+    async fn unsubscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<(), SubscribeError> {
+        let channel = OkxProtocol::channel(data_type);
+        let key = (channel, symbol);
+        let streams = self.refs.lock().unwrap().streams.remove(&key);
+        let streams = streams.unwrap_or_default();
+        self.route(Route::Release(key));
+        for _ in 0..streams {
+            self.release(channel, symbol).await;
+        }
         Ok(())
     }
+
+    fn connection(&self) -> watch::Receiver<ConnectionState> {
+        self.state.subscribe()
+    }
+
+    async fn shutdown(&mut self) {
+        self.refs.lock().unwrap().streams.clear();
+        self.route(Route::Clear);
+        match self.control.take() {
+            Some(control) if control.send(Control::Close).is_ok() => {}
+            _ => {
+                self.state.send_replace(ConnectionState::Closed);
+            }
+        }
+    }
+}
+
+/// Whether the watchdog resubscribes `channel` when it goes quiet. Books push at least every
+/// few seconds, the trades of an illiquid instrument can be quiet for minutes.
+fn watched(channel: &str) -> bool {
+    channel != "trades"
+}
+
+/// Build an OKX `op` request for a single channel
+pub(crate) fn op_message(op: &str, channel: &str, inst_id: &str) -> String {
+    serde_json::json!({
+        "op": op,
+        "args": [{
+            "channel": channel,
+            "instId": inst_id
+        }]
+    })
+    .to_string()
+}
+
+/// [`op_message`] with an `id`, which OKX repeats in its answer
+pub(crate) fn op_message_with_id(id: &str, op: &str, channel: &str, inst_id: &str) -> String {
+    serde_json::json!({
+        "id": id,
+        "op": op,
+        "args": [{
+            "channel": channel,
+            "instId": inst_id
+        }]
+    })
+    .to_string()
+}
+
+/// What to do with a book push after [`OkxBooks::validate`]
+#[derive(Debug)]
+pub enum Validated {
+    /// The book is consistent, its events were appended
+    Valid,
+    /// The book is waiting for a snapshot, or the push was a stale full book
+    Dropped,
+    /// The book broke. Forward the [`Event::Resync`] and resubscribe `channel` for `inst_id` so OKX
+    /// sends a fresh snapshot
+    Resync {
+        event: Event,
+        channel: String,
+        inst_id: Symbol,
+    },
+}
+
+/// Book state one OKX connection keeps to validate every depth push by sequence id and checksum
+#[derive(Debug)]
+pub struct OkxBooks {
+    /// Index into `books` by channel and instId, looked up without allocating
+    ids: HashMap<String, HashMap<Symbol, usize>>,
+    books: Vec<OkxBook>,
+    sequences: SequenceTracker<usize>,
+    stats: Arc<FeedStats>,
+}
+
+impl OkxBooks {
+    pub fn new(stats: Arc<FeedStats>) -> Self {
+        Self {
+            ids: HashMap::new(),
+            books: Vec::new(),
+            sequences: SequenceTracker::new(stats.clone()),
+            stats,
+        }
+    }
+
+    /// Forget every book, used when the connection drops since OKX sends fresh snapshots on
+    /// resubscribe
+    pub fn reset(&mut self) {
+        self.ids.clear();
+        self.books.clear();
+        self.sequences.clear();
+    }
+
+    /// Book of a channel, only the first push of a book allocates
+    fn id(&mut self, arg: ArgRef) -> usize {
+        if let Some(&id) = self
+            .ids
+            .get(arg.channel)
+            .and_then(|ids| ids.get(&arg.inst_id))
+        {
+            return id;
+        }
+        let id = self.books.len();
+        self.books.push(OkxBook::default());
+        self.ids
+            .entry(arg.channel.to_string())
+            .or_default()
+            .insert(arg.inst_id, id);
+        id
+    }
+
+    /// Check a push against the sequence of its channel and our copy of the book, appending its
+    /// events to `out` while the book holds. Only the first failure asks for a resync, everything
+    /// after it is dropped until the snapshot arrives.
+    pub fn validate(&mut self, push: &OkxPush, out: &mut Vec<Event>) -> Validated {
+        let id = self.id(push.arg);
+        let is_snapshot = push.is_snapshot();
+        let book = &mut self.books[id];
+        let sequences = &mut self.sequences;
+        let stats = &self.stats;
+        let was_valid = book.valid;
+        let len = out.len();
+        let mut dropped = false;
+        let mut reason = None;
+
+        let decoded = push.books(|data| {
+            if dropped || reason.is_some() {
+                return Ok(());
+            }
+            if let Some(seq) = data.seq_id {
+                let sequence = sequences.check(id, seq, data.prev_seq_id, is_snapshot);
+                if sequence.is_anomaly() {
+                    // A stale full book is simply replaced by the next push
+                    if data.prev_seq_id.is_none() {
+                        dropped = true;
+                        return Ok(());
+                    }
+                    book.valid = false;
+                    reason = Some(format!("{sequence:?}"));
+                    return Ok(());
+                }
+            }
+
+            let checked = book.valid || is_snapshot;
+            if !book.verify(&data, is_snapshot) {
+                if checked {
+                    FeedStats::incr(&stats.checksum_mismatches);
+                    reason = Some("Checksum mismatch".to_string());
+                }
+                return Ok(());
+            }
+            data.events(push.arg.inst_id, is_snapshot, out)
+        });
+        if let Err(e) = decoded {
+            book.valid = false;
+            reason = Some(format!("Undecodable push: {e}"));
+        }
+
+        if dropped {
+            out.truncate(len);
+            return Validated::Dropped;
+        }
+        if book.valid {
+            return Validated::Valid;
+        }
+        out.truncate(len);
+        let Some(reason) = reason.filter(|_| was_valid || is_snapshot) else {
+            return Validated::Dropped;
+        };
+
+        self.sequences.reset(&id);
+        FeedStats::incr(&self.stats.resyncs);
+        let (channel, inst_id) = (push.arg.channel, push.arg.inst_id);
+        Validated::Resync {
+            event: Event::Resync(Resync {
+                exchange: Exchange::Okx,
+                symbol: inst_id,
+                reason: format!("{reason} on {channel}"),
+                ts: Timestamps::default(),
+            }),
+            channel: channel.to_string(),
+            inst_id,
+        }
+    }
+}
+
+/// The OKX v5 public websocket. Books are validated by sequence id and checksum and resubscribed
+/// when they break, OKX answers every subscribe with a fresh snapshot.
+#[derive(Debug)]
+pub struct OkxProtocol {
+    url: String,
+    books: OkxBooks,
+    /// Reused for the events of every push
+    events: Vec<Event>,
+}
+
+impl OkxProtocol {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            books: OkxBooks::new(Arc::default()),
+            events: Vec::new(),
+        }
+    }
+
+    /// Channel a data type streams from
+    pub(crate) fn channel(data_type: DataTypes) -> &'static str {
+        match data_type {
+            DataTypes::Trade => "trades",
+            DataTypes::Book => "books",
+            DataTypes::Bbo => "bbo-tbt",
+        }
+    }
+
+    /// Static name of a channel pushed by OKX, `None` for channels never subscribed
+    fn channel_name(channel: &str) -> Option<&'static str> {
+        ["trades", "books", "books5", "bbo-tbt", "books50-l2-tbt"]
+            .into_iter()
+            .find(|name| *name == channel)
+    }
+
+    /// Request streamed from a channel
+    fn request_for(channel: &str, inst_id: Symbol) -> Option<SocketRequest> {
+        let data_type = match channel {
+            "trades" => DataTypes::Trade,
+            "books" | "books50-l2-tbt" => DataTypes::Book,
+            "bbo-tbt" => DataTypes::Bbo,
+            _ => return None,
+        };
+        Some(SocketRequest {
+            symbol: inst_id,
+            data_type,
+        })
+    }
+
+    /// Answer to an `op`. Errors don't say which request they answer.
+    fn answer(event: OkxEvent, outputs: &mut Vec<Output>) {
+        let op = match event.event.as_str() {
+            "subscribe" => Op::Subscribe,
+            "unsubscribe" => Op::Unsubscribe,
+            "error" => {
+                let error = SubscribeError::Exchange {
+                    code: event.code.unwrap_or_default(),
+                    message: event.msg.unwrap_or_default(),
+                };
+                outputs.push(Output::Rejected(None, error));
+                return;
+            }
+            _ => return,
+        };
+
+        outputs.extend(
+            event
+                .arg
+                .and_then(|arg| Self::request_for(&arg.channel, Symbol::intern(&arg.inst_id)))
+                .map(|request| Output::Acked(op, request)),
+        );
+    }
+}
+
+impl Protocol for OkxProtocol {
+    fn exchange(&self) -> Exchange {
+        Exchange::Okx
+    }
+
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn attach(&mut self, stats: Arc<FeedStats>) {
+        self.books = OkxBooks::new(stats);
+    }
+
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError> {
+        let channel = Self::channel(request.data_type);
+        Ok(op_message(op.as_str(), channel, &request.symbol))
+    }
+
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>) {
+        let Ok(push) = OkxPush::parse(frame) else {
+            if let Ok(event) = serde_json::from_str::<OkxEvent>(frame) {
+                Self::answer(event, outputs);
+            }
+            return;
+        };
+        let Some(request) = Self::request_for(push.arg.channel, push.arg.inst_id) else {
+            return;
+        };
+
+        self.events.clear();
+        if push.is_book() {
+            if let Validated::Resync { event, .. } = self.books.validate(&push, &mut self.events) {
+                outputs.extend([Output::Event(request, event), Output::Resubscribe(request)]);
+                return;
+            }
+        } else if push.events(&mut self.events).is_err() {
+            return;
+        }
+        outputs.extend(
+            self.events
+                .drain(..)
+                .map(|event| Output::Event(request, event)),
+        );
+    }
+
+    fn reset(&mut self) {
+        self.books.reset();
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    const URL: &'static str = "wss://ws.okx.com:8443/ws/v5/public";
+    use crate::mock::MockExchange;
+
+    fn push(action: &str, seq: i64, prev: i64, checksum: Option<i64>) -> String {
+        serde_json::json!({
+            "arg": { "channel": "books", "instId": "BTC-USDT" },
+            "action": action,
+            "data": [{
+                "asks": [["101", "1", "0", "1"]],
+                "bids": [["100", "2", "0", "1"]],
+                "ts": "1688060541909",
+                "checksum": checksum,
+                "seqId": seq,
+                "prevSeqId": prev
+            }]
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn test_validate_books() {
+        let stats = Arc::new(FeedStats::default());
+        let mut books = OkxBooks::new(stats.clone());
+        let checksum = crc32fast::hash(b"100:2:101:1") as i32 as i64;
+
+        let mut out = Vec::new();
+        let mut validate = |frame: String, out: &mut Vec<Event>| {
+            books.validate(&OkxPush::parse(&frame).unwrap(), out)
+        };
+
+        let validated = validate(push("snapshot", 10, -1, Some(checksum)), &mut out);
+        assert!(matches!(validated, Validated::Valid) && out.len() == 1);
+        let validated = validate(push("update", 11, 10, Some(checksum)), &mut out);
+        assert!(matches!(validated, Validated::Valid) && out.len() == 3);
+
+        // A gap asks for one resync, everything after is dropped until the snapshot
+        out.clear();
+        let validated = validate(push("update", 15, 13, Some(checksum)), &mut out);
+        assert!(
+            matches!(validated, Validated::Resync { ref channel, .. } if channel == "books"),
+            "{validated:?}"
+        );
+        let validated = validate(push("update", 16, 15, Some(checksum)), &mut out);
+        assert!(matches!(validated, Validated::Dropped));
+        assert!(out.is_empty());
+        let validated = validate(push("snapshot", 20, -1, Some(checksum)), &mut out);
+        assert!(matches!(validated, Validated::Valid));
+
+        // So does a checksum mismatch, and nothing of the push is kept
+        out.clear();
+        let validated = validate(push("update", 21, 20, Some(checksum + 1)), &mut out);
+        assert!(matches!(validated, Validated::Resync { .. }));
+        assert!(out.is_empty());
+
+        let counts = stats.counts();
+        assert_eq!(counts.gaps, 1);
+        assert_eq!(counts.checksum_mismatches, 1);
+        assert_eq!(counts.resyncs, 2);
+    }
+
     #[tokio::test]
     async fn test_connect() {
-        // let client = awc::Client::default();
-        let mut okx_adapter = Okx::new().connect(URL).await;
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
         okx_adapter.subscribe_trade("BTC-USDT".into()).await;
+
+        let ops = exchange.ops(1).await;
+        let id = ops[0]["id"].as_str().unwrap();
+        assert_eq!(
+            ops[0],
+            serde_json::from_str::<serde_json::Value>(&op_message_with_id(
+                id,
+                "subscribe",
+                "trades",
+                "BTC-USDT"
+            ))
+            .unwrap()
+        );
+        assert!(okx_adapter.is_connected());
     }
 
     #[tokio::test]
     async fn test_buffer() {
-        let mut okx_adapter = Okx::new().connect(URL).await;
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
         okx_adapter.subscribe_trade("BTC-USDT".into()).await;
-        let buff_read = okx_adapter.data_buffer.clone();
-        // let que = buff_read.lock().unwrap();
-        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+        exchange.ops(1).await;
+
+        // The trade goes through the ring to the receiver of the push's instId
+        let mut latest = okx_adapter.get_receiver("BTC-USDT").unwrap().clone();
+        exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
+        time::timeout(std::time::Duration::from_secs(5), latest.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            &*latest.borrow(),
+            Some(Event::Trade(t)) if t.symbol == "BTC-USDT"
+        ));
+        let counts = okx_adapter.ring_counts();
+        assert_eq!((counts.pushed, counts.dropped, counts.len), (1, 0, 0));
+        assert_eq!(counts.high_water, 1);
     }
 
     #[tokio::test]
     async fn test_read() {
-        let mut okx_adapter = Okx::new().connect(URL).await;
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+        okx_adapter.subscribe_orderbook("BTC-USDT".into()).await;
+        okx_adapter.subscribe_orderbook("BTC-USDT".into()).await;
+        assert!(okx_adapter.get_receiver("BTC-USDT").is_some());
+
+        // Only the last unsubscribe goes upstream and closes the receiver
+        okx_adapter.unsubscribe_orderbook("BTC-USDT".into()).await;
+        assert!(okx_adapter.get_receiver("BTC-USDT").is_some());
+        okx_adapter.unsubscribe_orderbook("BTC-USDT".into()).await;
+        assert!(okx_adapter.get_receiver("BTC-USDT").is_none());
+
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[0]["op"], "subscribe");
+        assert_eq!(ops[1]["op"], "unsubscribe");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_l2_tbt() {
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+        okx_adapter
+            .subscribe_orderbook_l2_tbt("BTC-USDT".into())
+            .await;
+        let ops = exchange.ops(1).await;
+        assert_eq!(ops[0]["args"][0]["channel"], "books50-l2-tbt");
+
+        // Its pushes are validated like `books` and reach the receiver of the instId
+        let mut latest = okx_adapter.get_receiver("BTC-USDT").unwrap().clone();
+        let (asks, bids) = ([("101", "1")], [("100", "2")]);
+        exchange.book_on("books50-l2-tbt", "snapshot", "BTC-USDT", &asks, &bids);
+        time::timeout(std::time::Duration::from_secs(5), latest.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            &*latest.borrow_and_update(),
+            Some(Event::OrderbookSnapshot(s)) if s.symbol == "BTC-USDT"
+        ));
+        exchange.book_on("books50-l2-tbt", "update", "BTC-USDT", &[("101", "3")], &[]);
+        time::timeout(std::time::Duration::from_secs(5), latest.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            &*latest.borrow(),
+            Some(Event::OrderbookUpdate(u)) if u.symbol == "BTC-USDT"
+        ));
+
+        okx_adapter
+            .unsubscribe_orderbook_l2_tbt("BTC-USDT".into())
+            .await;
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[1]["op"], "unsubscribe");
+        assert_eq!(ops[1]["args"][0]["channel"], "books50-l2-tbt");
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
         okx_adapter.subscribe_orderbook("BTC-USDT".into()).await;
-        // okx_adapter.stream().await;
+        exchange.ops(1).await;
+
+        // A gap resubscribes the book, and is counted where the adapter can see it
+        exchange.book("snapshot", "BTC-USDT", &[("101", "1")], &[("100", "2")]);
+        exchange.push(push("update", 15, 13, None));
+        let ops = exchange.ops(3).await;
+        assert_eq!(
+            (&ops[1]["op"], &ops[2]["op"]),
+            (&"unsubscribe".into(), &"subscribe".into())
+        );
+        let counts = okx_adapter.stats();
+        assert_eq!((counts.gaps, counts.resyncs), (1, 1));
+
+        // The counters outlive the connection
+        exchange.disconnect();
+        exchange.ops(4).await;
+        assert_eq!(okx_adapter.stats(), counts);
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+        okx_adapter.subscribe_trade("BTC-USDT".into()).await;
+        exchange.ops(1).await;
 
-        // okx_adapter.subscribe_trade("BTC-USDT".into()).await;
-        let read = okx_adapter.get_receiver("BTC-USDT").unwrap();
-        let mut read = read.clone();
-        while read.changed().await.is_ok() {
-            println!("Recieved: {}", *read.borrow());
+        // The connection task redials and replays the channel on its own
+        exchange.disconnect();
+        let ops = exchange.ops(2).await;
+        assert_eq!(
+            (&ops[0]["op"], &ops[0]["args"]),
+            (&ops[1]["op"], &ops[1]["args"])
+        );
+        assert_ne!(ops[0]["id"], ops[1]["id"]);
+        assert_eq!(exchange.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_adapter() {
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+        let mut connection = okx_adapter.connection();
+        let mut trades = okx_adapter
+            .subscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+        exchange.ops(1).await;
+
+        exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
+        let trade = time::timeout(std::time::Duration::from_secs(5), trades.next()).await;
+        assert!(
+            matches!(&trade, Ok(Some(Event::Trade(t))) if t.symbol == "BTC-USDT"),
+            "{trade:?}"
+        );
+
+        okx_adapter.shutdown().await;
+        assert!(trades.next().await.is_none());
+        connection
+            .wait_for(|state| *state == ConnectionState::Closed)
+            .await
+            .unwrap();
+        let subscribe = okx_adapter
+            .subscribe(DataTypes::Bbo, "BTC-USDT".into())
+            .await;
+        assert!(matches!(subscribe, Err(SubscribeError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_stream() {
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+        let mut trades = okx_adapter
+            .subscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+
+        // One push with more trades than the stream buffers
+        let trade = serde_json::json!({
+            "instId": "BTC-USDT",
+            "tradeId": "426790906",
+            "px": "30460.1",
+            "sz": "0.5",
+            "side": "sell",
+            "ts": "1688085963425"
+        });
+        let push = serde_json::json!({
+            "arg": {"channel": "trades", "instId": "BTC-USDT"},
+            "data": vec![trade; STREAM_CAPACITY + 1],
+        });
+        exchange.push(push.to_string());
+
+        // The stream that fell behind is released upstream, as if unsubscribed
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[1]["op"], "unsubscribe");
+        {
+            let refs = okx_adapter.refs.lock().unwrap();
+            assert!(refs.streams.is_empty() && refs.channels.is_empty());
+        }
+        assert!(okx_adapter.get_receiver("BTC-USDT").is_none());
+        let mut lagged = false;
+        while let Ok(Some(event)) =
+            time::timeout(std::time::Duration::from_secs(5), trades.next()).await
+        {
+            lagged = matches!(event, Event::Lagged(_));
         }
+        assert!(lagged);
+
+        // Subscribing again starts over
+        let _trades = okx_adapter
+            .subscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+        let ops = exchange.ops(3).await;
+        assert_eq!(ops[2]["op"], "subscribe");
+        assert!(okx_adapter.get_receiver("BTC-USDT").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_resubscribe_rejected() {
+        let exchange = MockExchange::start().await;
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+        let mut books = okx_adapter
+            .subscribe(DataTypes::Book, "BTC-USDT".into())
+            .await
+            .unwrap();
+        exchange.book("snapshot", "BTC-USDT", &[("101", "1")], &[("100", "2")]);
+        let snapshot = time::timeout(std::time::Duration::from_secs(5), books.next()).await;
+        assert!(matches!(snapshot, Ok(Some(Event::OrderbookSnapshot(_)))));
+
+        // A gap resubscribes the book, and OKX rejects it while a trade subscription waits on
+        // its own answer
+        exchange.reject("BTC-USDT");
+        exchange.set_delay(std::time::Duration::from_millis(100));
+        exchange.push(push("update", 999, 998, None));
+        exchange.ops(3).await;
+        let subscribe = okx_adapter
+            .subscribe(DataTypes::Trade, "ETH-USDT".into())
+            .await;
+        assert!(subscribe.is_ok(), "{:?}", subscribe.err());
+        let ops = exchange.ops(4).await;
+        assert_eq!(ops[1]["op"], "unsubscribe");
+        assert_eq!(ops[2]["op"], "subscribe");
+        assert_eq!(ops[3]["args"][0]["instId"], "ETH-USDT");
+    }
+
+    #[tokio::test]
+    async fn test_rejected() {
+        let exchange = MockExchange::start().await;
+        exchange.reject("ETH-USDT");
+        let mut okx_adapter = Okx::new().connect(exchange.url()).await.unwrap();
+
+        // The subscribe resolves on OKX's answer, not once it is sent
+        let subscribe = okx_adapter
+            .subscribe(DataTypes::Trade, "ETH-USDT".into())
+            .await;
+        let error = subscribe.err();
+        assert!(
+            matches!(&error, Some(SubscribeError::Exchange { code, .. }) if code == "60018"),
+            "{error:?}"
+        );
+        {
+            let refs = okx_adapter.refs.lock().unwrap();
+            assert!(refs.streams.is_empty() && refs.channels.is_empty());
+        }
+
+        let subscribe = okx_adapter
+            .subscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await;
+        assert!(subscribe.is_ok());
+        // The rejected channel was never subscribed, so nothing was unsubscribed
+        let ops = exchange.ops(2).await;
+        assert!(ops.iter().all(|op| op["op"] == "subscribe"), "{ops:?}");
     }
 }
@@ -0,0 +1,121 @@
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use crate::{
+    adapters::{
+        actor::SocketRequest,
+        capabilities::Capabilities,
+        sequence::FeedStats,
+        subscriptions::{Op, SubscribeError},
+    },
+    event::Event,
+    models::Exchange,
+};
+
+/// Body of a REST snapshot, or why it couldn't be fetched
+pub type SnapshotFuture = Pin<Box<dyn Future<Output = Result<String, String>> + Send>>;
+
+/// Endpoint of a new connection, or why it couldn't be negotiated
+pub type EndpointFuture = Pin<Box<dyn Future<Output = Result<Endpoint, String>> + Send>>;
+
+/// Where to dial a connection the exchange handed out, and how it wants to be kept alive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint {
+    pub url: String,
+    /// Replaces the keepalive's ping interval for this connection
+    pub ping_interval: Option<Duration>,
+    /// Replaces the keepalive's pong timeout for this connection
+    pub pong_timeout: Option<Duration>,
+}
+
+/// What a decoded frame asks of the actor
+#[derive(Debug)]
+pub enum Output {
+    /// Data for the subscribers of a stream
+    Event(SocketRequest, Event),
+    /// The exchange confirmed a request
+    Acked(Op, SocketRequest),
+    /// The exchange rejected a request. `None` if the error doesn't say which, the oldest one in
+    /// flight is assumed.
+    Rejected(Option<(Op, SocketRequest)>, SubscribeError),
+    /// The stream lost its place, unsubscribe and subscribe it again
+    Resubscribe(SocketRequest),
+    /// Fetch a snapshot of the stream with [`Protocol::snapshot`]
+    Fetch(SocketRequest),
+    /// The exchange is going away, drop the connection and dial again
+    Reconnect(String),
+    /// The exchange asks for a new connection, move to one the way [`Protocol::max_age`] does
+    Rotate,
+    /// Send a frame to the exchange as is, such as the answer to its own ping
+    Send(String),
+}
+
+/// The wire format of one exchange's public websocket. [`MyActor`](super::actor) owns the
+/// connection, subscriptions and keepalive and asks the protocol how to talk to the exchange.
+pub trait Protocol: Send + Sync + 'static {
+    fn exchange(&self) -> Exchange;
+
+    /// Endpoint dialled on every (re)connect, unless [`Protocol::endpoint`] negotiates one
+    fn url(&self) -> &str;
+
+    /// Negotiate the endpoint of a new connection, for exchanges that hand out a fresh one, and
+    /// the token to use it, every time
+    fn endpoint(&self) -> Option<EndpointFuture> {
+        None
+    }
+
+    /// What the connection can stream
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::for_exchange(self.exchange())
+    }
+
+    /// Counters the protocol reports sequence gaps and resyncs to
+    fn attach(&mut self, _stats: Arc<FeedStats>) {}
+
+    /// Frames sent first on every new connection, before the subscriptions are replayed
+    fn handshake(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Frame asking the exchange to `op` `request`
+    fn request(&mut self, op: Op, request: &SocketRequest) -> Result<String, SubscribeError>;
+
+    /// Decode a text frame into `outputs`. The actor hands in the same buffer for every frame, so
+    /// decoding allocates no list of its own.
+    fn decode(&mut self, frame: &str, outputs: &mut Vec<Output>);
+
+    /// Text of a binary frame, for exchanges that compress theirs. `None` drops the frame.
+    fn inflate(&self, _frame: &[u8]) -> Option<String> {
+        None
+    }
+
+    /// The connection was replaced, forget whatever state the old one carried
+    fn reset(&mut self) {}
+
+    /// How long the exchange keeps a connection open. The actor moves to a new connection before
+    /// then.
+    fn max_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether the actor keeps reading the old connection of a rotation until the new one has
+    /// every subscription acked. Only for protocols that drop what both connections deliver, the
+    /// others forget the old connection with [`Protocol::reset`] and close it right away.
+    fn overlap(&self) -> bool {
+        false
+    }
+
+    /// Fetch a snapshot of `request` outside the websocket, for exchanges whose streams only send
+    /// diffs
+    fn snapshot(&self, _request: &SocketRequest) -> Option<SnapshotFuture> {
+        None
+    }
+
+    /// A snapshot asked for with [`Output::Fetch`] arrived
+    fn snapshot_fetched(
+        &mut self,
+        _request: &SocketRequest,
+        _body: Result<String, String>,
+        _outputs: &mut Vec<Output>,
+    ) {
+    }
+}
@@ -0,0 +1,175 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crossbeam::queue::ArrayQueue;
+use tokio::sync::Notify;
+
+/// What a full [`EventRing`] does with one more item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wait for the consumer to make room, holding up the socket reader
+    #[default]
+    Block,
+    /// Make room by dropping the oldest item
+    DropOldest,
+    /// Drop the item pushed
+    DropNewest,
+}
+
+/// A point in time copy of an [`EventRing`]'s counters
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RingCounts {
+    pub pushed: u64,
+    pub dropped: u64,
+    /// Items waiting right now
+    pub len: usize,
+    /// Most items that ever waited at once
+    pub high_water: usize,
+}
+
+/// Bounded lock-free queue between a socket reader and whoever dispatches what it read. Pushing
+/// and popping are a few atomic operations, the [`Notify`]s are only waited on when the ring is
+/// empty, or full under [`Overflow::Block`].
+#[derive(Debug)]
+pub struct EventRing<T> {
+    queue: ArrayQueue<T>,
+    overflow: Overflow,
+    not_empty: Notify,
+    not_full: Notify,
+    pushed: AtomicU64,
+    dropped: AtomicU64,
+    high_water: AtomicUsize,
+}
+
+impl<T> EventRing<T> {
+    pub fn new(capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            queue: ArrayQueue::new(capacity),
+            overflow,
+            not_empty: Notify::new(),
+            not_full: Notify::new(),
+            pushed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            high_water: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queue `item` under the ring's [`Overflow`] policy. `false` if it, or an older item in its
+    /// place, was dropped.
+    pub async fn push(&self, mut item: T) -> bool {
+        let kept = loop {
+            match self.overflow {
+                Overflow::Block => match self.queue.push(item) {
+                    Ok(()) => break true,
+                    Err(rejected) => {
+                        item = rejected;
+                        let not_full = self.not_full.notified();
+                        // The consumer may have made room before we started waiting
+                        if self.queue.is_full() {
+                            not_full.await;
+                        }
+                    }
+                },
+                Overflow::DropOldest => break self.queue.force_push(item).is_none(),
+                Overflow::DropNewest => break self.queue.push(item).is_ok(),
+            }
+        };
+
+        self.pushed.fetch_add(1, Ordering::Relaxed);
+        if !kept {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.high_water
+            .fetch_max(self.queue.len(), Ordering::Relaxed);
+        self.not_empty.notify_one();
+        kept
+    }
+
+    pub fn try_pop(&self) -> Option<T> {
+        let item = self.queue.pop()?;
+        self.not_full.notify_one();
+        Some(item)
+    }
+
+    /// Wait for the next item. Cancel safe, nothing is taken off the ring until it resolves.
+    pub async fn pop(&self) -> T {
+        loop {
+            if let Some(item) = self.try_pop() {
+                return item;
+            }
+            let not_empty = self.not_empty.notified();
+            if let Some(item) = self.try_pop() {
+                return item;
+            }
+            not_empty.await;
+        }
+    }
+
+    pub fn counts(&self) -> RingCounts {
+        RingCounts {
+            pushed: self.pushed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            len: self.queue.len(),
+            high_water: self.high_water.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_overflow() {
+        let newest = EventRing::new(2, Overflow::DropNewest);
+        let oldest = EventRing::new(2, Overflow::DropOldest);
+        for item in 1..=3 {
+            assert_eq!(newest.push(item).await, item < 3);
+            assert_eq!(oldest.push(item).await, item < 3);
+        }
+        assert_eq!((newest.pop().await, newest.pop().await), (1, 2));
+        assert_eq!((oldest.pop().await, oldest.pop().await), (2, 3));
+
+        let counts = oldest.counts();
+        assert_eq!(
+            counts,
+            RingCounts {
+                pushed: 3,
+                dropped: 1,
+                len: 0,
+                high_water: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let ring = Arc::new(EventRing::new(1, Overflow::Block));
+        ring.push(1).await;
+
+        let producer = tokio::spawn({
+            let ring = ring.clone();
+            async move { ring.push(2).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!producer.is_finished(), "pushed into a full ring");
+
+        assert_eq!(ring.pop().await, 1);
+        assert!(producer.await.unwrap());
+        assert_eq!(ring.pop().await, 2);
+        assert_eq!(ring.counts().dropped, 0);
+    }
+}
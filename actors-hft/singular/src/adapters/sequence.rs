@@ -0,0 +1,190 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Where a push falls in its channel's sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sequence {
+    /// A snapshot, which (re)starts the sequence
+    Snapshot,
+    /// The push directly follows the last one
+    InOrder,
+    /// "No change" push, the exchange repeats the last sequence id to show the feed is alive
+    Heartbeat,
+    /// Pushes were lost between the last one and this one
+    Gap { expected: i64, received: i64 },
+    /// The last push was received again
+    Duplicate,
+    /// The push is older than the last one
+    OutOfOrder,
+    /// The sequence was reset and no snapshot has arrived yet
+    AwaitingSnapshot,
+}
+
+impl Sequence {
+    /// Anything that means the local book can no longer be trusted
+    pub fn is_anomaly(&self) -> bool {
+        matches!(
+            self,
+            Sequence::Gap { .. } | Sequence::Duplicate | Sequence::OutOfOrder
+        )
+    }
+}
+
+/// Counters an adapter keeps about the health of its feed. Shared with the adapter's handle so they
+/// can be read while the adapter runs.
+#[derive(Debug, Default)]
+pub struct FeedStats {
+    pub gaps: AtomicU64,
+    pub duplicates: AtomicU64,
+    pub out_of_order: AtomicU64,
+    pub heartbeats: AtomicU64,
+    pub checksum_mismatches: AtomicU64,
+    pub resyncs: AtomicU64,
+}
+
+/// A point in time copy of [`FeedStats`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FeedCounts {
+    pub gaps: u64,
+    pub duplicates: u64,
+    pub out_of_order: u64,
+    pub heartbeats: u64,
+    pub checksum_mismatches: u64,
+    pub resyncs: u64,
+}
+
+impl FeedStats {
+    pub fn counts(&self) -> FeedCounts {
+        FeedCounts {
+            gaps: self.gaps.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            out_of_order: self.out_of_order.load(Ordering::Relaxed),
+            heartbeats: self.heartbeats.load(Ordering::Relaxed),
+            checksum_mismatches: self.checksum_mismatches.load(Ordering::Relaxed),
+            resyncs: self.resyncs.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn incr(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Tracks the last sequence id of every channel on one connection
+#[derive(Debug)]
+pub struct SequenceTracker<K> {
+    last: HashMap<K, i64>,
+    stats: Arc<FeedStats>,
+}
+
+impl<K: Hash + Eq> SequenceTracker<K> {
+    pub fn new(stats: Arc<FeedStats>) -> Self {
+        Self {
+            last: HashMap::new(),
+            stats,
+        }
+    }
+
+    /// Classify a push. `prev` is the id the exchange says came before `seq`, `None` for pushes
+    /// that are always full snapshots (OKX `books5`, `bbo-tbt`).
+    pub fn check(&mut self, key: K, seq: i64, prev: Option<i64>, is_snapshot: bool) -> Sequence {
+        let result = match (self.last.get(&key).copied(), prev) {
+            _ if is_snapshot && prev.is_some() => Sequence::Snapshot,
+            (Some(last), _) if seq == last && prev == Some(seq) => Sequence::Heartbeat,
+            (Some(last), _) if seq == last => Sequence::Duplicate,
+            (Some(last), _) if seq < last => Sequence::OutOfOrder,
+            (_, None) => Sequence::Snapshot,
+            (None, Some(_)) => Sequence::AwaitingSnapshot,
+            (Some(last), Some(prev)) if prev == last => Sequence::InOrder,
+            (Some(last), Some(_)) => Sequence::Gap {
+                expected: last,
+                received: seq,
+            },
+        };
+
+        match result {
+            Sequence::Snapshot | Sequence::InOrder => {
+                self.last.insert(key, seq);
+            }
+            Sequence::Heartbeat => FeedStats::incr(&self.stats.heartbeats),
+            Sequence::Gap { .. } => FeedStats::incr(&self.stats.gaps),
+            Sequence::Duplicate => FeedStats::incr(&self.stats.duplicates),
+            Sequence::OutOfOrder => FeedStats::incr(&self.stats.out_of_order),
+            Sequence::AwaitingSnapshot => {}
+        }
+
+        result
+    }
+
+    /// Forget a channel so every push is ignored until the next snapshot
+    pub fn reset(&mut self, key: &K) {
+        self.last.remove(key);
+    }
+
+    /// Forget every channel
+    pub fn clear(&mut self) {
+        self.last.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sequence() {
+        let stats = Arc::new(FeedStats::default());
+        let mut tracker = SequenceTracker::new(stats.clone());
+
+        assert_eq!(
+            tracker.check("books", 10, Some(-1), true),
+            Sequence::Snapshot
+        );
+        assert_eq!(
+            tracker.check("books", 12, Some(10), false),
+            Sequence::InOrder
+        );
+        assert_eq!(
+            tracker.check("books", 12, Some(12), false),
+            Sequence::Heartbeat
+        );
+        assert_eq!(
+            tracker.check("books", 12, Some(10), false),
+            Sequence::Duplicate
+        );
+        assert_eq!(
+            tracker.check("books", 11, Some(10), false),
+            Sequence::OutOfOrder
+        );
+        assert_eq!(
+            tracker.check("books", 20, Some(15), false),
+            Sequence::Gap {
+                expected: 12,
+                received: 20
+            }
+        );
+
+        tracker.reset(&"books");
+        assert_eq!(
+            tracker.check("books", 21, Some(20), false),
+            Sequence::AwaitingSnapshot
+        );
+
+        // Channels without a previous id only catch stale pushes
+        assert_eq!(tracker.check("books5", 5, None, true), Sequence::Snapshot);
+        assert_eq!(tracker.check("books5", 4, None, true), Sequence::OutOfOrder);
+        assert_eq!(tracker.check("books5", 6, None, true), Sequence::Snapshot);
+
+        let counts = stats.counts();
+        assert_eq!(counts.heartbeats, 1);
+        assert_eq!(counts.duplicates, 1);
+        assert_eq!(counts.out_of_order, 2);
+        assert_eq!(counts.gaps, 1);
+    }
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    hash::Hash,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{mpsc, oneshot},
+    time,
+};
+
+use crate::interfaces::SUBSCRIBE_TIMEOUT;
+
+/// Downstream subscribers of every upstream channel on one connection. A channel stays subscribed
+/// upstream for as long as it has at least one subscriber.
+#[derive(Debug)]
+pub struct Subscriptions<K, T> {
+    clients: HashMap<K, Vec<mpsc::Sender<T>>>,
+}
+
+impl<K, T> Default for Subscriptions<K, T> {
+    fn default() -> Self {
+        Self {
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, T> Subscriptions<K, T> {
+    /// Add a reference to `key`. Returns `true` if it is the first, so the caller subscribes
+    /// upstream.
+    pub fn add(&mut self, key: K, client: mpsc::Sender<T>) -> bool {
+        let clients = self.clients.entry(key).or_default();
+        clients.push(client);
+        clients.len() == 1
+    }
+
+    /// Release one reference `client` holds on `key`. Returns `true` if it was the last, so the
+    /// caller unsubscribes upstream.
+    pub fn remove(&mut self, key: &K, client: &mpsc::Sender<T>) -> bool {
+        let Some(clients) = self.clients.get_mut(key) else {
+            return false;
+        };
+        let Some(index) = clients.iter().position(|c| c.same_channel(client)) else {
+            return false;
+        };
+
+        clients.remove(index);
+        if clients.is_empty() {
+            self.clients.remove(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop every subscriber of `key`
+    pub fn take(&mut self, key: &K) -> Vec<mpsc::Sender<T>> {
+        self.clients.remove(key).unwrap_or_default()
+    }
+
+    /// Release every reference held by clients that dropped their receiver. Returns the keys
+    /// nobody listens to anymore.
+    pub fn prune(&mut self) -> Vec<K> {
+        let mut released = Vec::new();
+        self.clients.retain(|key, clients| {
+            clients.retain(|c| !c.is_closed());
+            if clients.is_empty() {
+                released.push(key.clone());
+            }
+            !clients.is_empty()
+        });
+        released
+    }
+
+    /// Release every reference held by `dropped`. Returns the keys nobody listens to anymore.
+    pub fn disconnect(&mut self, dropped: &[mpsc::Sender<T>]) -> Vec<K> {
+        let mut released = Vec::new();
+        self.clients.retain(|key, clients| {
+            clients.retain(|c| !dropped.iter().any(|d| d.same_channel(c)));
+            if clients.is_empty() {
+                released.push(key.clone());
+            }
+            !clients.is_empty()
+        });
+        released
+    }
+
+    pub fn get(&self, key: &K) -> Option<&[mpsc::Sender<T>]> {
+        self.clients.get(key).map(Vec::as_slice)
+    }
+
+    pub fn refs(&self, key: &K) -> usize {
+        self.clients.get(key).map_or(0, Vec::len)
+    }
+
+    /// Every channel with at least one subscriber
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.clients.keys()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &[mpsc::Sender<T>])> {
+        self.clients.iter().map(|(k, c)| (k, c.as_slice()))
+    }
+}
+
+/// Why the exchange didn't confirm a subscribe or unsubscribe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscribeError {
+    /// The exchange rejected the request
+    Exchange { code: String, message: String },
+    /// The data type isn't offered by this adapter
+    Unsupported(String),
+    /// The adapter doesn't offer `what`, only what is in `offered`
+    NotOffered { what: String, offered: Vec<String> },
+    /// The connection carries as many channels as the exchange allows, another one may not
+    Full(String),
+    /// The adapter stopped before the exchange answered
+    Closed,
+    /// The exchange didn't answer within the given time
+    Timeout(Duration),
+}
+
+impl Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscribeError::Exchange { code, message } => {
+                write!(f, "Exchange rejected the request ({code}): {message}")
+            }
+            SubscribeError::Unsupported(what) => write!(f, "{what} is not available"),
+            SubscribeError::NotOffered { what, offered } => {
+                write!(
+                    f,
+                    "{what} is not offered, choose from: {}",
+                    offered.join(", ")
+                )
+            }
+            SubscribeError::Full(limit) => write!(f, "{limit}"),
+            SubscribeError::Closed => write!(f, "The adapter closed before the exchange answered"),
+            SubscribeError::Timeout(after) => {
+                write!(f, "The exchange didn't answer within {after:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+pub type Ack = oneshot::Sender<Result<(), SubscribeError>>;
+
+/// What was sent through an [`Ack`], waited on for at most [`SUBSCRIBE_TIMEOUT`]
+pub async fn answered(
+    answer: oneshot::Receiver<Result<(), SubscribeError>>,
+) -> Result<(), SubscribeError> {
+    match time::timeout(SUBSCRIBE_TIMEOUT, answer).await {
+        Ok(answer) => answer.unwrap_or(Err(SubscribeError::Closed)),
+        Err(_) => Err(SubscribeError::Timeout(SUBSCRIBE_TIMEOUT)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Subscribe,
+    Unsubscribe,
+}
+
+impl Op {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Op::Subscribe => "subscribe",
+            Op::Unsubscribe => "unsubscribe",
+        }
+    }
+}
+
+/// A request sent upstream and everyone waiting on its answer
+#[derive(Debug)]
+pub struct Pending<K> {
+    pub op: Op,
+    pub key: K,
+    pub waiters: Vec<Ack>,
+}
+
+/// Requests the exchange has not answered yet, oldest first. Exchanges answer in order, which is
+/// how errors that don't name their request are matched to one.
+#[derive(Debug)]
+pub struct Acks<K> {
+    pending: VecDeque<Pending<K>>,
+}
+
+impl<K> Default for Acks<K> {
+    fn default() -> Self {
+        Self {
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl<K: PartialEq> Acks<K> {
+    /// A request went upstream
+    pub fn sent(&mut self, op: Op, key: K, waiter: Option<Ack>) {
+        self.pending.push_back(Pending {
+            op,
+            key,
+            waiters: waiter.into_iter().collect(),
+        });
+    }
+
+    /// Wait on a request already in flight. Hands the waiter back if there is none.
+    pub fn join(&mut self, op: Op, key: &K, waiter: Ack) -> Result<(), Ack> {
+        match self
+            .pending
+            .iter_mut()
+            .rev()
+            .find(|p| p.op == op && &p.key == key)
+        {
+            Some(pending) => {
+                pending.waiters.push(waiter);
+                Ok(())
+            }
+            None => Err(waiter),
+        }
+    }
+
+    /// The exchange confirmed a request
+    pub fn acked(&mut self, op: Op, key: &K) {
+        if let Some(index) = self
+            .pending
+            .iter()
+            .position(|p| p.op == op && &p.key == key)
+        {
+            let pending = self.pending.remove(index).expect("index is in bounds");
+            resolve(pending.waiters, Ok(()));
+        }
+    }
+
+    /// The exchange rejected the oldest request. Returns it so the caller can roll it back.
+    pub fn rejected(&mut self, error: SubscribeError) -> Option<(Op, K)> {
+        let pending = self.pending.pop_front()?;
+        resolve(pending.waiters, Err(error));
+        Some((pending.op, pending.key))
+    }
+
+    /// The exchange rejected a request it named. Returns it so the caller can roll it back.
+    pub fn rejected_request(&mut self, op: Op, key: &K, error: SubscribeError) -> Option<(Op, K)> {
+        let index = self
+            .pending
+            .iter()
+            .position(|p| p.op == op && &p.key == key)?;
+        let pending = self.pending.remove(index).expect("index is in bounds");
+        resolve(pending.waiters, Err(error));
+        Some((pending.op, pending.key))
+    }
+
+    /// Take every request, used when the connection drops and nothing in flight will be answered
+    pub fn drain(&mut self) -> Vec<Pending<K>> {
+        self.pending.drain(..).collect()
+    }
+}
+
+pub fn resolve(waiters: Vec<Ack>, result: Result<(), SubscribeError>) {
+    for waiter in waiters {
+        let _ = waiter.send(result.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ref_counts() {
+        let mut subs: Subscriptions<&str, u8> = Subscriptions::default();
+        let (first, first_rx) = mpsc::channel(1);
+        let (second, second_rx) = mpsc::channel(1);
+
+        assert!(subs.add("trades", first.clone()));
+        assert!(!subs.add("trades", second.clone()));
+        assert!(subs.add("books", second.clone()));
+        assert_eq!(subs.refs(&"trades"), 2);
+
+        assert!(!subs.remove(&"trades", &first));
+        // A client without a reference releases nothing
+        assert!(!subs.remove(&"trades", &first));
+        assert!(subs.remove(&"trades", &second));
+        assert_eq!(subs.refs(&"trades"), 0);
+
+        // Dropping the receiver releases every reference of that client
+        assert!(!subs.add("books", first));
+        drop(second_rx);
+        assert!(subs.prune().is_empty());
+        assert_eq!(subs.refs(&"books"), 1);
+        drop(first_rx);
+        assert_eq!(subs.prune(), vec!["books"]);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut subs: Subscriptions<&str, u8> = Subscriptions::default();
+        let (first, _first_rx) = mpsc::channel(1);
+        let (second, _second_rx) = mpsc::channel(1);
+        subs.add("trades", first.clone());
+        subs.add("trades", second.clone());
+        subs.add("books", first.clone());
+
+        assert_eq!(subs.disconnect(&[first]), vec!["books"]);
+        assert_eq!(subs.refs(&"trades"), 1);
+        assert_eq!(subs.disconnect(&[second]), vec!["trades"]);
+    }
+
+    #[test]
+    fn test_acks() {
+        let mut acks = Acks::default();
+        let (first, mut first_rx) = oneshot::channel();
+        let (second, mut second_rx) = oneshot::channel();
+        let (third, mut third_rx) = oneshot::channel();
+
+        acks.sent(Op::Subscribe, "trades", Some(first));
+        assert!(acks.join(Op::Subscribe, &"trades", second).is_ok());
+        let third = acks.join(Op::Subscribe, &"books", third).unwrap_err();
+        acks.sent(Op::Subscribe, "books", Some(third));
+
+        acks.acked(Op::Subscribe, &"trades");
+        assert_eq!(first_rx.try_recv(), Ok(Ok(())));
+        assert_eq!(second_rx.try_recv(), Ok(Ok(())));
+
+        let error = SubscribeError::Exchange {
+            code: "60012".into(),
+            message: "Invalid request".into(),
+        };
+        assert_eq!(acks.rejected(error.clone()), Some((Op::Subscribe, "books")));
+        assert_eq!(third_rx.try_recv(), Ok(Err(error)));
+        assert!(acks.drain().is_empty());
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    iter::Sum,
+    ops::Neg,
+    str::FromStr,
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Most digits a [`Decimal`] keeps after the point
+pub const MAX_SCALE: u8 = 18;
+
+/// An exact decimal number, `mantissa * 10^-scale`. Prices and quantities are parsed into it
+/// straight from the exchange's text, so a level deleted by the same price it was added with
+/// always matches, sums don't drift and every number prints back the way it was sent, trailing
+/// zeros included.
+///
+/// Equality, ordering and hashing go by value, `1.50` and `1.5` are the same level. The mantissa
+/// stays within `-i64::MAX..=i64::MAX`, so negating one never overflows.
+#[derive(Clone, Copy, Default)]
+pub struct Decimal {
+    mantissa: i64,
+    scale: u8,
+}
+
+/// Why a string isn't a [`Decimal`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseDecimalError {
+    /// Not a decimal number
+    Invalid(String),
+    /// Too many digits for 64 bits, or more than [`MAX_SCALE`] after the point
+    OutOfRange(String),
+}
+
+impl fmt::Display for ParseDecimalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseDecimalError::Invalid(s) => write!(f, "{s:?} is not a decimal number"),
+            ParseDecimalError::OutOfRange(s) => write!(f, "{s:?} is out of the decimal range"),
+        }
+    }
+}
+
+impl std::error::Error for ParseDecimalError {}
+
+impl Decimal {
+    pub const ZERO: Self = Self {
+        mantissa: 0,
+        scale: 0,
+    };
+
+    /// `mantissa * 10^-scale`, `None` past [`MAX_SCALE`] or for `i64::MIN`
+    pub fn new(mantissa: i64, scale: u8) -> Option<Self> {
+        (scale <= MAX_SCALE && mantissa != i64::MIN).then_some(Self { mantissa, scale })
+    }
+
+    pub fn mantissa(&self) -> i64 {
+        self.mantissa
+    }
+
+    /// Digits after the point
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.mantissa == 0
+    }
+
+    pub fn is_sign_negative(&self) -> bool {
+        self.mantissa < 0
+    }
+
+    pub fn abs(self) -> Self {
+        Self {
+            mantissa: self.mantissa.abs(),
+            scale: self.scale,
+        }
+    }
+
+    /// The same value with `scale` digits after the point, rounded half away from zero when
+    /// digits are dropped. Books rescale prices to the tick size of their instrument. `None` past
+    /// [`MAX_SCALE`] or when the value no longer fits.
+    pub fn checked_rescale(self, scale: u8) -> Option<Self> {
+        if scale > MAX_SCALE {
+            return None;
+        }
+        let mantissa = match scale.cmp(&self.scale) {
+            Ordering::Equal => self.mantissa,
+            Ordering::Greater => self.mantissa.checked_mul(pow10(scale - self.scale))?,
+            Ordering::Less => {
+                let divisor = pow10(self.scale - scale);
+                let (quotient, remainder) = (self.mantissa / divisor, self.mantissa % divisor);
+                if remainder.abs() * 2 >= divisor {
+                    quotient + self.mantissa.signum()
+                } else {
+                    quotient
+                }
+            }
+        };
+        Some(Self { mantissa, scale })
+    }
+
+    /// The same value with `scale` digits after the point, without rounding. `None` if that would
+    /// drop a nonzero digit, past [`MAX_SCALE`] or when the value no longer fits.
+    pub fn rescale_exact(self, scale: u8) -> Option<Self> {
+        if scale < self.scale && self.mantissa % pow10(self.scale - scale) != 0 {
+            return None;
+        }
+        self.checked_rescale(scale)
+    }
+
+    /// `self + other` at the larger of the two scales, `None` on overflow
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let (a, b, scale) = self.aligned(other);
+        Self::new(i64::try_from(a + b).ok()?, scale)
+    }
+
+    /// `self - other` at the larger of the two scales, `None` on overflow
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let (a, b, scale) = self.aligned(other);
+        Self::new(i64::try_from(a - b).ok()?, scale)
+    }
+
+    /// The same value without trailing zeros
+    pub fn normalize(self) -> Self {
+        let Self {
+            mut mantissa,
+            mut scale,
+        } = self;
+        while scale > 0 && mantissa % 10 == 0 {
+            mantissa /= 10;
+            scale -= 1;
+        }
+        Self { mantissa, scale }
+    }
+
+    /// Closest `f64`, for consumers that want floats at the edge
+    pub fn to_f64(self) -> f64 {
+        self.mantissa as f64 / pow10(self.scale) as f64
+    }
+
+    /// Both mantissas at the larger of the two scales
+    fn aligned(self, other: Self) -> (i128, i128, u8) {
+        let scale = self.scale.max(other.scale);
+        let widen = |d: Self| d.mantissa as i128 * pow10(scale - d.scale) as i128;
+        (widen(self), widen(other), scale)
+    }
+}
+
+fn pow10(exponent: u8) -> i64 {
+    10i64.pow(exponent as u32)
+}
+
+impl FromStr for Decimal {
+    type Err = ParseDecimalError;
+
+    /// Plain (`-30460.10`) and scientific (`1.5e-7`) notation
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseDecimalError::Invalid(s.to_string());
+        let out_of_range = || ParseDecimalError::OutOfRange(s.to_string());
+
+        let (number, exponent) = match s.split_once(['e', 'E']) {
+            Some((number, exponent)) => (number, exponent.parse::<i32>().map_err(|_| invalid())?),
+            None => (s, 0),
+        };
+        // Past this even a single digit no longer fits, whatever the number
+        if exponent.unsigned_abs() > MAX_SCALE as u32 + 19 {
+            return Err(out_of_range());
+        }
+        let (negative, digits) = match number.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, number.strip_prefix('+').unwrap_or(number)),
+        };
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        if int.is_empty() && frac.is_empty() {
+            return Err(invalid());
+        }
+
+        let mut mantissa: i64 = 0;
+        for digit in int.bytes().chain(frac.bytes()) {
+            if !digit.is_ascii_digit() {
+                return Err(invalid());
+            }
+            mantissa = mantissa
+                .checked_mul(10)
+                .and_then(|m| m.checked_add((digit - b'0') as i64))
+                .ok_or_else(out_of_range)?;
+        }
+
+        let mut scale = i64::try_from(frac.len()).map_err(|_| out_of_range())? - exponent as i64;
+        while scale < 0 {
+            mantissa = mantissa.checked_mul(10).ok_or_else(out_of_range)?;
+            scale += 1;
+        }
+        if scale > MAX_SCALE as i64 {
+            return Err(out_of_range());
+        }
+        Ok(Self {
+            mantissa: if negative { -mantissa } else { mantissa },
+            scale: scale as u8,
+        })
+    }
+}
+
+/// All the digits of the scale, `30460.10` stays `30460.10`
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let divisor = pow10(self.scale).unsigned_abs();
+        let abs = self.mantissa.unsigned_abs();
+        if self.mantissa < 0 {
+            f.write_str("-")?;
+        }
+        write!(f, "{}", abs / divisor)?;
+        if self.scale > 0 {
+            write!(f, ".{:0width$}", abs % divisor, width = self.scale as usize)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        let (a, b, _) = self.aligned(*other);
+        a == b
+    }
+}
+
+impl Eq for Decimal {}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b, _) = self.aligned(*other);
+        a.cmp(&b)
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Of the normalized value, so equal decimals hash the same whatever their scale
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let Self { mantissa, scale } = self.normalize();
+        mantissa.hash(state);
+        scale.hash(state);
+    }
+}
+
+impl Neg for Decimal {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self {
+            mantissa: -self.mantissa,
+            scale: self.scale,
+        }
+    }
+}
+
+/// `None` when the total overflows
+impl Sum<Decimal> for Option<Decimal> {
+    fn sum<I: Iterator<Item = Decimal>>(mut iter: I) -> Self {
+        iter.try_fold(Decimal::ZERO, Decimal::checked_add)
+    }
+}
+
+impl From<i32> for Decimal {
+    fn from(value: i32) -> Self {
+        Self {
+            mantissa: value.into(),
+            scale: 0,
+        }
+    }
+}
+
+/// As a string, numbers keep their exact digits on the way to clients
+impl Serialize for Decimal {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// From a string, the way most exchanges send numbers, or from a JSON number. A JSON number has
+/// already been read as an `f64`, it comes back as the shortest decimal that reads as the same
+/// `f64`, which is what the exchange sent unless it had more than 15 significant digits.
+impl<'de> Deserialize<'de> for Decimal {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Decimal;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a decimal number or string")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Decimal, E> {
+                s.parse().map_err(E::custom)
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Decimal, E> {
+                Decimal::new(v, 0)
+                    .ok_or_else(|| E::custom(format!("{v} is out of the decimal range")))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Decimal, E> {
+                i64::try_from(v)
+                    .ok()
+                    .and_then(|v| Decimal::new(v, 0))
+                    .ok_or_else(|| E::custom(format!("{v} is out of the decimal range")))
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Decimal, E> {
+                if !v.is_finite() {
+                    return Err(E::custom(format!("{v} is not a decimal number")));
+                }
+                self.visit_str(&v.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn d(s: &str) -> Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for s in [
+            "30460.1",
+            "0.0010244",
+            "30460.10",
+            "-2.5",
+            "0",
+            "8476.98",
+            "1000",
+        ] {
+            assert_eq!(d(s).to_string(), s);
+        }
+        assert_eq!(d("1.5e-7").to_string(), "0.00000015");
+        assert_eq!(d("2E3").to_string(), "2000");
+        assert_eq!(d("+.5").to_string(), "0.5");
+        assert!("".parse::<Decimal>().is_err());
+        assert!("1.2.3".parse::<Decimal>().is_err());
+        assert!("99999999999999999999".parse::<Decimal>().is_err());
+
+        let json = serde_json::to_string(&d("30460.10")).unwrap();
+        assert_eq!(json, r#""30460.10""#);
+        assert_eq!(
+            serde_json::from_str::<Decimal>(&json).unwrap(),
+            d("30460.1")
+        );
+        assert_eq!(
+            serde_json::from_str::<Decimal>("0.1").unwrap().to_string(),
+            "0.1"
+        );
+        assert_eq!(serde_json::from_str::<Decimal>("-3").unwrap(), d("-3"));
+    }
+
+    #[test]
+    fn test_exact() {
+        assert_eq!(d("1.50"), d("1.5"));
+        assert!(d("0.1") < d("0.10001") && d("-1") < d("0"));
+
+        let mut levels = std::collections::HashMap::new();
+        levels.insert(d("100.10"), ());
+        assert!(levels.contains_key(&d("100.1")));
+
+        // Where 0.1 + 0.2 != 0.3 in floats
+        assert_eq!(d("0.1").checked_add(d("0.2")), Some(d("0.3")));
+        let total: Option<Decimal> = ["0.1"; 10].into_iter().map(d).sum();
+        assert_eq!(total, Some(Decimal::from(1)));
+        assert_eq!(d("1").checked_sub(d("0.25")), Some(d("0.75")));
+        assert_eq!(d("-0.5").abs(), d("0.5"));
+    }
+
+    #[test]
+    fn test_rescale() {
+        assert_eq!(
+            d("30460.1").checked_rescale(2).unwrap().to_string(),
+            "30460.10"
+        );
+        assert_eq!(d("0.125").checked_rescale(2).unwrap().to_string(), "0.13");
+        assert_eq!(d("-0.125").checked_rescale(2).unwrap().to_string(), "-0.13");
+        assert_eq!(d("0.124").checked_rescale(2).unwrap().to_string(), "0.12");
+        assert_eq!(d("5.000").normalize().to_string(), "5");
+        assert_eq!(d("30460.10").rescale_exact(1), Some(d("30460.1")));
+        assert_eq!(d("30460.15").rescale_exact(1), None);
+        assert_eq!(d("-2").rescale_exact(3).unwrap().to_string(), "-2.000");
+
+        // Exchange input out of range is refused rather than panicking
+        let max = Decimal::new(i64::MAX, 0).unwrap();
+        assert_eq!(max.checked_rescale(1), None);
+        assert_eq!(d("1").checked_rescale(MAX_SCALE + 1), None);
+        assert_eq!(max.checked_add(d("1")), None);
+        assert_eq!((-max).checked_sub(d("1")), None);
+        assert_eq!((-max).abs(), max);
+        assert_eq!(Decimal::new(1, MAX_SCALE + 1), None);
+        assert_eq!(Decimal::new(i64::MIN, 0), None);
+        assert!(serde_json::from_str::<Decimal>(&i64::MIN.to_string()).is_err());
+        for s in ["1e-2147483648", "0e2147483647", "1e38", "1e-38"] {
+            assert!(s.parse::<Decimal>().is_err(), "{s}");
+        }
+        assert_eq!(d("1e18").to_string(), "1000000000000000000");
+        assert_eq!(d("1e-18").to_string(), "0.000000000000000001");
+    }
+}
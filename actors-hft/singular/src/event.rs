@@ -1,7 +1,10 @@
-use std::fmt::Display;
+use std::{
+    fmt::{self, Display},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::models::{Exchange, Side};
-use serde::{Deserialize, Serialize};
+use crate::models::{normal::DataTypes, Decimal, Exchange, Side, Symbol};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
 // #[derive(Serialize, Deserialize)]
 // #[derive(Clone, Debug)]
@@ -16,11 +19,134 @@ use serde::{Deserialize, Serialize};
 //     }
 // }
 
-#[derive(Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Event {
     Trade(Trade),
     OrderbookUpdate(OrderbookUpdate),
     OrderbookSnapshot(OrderbookSnapshot),
+    /// The local book for `symbol` no longer matches the exchange. Drop it and wait for the next
+    /// snapshot.
+    Resync(Resync),
+    /// The adapter lost or regained its exchange connection
+    Connection(ConnectionStatus),
+    /// The subscriber fell behind and the stream was released, the last event it gets from it
+    Lagged(Lagged),
+}
+
+impl Event {
+    pub fn exchange(&self) -> Exchange {
+        match self {
+            Event::Trade(t) => t.exchange,
+            Event::OrderbookUpdate(u) => u.exchange,
+            Event::OrderbookSnapshot(s) => s.exchange,
+            Event::Resync(r) => r.exchange,
+            Event::Connection(c) => c.exchange,
+            Event::Lagged(l) => l.exchange,
+        }
+    }
+
+    pub fn symbol(&self) -> Symbol {
+        match self {
+            Event::Trade(t) => t.symbol,
+            Event::OrderbookUpdate(u) => u.symbol,
+            Event::OrderbookSnapshot(s) => s.symbol,
+            Event::Resync(r) => r.symbol,
+            Event::Connection(c) => c.symbol,
+            Event::Lagged(l) => l.symbol,
+        }
+    }
+
+    pub fn ts(&self) -> &Timestamps {
+        match self {
+            Event::Trade(t) => &t.ts,
+            Event::OrderbookUpdate(u) => &u.ts,
+            Event::OrderbookSnapshot(s) => &s.ts,
+            Event::Resync(r) => &r.ts,
+            Event::Connection(c) => &c.ts,
+            Event::Lagged(l) => &l.ts,
+        }
+    }
+
+    pub fn ts_mut(&mut self) -> &mut Timestamps {
+        match self {
+            Event::Trade(t) => &mut t.ts,
+            Event::OrderbookUpdate(u) => &mut u.ts,
+            Event::OrderbookSnapshot(s) => &mut s.ts,
+            Event::Resync(r) => &mut r.ts,
+            Event::Connection(c) => &mut c.ts,
+            Event::Lagged(l) => &mut l.ts,
+        }
+    }
+
+    pub fn data_type(&self) -> DataTypes {
+        match self {
+            Event::Trade(_) => DataTypes::Trade,
+            Event::OrderbookUpdate(_) | Event::OrderbookSnapshot(_) | Event::Resync(_) => {
+                DataTypes::Book
+            }
+            Event::Connection(c) => c.data_type,
+            Event::Lagged(l) => l.data_type,
+        }
+    }
+}
+
+/// Microseconds since the Unix epoch
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_micros() as u64)
+}
+
+/// Where an event was on its way from the exchange to its subscribers, in microseconds since the
+/// Unix epoch. `received` and `dispatched` are zero until the adapter stamps them.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamps {
+    /// When the exchange says it happened, `None` if it doesn't say
+    pub exchange: Option<u64>,
+    /// The frame carrying it was read off the socket, or the adapter raised it
+    pub received: u64,
+    /// Handed to the subscribers
+    pub dispatched: u64,
+}
+
+impl Timestamps {
+    /// Stamped by the exchange in milliseconds
+    pub fn millis(millis: u64) -> Self {
+        Self::micros(millis * 1_000)
+    }
+
+    /// Stamped by the exchange in microseconds
+    pub fn micros(micros: u64) -> Self {
+        Self {
+            exchange: Some(micros),
+            ..Self::default()
+        }
+    }
+
+    /// Stamped by the exchange in nanoseconds
+    pub fn nanos(nanos: u64) -> Self {
+        Self::micros(nanos / 1_000)
+    }
+
+    /// Stamped by the exchange with an RFC 3339 time, `2014-11-07T08:19:27.028459Z`
+    pub fn rfc3339(time: &str) -> Self {
+        let micros = chrono::DateTime::parse_from_rfc3339(time)
+            .ok()
+            .and_then(|time| u64::try_from(time.timestamp_micros()).ok());
+        Self {
+            exchange: micros,
+            ..Self::default()
+        }
+    }
+
+    /// Milliseconds from when the exchange says the event happened, or from when it was received
+    /// if the exchange doesn't say, until `now`. `None` if neither is known.
+    pub fn latency_ms(&self, now: u64) -> Option<f64> {
+        let since = self
+            .exchange
+            .or((self.received > 0).then_some(self.received))?;
+        Some((now as f64 - since as f64) / 1_000.0)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
@@ -36,36 +162,214 @@ impl Display for EventType {
         match self {
             EventType::Trade => write!(f, "Trade"),
             EventType::OrderbookUpdate => write!(f, "OrderbookUpdate"),
-            EventType::AdapterDisconnect => todo!(),
+            EventType::AdapterDisconnect => write!(f, "AdapterDisconnect"),
             EventType::OrderbookSnapshot => write!(f, "OrderbookSnapshot"),
         }
     }
 }
 
-// #[derive(Serialize, Deserialize)]
-#[derive(Clone, Debug)]
+/// A full picture of the book. Every level is carried as an [`OrderbookUpdate`] with
+/// `is_snapshot` set, so consumers can replace their book and then apply updates the same way.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct OrderbookSnapshot {
-    exchange: Exchange,
-    symbol: String,
-    // payload: [u8; 20000],
+    pub exchange: Exchange,
+    pub symbol: Symbol,
+    pub levels: Vec<OrderbookUpdate>,
+    /// Sequence number the exchange gave the book, for exchanges that number them
+    pub sequence: Option<u64>,
+    pub ts: Timestamps,
 }
 
-// #[derive(Serialize, Deserialize)]
-#[derive(Default, Clone, Debug)]
+/// A single price level change. A zero `quantity` removes the level.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct OrderbookUpdate {
-    exchange: Exchange,
-    symbol: String,
-    side: Side,
-    price: f64,
-    quantity: f64,
-    is_snapshot: bool,
+    pub exchange: Exchange,
+    pub symbol: Symbol,
+    pub side: Side,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    pub is_snapshot: bool,
+    /// Sequence number of the book push the level came in, for exchanges that number them
+    pub sequence: Option<u64>,
+    pub ts: Timestamps,
 }
 
 #[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct Trade {
     pub exchange: Exchange,
-    pub symbol: String,
+    pub symbol: Symbol,
     pub side: Side,
-    pub price: f64,
-    pub quantity: f64,
+    pub price: Decimal,
+    pub quantity: Decimal,
+    /// The exchange's id of the trade
+    pub trade_id: TradeId,
+    pub ts: Timestamps,
+}
+
+/// An exchange's id of a trade, kept inline so decoding a trade doesn't allocate. Most exchanges
+/// number their trades, the rest send ids of at most [`TradeId::CAPACITY`] bytes of text, a UUID
+/// at the longest.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TradeId {
+    Number(u64),
+    Text {
+        len: u8,
+        bytes: [u8; TradeId::CAPACITY],
+    },
+}
+
+impl TradeId {
+    /// Longest text id kept
+    pub const CAPACITY: usize = 36;
+
+    /// The id an exchange sent as text, a number when it reads back the same way. `None` when
+    /// it is longer than [`TradeId::CAPACITY`].
+    pub fn parse(id: &str) -> Option<Self> {
+        let number = id
+            .parse::<u64>()
+            .ok()
+            .filter(|_| id == "0" || !id.starts_with(['0', '+']));
+        if let Some(number) = number {
+            return Some(Self::Number(number));
+        }
+        let mut bytes = [0; Self::CAPACITY];
+        bytes.get_mut(..id.len())?.copy_from_slice(id.as_bytes());
+        Some(Self::Text {
+            len: id.len() as u8,
+            bytes,
+        })
+    }
+}
+
+impl Default for TradeId {
+    fn default() -> Self {
+        Self::Number(0)
+    }
+}
+
+impl From<u64> for TradeId {
+    fn from(id: u64) -> Self {
+        Self::Number(id)
+    }
+}
+
+impl PartialEq<&str> for TradeId {
+    fn eq(&self, other: &&str) -> bool {
+        Self::parse(other).is_some_and(|other| *self == other)
+    }
+}
+
+impl Display for TradeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TradeId::Number(id) => write!(f, "{id}"),
+            TradeId::Text { len, bytes } => {
+                // Copied whole out of a `&str`
+                f.write_str(std::str::from_utf8(&bytes[..*len as usize]).map_err(|_| fmt::Error)?)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for TradeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TradeId::Number(id) => fmt::Debug::fmt(id, f),
+            TradeId::Text { .. } => write!(f, "{:?}", self.to_string()),
+        }
+    }
+}
+
+/// As a string, whichever way the exchange sent it
+impl Serialize for TradeId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// From a JSON number or a string
+impl<'de> Deserialize<'de> for TradeId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = TradeId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a trade id")
+            }
+
+            fn visit_u64<E: de::Error>(self, id: u64) -> Result<TradeId, E> {
+                Ok(TradeId::Number(id))
+            }
+
+            fn visit_i64<E: de::Error>(self, id: i64) -> Result<TradeId, E> {
+                u64::try_from(id)
+                    .map(TradeId::Number)
+                    .map_err(|_| E::custom(format!("{id} is not a trade id")))
+            }
+
+            fn visit_str<E: de::Error>(self, id: &str) -> Result<TradeId, E> {
+                TradeId::parse(id)
+                    .ok_or_else(|| E::custom(format!("{id:?} is over {} bytes", TradeId::CAPACITY)))
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Resync {
+    pub exchange: Exchange,
+    pub symbol: Symbol,
+    pub reason: String,
+    pub ts: Timestamps,
+}
+
+/// Sent to every subscriber of a stream when its connection drops and again once it is back and
+/// the stream has been resubscribed
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct ConnectionStatus {
+    pub exchange: Exchange,
+    pub symbol: Symbol,
+    pub data_type: DataTypes,
+    pub connected: bool,
+    pub reason: String,
+    pub ts: Timestamps,
+}
+
+/// Sent to a subscriber whose buffer was full when an event of the stream arrived. Everything it
+/// missed is lost and the adapter no longer streams to it.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct Lagged {
+    pub exchange: Exchange,
+    pub symbol: Symbol,
+    pub data_type: DataTypes,
+    pub ts: Timestamps,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trade_id() {
+        let uuid = "20f43950-d8dd-5b31-9112-a178eb6023af";
+        for (json, id) in [
+            ("426790906", "426790906"),
+            (r#""48079254""#, "48079254"),
+            (r#""007""#, "007"),
+            (r#""5c24c5da03aa673885cd67aa""#, "5c24c5da03aa673885cd67aa"),
+            (&format!("{uuid:?}"), uuid),
+        ] {
+            let trade_id: TradeId = serde_json::from_str(json).unwrap();
+            assert_eq!(trade_id.to_string(), id);
+            assert_eq!(trade_id, id);
+            assert_eq!(serde_json::to_string(&trade_id).unwrap(), format!("{id:?}"));
+        }
+        assert_eq!(TradeId::parse("48079254"), Some(TradeId::Number(48079254)));
+        assert_eq!(TradeId::parse(&"x".repeat(TradeId::CAPACITY + 1)), None);
+        assert!(serde_json::from_str::<TradeId>("-1").is_err());
+    }
 }
@@ -1,95 +1,191 @@
+use std::{future::Future, pin::Pin, time::Duration};
+
 use crate::{
-    event::{Event, EventType, Trade},
-    models::{Exchange, Symbol},
+    adapters::{capabilities::Capabilities, subscriptions::SubscribeError},
+    event::{now_micros, Event, Lagged, Timestamps},
+    models::{normal::DataTypes, Exchange, Symbol},
 };
 use async_trait::async_trait;
-use futures_util::{
-    stream::{SplitSink, SplitStream},
-    SinkExt as _, StreamExt as _,
-};
-use std::{
-    collections::VecDeque,
-    net::TcpStream,
-    sync::{Arc, Mutex},
-};
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-pub type SocketStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
-#[async_trait(?Send)]
-pub trait Adapter {
-    /// Create an adapter with a provided exchange. Also generates a connection to the implemented
-    /// client;
-    async fn new(exchange: Exchange) -> Self
-    where
-        Self: Sized;
-
-    async fn subscribe_orderbook(&mut self, symbol: Symbol);
-    async fn subscribe_trade(&mut self, symbol: Symbol);
-    async fn subscribe_orderbook_snapshot(&mut self, symbol: Symbol);
-
-    // fn parse<T: Deserialize>(&self, buffer: &str) -> serde_json::Value {
-    //     serde_json::from_str(buffer).unwrap()
-    // }
-
-    /// Parse the buffer into a valid value in ['Event'] and push it onto the adapter's buffer
-    async fn parse(&mut self);
-
-    // fn run(&self);
-    fn connected(&self) -> bool;
-    fn buffer_lock(&mut self) -> &mut Arc<Mutex<VecDeque<String>>>;
-
-    async fn reconnect(&mut self) -> Result<(), ()>;
+use futures_util::{stream, Stream};
+use tokio::sync::{mpsc, watch};
+
+/// Events buffered for each stream before the adapter waits on its consumer
+pub const STREAM_CAPACITY: usize = 1024;
+
+/// How long a subscription waits on the exchange to answer
+pub const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Events of one subscription, in the order the exchange sent them. Ends once the subscription is
+/// released or the adapter shuts down.
+pub type EventStream = Pin<Box<dyn Stream<Item = Event> + Send>>;
+
+/// A subscription sent upstream, resolving with its stream once the exchange accepts it. Borrows
+/// nothing of its adapter, so whoever holds the adapter behind a lock can release it meanwhile.
+pub type Subscribing = Pin<Box<dyn Future<Output = Result<EventStream, SubscribeError>> + Send>>;
+
+/// Where an adapter's connection is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Dialling, for the first time or after the connection was lost
+    Connecting,
+    Connected,
+    /// The connection was lost for the given reason, the adapter dials again after backing off
+    Disconnected(String),
+    /// Shut down, the adapter won't connect again
+    Closed,
+}
+
+/// A market data connection to one exchange. Object safe and `Send`, so adapters of every exchange
+/// can sit behind a `Box<dyn Adapter>` and be driven from any task of the runtime.
+#[async_trait]
+pub trait Adapter: Send {
+    fn exchange(&self) -> Exchange;
+
+    /// What the adapter can stream, requests outside of it are rejected before going upstream
+    fn capabilities(&self) -> Capabilities;
+
+    /// Send the request to stream `data_type` for `symbol` without waiting on the exchange. Fails
+    /// only if it can't be sent, the exchange's answer comes through the [`Subscribing`].
+    async fn request(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<Subscribing, SubscribeError>;
+
+    /// Stream `data_type` for `symbol`. Resolves once the subscription is in place, or with the
+    /// exchange's reason it isn't.
+    async fn subscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<EventStream, SubscribeError> {
+        self.request(data_type, symbol).await?.await
+    }
+
+    /// Release every stream of `data_type` for `symbol` taken through this adapter, ending them
+    async fn unsubscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<(), SubscribeError>;
+
+    /// The connection state, updated as it changes
+    fn connection(&self) -> watch::Receiver<ConnectionState>;
+
+    /// Close the connection and end every stream. Subscribing afterwards fails with
+    /// [`SubscribeError::Closed`].
+    async fn shutdown(&mut self);
 }
 
-// #[cfg(test)]
-// mod test {
-//     use super::*;
-//
-//     struct MockAdapter {
-//         buffer: VecDeque<Event>,
-//     }
-//
-//     #[async_trait(?Send)]
-//     impl Adapter for MockAdapter {
-//         async fn new(exchange: Exchange) -> Self {
-//             MockAdapter {
-//                 buffer: VecDeque::new(),
-//             }
-//         }
-//
-//         async fn subscribe_orderbook(&mut self, symbol: Symbol) {}
-//
-//         async fn subscribe_trade(&mut self, symbol: Symbol) {}
-//
-//         async fn subscribe_orderbook_snapshot(&mut self, symbol: Symbol) {}
-//
-//         fn parse(&self, buffer: &str) {}
-//
-//         fn connected(&self) -> bool {
-//             true
-//         }
-//
-//         async fn reconnect(&mut self) -> Result<(), ()> {
-//             Ok(())
-//         }
-//         fn buffer(&mut self) -> &mut VecDeque<Event> {
-//             &mut self.buffer
-//         }
-//     }
-//
-//     #[test]
-//     fn test_send_trade() {
-//         // Create a mock adapter
-//         let mut adapter = MockAdapter {
-//             buffer: VecDeque::new(),
-//         };
-//
-//         // Create a sample event and call send_trade
-//         // let event = Trade::default();
-//         adapter.send(Event::Trade(Trade::default()));
-//
-//         // Assert that the event was pushed into the buffer
-//         assert_eq!(adapter.buffer().len(), 1);
-//
-//         dbg!(adapter.buffer);
-//     }
-// }
+/// [`EventStream`] of the events sent into `events`
+pub fn event_stream(events: mpsc::Receiver<Event>) -> EventStream {
+    Box::pin(stream::unfold(events, |mut events| async move {
+        events.recv().await.map(|event| (event, events))
+    }))
+}
+
+/// Tell a client that fell behind that its stream of `data_type` for `symbol` is released. Its
+/// buffer is full, so the [`Event::Lagged`] waits in a task until the client makes room for it or
+/// goes away.
+pub(crate) fn notify_lagged(
+    client: &mpsc::Sender<Event>,
+    exchange: Exchange,
+    symbol: Symbol,
+    data_type: DataTypes,
+) {
+    if client.is_closed() {
+        return;
+    }
+    let client = client.clone();
+    let lagged = Event::Lagged(Lagged {
+        exchange,
+        symbol,
+        data_type,
+        ts: Timestamps {
+            received: now_micros(),
+            ..Timestamps::default()
+        },
+    });
+    tokio::spawn(async move {
+        let _ = client.send(lagged).await;
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::StreamExt as _;
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        adapters::{actor::MyActorHandle, keepalive::KeepaliveConfig, okx::OkxProtocol},
+        mock::MockExchange,
+    };
+
+    async fn next(stream: &mut EventStream) -> Option<Event> {
+        time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("no event within 5s")
+    }
+
+    async fn adapter(exchange: &MockExchange) -> Box<dyn Adapter> {
+        let keepalive = KeepaliveConfig::for_exchange(Exchange::Okx);
+        let handle = MyActorHandle::with_protocol(OkxProtocol::new(exchange.url()), keepalive);
+        Box::new(handle)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_boxed_adapter() {
+        let exchange = MockExchange::start().await;
+        let mut adapter = adapter(&exchange).await;
+        assert_eq!(adapter.exchange(), Exchange::Okx);
+
+        // The adapter moves into another task of the multi-threaded runtime
+        let mut trades = tokio::spawn(async move {
+            let trades = adapter.subscribe(DataTypes::Trade, "BTC-USDT".into()).await;
+            (adapter, trades)
+        })
+        .await
+        .unwrap();
+        let mut stream = trades.1.unwrap();
+        assert_eq!(*trades.0.connection().borrow(), ConnectionState::Connected);
+
+        exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
+        let trade = next(&mut stream).await;
+        assert!(
+            matches!(&trade, Some(Event::Trade(t)) if t.symbol == "BTC-USDT"),
+            "{trade:?}"
+        );
+
+        trades
+            .0
+            .unsubscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+        assert!(next(&mut stream).await.is_none());
+        let ops = exchange.ops(2).await;
+        assert_eq!(ops[1]["op"], "unsubscribe");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let exchange = MockExchange::start().await;
+        let mut adapter = adapter(&exchange).await;
+        let mut connection = adapter.connection();
+        let mut stream = adapter
+            .subscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+
+        adapter.shutdown().await;
+        assert!(next(&mut stream).await.is_none());
+        connection
+            .wait_for(|state| *state == ConnectionState::Closed)
+            .await
+            .unwrap();
+        let subscribe = adapter.subscribe(DataTypes::Trade, "ETH-USDT".into()).await;
+        assert!(matches!(subscribe, Err(SubscribeError::Closed)));
+    }
+}
@@ -1,6 +1,10 @@
 pub mod adapters;
+pub mod decimal;
 pub mod event;
 pub mod interfaces;
+#[cfg(test)]
+pub mod mock;
 pub mod models;
+pub mod symbol;
 pub mod system;
 pub mod transmute;
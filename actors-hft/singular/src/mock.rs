@@ -0,0 +1,1605 @@
+//! In-process exchange that speaks the OKX public websocket protocol, so adapters can be tested
+//! without a network. It answers `ping`, acknowledges or rejects `subscribe` and `unsubscribe`,
+//! records every op it receives and streams whatever frames the test scripts.
+//!
+//! [`MockExchange::binance`] speaks the Binance combined stream protocol instead and serves the
+//! REST depth snapshot its books sync from, [`MockExchange::coinbase`] speaks the Coinbase
+//! Exchange feed, [`MockExchange::kraken`] the Kraken v2 websocket, [`MockExchange::bybit`] the
+//! Bybit v5 public stream, [`MockExchange::bitfinex`] the Bitfinex v2 websocket,
+//! [`MockExchange::huobi`] the Huobi market websocket, gzip-compressing everything it sends,
+//! [`MockExchange::deribit`] the Deribit JSON-RPC websocket, [`MockExchange::bitstamp`] the
+//! Bitstamp websocket, with the REST order book its books sync from, and [`MockExchange::kucoin`]
+//! the KuCoin websocket, handing out a new token for every connection.
+//!
+//! [`connect`], [`decoded`] and [`request`] are the scaffolding the adapter tests of every exchange share.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write as _,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use flate2::{write::GzEncoder, Compression};
+use futures_util::{SinkExt as _, StreamExt as _};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, Notify},
+    time,
+};
+use tokio_tungstenite::tungstenite::{
+    handshake::server::{Request, Response},
+    Message,
+};
+
+use crate::{
+    adapters::{
+        actor::{MyActorHandle, SocketRequest},
+        keepalive::KeepaliveConfig,
+        protocol::{Output, Protocol},
+    },
+    models::normal::DataTypes,
+    transmute::{
+        bitfinex::{self, BitfinexBook},
+        kraken::{self, KrakenBook},
+        okx::{BookRef, OkxBook},
+    },
+};
+
+/// How long [`MockExchange::ops`] waits for the adapter before failing the test
+const WAIT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flavour {
+    Okx,
+    Binance,
+    Coinbase,
+    Kraken,
+    Bybit,
+    Bitfinex,
+    Huobi,
+    Deribit,
+    Bitstamp,
+    Kucoin,
+}
+
+#[derive(Debug, Clone)]
+enum Script {
+    Push(String),
+    Disconnect,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    ops: Vec<Value>,
+    connections: usize,
+    /// Path and query every connection was opened with
+    dialled: Vec<String>,
+    /// KuCoin tokens handed out
+    tokens: usize,
+    /// Instruments every subscribe is rejected for
+    rejected: HashSet<String>,
+    /// Wait this long before answering ops and pings
+    delay: Duration,
+    /// Stop answering `ping`, to let keepalives time out
+    silent: bool,
+    /// Book and last seqId of every channel and instId pushed through [`MockExchange::book`] or
+    /// [`MockExchange::book_on`]
+    books: HashMap<(String, String), (OkxBook, i64)>,
+    /// Book of every Kraken pair pushed through [`MockExchange::kraken_book`]
+    kraken_books: HashMap<String, KrakenBook>,
+    /// `chanId` handed out for every Bitfinex channel and trading symbol
+    chan_ids: HashMap<(String, String), u64>,
+    /// Book of every Bitfinex symbol pushed through [`MockExchange::bitfinex_book`]
+    bitfinex_books: HashMap<String, BitfinexBook>,
+    /// REST depth snapshot of every Binance symbol and order book of every Bitstamp pair or KuCoin
+    /// symbol, `req` snapshot of every Huobi `mbp` topic
+    depths: HashMap<String, Value>,
+    /// Snapshots served
+    fetches: usize,
+    /// Bitstamp trades and tops of book pushed, their id and `microtimestamp` move forward with it
+    bitstamp_pushed: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MockExchange {
+    flavour: Flavour,
+    url: String,
+    /// Base of the REST endpoints, empty unless the flavour has any
+    rest_url: String,
+    state: Arc<Mutex<State>>,
+    received: Arc<Notify>,
+    script: broadcast::Sender<Script>,
+}
+
+impl MockExchange {
+    /// Listen on a free local port
+    pub async fn start() -> Self {
+        Self::listen(Flavour::Okx, String::new()).await
+    }
+
+    /// Listen as Binance, with the REST depth endpoint on a second port
+    pub async fn binance() -> Self {
+        Self::with_rest(Flavour::Binance, "/fapi/v1").await
+    }
+
+    /// Listen as Coinbase
+    pub async fn coinbase() -> Self {
+        Self::listen(Flavour::Coinbase, String::new()).await
+    }
+
+    /// Listen as Kraken
+    pub async fn kraken() -> Self {
+        Self::listen(Flavour::Kraken, String::new()).await
+    }
+
+    /// Listen as Bybit
+    pub async fn bybit() -> Self {
+        Self::listen(Flavour::Bybit, String::new()).await
+    }
+
+    /// Listen as Bitfinex
+    pub async fn bitfinex() -> Self {
+        Self::listen(Flavour::Bitfinex, String::new()).await
+    }
+
+    /// Listen as Huobi
+    pub async fn huobi() -> Self {
+        Self::listen(Flavour::Huobi, String::new()).await
+    }
+
+    /// Listen as Deribit
+    pub async fn deribit() -> Self {
+        Self::listen(Flavour::Deribit, String::new()).await
+    }
+
+    /// Listen as Bitstamp, with the REST order book endpoint on a second port
+    pub async fn bitstamp() -> Self {
+        Self::with_rest(Flavour::Bitstamp, "/api/v2").await
+    }
+
+    /// Listen as KuCoin, with `bullet-public` and the REST order book endpoint on a second port
+    pub async fn kucoin() -> Self {
+        Self::with_rest(Flavour::Kucoin, "/api/v1").await
+    }
+
+    /// Listen as `flavour` and serve its REST snapshots under `path` on a second port
+    async fn with_rest(flavour: Flavour, path: &str) -> Self {
+        let rest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rest_url = format!("http://{}{path}", rest.local_addr().unwrap());
+        let mock = Self::listen(flavour, rest_url).await;
+
+        let server = mock.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = rest.accept().await {
+                tokio::spawn(server.clone().serve_rest(stream));
+            }
+        });
+        mock
+    }
+
+    async fn listen(flavour: Flavour, rest_url: String) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (script, _) = broadcast::channel(1024);
+        let mock = Self {
+            flavour,
+            url,
+            rest_url,
+            state: Arc::default(),
+            received: Arc::default(),
+            script,
+        };
+
+        let server = mock.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                server.state.lock().unwrap().connections += 1;
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+        mock
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn rest_url(&self) -> &str {
+        &self.rest_url
+    }
+
+    /// Snapshots served so far
+    pub fn fetches(&self) -> usize {
+        self.state.lock().unwrap().fetches
+    }
+
+    /// Connections accepted so far
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections
+    }
+
+    /// Path and query of every connection opened so far
+    pub fn dialled(&self) -> Vec<String> {
+        self.state.lock().unwrap().dialled.clone()
+    }
+
+    /// Push a frame to every open connection
+    pub fn push(&self, frame: impl Into<String>) {
+        let _ = self.script.send(Script::Push(frame.into()));
+    }
+
+    /// Drop every open connection without a close frame
+    pub fn disconnect(&self) {
+        let _ = self.script.send(Script::Disconnect);
+    }
+
+    pub fn set_delay(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = delay;
+    }
+
+    /// Stop answering pings
+    pub fn silence(&self) {
+        self.state.lock().unwrap().silent = true;
+    }
+
+    /// Answer every subscribe for `inst_id`, a Binance, Bybit or Huobi symbol, Coinbase product
+    /// id, Kraken or Bitstamp pair, Bitfinex trading symbol, Deribit instrument or KuCoin symbol,
+    /// with an error
+    pub fn reject(&self, inst_id: &str) {
+        self.state.lock().unwrap().rejected.insert(inst_id.into());
+    }
+
+    /// Wait until at least `count` ops arrived and return all of them
+    pub async fn ops(&self, count: usize) -> Vec<Value> {
+        let wait = async {
+            loop {
+                let notified = self.received.notified();
+                {
+                    let state = self.state.lock().unwrap();
+                    if state.ops.len() >= count {
+                        return state.ops.clone();
+                    }
+                }
+                notified.await;
+            }
+        };
+        time::timeout(WAIT, wait)
+            .await
+            .unwrap_or_else(|_| panic!("expected {count} ops, got {:?}", self.recorded()))
+    }
+
+    /// Every op received so far
+    pub fn recorded(&self) -> Vec<Value> {
+        self.state.lock().unwrap().ops.clone()
+    }
+
+    /// Push a `trades` frame
+    pub fn trade(&self, inst_id: &str, px: &str, sz: &str, side: &str) {
+        self.push(trade_frame(inst_id, px, sz, side));
+    }
+
+    /// Push a `books` frame with correct sequence ids and checksum. A `snapshot` restarts the
+    /// book, an `update` applies to the last one pushed for `inst_id`.
+    pub fn book(&self, action: &str, inst_id: &str, asks: &[(&str, &str)], bids: &[(&str, &str)]) {
+        self.book_on("books", action, inst_id, asks, bids);
+    }
+
+    /// [`MockExchange::book`] on another checksummed depth channel, `books50-l2-tbt`
+    pub fn book_on(
+        &self,
+        channel: &str,
+        action: &str,
+        inst_id: &str,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let frame = {
+            let mut state = self.state.lock().unwrap();
+            let key = (channel.to_string(), inst_id.to_string());
+            let (book, last_seq) = state.books.entry(key).or_default();
+            let is_snapshot = action == "snapshot";
+            let prev = if is_snapshot { -1 } else { *last_seq };
+            *last_seq += 1;
+
+            let levels = |levels: &[(&str, &str)]| -> Vec<Value> {
+                levels
+                    .iter()
+                    .map(|(px, sz)| json!([px, sz, "0", "1"]))
+                    .collect()
+            };
+            let mut data = json!({
+                "asks": levels(asks),
+                "bids": levels(bids),
+                "ts": "1688085963425",
+                "seqId": *last_seq,
+                "prevSeqId": prev,
+            });
+            let raw = data.to_string();
+            let pushed: BookRef = serde_json::from_str(&raw).unwrap();
+            book.apply(&pushed, is_snapshot).unwrap();
+            data["checksum"] = json!(book.checksum());
+
+            json!({
+                "arg": {"channel": channel, "instId": inst_id},
+                "action": action,
+                "data": [data],
+            })
+            .to_string()
+        };
+        self.push(frame);
+    }
+
+    /// Serve the REST depth snapshot of `symbol` set with [`MockExchange::depth_snapshot`]
+    pub fn depth_snapshot(
+        &self,
+        symbol: &str,
+        last_update_id: i64,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let snapshot = json!({
+            "lastUpdateId": last_update_id,
+            "E": 1688085963425_i64,
+            "T": 1688085963420_i64,
+            "bids": price_levels(bids),
+            "asks": price_levels(asks),
+        });
+        let mut state = self.state.lock().unwrap();
+        state.depths.insert(symbol.to_string(), snapshot);
+    }
+
+    /// Push a Binance `depth@100ms` diff covering update ids `(U, u, pu)`
+    pub fn depth(
+        &self,
+        symbol: &str,
+        (first, last, prev): (i64, i64, i64),
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        self.push(combined(
+            symbol,
+            "depth@100ms",
+            json!({
+                "e": "depthUpdate",
+                "E": 1688085963425_i64,
+                "T": 1688085963420_i64,
+                "s": symbol,
+                "U": first,
+                "u": last,
+                "pu": prev,
+                "b": price_levels(bids),
+                "a": price_levels(asks),
+            }),
+        ));
+    }
+
+    /// Push a Binance `aggTrade`
+    pub fn agg_trade(&self, symbol: &str, price: &str, quantity: &str, buyer_is_maker: bool) {
+        self.push(combined(
+            symbol,
+            "aggTrade",
+            json!({
+                "e": "aggTrade",
+                "E": 1688085963425_i64,
+                "s": symbol,
+                "a": 1861244612,
+                "p": price,
+                "q": quantity,
+                "f": 3964584830_i64,
+                "l": 3964584831_i64,
+                "T": 1688085963420_i64,
+                "m": buyer_is_maker,
+            }),
+        ));
+    }
+
+    /// Push a Binance `bookTicker` with the best `(price, quantity)` on each side
+    pub fn book_ticker(&self, symbol: &str, bid: (&str, &str), ask: (&str, &str)) {
+        self.push(combined(
+            symbol,
+            "bookTicker",
+            json!({
+                "e": "bookTicker",
+                "u": 400900217,
+                "E": 1688085963425_i64,
+                "T": 1688085963420_i64,
+                "s": symbol,
+                "b": bid.0,
+                "B": bid.1,
+                "a": ask.0,
+                "A": ask.1,
+            }),
+        ));
+    }
+
+    /// Push a Coinbase `match`, `side` is the maker's
+    pub fn coinbase_match(&self, product_id: &str, price: &str, size: &str, side: &str) {
+        self.push(
+            json!({
+                "type": "match",
+                "trade_id": 10,
+                "sequence": 50,
+                "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+                "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1",
+                "time": "2014-11-07T08:19:27.028459Z",
+                "product_id": product_id,
+                "size": size,
+                "price": price,
+                "side": side,
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Coinbase `ticker` with the best `(price, size)` on each side
+    pub fn ticker(&self, product_id: &str, bid: (&str, &str), ask: (&str, &str)) {
+        self.push(
+            json!({
+                "type": "ticker",
+                "sequence": 51,
+                "product_id": product_id,
+                "price": bid.0,
+                "best_bid": bid.0,
+                "best_bid_size": bid.1,
+                "best_ask": ask.0,
+                "best_ask_size": ask.1,
+                "side": "sell",
+                "time": "2014-11-07T08:19:27.028459Z",
+                "trade_id": 10,
+                "last_size": "0.5",
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Coinbase `level2_batch` snapshot
+    pub fn level2_snapshot(&self, product_id: &str, asks: &[(&str, &str)], bids: &[(&str, &str)]) {
+        self.push(
+            json!({
+                "type": "snapshot",
+                "product_id": product_id,
+                "bids": price_levels(bids),
+                "asks": price_levels(asks),
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Coinbase `l2update` of `(side, price, size)` changes
+    pub fn level2_update(&self, product_id: &str, changes: &[(&str, &str, &str)]) {
+        let changes: Vec<Value> = changes
+            .iter()
+            .map(|(side, price, size)| json!([side, price, size]))
+            .collect();
+        self.push(
+            json!({
+                "type": "l2update",
+                "product_id": product_id,
+                "time": "2019-08-14T20:42:27.265Z",
+                "changes": changes,
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Bybit `publicTrade`, `side` is the taker's `Buy` or `Sell`
+    pub fn bybit_trade(&self, symbol: &str, price: &str, quantity: &str, side: &str) {
+        self.push(
+            json!({
+                "topic": format!("publicTrade.{symbol}"),
+                "type": "snapshot",
+                "ts": 1672304486868_i64,
+                "data": [{
+                    "T": 1672304486865_i64,
+                    "s": symbol,
+                    "S": side,
+                    "v": quantity,
+                    "p": price,
+                    "L": "PlusTick",
+                    "i": "20f43950-d8dd-5b31-9112-a178eb6023af",
+                    "BT": false,
+                }],
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Bybit linear `tickers` `snapshot` or `delta`. Empty prices and sizes are left out,
+    /// as Bybit does for fields that didn't change.
+    pub fn bybit_ticker(&self, kind: &str, symbol: &str, bid: (&str, &str), ask: (&str, &str)) {
+        let mut data = json!({"symbol": symbol, "lastPrice": bid.0});
+        let fields = [
+            ("bid1Price", bid.0),
+            ("bid1Size", bid.1),
+            ("ask1Price", ask.0),
+            ("ask1Size", ask.1),
+        ];
+        for (field, value) in fields.into_iter().filter(|(_, value)| !value.is_empty()) {
+            data[field] = value.into();
+        }
+        self.push(
+            json!({
+                "topic": format!("tickers.{symbol}"),
+                "type": kind,
+                "data": data,
+                "cs": 24987956059_i64,
+                "ts": 1673272861686_i64,
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Bybit `orderbook` `snapshot` or `delta` with update id `u`
+    pub fn orderbook(
+        &self,
+        topic: &str,
+        kind: &str,
+        u: i64,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let symbol = topic.rsplit('.').next().unwrap_or_default();
+        self.push(
+            json!({
+                "topic": topic,
+                "type": kind,
+                "ts": 1687940967466_i64,
+                "data": {
+                    "s": symbol,
+                    "b": price_levels(bids),
+                    "a": price_levels(asks),
+                    "u": u,
+                    "seq": 66544703342_i64 + u,
+                },
+                "cts": 1687940967464_i64,
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Kraken `trade`, `side` is the taker's
+    pub fn kraken_trade(&self, pair: &str, price: &str, qty: &str, side: &str) {
+        self.push(
+            json!({
+                "channel": "trade",
+                "type": "update",
+                "data": [{
+                    "symbol": pair,
+                    "side": side,
+                    "price": price.parse::<f64>().unwrap(),
+                    "qty": qty.parse::<f64>().unwrap(),
+                    "ord_type": "market",
+                    "trade_id": 4665906,
+                    "timestamp": "2023-09-25T07:49:37.708706Z",
+                }],
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Kraken `ticker` with the best `(price, qty)` on each side
+    pub fn kraken_ticker(&self, pair: &str, bid: (&str, &str), ask: (&str, &str)) {
+        let number = |text: &str| text.parse::<f64>().unwrap();
+        self.push(
+            json!({
+                "channel": "ticker",
+                "type": "update",
+                "data": [{
+                    "symbol": pair,
+                    "bid": number(bid.0),
+                    "bid_qty": number(bid.1),
+                    "ask": number(ask.0),
+                    "ask_qty": number(ask.1),
+                    "last": number(bid.0),
+                }],
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Kraken `book` frame with a correct checksum. A `snapshot` restarts the book, an
+    /// `update` applies to the last one pushed for `pair`. Prices and quantities go out exactly
+    /// as given, trailing zeros included.
+    pub fn kraken_book(
+        &self,
+        kind: &str,
+        pair: &str,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let levels = |levels: &[(&str, &str)]| {
+            let levels: Vec<String> = levels
+                .iter()
+                .map(|(price, qty)| format!(r#"{{"price":{price},"qty":{qty}}}"#))
+                .collect();
+            format!("[{}]", levels.join(","))
+        };
+        let data = |checksum| {
+            format!(
+                r#"{{"symbol":"{pair}","bids":{},"asks":{},"checksum":{checksum}}}"#,
+                levels(bids),
+                levels(asks)
+            )
+        };
+
+        let checksum = {
+            let mut state = self.state.lock().unwrap();
+            let book = state
+                .kraken_books
+                .entry(pair.to_string())
+                .or_insert_with(|| KrakenBook::new(kraken::DEPTH_LIMIT));
+            let raw: kraken::BookRaw = serde_json::from_str(&data(0)).unwrap();
+            book.apply(&raw, kind == "snapshot");
+            book.checksum()
+        };
+        self.push(format!(
+            r#"{{"channel":"book","type":"{kind}","data":[{}]}}"#,
+            data(checksum)
+        ));
+    }
+
+    /// `chanId` of the last Bitfinex subscribe to `channel` for `symbol`
+    pub fn chan_id(&self, channel: &str, symbol: &str) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.chan_ids[&(channel.to_string(), symbol.to_string())]
+    }
+
+    /// Push a Bitfinex `te` or `tu` trade, `amount` is negative when the taker sold
+    pub fn bitfinex_trade(&self, symbol: &str, kind: &str, id: i64, amount: &str, price: &str) {
+        let chan_id = self.chan_id("trades", symbol);
+        self.push(format!(
+            r#"[{chan_id},"{kind}",[{id},1574694478808,{amount},{price}]]"#
+        ));
+    }
+
+    /// Push a Bitfinex `ticker` with the best `(price, amount)` on each side
+    pub fn bitfinex_ticker(&self, symbol: &str, bid: (&str, &str), ask: (&str, &str)) {
+        let chan_id = self.chan_id("ticker", symbol);
+        self.push(format!(
+            "[{chan_id},[{},{},{},{},-8.7,-0.0012,{},1430.5,7440.6,7061]]",
+            bid.0, bid.1, ask.0, ask.1, bid.0
+        ));
+    }
+
+    /// Push `[price, count, amount]` levels of a Bitfinex `book`, the whole book when `snapshot`
+    /// and a single update per level otherwise, each followed by its `cs` checksum
+    pub fn bitfinex_book(&self, symbol: &str, snapshot: bool, levels: &[(&str, &str, &str)]) {
+        let chan_id = self.chan_id("book", symbol);
+        let levels: Vec<String> = levels
+            .iter()
+            .map(|(price, count, amount)| format!("[{price},{count},{amount}]"))
+            .collect();
+        let updates = match snapshot {
+            true => vec![format!("[{}]", levels.join(","))],
+            false => levels,
+        };
+
+        for data in updates {
+            let checksum = {
+                let mut state = self.state.lock().unwrap();
+                let book = state.bitfinex_books.entry(symbol.to_string()).or_default();
+                let raw: bitfinex::BookRaw = serde_json::from_str(&data).unwrap();
+                book.apply(&raw);
+                book.checksum()
+            };
+            self.push(format!("[{chan_id},{data}]"));
+            self.push(format!(r#"[{chan_id},"cs",{checksum}]"#));
+        }
+    }
+
+    /// Push a Huobi `trade.detail` with a single trade, `direction` is the taker's
+    pub fn huobi_trade(&self, symbol: &str, price: &str, amount: &str, direction: &str) {
+        self.push(
+            json!({
+                "ch": format!("market.{symbol}.trade.detail"),
+                "ts": 1630994963175_i64,
+                "tick": {
+                    "id": 137005445109_i64,
+                    "ts": 1630994963173_i64,
+                    "data": [{
+                        "id": 1.3700544510935929e26,
+                        "ts": 1630994963173_i64,
+                        "tradeId": 102523573486_i64,
+                        "amount": amount.parse::<f64>().unwrap(),
+                        "price": price.parse::<f64>().unwrap(),
+                        "direction": direction,
+                    }],
+                },
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Huobi `bbo` with the best `(price, size)` on each side
+    pub fn huobi_bbo(&self, symbol: &str, bid: (&str, &str), ask: (&str, &str)) {
+        let number = |text: &str| text.parse::<f64>().unwrap();
+        self.push(
+            json!({
+                "ch": format!("market.{symbol}.bbo"),
+                "ts": 1630994963175_i64,
+                "tick": {
+                    "seqId": 103273695595_i64,
+                    "ask": number(ask.0),
+                    "askSize": number(ask.1),
+                    "bid": number(bid.0),
+                    "bidSize": number(bid.1),
+                    "quoteTime": 1630994963173_i64,
+                    "symbol": symbol,
+                },
+            })
+            .to_string(),
+        );
+    }
+
+    /// Serve the `req` snapshot of a Huobi `mbp` topic at `seq_num`
+    pub fn mbp_snapshot(
+        &self,
+        topic: &str,
+        seq_num: i64,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let snapshot = json!({
+            "seqNum": seq_num,
+            "bids": number_levels(bids),
+            "asks": number_levels(asks),
+        });
+        let mut state = self.state.lock().unwrap();
+        state.depths.insert(topic.to_string(), snapshot);
+    }
+
+    /// Push a Huobi `mbp` update `(seqNum, prevSeqNum)`
+    pub fn mbp(
+        &self,
+        topic: &str,
+        (seq_num, prev_seq_num): (i64, i64),
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        self.push(
+            json!({
+                "ch": topic,
+                "ts": 1573199608679_i64,
+                "tick": {
+                    "seqNum": seq_num,
+                    "prevSeqNum": prev_seq_num,
+                    "bids": number_levels(bids),
+                    "asks": number_levels(asks),
+                },
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Deribit `heartbeat` asking for a `public/test` call
+    pub fn test_request(&self) {
+        self.push(
+            json!({
+                "jsonrpc": "2.0",
+                "method": "heartbeat",
+                "params": {"type": "test_request"},
+            })
+            .to_string(),
+        );
+    }
+
+    /// Push a Deribit `trades` notification with a single trade, `direction` is the taker's
+    pub fn deribit_trade(&self, instrument: &str, price: &str, amount: &str, direction: &str) {
+        let data = json!([{
+            "trade_seq": 30289432,
+            "trade_id": "48079254",
+            "timestamp": 1590484156350_i64,
+            "tick_direction": 0,
+            "price": price.parse::<f64>().unwrap(),
+            "mark_price": 8895.19,
+            "instrument_name": instrument,
+            "index_price": 8894.98,
+            "direction": direction,
+            "amount": amount.parse::<f64>().unwrap(),
+        }]);
+        self.push(subscription(&format!("trades.{instrument}.100ms"), data));
+    }
+
+    /// Push a Deribit `quote` with the best `(price, amount)` on each side
+    pub fn deribit_quote(&self, instrument: &str, bid: (&str, &str), ask: (&str, &str)) {
+        let number = |text: &str| text.parse::<f64>().unwrap();
+        let data = json!({
+            "timestamp": 1550658624149_i64,
+            "instrument_name": instrument,
+            "best_bid_price": number(bid.0),
+            "best_bid_amount": number(bid.1),
+            "best_ask_price": number(ask.0),
+            "best_ask_amount": number(ask.1),
+        });
+        self.push(subscription(&format!("quote.{instrument}"), data));
+    }
+
+    /// Push a Deribit `book` notification `(change_id, prev_change_id)` of `(action, price,
+    /// amount)` levels, a `snapshot` when there is no previous change
+    pub fn deribit_book(
+        &self,
+        instrument: &str,
+        (change_id, prev_change_id): (i64, Option<i64>),
+        asks: &[(&str, &str, &str)],
+        bids: &[(&str, &str, &str)],
+    ) {
+        let levels = |levels: &[(&str, &str, &str)]| -> Vec<Value> {
+            levels
+                .iter()
+                .map(|(action, price, amount)| {
+                    json!([
+                        action,
+                        price.parse::<f64>().unwrap(),
+                        amount.parse::<f64>().unwrap()
+                    ])
+                })
+                .collect()
+        };
+        let mut data = json!({
+            "type": if prev_change_id.is_some() { "change" } else { "snapshot" },
+            "timestamp": 1554375447971_i64,
+            "instrument_name": instrument,
+            "change_id": change_id,
+            "bids": levels(bids),
+            "asks": levels(asks),
+        });
+        if let Some(prev_change_id) = prev_change_id {
+            data["prev_change_id"] = prev_change_id.into();
+        }
+        self.push(subscription(&format!("book.{instrument}.100ms"), data));
+    }
+
+    /// Push a Bitstamp `trade` on `live_trades`, `kind` is `0` when the taker bought
+    pub fn bitstamp_trade(&self, pair: &str, price: &str, amount: &str, kind: u8) {
+        let pushed = self.bitstamp_pushed();
+        let data = json!({
+            "id": 288436146 + pushed,
+            "timestamp": "1688085963",
+            "amount": amount.parse::<f64>().unwrap(),
+            "amount_str": amount,
+            "price": price.parse::<f64>().unwrap(),
+            "price_str": price,
+            "type": kind,
+            "microtimestamp": (1688085963425000 + pushed).to_string(),
+            "buy_order_id": 1636781578252288_i64,
+            "sell_order_id": 1636781579808768_i64,
+        });
+        self.push(bitstamp_frame(
+            "trade",
+            &format!("live_trades_{pair}"),
+            data,
+        ));
+    }
+
+    /// Push a Bitstamp `order_book`, the top of the book
+    pub fn bitstamp_order_book(&self, pair: &str, asks: &[(&str, &str)], bids: &[(&str, &str)]) {
+        let pushed = self.bitstamp_pushed();
+        let data = json!({
+            "timestamp": "1688085963",
+            "microtimestamp": (1688085963425000 + pushed).to_string(),
+            "bids": price_levels(bids),
+            "asks": price_levels(asks),
+        });
+        self.push(bitstamp_frame("data", &format!("order_book_{pair}"), data));
+    }
+
+    fn bitstamp_pushed(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.bitstamp_pushed += 1;
+        state.bitstamp_pushed
+    }
+
+    /// Serve the REST order book of a Bitstamp pair at `microtimestamp`
+    pub fn bitstamp_snapshot(
+        &self,
+        pair: &str,
+        microtimestamp: i64,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let snapshot = json!({
+            "timestamp": (microtimestamp / 1_000_000).to_string(),
+            "microtimestamp": microtimestamp.to_string(),
+            "bids": price_levels(bids),
+            "asks": price_levels(asks),
+        });
+        let mut state = self.state.lock().unwrap();
+        state.depths.insert(pair.to_string(), snapshot);
+    }
+
+    /// Push a Bitstamp `diff_order_book` at `microtimestamp`
+    pub fn bitstamp_diff(
+        &self,
+        pair: &str,
+        microtimestamp: i64,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let data = json!({
+            "timestamp": (microtimestamp / 1_000_000).to_string(),
+            "microtimestamp": microtimestamp.to_string(),
+            "bids": price_levels(bids),
+            "asks": price_levels(asks),
+        });
+        self.push(bitstamp_frame(
+            "data",
+            &format!("diff_order_book_{pair}"),
+            data,
+        ));
+    }
+
+    /// Push a KuCoin `/market/match`, `side` is the taker's
+    pub fn kucoin_match(&self, symbol: &str, price: &str, size: &str, side: &str) {
+        let data = json!({
+            "sequence": "1545896669145",
+            "type": "match",
+            "symbol": symbol,
+            "side": side,
+            "price": price,
+            "size": size,
+            "tradeId": "5c24c5da03aa673885cd67aa",
+            "takerOrderId": "5c24c5d903aa6772d55b371e",
+            "makerOrderId": "5c2187d003aa677bd09d5c93",
+            "time": "1545913818099033203",
+        });
+        self.push(kucoin_message(
+            &format!("/market/match:{symbol}"),
+            "trade.l3match",
+            data,
+        ));
+    }
+
+    /// Push a KuCoin `/market/ticker` with the best `(price, size)` on each side
+    pub fn kucoin_ticker(&self, symbol: &str, bid: (&str, &str), ask: (&str, &str)) {
+        let data = json!({
+            "sequence": "1545896668986",
+            "price": ask.0,
+            "size": "0.00001",
+            "bestAsk": ask.0,
+            "bestAskSize": ask.1,
+            "bestBid": bid.0,
+            "bestBidSize": bid.1,
+            "time": 1545913818099_i64,
+        });
+        self.push(kucoin_message(
+            &format!("/market/ticker:{symbol}"),
+            "trade.ticker",
+            data,
+        ));
+    }
+
+    /// Serve the REST order book of a KuCoin symbol at `sequence`
+    pub fn kucoin_snapshot(
+        &self,
+        symbol: &str,
+        sequence: i64,
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let snapshot = json!({
+            "code": "200000",
+            "data": {
+                "sequence": sequence.to_string(),
+                "time": 1663747970273_i64,
+                "bids": price_levels(bids),
+                "asks": price_levels(asks),
+            },
+        });
+        let mut state = self.state.lock().unwrap();
+        state.depths.insert(symbol.to_string(), snapshot);
+    }
+
+    /// Push a KuCoin `/market/level2` update covering `(sequenceStart, sequenceEnd)`. Changes take
+    /// the sequences from `sequenceStart` on, asks first.
+    pub fn kucoin_level2(
+        &self,
+        symbol: &str,
+        (start, end): (i64, i64),
+        asks: &[(&str, &str)],
+        bids: &[(&str, &str)],
+    ) {
+        let mut sequence = start..;
+        let mut changes = |levels: &[(&str, &str)]| -> Vec<Value> {
+            levels
+                .iter()
+                .map(|(price, size)| {
+                    let sequence = sequence.next().unwrap().min(end);
+                    json!([price, size, sequence.to_string()])
+                })
+                .collect()
+        };
+        let data = json!({
+            "changes": {"asks": changes(asks), "bids": changes(bids)},
+            "sequenceStart": start,
+            "sequenceEnd": end,
+            "symbol": symbol,
+            "time": 1663747970273_i64,
+        });
+        self.push(kucoin_message(
+            &format!("/market/level2:{symbol}"),
+            "trade.l2update",
+            data,
+        ));
+    }
+
+    /// Huobi compresses every frame it sends
+    fn message(&self, frame: String) -> Message {
+        match self.flavour {
+            Flavour::Huobi => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(frame.as_bytes()).unwrap();
+                Message::Binary(encoder.finish().unwrap())
+            }
+            _ => Message::Text(frame),
+        }
+    }
+
+    async fn serve(self, stream: TcpStream) {
+        let state = self.state.clone();
+        // tungstenite picks the callback's error type, a whole HTTP response
+        #[allow(clippy::result_large_err)]
+        let record = move |request: &Request, response: Response| {
+            let path = request.uri().to_string();
+            state.lock().unwrap().dialled.push(path);
+            Ok(response)
+        };
+        let Ok(mut ws) = tokio_tungstenite::accept_hdr_async(stream, record).await else {
+            return;
+        };
+        let mut script = self.script.subscribe();
+
+        if self.flavour == Flavour::Kucoin {
+            let welcome = json!({"id": "hQvf8jkno", "type": "welcome"}).to_string();
+            if ws.send(self.message(welcome)).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            tokio::select! {
+                frame = ws.next() => {
+                    let text = match frame {
+                        Some(Ok(Message::Text(text))) => text,
+                        // tungstenite answers pings itself
+                        Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                        _ => break,
+                    };
+                    for answer in self.answer(&text) {
+                        let delay = self.state.lock().unwrap().delay;
+                        time::sleep(delay).await;
+                        if ws.send(self.message(answer)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+                script = script.recv() => match script {
+                    Ok(Script::Push(frame)) => {
+                        if ws.send(self.message(frame)).await.is_err() {
+                            return;
+                        }
+                    }
+                    // Dropping the socket resets the connection
+                    Ok(Script::Disconnect) | Err(_) => return,
+                },
+            }
+        }
+    }
+
+    /// Record an incoming frame and build the exchange's answers to it
+    fn answer(&self, text: &str) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        if self.flavour == Flavour::Okx && text == "ping" {
+            return match state.silent {
+                true => Vec::new(),
+                false => vec!["pong".into()],
+            };
+        }
+
+        let Ok(op) = serde_json::from_str::<Value>(text) else {
+            return vec![match self.flavour {
+                Flavour::Okx => error_frame("60012", &format!("Invalid request: {text}")),
+                Flavour::Binance => json!({"code": 3, "msg": "Invalid JSON"}).to_string(),
+                Flavour::Coinbase => {
+                    json!({"type": "error", "message": "Failed to parse"}).to_string()
+                }
+                Flavour::Kraken => {
+                    json!({"error": "Malformed request", "success": false}).to_string()
+                }
+                Flavour::Bybit => {
+                    json!({"success": false, "ret_msg": "Invalid request", "op": ""}).to_string()
+                }
+                Flavour::Bitfinex => {
+                    json!({"event": "error", "msg": "Invalid request", "code": 10000}).to_string()
+                }
+                Flavour::Huobi => json!({
+                    "status": "error",
+                    "err-code": "bad-request",
+                    "err-msg": "invalid json",
+                })
+                .to_string(),
+                Flavour::Deribit => json!({
+                    "jsonrpc": "2.0",
+                    "error": {"code": -32700, "message": "parse_error"},
+                })
+                .to_string(),
+                Flavour::Bitstamp => bitstamp_frame(
+                    "bts:error",
+                    "",
+                    json!({"code": null, "message": "Bad request"}),
+                ),
+                Flavour::Kucoin => {
+                    json!({"type": "error", "code": 400, "data": "Invalid request"}).to_string()
+                }
+            }];
+        };
+        state.ops.push(op.clone());
+        self.received.notify_waiters();
+
+        match self.flavour {
+            Flavour::Okx => okx_answers(&state, &op),
+            Flavour::Binance => vec![binance_answer(&state, &op)],
+            Flavour::Coinbase => vec![coinbase_answer(&state, &op)],
+            Flavour::Kraken => vec![kraken_answer(&state, &op)],
+            Flavour::Bybit => vec![bybit_answer(&state, &op)],
+            Flavour::Bitfinex => bitfinex_answer(&mut state, &op).into_iter().collect(),
+            Flavour::Huobi => huobi_answer(&mut state, &op).into_iter().collect(),
+            Flavour::Deribit => vec![deribit_answer(&state, &op)],
+            Flavour::Bitstamp => bitstamp_answer(&state, &op).into_iter().collect(),
+            Flavour::Kucoin => kucoin_answer(&state, &op).into_iter().collect(),
+        }
+    }
+}
+
+/// One answer per arg of an OKX `op`, with the op's `id` if it had one
+fn okx_answers(state: &State, op: &Value) -> Vec<String> {
+    let event = op["op"].as_str().unwrap_or_default().to_string();
+    let args = op["args"].as_array().cloned().unwrap_or_default();
+    args.into_iter()
+        .map(|arg| {
+            let inst_id = arg["instId"].as_str().unwrap_or_default();
+            let mut answer = if event == "subscribe" && state.rejected.contains(inst_id) {
+                let channel = arg["channel"].as_str().unwrap_or_default();
+                json!({
+                    "event": "error",
+                    "code": "60018",
+                    "msg": format!("Wrong URL or channel:{channel},instId:{inst_id} doesn't exist."),
+                })
+            } else {
+                json!({"event": event, "arg": arg})
+            };
+            if let Some(id) = op.get("id") {
+                answer["id"] = id.clone();
+            }
+            answer.to_string()
+        })
+        .collect()
+}
+
+fn binance_answer(state: &State, op: &Value) -> String {
+    let rejected = op["method"] == "SUBSCRIBE"
+        && op["params"].as_array().into_iter().flatten().any(|stream| {
+            let stream = stream.as_str().unwrap_or_default();
+            let symbol = stream.split('@').next().unwrap_or_default();
+            state.rejected.contains(&symbol.to_uppercase())
+        });
+    match rejected {
+        true => json!({"error": {"code": -1121, "msg": "Invalid symbol."}, "id": op["id"]}),
+        false => json!({"result": null, "id": op["id"]}),
+    }
+    .to_string()
+}
+
+fn coinbase_answer(state: &State, op: &Value) -> String {
+    let product_ids = op["product_ids"].as_array().cloned().unwrap_or_default();
+    let rejected = product_ids
+        .iter()
+        .filter_map(Value::as_str)
+        .find(|product_id| state.rejected.contains(*product_id));
+    match rejected {
+        Some(product_id) if op["type"] == "subscribe" => json!({
+            "type": "error",
+            "message": "Failed to subscribe",
+            "reason": format!("{product_id} is not a valid product"),
+        }),
+        _ => {
+            let channels: Vec<Value> = op["channels"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .map(|name| json!({"name": name, "product_ids": product_ids}))
+                .collect();
+            json!({"type": "subscriptions", "channels": channels})
+        }
+    }
+    .to_string()
+}
+
+fn kraken_answer(state: &State, op: &Value) -> String {
+    let method = &op["method"];
+    let params = &op["params"];
+    let pair = params["symbol"][0].as_str().unwrap_or_default();
+    if method == "ping" {
+        return json!({"method": "pong", "req_id": op["req_id"]}).to_string();
+    }
+    if method == "subscribe" && state.rejected.contains(pair) {
+        return json!({
+            "method": method,
+            "error": format!("Currency pair not supported {pair}"),
+            "success": false,
+            "symbol": pair,
+            "req_id": op["req_id"],
+        })
+        .to_string();
+    }
+    json!({
+        "method": method,
+        "result": {"channel": params["channel"], "symbol": pair},
+        "success": true,
+        "req_id": op["req_id"],
+    })
+    .to_string()
+}
+
+fn bybit_answer(state: &State, op: &Value) -> String {
+    let topic = op["args"][0].as_str().unwrap_or_default();
+    let symbol = topic.rsplit('.').next().unwrap_or_default();
+    if op["op"] == "ping" {
+        return json!({"req_id": op["req_id"], "op": "pong", "args": ["1675418560633"]})
+            .to_string();
+    }
+    let rejected = op["op"] == "subscribe" && state.rejected.contains(symbol);
+    json!({
+        "success": !rejected,
+        "ret_msg": if rejected { format!("error:handler not found,topic:{topic}") } else { String::new() },
+        "conn_id": "cejreaspqfh3sjdnldmg-p",
+        "req_id": op["req_id"],
+        "op": op["op"],
+    })
+    .to_string()
+}
+
+/// Bitfinex hands out a new `chanId` for every subscribe. Silent mocks leave pings unanswered.
+fn bitfinex_answer(state: &mut State, op: &Value) -> Option<String> {
+    let answer = match op["event"].as_str().unwrap_or_default() {
+        "ping" if state.silent => return None,
+        "ping" => json!({"event": "pong", "ts": 1511545528111_i64, "cid": op["cid"]}),
+        "conf" => json!({"event": "conf", "status": "OK", "flags": op["flags"]}),
+        "subscribe" => {
+            let channel = op["channel"].as_str().unwrap_or_default().to_string();
+            let symbol = op["symbol"].as_str().unwrap_or_default().to_string();
+            if state.rejected.contains(&symbol) {
+                let mut error = op.clone();
+                error["event"] = "error".into();
+                error["msg"] = "symbol: invalid".into();
+                error["code"] = 10300.into();
+                return Some(error.to_string());
+            }
+            let chan_id = 17000 + state.ops.len() as u64;
+            state.chan_ids.insert((channel, symbol.clone()), chan_id);
+            let mut subscribed = op.clone();
+            subscribed["event"] = "subscribed".into();
+            subscribed["chanId"] = chan_id.into();
+            subscribed["pair"] = bitfinex::pair(&symbol).into();
+            subscribed
+        }
+        "unsubscribe" => json!({"event": "unsubscribed", "status": "OK", "chanId": op["chanId"]}),
+        _ => json!({"event": "error", "msg": "Unknown event", "code": 10000}),
+    };
+    Some(answer.to_string())
+}
+
+/// Huobi answers `sub`, `unsub` and `req` by `id` and takes `pong`s without a word
+fn huobi_answer(state: &mut State, op: &Value) -> Option<String> {
+    let id = &op["id"];
+    let answer = if op.get("pong").is_some() {
+        return None;
+    } else if let Some(topic) = op["sub"].as_str() {
+        let symbol = topic.split('.').nth(1).unwrap_or_default();
+        match state.rejected.contains(symbol) {
+            true => json!({
+                "id": id,
+                "status": "error",
+                "err-code": "bad-request",
+                "err-msg": format!("invalid topic {topic}"),
+                "ts": 1494301904959_i64,
+            }),
+            false => json!({"id": id, "status": "ok", "subbed": topic, "ts": 1489474081631_i64}),
+        }
+    } else if let Some(topic) = op["unsub"].as_str() {
+        json!({"id": id, "status": "ok", "unsubbed": topic, "ts": 1494326028889_i64})
+    } else if let Some(topic) = op["req"].as_str() {
+        state.fetches += 1;
+        match state.depths.get(topic) {
+            Some(snapshot) => json!({"id": id, "rep": topic, "status": "ok", "data": snapshot}),
+            None => json!({
+                "id": id,
+                "status": "error",
+                "err-code": "bad-request",
+                "err-msg": format!("invalid topic {topic}"),
+            }),
+        }
+    } else {
+        json!({"id": id, "status": "error", "err-code": "bad-request", "err-msg": "unknown op"})
+    };
+    Some(answer.to_string())
+}
+
+/// Deribit leaves instruments it doesn't know out of the channels a subscribe returns
+fn deribit_answer(state: &State, op: &Value) -> String {
+    let result = match op["method"].as_str().unwrap_or_default() {
+        "public/set_heartbeat" => json!("ok"),
+        "public/test" => json!({"version": "1.2.26"}),
+        "public/subscribe" => {
+            let channels: Vec<Value> = op["params"]["channels"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|channel| {
+                    let channel = channel.as_str().unwrap_or_default();
+                    let instrument = channel.split('.').nth(1).unwrap_or_default();
+                    !state.rejected.contains(instrument)
+                })
+                .cloned()
+                .collect();
+            json!(channels)
+        }
+        "public/unsubscribe" => op["params"]["channels"].clone(),
+        method => {
+            return json!({
+                "jsonrpc": "2.0",
+                "id": op["id"],
+                "error": {"code": -32601, "message": format!("Method not found {method}")},
+            })
+            .to_string()
+        }
+    };
+    json!({
+        "jsonrpc": "2.0",
+        "id": op["id"],
+        "result": result,
+        "usIn": 1535043730126248_i64,
+        "usOut": 1535043730126250_i64,
+        "usDiff": 2,
+    })
+    .to_string()
+}
+
+/// Bitstamp answers by channel. Silent mocks leave heartbeats unanswered.
+fn bitstamp_answer(state: &State, op: &Value) -> Option<String> {
+    let channel = op["data"]["channel"].as_str().unwrap_or_default();
+    let pair = channel.rsplit('_').next().unwrap_or_default();
+    let (event, data) = match op["event"].as_str().unwrap_or_default() {
+        "bts:heartbeat" if state.silent => return None,
+        "bts:heartbeat" => ("bts:heartbeat", json!({"status": "success"})),
+        "bts:subscribe" if state.rejected.contains(pair) => (
+            "bts:error",
+            json!({"code": null, "message": format!("Bad subscription string: {channel}")}),
+        ),
+        "bts:subscribe" => ("bts:subscription_succeeded", json!({})),
+        "bts:unsubscribe" => ("bts:unsubscription_succeeded", json!({})),
+        _ => ("bts:error", json!({"code": null, "message": "Bad request"})),
+    };
+    Some(bitstamp_frame(event, channel, data))
+}
+
+/// KuCoin answers by `id`. Silent mocks leave pings unanswered.
+fn kucoin_answer(state: &State, op: &Value) -> Option<String> {
+    let id = &op["id"];
+    let topic = op["topic"].as_str().unwrap_or_default();
+    let symbols = topic.split_once(':').map_or("", |(_, symbols)| symbols);
+    let answer = match op["type"].as_str().unwrap_or_default() {
+        "ping" if state.silent => return None,
+        "ping" => json!({"id": id, "type": "pong"}),
+        "subscribe" if symbols.split(',').any(|s| state.rejected.contains(s)) => json!({
+            "id": id,
+            "type": "error",
+            "code": 404,
+            "data": format!("topic {topic} is not found"),
+        }),
+        "subscribe" | "unsubscribe" => json!({"id": id, "type": "ack"}),
+        _ => json!({"id": id, "type": "error", "code": 400, "data": "Invalid request"}),
+    };
+    Some(answer.to_string())
+}
+
+impl MockExchange {
+    /// `bullet-public` answer, a new token for this mock's websocket
+    fn bullet(&self) -> Value {
+        let mut state = self.state.lock().unwrap();
+        state.tokens += 1;
+        json!({
+            "code": "200000",
+            "data": {
+                "token": format!("token-{}", state.tokens),
+                "instanceServers": [{
+                    "endpoint": self.url,
+                    "encrypt": false,
+                    "protocol": "websocket",
+                    "pingInterval": 18000,
+                    "pingTimeout": 10000,
+                }],
+            },
+        })
+    }
+
+    /// Answer a single HTTP request for `depth?symbol=..`, `order_book/{pair}/` or
+    /// `bullet-public`
+    async fn serve_rest(self, mut stream: TcpStream) {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => request.extend_from_slice(&buf[..n]),
+            }
+        }
+
+        let request = String::from_utf8_lossy(&request);
+        let path = request.split(' ').nth(1).unwrap_or_default();
+        if path.ends_with("/bullet-public") {
+            return respond(stream, "200 OK", self.bullet()).await;
+        }
+        let symbol = match path.split_once("symbol=") {
+            Some((_, rest)) => rest.split('&').next(),
+            None => path.trim_end_matches('/').rsplit('/').next(),
+        }
+        .unwrap_or_default();
+        let snapshot = {
+            let mut state = self.state.lock().unwrap();
+            state.fetches += 1;
+            state.depths.get(symbol).cloned()
+        };
+        match snapshot {
+            Some(snapshot) => respond(stream, "200 OK", snapshot).await,
+            None => {
+                let error = json!({"code": -1121, "msg": "Invalid symbol."});
+                respond(stream, "400 Bad Request", error).await
+            }
+        }
+    }
+}
+
+/// Write a JSON HTTP response and close the connection
+async fn respond(mut stream: TcpStream, status: &str, body: Value) {
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Frame of the Binance combined stream endpoint
+fn combined(symbol: &str, stream: &str, data: Value) -> String {
+    let stream = format!("{}@{stream}", symbol.to_lowercase());
+    json!({"stream": stream, "data": data}).to_string()
+}
+
+/// `[price, size]` levels, as Binance, Bybit and Coinbase send them
+fn price_levels(levels: &[(&str, &str)]) -> Vec<Value> {
+    levels.iter().map(|(px, sz)| json!([px, sz])).collect()
+}
+
+/// `subscription` notification of the Deribit websocket
+fn subscription(channel: &str, data: Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "subscription",
+        "params": {"channel": channel, "data": data},
+    })
+    .to_string()
+}
+
+/// Every frame of the Bitstamp websocket
+fn bitstamp_frame(event: &str, channel: &str, data: Value) -> String {
+    json!({"event": event, "channel": channel, "data": data}).to_string()
+}
+
+/// `message` of the KuCoin websocket
+fn kucoin_message(topic: &str, subject: &str, data: Value) -> String {
+    json!({"type": "message", "topic": topic, "subject": subject, "data": data}).to_string()
+}
+
+/// `[price, size]` levels as numbers, as Huobi sends them
+fn number_levels(levels: &[(&str, &str)]) -> Vec<Value> {
+    levels
+        .iter()
+        .map(|(px, sz)| json!([px.parse::<f64>().unwrap(), sz.parse::<f64>().unwrap()]))
+        .collect()
+}
+
+pub fn trade_frame(inst_id: &str, px: &str, sz: &str, side: &str) -> String {
+    json!({
+        "arg": {"channel": "trades", "instId": inst_id},
+        "data": [{
+            "instId": inst_id,
+            "tradeId": "426790906",
+            "px": px,
+            "sz": sz,
+            "side": side,
+            "ts": "1688085963425"
+        }]
+    })
+    .to_string()
+}
+
+pub fn error_frame(code: &str, msg: &str) -> String {
+    json!({"event": "error", "code": code, "msg": msg}).to_string()
+}
+
+/// Run an actor for `protocol`, kept alive the way its exchange wants
+pub fn connect<P: Protocol>(protocol: P) -> MyActorHandle {
+    let keepalive = KeepaliveConfig::for_exchange(protocol.exchange());
+    MyActorHandle::with_protocol(protocol, keepalive)
+}
+
+/// Decode `frame` into a fresh list, for tests that check what a single frame turns into
+pub fn decoded<P: Protocol>(protocol: &mut P, frame: &str) -> Vec<Output> {
+    let mut outputs = Vec::new();
+    protocol.decode(frame, &mut outputs);
+    outputs
+}
+
+pub fn request(symbol: &str, data_type: DataTypes) -> SocketRequest {
+    SocketRequest {
+        symbol: symbol.into(),
+        data_type,
+    }
+}
+
+#[tokio::test]
+async fn test_mock_exchange() {
+    use tokio_tungstenite::connect_async;
+
+    let mock = MockExchange::start().await;
+    mock.reject("NOPE-USDT");
+    let (mut ws, _) = connect_async(mock.url()).await.unwrap();
+
+    ws.send(Message::Text("ping".into())).await.unwrap();
+    let frame = time::timeout(WAIT, ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(frame.into_text().unwrap(), "pong");
+
+    let op = json!({"op": "subscribe", "args": [
+        {"channel": "trades", "instId": "BTC-USDT"},
+        {"channel": "trades", "instId": "NOPE-USDT"},
+    ]});
+    ws.send(Message::Text(op.to_string())).await.unwrap();
+    let mut answers = Vec::new();
+    for _ in 0..2 {
+        let frame = time::timeout(WAIT, ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        answers.push(serde_json::from_str::<Value>(&frame.into_text().unwrap()).unwrap());
+    }
+    assert_eq!(answers[0]["event"], "subscribe");
+    assert_eq!(answers[1]["code"], "60018");
+    assert_eq!(mock.ops(1).await, vec![op]);
+
+    mock.book(
+        "snapshot",
+        "BTC-USDT",
+        &[("8476.98", "415")],
+        &[("8476.97", "256")],
+    );
+    let frame = time::timeout(WAIT, ws.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let events = crate::transmute::okx::parse(&frame.into_text().unwrap()).unwrap();
+    assert_eq!(events.len(), 1);
+
+    mock.disconnect();
+    let frame = time::timeout(WAIT, ws.next()).await.unwrap();
+    assert!(!matches!(frame, Some(Ok(Message::Text(_)))));
+    assert_eq!(mock.connections(), 1);
+}
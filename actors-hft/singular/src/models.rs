@@ -1,7 +1,8 @@
+pub use crate::decimal::Decimal;
+pub use crate::symbol::Symbol;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use strum::EnumString;
-pub type Symbol = String;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Currency {
@@ -38,6 +39,8 @@ pub enum Exchange {
     BitStamp = 8,
     #[strum(serialize = "Deribit", serialize = "deribit")]
     Deribit = 4,
+    #[strum(serialize = "KuCoin", serialize = "kucoin")]
+    KuCoin = 11,
 }
 impl std::fmt::Display for Exchange {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -52,6 +55,7 @@ impl std::fmt::Display for Exchange {
             Exchange::ByBit => write!(f, "bybit"),
             Exchange::BitStamp => write!(f, "bitstamp"),
             Exchange::Deribit => write!(f, "deribit"),
+            Exchange::KuCoin => write!(f, "kucoin"),
         }
     }
 }
@@ -61,11 +65,28 @@ pub struct Instrument {
     exchange: Exchange,
     symbol: String,
     r#type: InstrumentType,
-    min_price: f64,
-    min_quantity: f64,
+    min_price: Decimal,
+    min_quantity: Decimal,
     contract_mult: f64,
 }
 
+impl Instrument {
+    /// Digits after the point of the price tick, `0.01` has 2
+    pub fn price_scale(&self) -> u8 {
+        self.min_price.normalize().scale()
+    }
+
+    /// Smallest price step, without trailing zeros
+    pub fn tick_size(&self) -> Decimal {
+        self.min_price.normalize()
+    }
+
+    /// Digits after the point of the lot size
+    pub fn quantity_scale(&self) -> u8 {
+        self.min_quantity.normalize().scale()
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Copy, Debug)]
 pub enum InstrumentType {
     LinearFuture = 1,
@@ -77,9 +98,46 @@ pub enum InstrumentType {
 
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct Orderbook {
-    pub asks: BTreeMap<ordered_float::OrderedFloat<f64>, f64>,
-    pub bids: BTreeMap<ordered_float::OrderedFloat<f64>, f64>,
+    pub asks: BTreeMap<Decimal, Decimal>,
+    pub bids: BTreeMap<Decimal, Decimal>,
     pub is_snap: bool,
+    /// Tick size of the instrument, prices are rescaled to its scale as levels are set so the
+    /// same price always keys the same level. Prices keep the exchange's scale when the instrument
+    /// isn't known, quantities always do.
+    pub tick_size: Option<Decimal>,
+}
+
+impl Orderbook {
+    /// An empty book at the tick size of `instrument`
+    pub fn new(instrument: &Instrument) -> Self {
+        Self {
+            tick_size: Some(instrument.tick_size()),
+            ..Self::default()
+        }
+    }
+
+    /// Replace a level, or remove it when `quantity` is zero. A price that isn't a whole number
+    /// of ticks is dropped rather than rounded onto a neighbouring level, returns whether the
+    /// level was applied.
+    pub fn set_level(&mut self, side: Side, price: Decimal, quantity: Decimal) -> bool {
+        let price = match self.tick_size {
+            Some(tick) => match price.rescale_exact(tick.scale()) {
+                Some(price) if tick.is_zero() || price.mantissa() % tick.mantissa() == 0 => price,
+                _ => return false,
+            },
+            None => price,
+        };
+        let levels = match side {
+            Side::BUY => &mut self.bids,
+            Side::SELL => &mut self.asks,
+        };
+        if quantity.is_zero() {
+            levels.remove(&price);
+        } else {
+            levels.insert(price, quantity);
+        }
+        true
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
@@ -96,8 +154,8 @@ pub mod normal {
     use strum::EnumString;
     #[derive(Serialize, Deserialize, Clone, Default)]
     pub struct Snapshot {
-        pub asks: Vec<(f64, f64)>,
-        pub bids: Vec<(f64, f64)>,
+        pub asks: Vec<(Decimal, Decimal)>,
+        pub bids: Vec<(Decimal, Decimal)>,
         pub symbol: String,
     }
 
@@ -118,6 +176,9 @@ pub mod normal {
         Book,
         #[strum(serialize = "Trade", serialize = "trade")]
         Trade,
+        /// Best bid and offer, pushed as a two level snapshot
+        #[strum(serialize = "Bbo", serialize = "bbo")]
+        Bbo,
         // #[serde(rename = "snapshot")]
         // #[strum(serialize = "Snapshot", serialize = "snapshot")]
         // BookSnapshot,
@@ -128,6 +189,7 @@ pub mod normal {
             match self {
                 DataTypes::Book => write!(f, "book"),
                 DataTypes::Trade => write!(f, "trade"),
+                DataTypes::Bbo => write!(f, "bbo"),
             }
         }
     }
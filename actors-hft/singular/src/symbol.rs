@@ -0,0 +1,345 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt,
+    ops::Deref,
+    sync::{OnceLock, RwLock},
+};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::models::Exchange;
+
+/// An interned instrument name. Copied around as a `u32` and resolved back to its string only at
+/// the edges, where it is sent to an exchange or a client.
+///
+/// Interned strings live for the rest of the process, there are only so many instruments.
+/// Resolving a symbol to its name never locks, see [`Names`].
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+/// Names in the first chunk of [`Names`], every chunk after it is twice the size of the last
+const FIRST_CHUNK: usize = 256;
+/// Enough chunks for every `u32` id
+const CHUNKS: usize = 25;
+
+/// Append-only arena of the interned names, indexed by id. A chunk is allocated the first time an
+/// id falls into it and never moves, and a name is written once before its id is handed out, so
+/// reading one is a pair of atomic loads.
+struct Names {
+    chunks: [OnceLock<Box<[OnceLock<&'static str>]>>; CHUNKS],
+}
+
+static NAMES: Names = Names {
+    chunks: [const { OnceLock::new() }; CHUNKS],
+};
+
+impl Names {
+    /// Chunk and offset of `id`
+    fn slot(id: u32) -> (usize, usize) {
+        let chunk = (id as usize / FIRST_CHUNK + 1).ilog2() as usize;
+        (chunk, id as usize - FIRST_CHUNK * ((1 << chunk) - 1))
+    }
+
+    fn get(&self, id: u32) -> &'static str {
+        let (chunk, offset) = Self::slot(id);
+        self.chunks[chunk]
+            .get()
+            .and_then(|chunk| chunk[offset].get())
+            .expect("Symbols are named before they are handed out")
+    }
+
+    /// Only called by the interner, under its write lock
+    fn set(&self, id: u32, name: &'static str) {
+        let (chunk, offset) = Self::slot(id);
+        let chunk = self.chunks[chunk]
+            .get_or_init(|| (0..FIRST_CHUNK << chunk).map(|_| OnceLock::new()).collect());
+        let _ = chunk[offset].set(name);
+    }
+}
+
+#[derive(Default)]
+struct Interner {
+    ids: HashMap<&'static str, Symbol>,
+    /// Names in [`NAMES`]
+    len: u32,
+    /// Canonical symbol of every exchange-native name seen, see [`Symbol::canonical`]
+    canonical: HashMap<(Exchange, &'static str), Symbol>,
+    /// Native name each exchange was first asked for a symbol by
+    native: HashMap<(Exchange, Symbol), &'static str>,
+}
+
+impl Interner {
+    fn intern(&mut self, name: &str) -> (Symbol, &'static str) {
+        if let Some((&name, &symbol)) = self.ids.get_key_value(name) {
+            return (symbol, name);
+        }
+        let name: &'static str = Box::leak(name.into());
+        let symbol = Symbol(self.len);
+        NAMES.set(symbol.0, name);
+        self.len += 1;
+        self.ids.insert(name, symbol);
+        (symbol, name)
+    }
+}
+
+fn interner() -> &'static RwLock<Interner> {
+    static INTERNER: OnceLock<RwLock<Interner>> = OnceLock::new();
+    INTERNER.get_or_init(|| {
+        let mut interner = Interner::default();
+        // `Symbol::default()`
+        interner.intern("");
+        RwLock::new(interner)
+    })
+}
+
+thread_local! {
+    /// Names this thread looked up before, so decoding the same instruments over and over never
+    /// touches the interner's lock
+    static SEEN: RefCell<HashMap<&'static str, Symbol>> = RefCell::default();
+    /// Exchange-native names this thread looked up before, see [`Symbol::canonical`]
+    static SEEN_NATIVE: RefCell<HashMap<Exchange, HashMap<&'static str, Symbol>>> =
+        RefCell::default();
+}
+
+impl Symbol {
+    /// Symbol of `name`, only allocates the first time it is seen
+    pub fn intern(name: &str) -> Self {
+        if let Some(symbol) = Self::get(name) {
+            return symbol;
+        }
+        let (symbol, name) = interner().write().unwrap().intern(name);
+        SEEN.with_borrow_mut(|seen| seen.insert(name, symbol));
+        symbol
+    }
+
+    /// Symbol of `name` if it was interned before
+    pub fn get(name: &str) -> Option<Self> {
+        if let Some(symbol) = SEEN.with_borrow(|seen| seen.get(name).copied()) {
+            return Some(symbol);
+        }
+        let interner = interner().read().unwrap();
+        let (&name, &symbol) = interner.ids.get_key_value(name)?;
+        SEEN.with_borrow_mut(|seen| seen.insert(name, symbol));
+        Some(symbol)
+    }
+
+    /// Symbol of an exchange-native name, `to_canonical` names it the way events carry it. Only
+    /// the first time a name is seen calls `to_canonical`, so adapters can map names on every
+    /// push without allocating.
+    pub fn canonical(
+        exchange: Exchange,
+        native: &str,
+        to_canonical: impl FnOnce(&str) -> String,
+    ) -> Self {
+        let seen = SEEN_NATIVE.with_borrow(|seen| seen.get(&exchange)?.get(native).copied());
+        if let Some(symbol) = seen {
+            return symbol;
+        }
+
+        let cached = {
+            let interner = interner().read().unwrap();
+            interner
+                .canonical
+                .get(&(exchange, native))
+                .zip(interner.ids.get_key_value(native))
+                .map(|(symbol, (native, _))| (*native, *symbol))
+        };
+        let (native, symbol) = match cached {
+            Some(cached) => cached,
+            None => {
+                let canonical = to_canonical(native);
+                let mut interner = interner().write().unwrap();
+                let (_, native) = interner.intern(native);
+                let (symbol, _) = interner.intern(&canonical);
+                interner.canonical.insert((exchange, native), symbol);
+                interner.native.entry((exchange, symbol)).or_insert(native);
+                (native, symbol)
+            }
+        };
+        SEEN_NATIVE
+            .with_borrow_mut(|seen| seen.entry(exchange).or_default().insert(native, symbol));
+        symbol
+    }
+
+    /// The name events and clients know the symbol by
+    pub fn as_str(&self) -> &'static str {
+        NAMES.get(self.0)
+    }
+
+    /// The name `exchange` knows the symbol by, the canonical one unless it was interned through
+    /// [`Symbol::canonical`]
+    pub fn native(&self, exchange: Exchange) -> &'static str {
+        let interner = interner().read().unwrap();
+        match interner.native.get(&(exchange, *self)) {
+            Some(native) => native,
+            None => self.as_str(),
+        }
+    }
+}
+
+impl Default for Symbol {
+    fn default() -> Self {
+        // Interned first
+        interner();
+        Self(0)
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl From<String> for Symbol {
+    fn from(name: String) -> Self {
+        Self::intern(&name)
+    }
+}
+
+impl From<Symbol> for String {
+    fn from(symbol: Symbol) -> Self {
+        symbol.as_str().to_string()
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl PartialEq<String> for Symbol {
+    fn eq(&self, other: &String) -> bool {
+        self.as_str() == other
+    }
+}
+
+/// By name, so ordered collections of symbols read alphabetically
+impl Ord for Symbol {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if self == other {
+            return std::cmp::Ordering::Equal;
+        }
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for Symbol {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for Symbol {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Symbol {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = Symbol;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a symbol")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Symbol, E> {
+                Ok(Symbol::intern(name))
+            }
+        }
+
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let symbol = Symbol::intern("BTC-USDT");
+        assert_eq!(symbol, Symbol::from(String::from("BTC-USDT")));
+        assert_ne!(symbol, Symbol::intern("ETH-USDT"));
+        assert_eq!(symbol, "BTC-USDT");
+        assert_eq!(Symbol::default(), "");
+        assert_eq!(std::mem::size_of::<Symbol>(), 4);
+
+        let json = serde_json::to_string(&symbol).unwrap();
+        assert_eq!(json, r#""BTC-USDT""#);
+        assert_eq!(serde_json::from_str::<Symbol>(&json).unwrap(), symbol);
+        assert!(Symbol::intern("A") < Symbol::intern("B") && Symbol::intern("Z") > "B".into());
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Names::slot(0), (0, 0));
+        assert_eq!(Names::slot(255), (0, 255));
+        assert_eq!(Names::slot(256), (1, 0));
+        assert_eq!(Names::slot(767), (1, 511));
+        assert_eq!(Names::slot(768), (2, 0));
+        assert_eq!(Names::slot(u32::MAX).0, CHUNKS - 1);
+
+        // Resolved from another thread, which never looked it up
+        let symbol = Symbol::intern("SOL-USDT");
+        let name = std::thread::spawn(move || symbol.as_str()).join().unwrap();
+        assert_eq!(name, "SOL-USDT");
+    }
+
+    #[test]
+    fn test_canonical() {
+        let mut calls = 0;
+        let mut canonical = |native: &str| {
+            Symbol::canonical(Exchange::Kraken, native, |native| {
+                calls += 1;
+                native.replace("XBT", "BTC")
+            })
+        };
+        let symbol = canonical("XBT/EUR");
+        assert_eq!(canonical("XBT/EUR"), symbol);
+        assert_eq!(canonical("BTC/EUR"), symbol);
+        assert_eq!(calls, 2);
+
+        assert_eq!(symbol, "BTC/EUR");
+        assert_eq!(symbol.native(Exchange::Kraken), "XBT/EUR");
+        assert_eq!(symbol.native(Exchange::Okx), "BTC/EUR");
+    }
+}
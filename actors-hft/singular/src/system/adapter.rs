@@ -1,376 +1,604 @@
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::{btree_map::Entry, BTreeMap, HashMap};
 
 use crate::{
-    adapters::okx::Okx,
-    event::EventType,
-    interfaces::Adapter,
-    models::{Exchange, Symbol},
+    adapters::{capabilities::Capabilities, subscriptions::SubscribeError},
+    interfaces::{Adapter, EventStream, Subscribing},
+    models::{normal::DataTypes, Exchange, Symbol},
 };
 
-pub type BatchId = i32;
-pub type BackupId = i32;
+pub type BatchId = u32;
 
-pub struct AdapterSystem {
-    exchange: Exchange,
-    batch_dim: i32,
-    backup_dim: i32,
-    batch_id: BatchId,
+/// Channels one connection carries when the exchange doesn't say how many it allows
+pub const DEFAULT_BATCH_DIM: usize = 100;
 
-    sub_count_map: BTreeMap<BatchId, i32>,
-    map_batch_id_to_primary_backup_id: BTreeMap<BatchId, BackupId>,
+/// Opens one more connection to the exchange
+pub type Connect = Box<dyn FnMut() -> Box<dyn Adapter> + Send>;
 
-    trade_subs: BTreeSet<String>,
-    orderbook_subs: BTreeSet<String>,
-    orderbook_snapshot_subs: BTreeSet<String>,
+/// A subscription sent through [`AdapterSystem::request`], waiting on the exchange's answer
+#[derive(Debug)]
+pub struct Request {
+    channel: (DataTypes, Symbol),
+    batch_id: BatchId,
+    /// The channel wasn't carried yet and took room in its batch
+    took_room: bool,
+}
 
-    pub map_orderbook_subs_to_batch_id: HashMap<Symbol, BatchId>,
-    pub map_orderbook_snapshot_subs_to_batch_id: HashMap<Symbol, BatchId>,
-    pub map_trade_subs_to_batch_id: HashMap<Symbol, BatchId>,
+/// One connection and how many channels it carries
+struct Batch {
+    adapter: Box<dyn Adapter>,
+    channels: usize,
+    /// The adapter refused another channel, see [`SubscribeError::Full`]
+    full: bool,
+}
 
-    pub adapter_map: BTreeMap<BatchId, Vec<Box<dyn Adapter>>>,
+/// Shards the subscriptions to one exchange over as many connections as it takes to keep each at
+/// or under `batch_dim` channels, or under what the adapter itself allows when that is fewer. A new
+/// channel goes to the lowest batch with room, a batch is opened when every one is full and closed
+/// once its last channel is released. A batch whose adapter refuses a channel as
+/// [`SubscribeError::Full`] counts as full until it releases one.
+///
+/// Channels never move between open batches, that would end the streams taken through the old
+/// connection. Room freed by unsubscribing is filled by the next subscriptions instead.
+pub struct AdapterSystem {
+    exchange: Exchange,
+    batch_dim: usize,
+    connect: Connect,
+    next_batch_id: BatchId,
+    batches: BTreeMap<BatchId, Batch>,
+    /// Batch carrying each subscribed channel
+    pub map_subs_to_batch_id: HashMap<(DataTypes, Symbol), BatchId>,
 }
 
 impl AdapterSystem {
-    pub async fn new(exchange: Exchange, batch_dim: i32, backup_dim: i32) -> Self {
-        let mut system = Self {
+    /// Shard over connections opened by `connect`, each carrying at most `batch_dim` channels.
+    /// Nothing is opened until the first subscription.
+    pub fn new(
+        exchange: Exchange,
+        batch_dim: usize,
+        connect: impl FnMut() -> Box<dyn Adapter> + Send + 'static,
+    ) -> Self {
+        assert!(batch_dim > 0, "A batch carries at least one channel");
+        Self {
             exchange,
             batch_dim,
-            backup_dim,
-            batch_id: 0,
-            sub_count_map: BTreeMap::new(),
-            map_batch_id_to_primary_backup_id: BTreeMap::new(),
-            trade_subs: BTreeSet::new(),
-            orderbook_subs: BTreeSet::new(),
-            orderbook_snapshot_subs: BTreeSet::new(),
-            map_orderbook_subs_to_batch_id: HashMap::new(),
-            map_orderbook_snapshot_subs_to_batch_id: HashMap::new(),
-            map_trade_subs_to_batch_id: HashMap::new(),
-            adapter_map: BTreeMap::new(),
+            connect: Box::new(connect),
+            next_batch_id: 0,
+            batches: BTreeMap::new(),
+            map_subs_to_batch_id: HashMap::new(),
+        }
+    }
+
+    /// Shard up to the most channels the exchange allows on one connection. The adapters may allow
+    /// fewer, the first one opened says.
+    pub fn for_exchange(
+        exchange: Exchange,
+        connect: impl FnMut() -> Box<dyn Adapter> + Send + 'static,
+    ) -> Self {
+        let batch_dim = Capabilities::for_exchange(exchange)
+            .max_subscriptions
+            .unwrap_or(DEFAULT_BATCH_DIM);
+        Self::new(exchange, batch_dim, connect)
+    }
+
+    pub fn exchange(&self) -> Exchange {
+        self.exchange
+    }
+
+    pub fn batch_dim(&self) -> usize {
+        self.batch_dim
+    }
+
+    /// Batch carrying `data_type` for `symbol`, `None` if it isn't subscribed
+    pub fn batch_id(&self, data_type: DataTypes, symbol: Symbol) -> Option<BatchId> {
+        self.map_subs_to_batch_id.get(&(data_type, symbol)).copied()
+    }
+
+    /// Channels carried by every open batch
+    pub fn load(&self) -> BTreeMap<BatchId, usize> {
+        self.batches
+            .iter()
+            .map(|(batch_id, batch)| (*batch_id, batch.channels))
+            .collect()
+    }
+
+    /// Connection of `batch_id`, if it is open
+    pub fn adapter(&self, batch_id: BatchId) -> Option<&dyn Adapter> {
+        self.batches
+            .get(&batch_id)
+            .map(|batch| batch.adapter.as_ref())
+    }
+}
+
+impl AdapterSystem {
+    /// Stream `data_type` for `symbol`. Another stream of a channel already carried comes from its
+    /// batch and takes no room.
+    pub async fn subscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<EventStream, SubscribeError> {
+        loop {
+            let (request, subscribing) = self.request(data_type, symbol).await?;
+            let answer = subscribing.await;
+            if !self.settle(request, answer.as_ref().err()).await {
+                return answer;
+            }
+        }
+    }
+
+    /// Send the request for `data_type` for `symbol` to the batch that carries the channel, or
+    /// to the lowest one with room, without waiting on the exchange. The channel takes its room
+    /// right away, the answer goes back through [`AdapterSystem::settle`].
+    pub async fn request(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<(Request, Subscribing), SubscribeError> {
+        let channel = (data_type, symbol);
+        if let Some(batch_id) = self.batch_id(data_type, symbol) {
+            if let Some(batch) = self.batches.get_mut(&batch_id) {
+                let subscribing = batch.adapter.request(data_type, symbol).await?;
+                let request = Request {
+                    channel,
+                    batch_id,
+                    took_room: false,
+                };
+                return Ok((request, subscribing));
+            }
+        }
+
+        let batch_id = self.batch_with_room();
+        let batch = match self.batches.entry(batch_id) {
+            Entry::Occupied(batch) => batch.into_mut(),
+            Entry::Vacant(batch) => {
+                eprintln!(
+                    "AdapterSystem: opening batch {batch_id} on {}",
+                    self.exchange
+                );
+                let adapter = (self.connect)();
+                if let Some(max) = adapter.capabilities().max_subscriptions {
+                    self.batch_dim = self.batch_dim.min(max);
+                }
+                batch.insert(Batch {
+                    adapter,
+                    channels: 0,
+                    full: false,
+                })
+            }
         };
 
-        system.batch_id += 1;
-        let batch_id = system.batch_id;
-        for backup_id in 0..system.backup_dim {
-            system.register_adapter(&batch_id, backup_id).await;
+        match batch.adapter.request(data_type, symbol).await {
+            Ok(subscribing) => {
+                batch.channels += 1;
+                self.map_subs_to_batch_id.insert(channel, batch_id);
+                let request = Request {
+                    channel,
+                    batch_id,
+                    took_room: true,
+                };
+                Ok((request, subscribing))
+            }
+            Err(e) => {
+                self.close_if_empty(batch_id).await;
+                Err(e)
+            }
         }
+    }
 
-        system
+    /// Account for the exchange's answer to `request`, `refused` being why it refused. A refused
+    /// channel gives its room back, and is released on its connection so no stream that joined it
+    /// meanwhile is left hanging. Returns whether to request it again because its batch turned out
+    /// to be full.
+    pub async fn settle(&mut self, request: Request, refused: Option<&SubscribeError>) -> bool {
+        let Some(e) = refused else { return false };
+        let Request {
+            channel: (data_type, symbol),
+            batch_id,
+            took_room,
+        } = request;
+        // Released by someone else meanwhile, if it no longer counts here
+        if !took_room || self.batch_id(data_type, symbol) != Some(batch_id) {
+            return false;
+        }
+        self.map_subs_to_batch_id.remove(&(data_type, symbol));
+        let Some(batch) = self.batches.get_mut(&batch_id) else {
+            return false;
+        };
+        batch.channels -= 1;
+
+        match e {
+            // An empty batch refusing would refuse on every connection
+            SubscribeError::Full(limit) if batch.channels > 0 => {
+                eprintln!(
+                    "AdapterSystem: batch {batch_id} on {} is full: {limit}",
+                    self.exchange
+                );
+                batch.full = true;
+                true
+            }
+            _ => {
+                let _ = batch.adapter.unsubscribe(data_type, symbol).await;
+                self.close_if_empty(batch_id).await;
+                false
+            }
+        }
+    }
+
+    /// Release every stream of `data_type` for `symbol`, closing its batch if it was the last
+    /// channel there
+    pub async fn unsubscribe(
+        &mut self,
+        data_type: DataTypes,
+        symbol: Symbol,
+    ) -> Result<(), SubscribeError> {
+        let Some(batch_id) = self.batch_id(data_type, symbol) else {
+            return Ok(());
+        };
+        if let Some(batch) = self.batches.get_mut(&batch_id) {
+            batch.adapter.unsubscribe(data_type, symbol).await?;
+            batch.channels -= 1;
+            batch.full = false;
+        }
+
+        self.map_subs_to_batch_id.remove(&(data_type, symbol));
+        self.close_if_empty(batch_id).await;
+        Ok(())
     }
 
-    /// Find where symbol for a certain event exists. If EventType is AdapterDisconnect then false
-    fn subscribed(&self, symbol: &Symbol, kind: EventType) -> bool {
-        match kind {
-            EventType::Trade => self.trade_subs.get(symbol).is_some(),
-            EventType::OrderbookUpdate => self.orderbook_subs.get(symbol).is_some(),
-            EventType::OrderbookSnapshot => self.orderbook_snapshot_subs.get(symbol).is_some(),
-            EventType::AdapterDisconnect => false,
+    /// Close every connection, ending all streams
+    pub async fn shutdown(&mut self) {
+        for (_, mut batch) in std::mem::take(&mut self.batches) {
+            batch.adapter.shutdown().await;
         }
+        self.map_subs_to_batch_id.clear();
     }
 
-    /// Returns a backup id
-    fn primary_backup_id(&self, batch_id: &i32) -> BackupId {
-        *self
-            .map_batch_id_to_primary_backup_id
-            .get(batch_id)
-            .unwrap()
+    /// Lowest open batch with room for another channel, or the id of a new one
+    fn batch_with_room(&mut self) -> BatchId {
+        let open = self
+            .batches
+            .iter()
+            .find(|(_, batch)| !batch.full && batch.channels < self.batch_dim)
+            .map(|(batch_id, _)| *batch_id);
+
+        open.unwrap_or_else(|| {
+            let batch_id = self.next_batch_id;
+            self.next_batch_id += 1;
+            batch_id
+        })
     }
 
-    /// If you recieve none then either there isn't a symbol for the Trade or Orderbook. Or You've
-    /// provided an invalid event: (OrderbookSnapshot, AdapterDisconnect)
-    fn subscription_batch_id(&self, symbol: &Symbol, event: EventType) -> Option<BatchId> {
-        match event {
-            EventType::Trade => self.map_trade_subs_to_batch_id.get(symbol).copied(),
-            EventType::OrderbookUpdate => self.map_orderbook_subs_to_batch_id.get(symbol).copied(),
-            _ => None,
+    async fn close_if_empty(&mut self, batch_id: BatchId) {
+        let Entry::Occupied(batch) = self.batches.entry(batch_id) else {
+            return;
+        };
+        if batch.get().channels > 0 {
+            return;
         }
+
+        batch.remove().adapter.shutdown().await;
+        eprintln!(
+            "AdapterSystem: closed empty batch {batch_id} on {}",
+            self.exchange
+        );
     }
 }
 
-impl AdapterSystem {
-    pub fn unsubscribe_trades(&mut self, symbol: &Symbol) {
-        self.batch_id = *self.map_trade_subs_to_batch_id.get(symbol).unwrap();
-        self.trade_subs.remove(symbol);
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::HashSet,
+        future,
+        sync::{Arc, Mutex},
+    };
 
-        // TODO: Tear down adapters if active subs = 0;
-    }
+    use async_trait::async_trait;
+    use tokio::sync::{mpsc, watch};
 
-    pub fn unsubscribe_orderbook(&mut self, symbol: &Symbol) {
-        self.batch_id = *self.map_orderbook_subs_to_batch_id.get(symbol).unwrap();
-        self.orderbook_subs.remove(symbol);
+    use super::*;
+    use crate::interfaces::{event_stream, ConnectionState};
 
-        // TODO: Tear down adapters if active subs = 0;
-    }
+    /// Most channels a [`MockAdapter`] takes before it refuses like an exchange over its limit
+    const LIMIT: usize = 2;
 
-    pub fn unsubscribe_orderbook_snapshot(&mut self, symbol: &Symbol) {
-        self.batch_id = *self
-            .map_orderbook_snapshot_subs_to_batch_id
-            .get(symbol)
-            .unwrap();
-        self.orderbook_snapshot_subs.remove(symbol);
+    /// What one mock connection was left carrying
+    #[derive(Default)]
+    struct Connection {
+        channels: HashSet<(DataTypes, Symbol)>,
+        closed: bool,
+    }
 
-        // TODO: Tear down adapters if active subs = 0;
+    /// Adapter that keeps its channels where the test can see them. The exchange rejects any
+    /// symbol starting with `BAD`.
+    struct MockAdapter {
+        connection: Arc<Mutex<Connection>>,
+        state: watch::Sender<ConnectionState>,
+        /// Whether [`Adapter::capabilities`] tells the [`LIMIT`]
+        advertised: bool,
     }
 
-    pub async fn resubscribe(&mut self, batch_id: BatchId, backup_id: BackupId) {
-        println!("AdapterManagementSystem: Resubscribing {batch_id}  {backup_id}");
+    #[async_trait]
+    impl Adapter for MockAdapter {
+        fn exchange(&self) -> Exchange {
+            Exchange::Okx
+        }
 
-        while !self
-            .adapter_map
-            .get(&batch_id)
-            .unwrap()
-            .get(backup_id as usize)
-            .unwrap()
-            .connected()
-        {}
-
-        for (sym, id) in self.map_orderbook_subs_to_batch_id.iter() {
-            if id == &self.batch_id {
-                let symbol = sym.to_string();
-                println!("AdapterManagementSystem: subscribing orderbook for {symbol}");
-                self.adapter_map
-                    .get_mut(&batch_id)
-                    .unwrap()
-                    .get_mut(backup_id as usize)
-                    .unwrap()
-                    .subscribe_orderbook(symbol)
-                    .await;
+        fn capabilities(&self) -> Capabilities {
+            Capabilities {
+                max_subscriptions: self.advertised.then_some(LIMIT),
+                ..Capabilities::for_exchange(Exchange::Okx)
             }
         }
 
-        for (sym, id) in self.map_orderbook_snapshot_subs_to_batch_id.iter() {
-            if id == &self.batch_id {
-                let symbol = sym.to_string();
-                println!("AdapterManagementSystem: subscribing orderbook snapshot for {symbol}");
-                self.adapter_map
-                    .get_mut(&batch_id)
-                    .unwrap()
-                    .get_mut(backup_id as usize)
-                    .unwrap()
-                    .subscribe_orderbook_snapshot(symbol)
-                    .await;
+        async fn request(
+            &mut self,
+            data_type: DataTypes,
+            symbol: Symbol,
+        ) -> Result<Subscribing, SubscribeError> {
+            let mut connection = self.connection.lock().unwrap();
+            if connection.closed {
+                return Err(SubscribeError::Closed);
             }
+            let answer = if symbol.starts_with("BAD") {
+                Err(SubscribeError::Exchange {
+                    code: "60018".into(),
+                    message: format!("Doesn't exist: {symbol}"),
+                })
+            } else if connection.channels.len() == LIMIT
+                && !connection.channels.contains(&(data_type, symbol))
+            {
+                Err(SubscribeError::Full(format!(
+                    "More than {LIMIT} channels on one connection"
+                )))
+            } else {
+                connection.channels.insert((data_type, symbol));
+                let (_, events) = mpsc::channel(1);
+                Ok(event_stream(events))
+            };
+            Ok(Box::pin(future::ready(answer)))
         }
-        for (sym, id) in self.map_trade_subs_to_batch_id.iter() {
-            if id == &self.batch_id {
-                let symbol = sym.to_string();
-                println!("AdapterManagementSystem: subscribing orderbook snapshot for {symbol}");
-                self.adapter_map
-                    .get_mut(&batch_id)
-                    .unwrap()
-                    .get_mut(backup_id as usize)
-                    .unwrap()
-                    .subscribe_trade(symbol)
-                    .await;
-            }
+
+        async fn unsubscribe(
+            &mut self,
+            data_type: DataTypes,
+            symbol: Symbol,
+        ) -> Result<(), SubscribeError> {
+            let mut connection = self.connection.lock().unwrap();
+            connection.channels.remove(&(data_type, symbol));
+            Ok(())
+        }
+
+        fn connection(&self) -> watch::Receiver<ConnectionState> {
+            self.state.subscribe()
+        }
+
+        async fn shutdown(&mut self) {
+            self.connection.lock().unwrap().closed = true;
+            self.state.send_replace(ConnectionState::Closed);
         }
     }
-}
 
-impl AdapterSystem {
-    /// Completed
-    pub async fn subscribe_orderbook_snapshot(&mut self, symbol: Symbol) {
-        if self.orderbook_snapshot_subs.insert(symbol.clone()) {
-            println!("AdapterManagementSystem: new orderbook snapshot subscription");
+    /// Every connection the system opened, in order
+    type Opened = Arc<Mutex<Vec<Arc<Mutex<Connection>>>>>;
 
-            if (self.sub_count_map.get(&self.batch_id).unwrap() >= &(&self.batch_dim - 1))
-                || self.batch_id == -1
-            {
-                self.batch_id += 1;
-                for backup_id in 0..self.backup_dim {
-                    self.register_adapter(&backup_id, self.batch_id).await;
-                }
+    fn system(batch_dim: usize) -> (AdapterSystem, Opened) {
+        system_with(batch_dim, false)
+    }
 
-                *self
-                    .map_batch_id_to_primary_backup_id
-                    .get_mut(&self.batch_id)
-                    .unwrap() = 0;
-
-                for backup_id in 0..self.batch_id {
-                    self.adapter_map
-                        .get_mut(&self.batch_id)
-                        .unwrap()
-                        .get_mut(self.batch_id as usize)
-                        .unwrap()
-                        .subscribe_orderbook_snapshot(symbol.clone())
-                        .await;
-
-                    println!("AdapterManagementSystem: at: {} - {} subscribing to orderbook snapshots for symbol: {}", self.batch_id, backup_id, symbol);
-                }
-            }
-        } else {
-            // Add to existing batch
-            for backup_id in 0..self.batch_id {
-                self.adapter_map
-                    .get_mut(&self.batch_id)
-                    .unwrap()
-                    .get_mut(self.batch_id as usize)
-                    .unwrap()
-                    .subscribe_orderbook_snapshot(symbol.clone())
-                    .await;
-
-                println!("AdapterManagementSystem: at: {} - {} subscribing to orderbook snapshots for symbol: {}", self.batch_id, backup_id, symbol);
-            }
-            *self
-                .map_orderbook_snapshot_subs_to_batch_id
-                .get_mut(&symbol)
-                .unwrap() = self.batch_id;
-        }
+    fn system_with(batch_dim: usize, advertised: bool) -> (AdapterSystem, Opened) {
+        let opened = Opened::default();
+        let connections = opened.clone();
+        let system = AdapterSystem::new(Exchange::Okx, batch_dim, move || {
+            let connection = Arc::new(Mutex::new(Connection::default()));
+            connections.lock().unwrap().push(connection.clone());
+            let (state, _) = watch::channel(ConnectionState::Connected);
+            Box::new(MockAdapter {
+                connection,
+                state,
+                advertised,
+            }) as Box<dyn Adapter>
+        });
+        (system, opened)
+    }
+
+    async fn subscribe(system: &mut AdapterSystem, data_type: DataTypes, symbol: &str) {
+        let subscribed = system.subscribe(data_type, symbol.into()).await;
+        assert!(subscribed.is_ok(), "{data_type} {symbol}");
+    }
 
-        *self.sub_count_map.get_mut(&self.batch_id).unwrap() += 1;
-    }
-    /// Completed
-    async fn register_adapter(&mut self, batch_id: &i32, backup_id: i32) {
-        match self.exchange {
-            Exchange::OKX => self
-                .adapter_map
-                .get_mut(batch_id)
-                .unwrap()
-                .push(Box::from(Okx::new(self.exchange).await)),
-            // Exchange::BINANCEUSDM => todo!(),
-            // Exchange::BINANCECOINM => todo!(),
-            // Exchange::DERIBIT => todo!(),
-            // Exchange::KRAKEN => todo!(),
-            // Exchange::COINBASE => todo!(),
-            // Exchange::HUOBI => todo!(),
-            // Exchange::BITSTAMP => todo!(),
-            // Exchange::BYBIT => todo!(),
-            // Exchange::BITFINEX => todo!(),
-            _ => todo!(),
+    /// Channels carried by the `index`th connection opened
+    fn channels(opened: &Opened, index: usize) -> usize {
+        opened.lock().unwrap()[index].lock().unwrap().channels.len()
+    }
+
+    fn closed(opened: &Opened, index: usize) -> bool {
+        opened.lock().unwrap()[index].lock().unwrap().closed
+    }
+
+    #[tokio::test]
+    async fn test_packs_up_to_batch_dim() {
+        let (mut system, opened) = system(LIMIT);
+        assert!(system.load().is_empty());
+
+        for symbol in ["BTC-USDT", "ETH-USDT", "SOL-USDT", "XRP-USDT", "ADA-USDT"] {
+            subscribe(&mut system, DataTypes::Trade, symbol).await;
         }
 
-        println!("Picked");
-        println!("Created");
-        println!("AdapterManagementSystem: registered adapter to {batch_id} {backup_id}");
-
-        while !self
-            .adapter_map
-            .get(batch_id)
-            .unwrap()
-            .get(backup_id as usize)
-            .unwrap()
-            .connected()
-        {}
-
-        println!(
-            "AdapterManagementSystem: adapter at {batch_id} {backup_id} sucessfully connected"
+        assert_eq!(system.load(), BTreeMap::from([(0, 2), (1, 2), (2, 1)]));
+        assert_eq!(opened.lock().unwrap().len(), 3);
+        assert_eq!(channels(&opened, 0), 2);
+        assert_eq!(channels(&opened, 2), 1);
+        assert_eq!(
+            system.batch_id(DataTypes::Trade, "BTC-USDT".into()),
+            Some(0)
+        );
+        assert_eq!(
+            system.batch_id(DataTypes::Trade, "SOL-USDT".into()),
+            Some(1)
         );
+        assert_eq!(
+            system.batch_id(DataTypes::Trade, "ADA-USDT".into()),
+            Some(2)
+        );
+        assert_eq!(system.batch_id(DataTypes::Book, "BTC-USDT".into()), None);
     }
 
-    pub async fn subscribe_trades(&mut self, symbol: Symbol) {
-        if self.trade_subs.insert(symbol.clone()) {
-            println!("AdapterManagementSystem: New Trade Subscription");
+    #[tokio::test]
+    async fn test_channel_takes_one_slot() {
+        let (mut system, opened) = system(LIMIT);
 
-            if self.sub_count_map.get(&self.batch_id).unwrap() >= &(&self.backup_dim - 1)
-                || self.batch_id == -1
-            {
-                // make new batch
-                self.batch_id += 1;
-                for backup_id in 0..self.backup_dim {
-                    self.register_adapter(&backup_id, self.batch_id).await;
-                }
+        subscribe(&mut system, DataTypes::Trade, "BTC-USDT").await;
+        subscribe(&mut system, DataTypes::Trade, "BTC-USDT").await;
+        assert_eq!(system.load(), BTreeMap::from([(0, 1)]));
 
-                *self
-                    .map_batch_id_to_primary_backup_id
-                    .get_mut(&self.batch_id)
-                    .unwrap() = 0;
-
-                for backup_id in 0..self.batch_id {
-                    self.adapter_map
-                        .get_mut(&self.batch_id)
-                        .unwrap()
-                        .get_mut(self.batch_id as usize)
-                        .unwrap()
-                        .subscribe_trade(symbol.clone())
-                        .await;
-
-                    println!(
-                        "AdapterManagementSystem: at: {} - {} subscribing to trades for symbol: {}",
-                        self.batch_id, backup_id, symbol
-                    );
-                }
-            }
-            *self.map_trade_subs_to_batch_id.get_mut(&symbol).unwrap() = self.batch_id;
-        } else {
-            // Add to existing batch
-            for backup_id in 0..self.batch_id {
-                self.adapter_map
-                    .get_mut(&self.batch_id)
-                    .unwrap()
-                    .get_mut(self.batch_id as usize)
-                    .unwrap()
-                    .subscribe_trade(symbol.clone())
-                    .await;
-
-                println!(
-                    "AdapterManagementSystem: at: {} - {} subscribing to trades for symbol: {}",
-                    self.batch_id, backup_id, symbol
-                );
-            }
-            *self.map_trade_subs_to_batch_id.get_mut(&symbol).unwrap() = self.batch_id;
+        // Another data type is another channel
+        subscribe(&mut system, DataTypes::Book, "BTC-USDT").await;
+        assert_eq!(system.load(), BTreeMap::from([(0, 2)]));
+        assert_eq!(opened.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_refills_and_closes_batches() {
+        let (mut system, opened) = system(LIMIT);
+        for symbol in ["BTC-USDT", "ETH-USDT", "SOL-USDT", "XRP-USDT"] {
+            subscribe(&mut system, DataTypes::Trade, symbol).await;
         }
 
-        *self.sub_count_map.get_mut(&self.batch_id).unwrap() += 1;
+        // Room freed in the first batch is taken before opening a third
+        system
+            .unsubscribe(DataTypes::Trade, "ETH-USDT".into())
+            .await
+            .unwrap();
+        assert_eq!(system.load(), BTreeMap::from([(0, 1), (1, 2)]));
+        subscribe(&mut system, DataTypes::Bbo, "ETH-USDT").await;
+        assert_eq!(system.batch_id(DataTypes::Bbo, "ETH-USDT".into()), Some(0));
+        assert_eq!(system.batch_id(DataTypes::Trade, "ETH-USDT".into()), None);
+        assert_eq!(opened.lock().unwrap().len(), 2);
+
+        // The second batch closes with its last channel
+        system
+            .unsubscribe(DataTypes::Trade, "SOL-USDT".into())
+            .await
+            .unwrap();
+        assert!(!closed(&opened, 1));
+        system
+            .unsubscribe(DataTypes::Trade, "XRP-USDT".into())
+            .await
+            .unwrap();
+        assert!(closed(&opened, 1));
+        assert!(system.adapter(1).is_none());
+        assert_eq!(system.load(), BTreeMap::from([(0, 2)]));
+
+        // A full system opens a fresh batch rather than reusing the closed one's id
+        subscribe(&mut system, DataTypes::Trade, "ADA-USDT").await;
+        assert_eq!(system.load(), BTreeMap::from([(0, 2), (2, 1)]));
+        assert_eq!(channels(&opened, 2), 1);
     }
 
-    pub async fn subscribe_orderbook(&mut self, symbol: Symbol) {
-        if self.orderbook_subs.insert(symbol.clone()) {
-            println!("AdapterManagementSystem: New Orderbook Subscription");
+    #[tokio::test]
+    async fn test_rejected_subscription() {
+        let (mut system, opened) = system(LIMIT);
+
+        let rejected = system.subscribe(DataTypes::Trade, "BAD-USDT".into()).await;
+        assert!(matches!(rejected, Err(SubscribeError::Exchange { .. })));
+        // The batch opened for it carries nothing and is closed again
+        assert!(system.load().is_empty());
+        assert!(closed(&opened, 0));
+        assert_eq!(system.batch_id(DataTypes::Trade, "BAD-USDT".into()), None);
+
+        subscribe(&mut system, DataTypes::Trade, "BTC-USDT").await;
+        let rejected = system.subscribe(DataTypes::Trade, "BAD-USDT".into()).await;
+        assert!(rejected.is_err());
+        assert_eq!(system.load(), BTreeMap::from([(1, 1)]));
+        assert!(!closed(&opened, 1));
+    }
 
-            if self.sub_count_map.get(&self.batch_id).unwrap() >= &(&self.backup_dim - 1)
-                || self.batch_id == -1
-            {
-                // make new batch
-                self.batch_id += 1;
-                for backup_id in 0..self.backup_dim {
-                    self.register_adapter(&backup_id, self.batch_id).await;
-                }
+    #[tokio::test]
+    async fn test_room_taken_while_waiting() {
+        let (mut system, opened) = system(LIMIT);
 
-                *self
-                    .map_batch_id_to_primary_backup_id
-                    .get_mut(&self.batch_id)
-                    .unwrap() = 0;
-
-                for backup_id in 0..self.batch_id {
-                    self.adapter_map
-                        .get_mut(&self.batch_id)
-                        .unwrap()
-                        .get_mut(self.batch_id as usize)
-                        .unwrap()
-                        .subscribe_orderbook(symbol.clone())
-                        .await;
-
-                    println!(
-                        "AdapterManagementSystem: at: {} - {} subscribing to orderbook for symbol: {}",
-                        self.batch_id, backup_id, symbol
-                    );
-                }
-            }
-            *self
-                .map_orderbook_subs_to_batch_id
-                .get_mut(&symbol)
-                .unwrap() = self.batch_id;
-        } else {
-            // Add to existing batch
-            for backup_id in 0..self.batch_id {
-                self.adapter_map
-                    .get_mut(&self.batch_id)
-                    .unwrap()
-                    .get_mut(self.batch_id as usize)
-                    .unwrap()
-                    .subscribe_orderbook(symbol.clone())
-                    .await;
-
-                println!(
-                    "AdapterManagementSystem: at: {} - {} subscribing to trades for symbol: {}",
-                    self.batch_id, backup_id, symbol
-                );
-            }
-            *self
-                .map_orderbook_subs_to_batch_id
-                .get_mut(&symbol)
-                .unwrap() = self.batch_id;
+        // Both requests are out before either is answered, the second one goes where the first
+        // left room
+        let (first, first_answer) = system
+            .request(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+        let (bad, bad_answer) = system
+            .request(DataTypes::Trade, "BAD-USDT".into())
+            .await
+            .unwrap();
+        assert_eq!(system.load(), BTreeMap::from([(0, 2)]));
+
+        let answer = bad_answer.await;
+        assert!(!system.settle(bad, answer.as_ref().err()).await);
+        assert_eq!(system.batch_id(DataTypes::Trade, "BAD-USDT".into()), None);
+        let answer = first_answer.await;
+        assert!(!system.settle(first, answer.as_ref().err()).await);
+        assert!(answer.is_ok());
+        assert_eq!(system.load(), BTreeMap::from([(0, 1)]));
+        assert_eq!(opened.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_unknown() {
+        let (mut system, opened) = system(LIMIT);
+        system
+            .unsubscribe(DataTypes::Trade, "BTC-USDT".into())
+            .await
+            .unwrap();
+        assert!(opened.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let (mut system, opened) = system(LIMIT);
+        for symbol in ["BTC-USDT", "ETH-USDT", "SOL-USDT"] {
+            subscribe(&mut system, DataTypes::Trade, symbol).await;
+        }
+
+        system.shutdown().await;
+        assert!(closed(&opened, 0) && closed(&opened, 1));
+        assert!(system.load().is_empty());
+        assert!(system.map_subs_to_batch_id.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_batch_dim_from_adapter() {
+        let (mut system, opened) = system_with(DEFAULT_BATCH_DIM, true);
+        for symbol in ["BTC-USDT", "ETH-USDT", "SOL-USDT"] {
+            subscribe(&mut system, DataTypes::Trade, symbol).await;
+        }
+        assert_eq!(system.batch_dim(), LIMIT);
+        assert_eq!(system.load(), BTreeMap::from([(0, 2), (1, 1)]));
+        assert_eq!(opened.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_full_batch() {
+        // The adapter doesn't tell its limit, refusing a channel over it fills the batch
+        let (mut system, opened) = system(DEFAULT_BATCH_DIM);
+        for symbol in ["BTC-USDT", "ETH-USDT", "SOL-USDT", "XRP-USDT", "ADA-USDT"] {
+            subscribe(&mut system, DataTypes::Trade, symbol).await;
         }
+        assert_eq!(system.load(), BTreeMap::from([(0, 2), (1, 2), (2, 1)]));
+        assert_eq!(channels(&opened, 1), 2);
+
+        // Releasing a channel makes room again
+        system
+            .unsubscribe(DataTypes::Trade, "ETH-USDT".into())
+            .await
+            .unwrap();
+        subscribe(&mut system, DataTypes::Book, "ETH-USDT").await;
+        assert_eq!(system.batch_id(DataTypes::Book, "ETH-USDT".into()), Some(0));
+        assert_eq!(opened.lock().unwrap().len(), 3);
+    }
 
-        *self.sub_count_map.get_mut(&self.batch_id).unwrap() += 1;
+    #[test]
+    fn test_batch_dim_for_exchange() {
+        let system = AdapterSystem::for_exchange(Exchange::Bitfinex, || unreachable!());
+        assert_eq!(system.batch_dim(), 30);
+        // OKX doesn't publish a limit
+        let system = AdapterSystem::for_exchange(Exchange::Okx, || unreachable!());
+        assert_eq!(system.batch_dim(), DEFAULT_BATCH_DIM);
     }
 }
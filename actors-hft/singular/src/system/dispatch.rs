@@ -1,59 +1,36 @@
-use std::{
-    collections::{BTreeMap, BTreeSet, HashMap},
-    time::Duration,
-};
-
-use super::orderbook::OrderbookManagementSystem;
 use crate::{event::EventType, models::*};
 
 #[derive(Debug)]
 pub enum DispatchCommands {
     Subscribe {
         event_type: EventType,
-        symbol: String,
+        symbol: Symbol,
     },
     Unsubscribe {
         event_type: EventType,
-        symbol: String,
+        symbol: Symbol,
     },
 }
 
 /// Spawns and manages adpaters
-/// holds state regarding clients
-///
-///
-/// Handling state
-/// Multiple adapters
-/// |--->
-///
-/// Adapters, Symbol, Clients
-///
-/// Adapter:
-///     -> Symbols
-///         -> Clients
-/// HashMap<Adapters, BTreeMap<Symbols, BTreeSet<Clients>>>
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct DispatchSystem {
     // subs_tx: Sender<DispatchCommands>,
-    orderbook_system: OrderbookManagementSystem,
-    state: HashMap<Exchange, BTreeMap<String, BTreeSet<()>>>,
     adpater_handlers: Vec<DemoHandler>,
 }
 
 impl DispatchSystem {
     pub fn new() -> Self {
-        Self {
-            orderbook_system: OrderbookManagementSystem::new(),
-            state: HashMap::new(),
-            adpater_handlers: Vec::new(),
-        }
+        Self::default()
     }
 
     pub async fn build_adapters(&mut self) -> &mut Self {
-        let okx_handle = Demo::new("wss://ws.okx.com:8443/ws/v5/public")
-            .await
-            .run()
-            .await;
+        self.build_adapters_at(OKX_PUBLIC_URL).await
+    }
+
+    /// Build the adapters against `okx_url` instead of the public endpoint
+    pub async fn build_adapters_at(&mut self, okx_url: &str) -> &mut Self {
+        let okx_handle = Demo::new(okx_url).await.run().await;
 
         self.adpater_handlers.push(okx_handle);
         self
@@ -63,36 +40,46 @@ impl DispatchSystem {
         self.adpater_handlers.get(index)
     }
 }
-use crate::adapters::less::*;
-#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-async fn help() {
-    let mut dispatch = DispatchSystem::new();
+use crate::adapters::{less::*, okx::OKX_PUBLIC_URL};
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
 
-    dispatch.build_adapters().await;
+    use super::*;
 
-    let handler = dispatch.get_adapter(0).unwrap();
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn help() {
+        let exchange = crate::mock::MockExchange::start().await;
+        let mut dispatch = DispatchSystem::new();
 
-    handler
-        .dispatch_sender
-        .send(DemoCmd::Sub {
-            symbol: "BTC-USDT".into(),
-            event_kind: EventType::Trade,
-        })
-        .unwrap();
+        dispatch.build_adapters_at(exchange.url()).await;
 
-    let rx = handler.dispatch_receiver.clone();
+        let handler = dispatch.get_adapter(0).unwrap();
 
-    // let orderbook = OrderbookManagementSystem::new();
-    // let orderbook_tx = orderbook.start();
+        handler
+            .dispatch_sender
+            .send(DemoCmd::Sub {
+                symbol: "BTC-USDT".into(),
+                event_kind: EventType::Trade,
+            })
+            .unwrap();
+        exchange.ops(1).await;
+        exchange.trade("BTC-USDT", "30460.1", "0.5", "sell");
 
-    std::thread::spawn(move || {
-        while let Ok(v) = rx.clone().recv() {
-            dbg!(v);
-            // orderbook_tx.send("Hello Orderbook system".into()).unwrap();
-        }
-    });
+        let rx = handler.dispatch_receiver.clone();
 
-    tokio::time::sleep(Duration::from_secs(5)).await;
+        let trade = std::thread::spawn(move || {
+            while let Ok(v) = rx.recv_timeout(Duration::from_secs(5)) {
+                if let DispatchEvents::DataReal(crate::event::Event::Trade(t)) = v {
+                    return Some(t);
+                }
+            }
+            None
+        });
 
-    ()
+        let trade = trade.join().unwrap().expect("trade within 5s");
+        assert_eq!(trade.symbol, "BTC-USDT");
+        assert_eq!(trade.price, "30460.1".parse::<Decimal>().unwrap());
+    }
 }
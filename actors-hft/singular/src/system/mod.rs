@@ -1,4 +1,4 @@
-// pub mod adapter;
+pub mod adapter;
 pub mod dispatch;
 pub mod instrument;
 pub mod orderbook;
@@ -2,18 +2,23 @@ use rayon::iter::ParallelBridge;
 use rayon::prelude::*;
 use std::collections::HashMap;
 
-use crate::models::{normal::Snapshot, Orderbook, Side, Symbol};
+use crate::event::Event;
+use crate::models::{
+    normal::{DataTypes, Snapshot},
+    Decimal, Exchange, Instrument, Orderbook, Side, Symbol,
+};
 use crossbeam::channel;
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct OrderbookManagementSystem {
-    orderbook_map: HashMap<Symbol, HashMap<usize, Box<Orderbook>>>,
+    /// Books by exchange and symbol, each exchange lists the same symbol with its own levels
+    orderbook_map: HashMap<(Exchange, Symbol), HashMap<usize, Box<Orderbook>>>,
+    /// Tick sizes books are kept at, books of other symbols keep the exchange's scale
+    instruments: HashMap<(Exchange, Symbol), Instrument>,
 }
 
 impl OrderbookManagementSystem {
     pub fn new() -> Self {
-        Self {
-            orderbook_map: HashMap::new(),
-        }
+        Self::default()
     }
 
     pub fn start(&self) -> channel::Sender<String> {
@@ -24,82 +29,127 @@ impl OrderbookManagementSystem {
             let par_iter = iter.par_bridge();
 
             par_iter.for_each(|s| {
-                dbg!(s);
+                eprintln!("OrderbookManagementSystem: {s}");
             });
         });
 
         tx
     }
 
-    fn register_orderbook(&mut self, symbol: Symbol, backup_index: usize) {
-        println!(
-            "OrderbookManagementSystem: registering orderbook for {} at backup_id {}",
-            symbol, backup_index
-        );
-
-        self.orderbook_map
-            .entry(symbol)
-            .or_insert_with(HashMap::new)
-            .insert(backup_index, Box::new(Orderbook::default()));
-    }
-
-    fn deregister_orderbook(&mut self, symbol: Symbol) {
-        println!(
-            "OrderbookManagementSystem: deregistering orderbook for {}",
-            symbol
-        );
-        self.orderbook_map.remove(&symbol);
+    /// Keep the books of `symbol` on `exchange` registered from now on at the scales of
+    /// `instrument`
+    pub fn register_instrument(
+        &mut self,
+        exchange: Exchange,
+        symbol: Symbol,
+        instrument: Instrument,
+    ) {
+        self.instruments.insert((exchange, symbol), instrument);
     }
 
-    pub fn update_level(&self, orderbook: &mut Orderbook, side: Side, price: f64, quantity: f64) {
-        match side {
-            Side::BUY if quantity > 0.0 => {
-                *orderbook.bids.get_mut(&price.into()).unwrap() = quantity;
+    /// Apply an adapter event to the book it belongs to. Snapshots register the book if needed,
+    /// a [`Event::Resync`] clears the book until the next snapshot arrives.
+    pub fn apply(&mut self, event: &Event, backup_index: usize) {
+        match event {
+            Event::OrderbookSnapshot(snapshot) => {
+                let key = (snapshot.exchange, snapshot.symbol);
+                let registered = self
+                    .orderbook_map
+                    .get(&key)
+                    .is_some_and(|backups| backups.contains_key(&backup_index));
+                if registered {
+                    self.disconnect(key, backup_index);
+                } else {
+                    self.register_orderbook(key, backup_index);
+                }
+                for level in &snapshot.levels {
+                    self.update_orderbook(
+                        (level.exchange, level.symbol),
+                        backup_index,
+                        level.side,
+                        level.is_snapshot,
+                        level.price,
+                        level.quantity,
+                    );
+                }
             }
-            Side::BUY => {
-                orderbook.bids.remove(&price.into());
+            Event::OrderbookUpdate(level) => self.update_orderbook(
+                (level.exchange, level.symbol),
+                backup_index,
+                level.side,
+                level.is_snapshot,
+                level.price,
+                level.quantity,
+            ),
+            Event::Resync(resync) => {
+                self.disconnect((resync.exchange, resync.symbol), backup_index)
             }
-            Side::SELL if quantity > 0.0 => {
-                *orderbook.asks.get_mut(&price.into()).unwrap() = quantity;
+            Event::Connection(status) if !status.connected => {
+                self.disconnect((status.exchange, status.symbol), backup_index)
             }
-            Side::SELL => {
-                orderbook.asks.remove(&price.into());
+            // The book missed updates
+            Event::Lagged(lagged) if lagged.data_type == DataTypes::Book => {
+                self.disconnect((lagged.exchange, lagged.symbol), backup_index)
             }
+            Event::Connection(_) | Event::Lagged(_) | Event::Trade(_) => {}
         }
     }
 
+    fn register_orderbook(&mut self, key: (Exchange, Symbol), backup_index: usize) {
+        let (exchange, symbol) = key;
+        eprintln!(
+            "OrderbookManagementSystem: registering orderbook for {exchange} {symbol} at backup_id {backup_index}"
+        );
+
+        let orderbook = self
+            .instruments
+            .get(&key)
+            .map_or_else(Orderbook::default, Orderbook::new);
+        self.orderbook_map
+            .entry(key)
+            .or_default()
+            .insert(backup_index, Box::new(orderbook));
+    }
+
+    /// Drop every book of `key`
+    pub fn deregister_orderbook(&mut self, key: (Exchange, Symbol)) {
+        let (exchange, symbol) = key;
+        eprintln!("OrderbookManagementSystem: deregistering orderbook for {exchange} {symbol}");
+        self.orderbook_map.remove(&key);
+    }
+
+    pub fn update_level(
+        &self,
+        orderbook: &mut Orderbook,
+        side: Side,
+        price: Decimal,
+        quantity: Decimal,
+    ) {
+        orderbook.set_level(side, price, quantity);
+    }
+
     fn update_orderbook(
         &mut self,
-        symbol: Symbol,
+        key: (Exchange, Symbol),
         backup_index: usize,
         side: Side,
         is_snapshot: bool,
-        price: f64,
-        quantity: f64,
+        price: Decimal,
+        quantity: Decimal,
     ) {
-        if let Some(backup_map) = self.orderbook_map.get_mut(&symbol) {
+        if let Some(backup_map) = self.orderbook_map.get_mut(&key) {
             if let Some(orderbook) = backup_map.get_mut(&backup_index) {
-                if (orderbook.is_snap && !is_snapshot) || (!orderbook.is_snap && is_snapshot) {
-                    // Update snapshot status
-                    orderbook.is_snap = is_snapshot;
-                    // Clear bids and asks
+                if !orderbook.is_snap && is_snapshot {
+                    // A new snapshot replaces the book
                     orderbook.bids.clear();
                     orderbook.asks.clear();
                 }
-
-                match side {
-                    Side::BUY if quantity > 0.0 => {
-                        *orderbook.bids.get_mut(&price.into()).unwrap() = quantity;
-                    }
-                    Side::BUY => {
-                        orderbook.bids.remove(&price.into());
-                    }
-                    Side::SELL if quantity > 0.0 => {
-                        *orderbook.asks.get_mut(&price.into()).unwrap() = quantity;
-                    }
-                    Side::SELL => {
-                        orderbook.asks.remove(&price.into());
-                    }
+                orderbook.is_snap = is_snapshot;
+                if !orderbook.set_level(side, price, quantity) {
+                    let (exchange, symbol) = key;
+                    eprintln!(
+                        "OrderbookManagementSystem: dropped {exchange} {symbol} level at {price}, off the tick size or out of the decimal range"
+                    );
                 }
             }
         } else {
@@ -107,18 +157,21 @@ impl OrderbookManagementSystem {
         }
     }
 
-    fn snapshot(&self, symbol: Symbol, backup_index: usize) -> Option<Snapshot> {
-        if let Some(backup_map) = self.orderbook_map.get(&symbol) {
+    /// Levels of the book of `key` kept for `backup_index`, bids then asks from the best price
+    pub fn snapshot(&self, key: (Exchange, Symbol), backup_index: usize) -> Option<Snapshot> {
+        if let Some(orderbook) = self
+            .orderbook_map
+            .get(&key)
+            .and_then(|backup_map| backup_map.get(&backup_index))
+        {
             let mut message = Snapshot::default();
 
-            for (price, quantity) in &backup_map[&backup_index].bids {
-                println!("Bids: {:?}", (price, quantity));
-                message.bids.push((**price, *quantity));
+            for (price, quantity) in &orderbook.bids {
+                message.bids.push((*price, *quantity));
             }
 
-            for (price, quantity) in &backup_map[&backup_index].asks {
-                println!("Asks: {:?}", (price, quantity));
-                message.asks.push((**price, *quantity));
+            for (price, quantity) in &orderbook.asks {
+                message.asks.push((*price, *quantity));
             }
 
             return Some(message);
@@ -127,13 +180,194 @@ impl OrderbookManagementSystem {
         // TODO: handle request for snapshot for unknown orderbook
     }
 
-    fn disconnect(&mut self, symbol: Symbol, backup_id: usize) {
-        println!("OrderbookManagementSystem: clearing invalid orderbooks");
-        if let Some(backup_map) = self.orderbook_map.get_mut(&symbol) {
-            backup_map.get_mut(&backup_id).map(|orderbook| {
+    fn disconnect(&mut self, key: (Exchange, Symbol), backup_id: usize) {
+        let (exchange, symbol) = key;
+        eprintln!("OrderbookManagementSystem: clearing orderbook for {exchange} {symbol}");
+        if let Some(backup_map) = self.orderbook_map.get_mut(&key) {
+            if let Some(orderbook) = backup_map.get_mut(&backup_id) {
                 orderbook.bids.clear();
                 orderbook.asks.clear();
-            });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::event::{OrderbookSnapshot, OrderbookUpdate, Resync};
+
+    fn btc_usdt() -> (Exchange, Symbol) {
+        (Exchange::Okx, "BTC-USDT".into())
+    }
+
+    fn level(side: Side, price: &str, quantity: &str, is_snapshot: bool) -> OrderbookUpdate {
+        OrderbookUpdate {
+            exchange: Exchange::Okx,
+            symbol: "BTC-USDT".into(),
+            side,
+            price: price.parse().unwrap(),
+            quantity: quantity.parse().unwrap(),
+            is_snapshot,
+            ..Default::default()
+        }
+    }
+
+    /// Levels as the exchange's text
+    fn text<T: ToString>(levels: &[(T, T)]) -> Vec<(String, String)> {
+        levels
+            .iter()
+            .map(|(price, quantity)| (price.to_string(), quantity.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_events() {
+        let mut system = OrderbookManagementSystem::new();
+        let snapshot = Event::OrderbookSnapshot(OrderbookSnapshot {
+            exchange: Exchange::Okx,
+            symbol: "BTC-USDT".into(),
+            levels: vec![
+                level(Side::BUY, "100", "1", true),
+                level(Side::SELL, "101", "2", true),
+            ],
+            ..Default::default()
+        });
+
+        system.apply(&snapshot, 0);
+        system.apply(
+            &Event::OrderbookUpdate(level(Side::BUY, "99.5", "3", false)),
+            0,
+        );
+        system.apply(
+            &Event::OrderbookUpdate(level(Side::SELL, "101.0", "0", false)),
+            0,
+        );
+
+        let book = system.snapshot(btc_usdt(), 0).unwrap();
+        assert_eq!(text(&book.bids), text(&[("99.5", "3"), ("100", "1")]));
+        assert!(book.asks.is_empty());
+
+        // A second snapshot replaces rather than merges
+        system.apply(&snapshot, 0);
+        let book = system.snapshot(btc_usdt(), 0).unwrap();
+        assert_eq!(text(&book.bids), text(&[("100", "1")]));
+        assert_eq!(text(&book.asks), text(&[("101", "2")]));
+
+        let resync = Event::Resync(Resync {
+            exchange: Exchange::Okx,
+            symbol: "BTC-USDT".into(),
+            ..Default::default()
+        });
+        system.apply(&resync, 0);
+        let book = system.snapshot(btc_usdt(), 0).unwrap();
+        assert!(book.bids.is_empty() && book.asks.is_empty());
+    }
+
+    #[test]
+    fn test_instrument_scales() {
+        let mut system = OrderbookManagementSystem::new();
+        let instrument: Instrument = serde_json::from_value(serde_json::json!({
+            "exchange": "okx",
+            "symbol": "BTC-USDT",
+            "type": "Spot",
+            "min_price": "0.1",
+            "min_quantity": "0.00000001",
+            "contract_mult": 1.0,
+        }))
+        .unwrap();
+        system.register_instrument(Exchange::Okx, "BTC-USDT".into(), instrument);
+
+        let snapshot = Event::OrderbookSnapshot(OrderbookSnapshot {
+            exchange: Exchange::Okx,
+            symbol: "BTC-USDT".into(),
+            levels: vec![
+                level(Side::BUY, "30460.1", "0.0010244", true),
+                level(Side::BUY, "30460", "0.5", true),
+            ],
+            ..Default::default()
+        });
+        system.apply(&snapshot, 0);
+
+        // Deleted by a price sent with another number of digits
+        system.apply(
+            &Event::OrderbookUpdate(level(Side::BUY, "30460.10", "0", false)),
+            0,
+        );
+        let book = system.snapshot(btc_usdt(), 0).unwrap();
+        assert_eq!(text(&book.bids), text(&[("30460.0", "0.5")]));
+    }
+
+    #[test]
+    fn test_off_tick_prices() {
+        let mut system = OrderbookManagementSystem::new();
+        for (symbol, tick) in [("BTC-USDT", "0.5"), ("ETH-USDT", "25")] {
+            let instrument: Instrument = serde_json::from_value(serde_json::json!({
+                "exchange": "okx",
+                "symbol": symbol,
+                "type": "Spot",
+                "min_price": tick,
+                "min_quantity": "1",
+                "contract_mult": 1.0,
+            }))
+            .unwrap();
+            system.register_instrument(Exchange::Okx, symbol.into(), instrument);
         }
+        let snapshot = |symbol: &str, prices: [&str; 2]| {
+            Event::OrderbookSnapshot(OrderbookSnapshot {
+                exchange: Exchange::Okx,
+                symbol: symbol.into(),
+                levels: prices
+                    .into_iter()
+                    .map(|price| OrderbookUpdate {
+                        symbol: symbol.into(),
+                        ..level(Side::BUY, price, "1", true)
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+        };
+
+        // Neither rounded onto the level next to it
+        system.apply(&snapshot("BTC-USDT", ["100.5", "100.3"]), 0);
+        system.apply(&snapshot("ETH-USDT", ["100", "110"]), 0);
+        let book = system.snapshot(btc_usdt(), 0).unwrap();
+        assert_eq!(text(&book.bids), text(&[("100.5", "1")]));
+        let book = system
+            .snapshot((Exchange::Okx, "ETH-USDT".into()), 0)
+            .unwrap();
+        assert_eq!(text(&book.bids), text(&[("100", "1")]));
+    }
+
+    #[test]
+    fn test_books_per_exchange() {
+        let mut system = OrderbookManagementSystem::new();
+        let snapshot = |exchange| {
+            Event::OrderbookSnapshot(OrderbookSnapshot {
+                exchange,
+                symbol: "BTC-USDT".into(),
+                levels: vec![OrderbookUpdate {
+                    exchange,
+                    ..level(Side::BUY, "100", "1", true)
+                }],
+                ..Default::default()
+            })
+        };
+        system.apply(&snapshot(Exchange::Okx), 0);
+        system.apply(&snapshot(Exchange::KuCoin), 0);
+
+        // Clearing one exchange's book leaves the other's alone
+        let resync = Event::Resync(Resync {
+            exchange: Exchange::KuCoin,
+            symbol: "BTC-USDT".into(),
+            ..Default::default()
+        });
+        system.apply(&resync, 0);
+        let book = system.snapshot(btc_usdt(), 0).unwrap();
+        assert_eq!(text(&book.bids), text(&[("100", "1")]));
+        let book = system
+            .snapshot((Exchange::KuCoin, "BTC-USDT".into()), 0)
+            .unwrap();
+        assert!(book.bids.is_empty());
     }
 }
@@ -0,0 +1,315 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::Deserialize;
+
+#[cfg(test)]
+const RAW_AGG_TRADE: &str = r#"
+        {
+            "stream": "btcusdt@aggTrade",
+            "data": {
+                "e": "aggTrade",
+                "E": 1688085963425,
+                "s": "BTCUSDT",
+                "a": 1861244612,
+                "p": "30460.10",
+                "q": "0.025",
+                "f": 3964584830,
+                "l": 3964584831,
+                "T": 1688085963272,
+                "m": true
+            }
+        }"#;
+
+#[cfg(test)]
+const RAW_DEPTH: &str = r#"
+        {
+            "stream": "btcusdt@depth@100ms",
+            "data": {
+                "e": "depthUpdate",
+                "E": 1688060541909,
+                "T": 1688060541905,
+                "s": "BTCUSDT",
+                "U": 2951374932371,
+                "u": 2951374940390,
+                "pu": 2951374932362,
+                "b": [
+                    ["30545.00", "5.102"],
+                    ["30544.90", "0.000"]
+                ],
+                "a": [
+                    ["30557.30", "0.000"]
+                ]
+            }
+        }"#;
+
+/// A frame of the combined stream endpoint, `{"stream":"btcusdt@aggTrade","data":{..}}`
+#[derive(Deserialize)]
+pub struct BinanceStream {
+    pub stream: String,
+    pub data: BinanceData,
+}
+
+/// Every market stream we decode, tagged by its `e` field
+#[derive(Deserialize)]
+#[serde(tag = "e")]
+pub enum BinanceData {
+    #[serde(rename = "aggTrade")]
+    AggTrade(AggTradeRaw),
+    #[serde(rename = "depthUpdate")]
+    Depth(DepthUpdateRaw),
+    #[serde(rename = "bookTicker")]
+    BookTicker(BookTickerRaw),
+}
+
+/// Answer to a `SUBSCRIBE` or `UNSUBSCRIBE` request. Either `{"result":null,"id":1}` or an error,
+/// sent as `{"code":2,"msg":"..","id":1}` or nested under `error`. Errors about unparseable
+/// requests carry no id.
+#[derive(Deserialize, Debug)]
+pub struct BinanceReply {
+    pub id: Option<u64>,
+    pub code: Option<i64>,
+    pub msg: Option<String>,
+    pub error: Option<BinanceError>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BinanceError {
+    pub code: i64,
+    pub msg: String,
+}
+
+impl BinanceReply {
+    /// Code and message if the request was rejected
+    pub fn error(self) -> Option<(String, String)> {
+        match (self.error, self.code) {
+            (Some(error), _) => Some((error.code.to_string(), error.msg)),
+            (None, Some(code)) => Some((code.to_string(), self.msg.unwrap_or_default())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Anything the websocket sends
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum BinanceFrame {
+    Data(BinanceStream),
+    Reply(BinanceReply),
+}
+
+#[derive(Deserialize)]
+pub struct AggTradeRaw {
+    #[serde(rename = "s")]
+    pub symbol: Symbol,
+    #[serde(rename = "p")]
+    pub price: Decimal,
+    #[serde(rename = "q")]
+    pub quantity: Decimal,
+    /// The buyer was the maker, so the taker sold
+    #[serde(rename = "m")]
+    pub buyer_is_maker: bool,
+    #[serde(rename = "a")]
+    pub agg_trade_id: u64,
+    #[serde(rename = "T")]
+    pub trade_time: u64,
+}
+
+impl AggTradeRaw {
+    pub fn into_trade(self, exchange: Exchange) -> event::Trade {
+        event::Trade {
+            exchange,
+            symbol: self.symbol,
+            side: if self.buyer_is_maker {
+                Side::SELL
+            } else {
+                Side::BUY
+            },
+            price: self.price,
+            quantity: self.quantity,
+            trade_id: self.agg_trade_id.into(),
+            ts: event::Timestamps::millis(self.trade_time),
+        }
+    }
+}
+
+/// Diff of the `depth` stream. `U` and `u` are the first and last update id it covers, `pu` the
+/// `u` of the diff before it.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DepthUpdateRaw {
+    #[serde(rename = "s")]
+    pub symbol: Symbol,
+    #[serde(rename = "E")]
+    pub event_time: u64,
+    #[serde(rename = "U")]
+    pub first_update_id: i64,
+    #[serde(rename = "u")]
+    pub final_update_id: i64,
+    #[serde(rename = "pu")]
+    pub prev_final_update_id: i64,
+    #[serde(rename = "b")]
+    pub bids: Vec<Level>,
+    #[serde(rename = "a")]
+    pub asks: Vec<Level>,
+}
+
+impl DepthUpdateRaw {
+    /// One [`event::OrderbookUpdate`] per level
+    pub fn into_events(self, exchange: Exchange) -> Vec<event::Event> {
+        let sequence = u64::try_from(self.final_update_id).ok();
+        let ts = event::Timestamps::millis(self.event_time);
+        levels(
+            exchange,
+            self.symbol,
+            self.asks,
+            self.bids,
+            false,
+            sequence,
+            ts,
+        )
+        .map(event::Event::OrderbookUpdate)
+        .collect()
+    }
+}
+
+/// Answer of the REST `depth` endpoint the diffs are applied on top of
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DepthSnapshotRaw {
+    pub last_update_id: i64,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+impl DepthSnapshotRaw {
+    /// The spot endpoint doesn't say when the book was taken
+    pub fn into_event(self, exchange: Exchange, symbol: Symbol) -> event::Event {
+        let sequence = u64::try_from(self.last_update_id).ok();
+        let ts = event::Timestamps::default();
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange,
+            symbol,
+            levels: levels(exchange, symbol, self.asks, self.bids, true, sequence, ts).collect(),
+            sequence,
+            ts,
+        })
+    }
+}
+
+/// Best bid and ask, pushed whenever either changes
+#[derive(Deserialize)]
+pub struct BookTickerRaw {
+    #[serde(rename = "s")]
+    pub symbol: Symbol,
+    #[serde(rename = "b")]
+    pub bid_price: Decimal,
+    #[serde(rename = "B")]
+    pub bid_quantity: Decimal,
+    #[serde(rename = "a")]
+    pub ask_price: Decimal,
+    #[serde(rename = "A")]
+    pub ask_quantity: Decimal,
+    #[serde(rename = "u")]
+    pub update_id: u64,
+    /// Only futures streams say when
+    #[serde(rename = "E")]
+    pub event_time: Option<u64>,
+}
+
+impl BookTickerRaw {
+    /// A two level [`event::OrderbookSnapshot`]
+    pub fn into_event(self, exchange: Exchange) -> event::Event {
+        let ask = Level {
+            price: self.ask_price,
+            quantity: self.ask_quantity,
+        };
+        let bid = Level {
+            price: self.bid_price,
+            quantity: self.bid_quantity,
+        };
+        let sequence = Some(self.update_id);
+        let ts = self
+            .event_time
+            .map_or_else(event::Timestamps::default, event::Timestamps::millis);
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange,
+            levels: levels(
+                exchange,
+                self.symbol,
+                vec![ask],
+                vec![bid],
+                true,
+                sequence,
+                ts,
+            )
+            .collect(),
+            symbol: self.symbol,
+            sequence,
+            ts,
+        })
+    }
+}
+
+#[test]
+fn test_agg_trade() {
+    let Ok(BinanceFrame::Data(frame)) = serde_json::from_str(RAW_AGG_TRADE) else {
+        panic!("Expected a data frame");
+    };
+    assert_eq!(frame.stream, "btcusdt@aggTrade");
+    let BinanceData::AggTrade(raw) = frame.data else {
+        panic!("Expected an aggTrade");
+    };
+
+    let trade = raw.into_trade(Exchange::BinanceUsdm);
+    assert_eq!(trade.symbol, "BTCUSDT");
+    assert!(matches!(trade.side, Side::SELL));
+    assert_eq!(trade.price, "30460.1".parse::<Decimal>().unwrap());
+    assert_eq!(trade.quantity, "0.025".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "1861244612");
+    assert_eq!(trade.ts.exchange, Some(1688085963272000));
+}
+
+#[test]
+fn test_depth_update() {
+    let Ok(BinanceFrame::Data(frame)) = serde_json::from_str(RAW_DEPTH) else {
+        panic!("Expected a data frame");
+    };
+    let BinanceData::Depth(raw) = frame.data else {
+        panic!("Expected a depthUpdate");
+    };
+    assert_eq!(raw.prev_final_update_id, 2951374932362);
+
+    let events = raw.into_events(Exchange::BinanceUsdm);
+    assert_eq!(events.len(), 3);
+    let event::Event::OrderbookUpdate(first) = &events[0] else {
+        panic!("Expected an orderbook update, got {:?}", events[0]);
+    };
+    assert!(matches!(first.side, Side::SELL));
+    assert!(first.quantity.is_zero());
+    assert!(!first.is_snapshot);
+    assert_eq!(first.sequence, Some(2951374940390));
+    assert_eq!(first.ts.exchange, Some(1688060541909000));
+}
+
+#[test]
+fn test_replies() {
+    let ack = r#"{"result":null,"id":1}"#;
+    let Ok(BinanceFrame::Reply(reply)) = serde_json::from_str(ack) else {
+        panic!("Expected a reply");
+    };
+    assert_eq!(reply.id, Some(1));
+    assert!(reply.error().is_none());
+
+    let nested = r#"{"error":{"code":2,"msg":"Invalid request"},"id":2}"#;
+    let Ok(BinanceFrame::Reply(reply)) = serde_json::from_str(nested) else {
+        panic!("Expected a reply");
+    };
+    assert_eq!(reply.error(), Some(("2".into(), "Invalid request".into())));
+
+    let flat = r#"{"code":3,"msg":"Invalid JSON"}"#;
+    let Ok(BinanceFrame::Reply(reply)) = serde_json::from_str(flat) else {
+        panic!("Expected a reply");
+    };
+    assert_eq!(reply.id, None);
+    assert_eq!(reply.error(), Some(("3".into(), "Invalid JSON".into())));
+}
@@ -0,0 +1,415 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+const RAW_BOOK_SNAPSHOT: &str = r#"[
+        17082,
+        [
+            [7254.7, 3, 3.3],
+            [7254.6, 2, 1.5],
+            [7254.8, 1, -0.25],
+            [7254.9, 4, -0.03]
+        ]
+    ]"#;
+
+/// Bitfinex trading symbol of `symbol`, `tBTCUSD` for `BTC-USD`, `BTC/USD`, `btcusd` or
+/// `tBTCUSD`. Assets longer than three letters are joined with a colon, `tTESTBTC:TESTUSD`.
+pub fn trading_symbol(symbol: &str) -> String {
+    let symbol = match symbol.strip_prefix('t') {
+        Some(rest) if rest.chars().next().is_some_and(|c| c.is_ascii_uppercase()) => rest,
+        _ => symbol,
+    };
+    let assets: Vec<String> = symbol
+        .split(['/', '-', '_', ':'])
+        .map(str::to_uppercase)
+        .collect();
+    match assets.as_slice() {
+        [base, quote] if base.len() == 3 && quote.len() == 3 => format!("t{base}{quote}"),
+        [base, quote] => format!("t{base}:{quote}"),
+        _ => format!("t{}", assets.concat()),
+    }
+}
+
+/// Symbol events carry, the trading symbol without its `t`
+pub fn pair(trading_symbol: &str) -> &str {
+    trading_symbol.strip_prefix('t').unwrap_or(trading_symbol)
+}
+
+/// `{"event":"subscribed","channel":"book","chanId":10092,"symbol":"tBTCUSD",..}` and every other
+/// answer or notice that isn't channel data. Errors echo the `channel` and `symbol` of the
+/// request they answer.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BitfinexEvent {
+    pub event: String,
+    pub channel: Option<String>,
+    pub chan_id: Option<u64>,
+    pub symbol: Option<String>,
+    pub code: Option<i64>,
+    pub msg: Option<String>,
+}
+
+/// A channel frame, `[chanId, ..]`
+#[derive(Debug)]
+pub struct ChannelFrame {
+    pub chan_id: u64,
+    pub data: ChannelData,
+}
+
+/// What follows the channel id
+#[derive(Debug)]
+pub enum ChannelData {
+    /// `hb`, sent every 15 seconds on a quiet channel
+    Heartbeat,
+    /// `cs`, CRC32 of the top of the book, sent when the `conf` flag 131072 is set
+    Checksum(i32),
+    /// `te`, a trade as it executes
+    Executed(TradeRaw),
+    /// `tu`, the same trade once it settled
+    Updated(TradeRaw),
+    /// A snapshot or an update, decoded by the channel it arrived on
+    Data(Box<RawValue>),
+}
+
+impl<'de> Deserialize<'de> for ChannelFrame {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let parts = Vec::<Box<RawValue>>::deserialize(deserializer)?;
+        let mut parts = parts.into_iter();
+        let (Some(chan_id), Some(first)) = (parts.next(), parts.next()) else {
+            return Err(de::Error::custom("Expected [chanId, data]"));
+        };
+        let chan_id = chan_id.get().parse().map_err(de::Error::custom)?;
+        fn payload<'a, T: Deserialize<'a>, E: de::Error>(
+            part: Option<&'a RawValue>,
+        ) -> Result<T, E> {
+            let part = part.ok_or_else(|| E::custom("Expected a payload"))?;
+            serde_json::from_str(part.get()).map_err(E::custom)
+        }
+        let second = parts.next();
+        let second = second.as_deref();
+
+        let data = match first.get() {
+            r#""hb""# => ChannelData::Heartbeat,
+            r#""cs""# => ChannelData::Checksum(payload(second)?),
+            r#""te""# => ChannelData::Executed(payload(second)?),
+            r#""tu""# => ChannelData::Updated(payload(second)?),
+            _ => ChannelData::Data(first),
+        };
+        Ok(Self { chan_id, data })
+    }
+}
+
+/// `[ID, MTS, AMOUNT, PRICE]`. A positive amount was bought by the taker, a negative one sold.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TradeRaw(pub i64, pub i64, pub Decimal, pub Decimal);
+
+impl TradeRaw {
+    pub fn id(&self) -> i64 {
+        self.0
+    }
+
+    pub fn into_trade(self, symbol: Symbol) -> event::Trade {
+        let TradeRaw(id, mts, amount, price) = self;
+        event::Trade {
+            exchange: Exchange::Bitfinex,
+            symbol,
+            side: if amount.is_sign_negative() {
+                Side::SELL
+            } else {
+                Side::BUY
+            },
+            price,
+            quantity: amount.abs(),
+            trade_id: u64::try_from(id)
+                .map_or_else(|_| event::TradeId::default(), event::TradeId::from),
+            ts: u64::try_from(mts)
+                .map_or_else(|_| event::Timestamps::default(), event::Timestamps::millis),
+        }
+    }
+}
+
+/// `[BID, BID_SIZE, ASK, ASK_SIZE, ..]` of the `ticker` channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TickerRaw {
+    pub bid: Decimal,
+    pub bid_size: Decimal,
+    pub ask: Decimal,
+    pub ask_size: Decimal,
+}
+
+impl<'de> Deserialize<'de> for TickerRaw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = Vec::<Option<Decimal>>::deserialize(deserializer)?;
+        let value = |i: usize| {
+            values
+                .get(i)
+                .copied()
+                .flatten()
+                .ok_or_else(|| de::Error::custom(format!("Missing ticker field {i}")))
+        };
+        Ok(Self {
+            bid: value(0)?,
+            bid_size: value(1)?,
+            ask: value(2)?,
+            ask_size: value(3)?,
+        })
+    }
+}
+
+impl TickerRaw {
+    /// A two level [`event::OrderbookSnapshot`]. Tickers say neither when nor in which order.
+    pub fn into_event(self, symbol: Symbol) -> event::Event {
+        let ts = event::Timestamps::default();
+        let ask = Level {
+            price: self.ask,
+            quantity: self.ask_size,
+        };
+        let bid = Level {
+            price: self.bid,
+            quantity: self.bid_size,
+        };
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Bitfinex,
+            levels: levels(
+                Exchange::Bitfinex,
+                symbol,
+                vec![ask],
+                vec![bid],
+                true,
+                None,
+                ts,
+            )
+            .collect(),
+            symbol,
+            sequence: None,
+            ts,
+        })
+    }
+}
+
+/// One `[PRICE, COUNT, AMOUNT]` entry of a P0 book. Bids have a positive amount, asks a negative
+/// one, and a count of `0` removes the level.
+///
+/// The text of price and amount is kept next to the parsed numbers since the checksum is computed
+/// over it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelRaw {
+    pub price: String,
+    pub count: u64,
+    pub amount: String,
+    pub px: Decimal,
+    pub amt: Decimal,
+}
+
+impl<'de> Deserialize<'de> for LevelRaw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let [price, count, amount] = <[Box<RawValue>; 3]>::deserialize(deserializer)?;
+        let price = price.get().to_string();
+        let amount = amount.get().to_string();
+        Ok(Self {
+            px: price.parse().map_err(de::Error::custom)?,
+            count: count.get().parse().map_err(de::Error::custom)?,
+            amt: amount.parse().map_err(de::Error::custom)?,
+            price,
+            amount,
+        })
+    }
+}
+
+impl LevelRaw {
+    pub fn side(&self) -> Side {
+        if self.amt.is_sign_negative() || self.amt.is_zero() {
+            Side::SELL
+        } else {
+            Side::BUY
+        }
+    }
+
+    /// The `conf` handshake only asks for checksums, so levels say neither when nor in which
+    /// order
+    pub fn into_update(self, symbol: Symbol, is_snapshot: bool) -> event::OrderbookUpdate {
+        event::OrderbookUpdate {
+            exchange: Exchange::Bitfinex,
+            symbol,
+            side: self.side(),
+            price: self.px,
+            quantity: match self.count {
+                0 => Decimal::ZERO,
+                _ => self.amt.abs(),
+            },
+            is_snapshot,
+            sequence: None,
+            ts: event::Timestamps::default(),
+        }
+    }
+}
+
+/// Data of a `book` channel, the whole book on subscribe and one level after that
+#[derive(Debug)]
+pub enum BookRaw {
+    Snapshot(Vec<LevelRaw>),
+    Update(LevelRaw),
+}
+
+impl<'de> Deserialize<'de> for BookRaw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Box::<RawValue>::deserialize(deserializer)?;
+        match serde_json::from_str(raw.get()) {
+            Ok(levels) => Ok(BookRaw::Snapshot(levels)),
+            Err(_) => serde_json::from_str(raw.get())
+                .map(BookRaw::Update)
+                .map_err(de::Error::custom),
+        }
+    }
+}
+
+impl BookRaw {
+    /// A snapshot becomes a single [`event::OrderbookSnapshot`], an update a single
+    /// [`event::OrderbookUpdate`]
+    pub fn into_event(self, symbol: Symbol) -> event::Event {
+        match self {
+            BookRaw::Snapshot(levels) => {
+                event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+                    exchange: Exchange::Bitfinex,
+                    symbol,
+                    levels: levels
+                        .into_iter()
+                        .map(|level| level.into_update(symbol, true))
+                        .collect(),
+                    sequence: None,
+                    ts: event::Timestamps::default(),
+                })
+            }
+            BookRaw::Update(level) => {
+                event::Event::OrderbookUpdate(level.into_update(symbol, false))
+            }
+        }
+    }
+}
+
+/// Number of levels per side Bitfinex includes in the book checksum
+const CHECKSUM_DEPTH: usize = 25;
+
+/// Local copy of a P0 book, kept in the exchange's text so it can be verified against every `cs`
+#[derive(Default, Debug)]
+pub struct BitfinexBook {
+    bids: BTreeMap<Decimal, LevelRaw>,
+    asks: BTreeMap<Decimal, LevelRaw>,
+    /// Cleared on a checksum mismatch, set again by the next snapshot
+    pub valid: bool,
+}
+
+impl BitfinexBook {
+    pub fn apply(&mut self, data: &BookRaw) {
+        let levels = match data {
+            BookRaw::Snapshot(levels) => {
+                self.bids.clear();
+                self.asks.clear();
+                self.valid = true;
+                levels.as_slice()
+            }
+            BookRaw::Update(level) => std::slice::from_ref(level),
+        };
+
+        for level in levels {
+            let book = match level.side() {
+                Side::BUY => &mut self.bids,
+                Side::SELL => &mut self.asks,
+            };
+            if level.count == 0 {
+                book.remove(&level.px);
+            } else {
+                book.insert(level.px, level.clone());
+            }
+        }
+    }
+
+    /// CRC32 over the best 25 bids and asks interleaved as `bid:amount:ask:amount:...`, asks with
+    /// their negative amount. When one side runs out the other goes on alone.
+    pub fn checksum(&self) -> i32 {
+        let mut bids = self.bids.values().rev().take(CHECKSUM_DEPTH);
+        let mut asks = self.asks.values().take(CHECKSUM_DEPTH);
+        let mut parts: Vec<&str> = Vec::with_capacity(CHECKSUM_DEPTH * 4);
+
+        loop {
+            let (bid, ask) = (bids.next(), asks.next());
+            if bid.is_none() && ask.is_none() {
+                break;
+            }
+            for level in [bid, ask].into_iter().flatten() {
+                parts.push(&level.price);
+                parts.push(&level.amount);
+            }
+        }
+
+        crc32fast::hash(parts.join(":").as_bytes()) as i32
+    }
+
+    /// Compare against a `cs` frame. A mismatch marks the book invalid.
+    pub fn verify(&mut self, checksum: i32) -> bool {
+        self.valid &= self.checksum() == checksum;
+        self.valid
+    }
+}
+
+#[test]
+fn test_channel_frames() {
+    let frame: ChannelFrame = serde_json::from_str(r#"[17470,"hb"]"#).unwrap();
+    assert_eq!(frame.chan_id, 17470);
+    assert!(matches!(frame.data, ChannelData::Heartbeat));
+
+    let frame: ChannelFrame =
+        serde_json::from_str(r#"[17470,"te",[401597395,1574694478808,-0.005,7245.3]]"#).unwrap();
+    let ChannelData::Executed(trade) = frame.data else {
+        panic!("Expected a te, got {:?}", frame.data);
+    };
+    assert_eq!(trade.id(), 401597395);
+    let trade = trade.into_trade("BTCUSD".into());
+    assert!(matches!(trade.side, Side::SELL));
+    assert_eq!(trade.quantity, "0.005".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "401597395");
+    assert_eq!(trade.ts.exchange, Some(1574694478808000));
+
+    let frame: ChannelFrame = serde_json::from_str(r#"[17082,"cs",-1596519911]"#).unwrap();
+    assert!(matches!(frame.data, ChannelData::Checksum(-1596519911)));
+}
+
+#[test]
+fn test_book_checksum() {
+    let frame: ChannelFrame = serde_json::from_str(RAW_BOOK_SNAPSHOT).unwrap();
+    let ChannelData::Data(data) = frame.data else {
+        panic!("Expected book data, got {:?}", frame.data);
+    };
+    let snapshot: BookRaw = serde_json::from_str(data.get()).unwrap();
+    let mut book = BitfinexBook::default();
+    book.apply(&snapshot);
+
+    let expected = "7254.7:3.3:7254.8:-0.25:7254.6:1.5:7254.9:-0.03";
+    assert!(book.verify(crc32fast::hash(expected.as_bytes()) as i32));
+
+    let update: BookRaw = serde_json::from_str("[7254.8,0,-1]").unwrap();
+    let BookRaw::Update(level) = &update else {
+        panic!("Expected an update, got {update:?}");
+    };
+    let removed = level.clone().into_update("BTCUSD".into(), false);
+    assert!(matches!(removed.side, Side::SELL));
+    assert!(removed.quantity.is_zero());
+
+    book.apply(&update);
+    let expected = "7254.7:3.3:7254.9:-0.03:7254.6:1.5";
+    assert!(book.verify(crc32fast::hash(expected.as_bytes()) as i32));
+    assert!(!book.verify(0));
+}
+
+#[test]
+fn test_trading_symbol() {
+    for symbol in ["BTC-USD", "BTC/USD", "btcusd", "tBTCUSD"] {
+        assert_eq!(trading_symbol(symbol), "tBTCUSD");
+    }
+    assert_eq!(trading_symbol("TESTBTC-TESTUSD"), "tTESTBTC:TESTUSD");
+    assert_eq!(pair("tBTCUSD"), "BTCUSD");
+}
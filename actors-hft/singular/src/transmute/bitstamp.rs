@@ -0,0 +1,179 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::Deserialize;
+use serde_aux::prelude::*;
+use serde_json::value::RawValue;
+
+#[cfg(test)]
+const RAW_TRADE: &str = r#"
+        {
+            "data": {
+                "id": 288436146,
+                "timestamp": "1688085963",
+                "amount": 0.0106,
+                "amount_str": "0.01060000",
+                "price": 30460,
+                "price_str": "30460",
+                "type": 1,
+                "microtimestamp": "1688085963425000",
+                "buy_order_id": 1636781578252288,
+                "sell_order_id": 1636781579808768
+            },
+            "channel": "live_trades_btcusd",
+            "event": "trade"
+        }"#;
+
+#[cfg(test)]
+const RAW_DIFF: &str = r#"
+        {
+            "data": {
+                "timestamp": "1688085963",
+                "microtimestamp": "1688085963425123",
+                "bids": [["30459", "0.50000000"]],
+                "asks": [["30461", "0.00000000"]]
+            },
+            "channel": "diff_order_book_btcusd",
+            "event": "data"
+        }"#;
+
+/// Every frame, `{"event":"trade","channel":"live_trades_btcusd","data":{..}}`. The shape of
+/// `data` depends on the event and channel, it is decoded once they are known.
+#[derive(Deserialize, Debug)]
+pub struct BitstampFrame {
+    pub event: String,
+    #[serde(default)]
+    pub channel: String,
+    pub data: Box<RawValue>,
+}
+
+impl BitstampFrame {
+    /// Decode `data` as `T`
+    pub fn data<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.data.get()).ok()
+    }
+}
+
+/// Data of `bts:error`
+#[derive(Deserialize, Debug)]
+pub struct ErrorRaw {
+    pub code: Option<i64>,
+    pub message: String,
+}
+
+/// Data of a `trade` on `live_trades_{pair}`
+#[derive(Deserialize, Debug)]
+pub struct TradeRaw {
+    pub id: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub microtimestamp: u64,
+    /// Exact text of `price`
+    #[serde(rename = "price_str")]
+    pub price: Decimal,
+    /// Exact text of `amount`
+    #[serde(rename = "amount_str")]
+    pub amount: Decimal,
+    /// `0` when the taker bought, `1` when it sold
+    #[serde(rename = "type")]
+    pub kind: u8,
+}
+
+impl TradeRaw {
+    pub fn into_trade(self, pair: Symbol) -> event::Trade {
+        event::Trade {
+            exchange: Exchange::BitStamp,
+            symbol: pair,
+            side: if self.kind == 0 {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: self.price,
+            quantity: self.amount,
+            trade_id: self.id.into(),
+            ts: event::Timestamps::micros(self.microtimestamp),
+        }
+    }
+}
+
+/// Data of `diff_order_book_{pair}` and `order_book_{pair}`, and the REST order book. Diffs are
+/// ordered by `microtimestamp`, the only thing tying them to the snapshot. An amount of `0`
+/// removes the level.
+#[derive(Deserialize, Debug)]
+pub struct OrderBookRaw {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub microtimestamp: i64,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+}
+
+impl OrderBookRaw {
+    /// A snapshot becomes a single [`event::OrderbookSnapshot`], a diff one
+    /// [`event::OrderbookUpdate`] per level. The `microtimestamp` doubles as their sequence.
+    pub fn into_events(self, pair: Symbol, is_snapshot: bool) -> Vec<event::Event> {
+        let sequence = u64::try_from(self.microtimestamp).ok();
+        let ts = sequence.map_or_else(event::Timestamps::default, event::Timestamps::micros);
+        let levels = levels(
+            Exchange::BitStamp,
+            pair,
+            self.asks,
+            self.bids,
+            is_snapshot,
+            sequence,
+            ts,
+        );
+        if is_snapshot {
+            vec![event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+                exchange: Exchange::BitStamp,
+                symbol: pair,
+                levels: levels.collect(),
+                sequence,
+                ts,
+            })]
+        } else {
+            levels.map(event::Event::OrderbookUpdate).collect()
+        }
+    }
+
+    /// The best level of each side as a two level [`event::OrderbookSnapshot`]
+    pub fn into_bbo(mut self, pair: Symbol) -> event::Event {
+        self.asks.truncate(1);
+        self.bids.truncate(1);
+        let mut events = self.into_events(pair, true);
+        events.remove(0)
+    }
+}
+
+#[test]
+fn test_trade() {
+    let frame: BitstampFrame = serde_json::from_str(RAW_TRADE).unwrap();
+    assert_eq!(frame.event, "trade");
+    let Some(raw) = frame.data::<TradeRaw>() else {
+        panic!("Expected a trade");
+    };
+    let trade = raw.into_trade("btcusd".into());
+    assert!(matches!(trade.side, Side::SELL));
+    assert_eq!(trade.price, "30460.0".parse::<Decimal>().unwrap());
+    assert_eq!(trade.quantity, "0.0106".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "288436146");
+    assert_eq!(trade.ts.exchange, Some(1688085963425000));
+}
+
+#[test]
+fn test_diff() {
+    let frame: BitstampFrame = serde_json::from_str(RAW_DIFF).unwrap();
+    let Some(diff) = frame.data::<OrderBookRaw>() else {
+        panic!("Expected a diff");
+    };
+    assert_eq!(diff.microtimestamp, 1688085963425123);
+
+    let events = diff.into_events("btcusd".into(), false);
+    let [event::Event::OrderbookUpdate(ask), event::Event::OrderbookUpdate(bid)] = &events[..]
+    else {
+        panic!("Expected two orderbook updates, got {events:?}");
+    };
+    assert!(ask.quantity.is_zero());
+    assert!(matches!(bid.side, Side::BUY));
+    assert_eq!(bid.sequence, Some(1688085963425123));
+    assert_eq!(bid.ts.exchange, Some(1688085963425123));
+}
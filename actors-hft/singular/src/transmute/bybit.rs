@@ -0,0 +1,270 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+#[cfg(test)]
+const RAW_ORDERBOOK: &str = r#"
+        {
+            "topic": "orderbook.50.BTCUSDT",
+            "type": "delta",
+            "ts": 1687940967466,
+            "data": {
+                "s": "BTCUSDT",
+                "b": [
+                    ["30247.20", "30.028"],
+                    ["30245.40", "0.224"]
+                ],
+                "a": [
+                    ["30248.70", "0"]
+                ],
+                "u": 177400507,
+                "seq": 66544703342
+            },
+            "cts": 1687940967464
+        }"#;
+
+#[cfg(test)]
+const RAW_TRADE: &str = r#"
+        {
+            "topic": "publicTrade.BTCUSDT",
+            "type": "snapshot",
+            "ts": 1672304486868,
+            "data": [
+                {
+                    "T": 1672304486865,
+                    "s": "BTCUSDT",
+                    "S": "Buy",
+                    "v": "0.001",
+                    "p": "16578.50",
+                    "L": "PlusTick",
+                    "i": "20f43950-d8dd-5b31-9112-a178eb6023af",
+                    "BT": false
+                }
+            ]
+        }"#;
+
+/// A topic push, `{"topic":"orderbook.50.BTCUSDT","type":"delta","data":{..}}`. The shape of
+/// `data` depends on the topic, it is decoded once the topic is known.
+#[derive(Deserialize)]
+pub struct BybitPush {
+    pub topic: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// When Bybit sent the push, in milliseconds
+    pub ts: Option<u64>,
+    pub data: Box<RawValue>,
+}
+
+impl BybitPush {
+    pub fn is_snapshot(&self) -> bool {
+        self.kind.as_deref() == Some("snapshot")
+    }
+
+    pub fn timestamps(&self) -> event::Timestamps {
+        self.ts
+            .map_or_else(event::Timestamps::default, event::Timestamps::millis)
+    }
+
+    /// Decode `data` as `T`
+    pub fn data<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.data.get()).ok()
+    }
+}
+
+/// Answer to an `op`, matched to it by `req_id`.
+/// `{"success":true,"ret_msg":"","conn_id":"..","req_id":"1","op":"subscribe"}`. Pongs come back
+/// the same way on spot and as `{"op":"pong","args":[..]}` on linear.
+#[derive(Deserialize, Debug)]
+pub struct BybitReply {
+    pub op: String,
+    pub success: Option<bool>,
+    #[serde(default)]
+    pub ret_msg: String,
+    pub req_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TradeRaw {
+    #[serde(rename = "s")]
+    pub symbol: Symbol,
+    /// Side of the taker, `Buy` or `Sell`
+    #[serde(rename = "S")]
+    pub side: String,
+    #[serde(rename = "p")]
+    pub price: Decimal,
+    #[serde(rename = "v")]
+    pub quantity: Decimal,
+    #[serde(rename = "i")]
+    pub trade_id: event::TradeId,
+    /// When the trade filled, in milliseconds
+    #[serde(rename = "T")]
+    pub time: u64,
+}
+
+impl From<TradeRaw> for event::Trade {
+    fn from(value: TradeRaw) -> Self {
+        Self {
+            exchange: Exchange::ByBit,
+            symbol: value.symbol,
+            side: if value.side == "Buy" {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: value.price,
+            quantity: value.quantity,
+            trade_id: value.trade_id,
+            ts: event::Timestamps::millis(value.time),
+        }
+    }
+}
+
+/// Data of `orderbook.{depth}.{symbol}`. `u` goes up by one with every delta and starts over at
+/// `1` when Bybit restarts the book, `seq` is shared across all of a symbol's books.
+#[derive(Deserialize, Debug)]
+pub struct OrderbookRaw {
+    #[serde(rename = "s")]
+    pub symbol: Symbol,
+    #[serde(rename = "b")]
+    pub bids: Vec<Level>,
+    #[serde(rename = "a")]
+    pub asks: Vec<Level>,
+    #[serde(rename = "u")]
+    pub update_id: i64,
+    pub seq: i64,
+}
+
+impl OrderbookRaw {
+    /// A snapshot becomes a single [`event::OrderbookSnapshot`], a delta becomes one
+    /// [`event::OrderbookUpdate`] per level. `ts` is that of the push, `data` doesn't carry one.
+    pub fn into_events(self, is_snapshot: bool, ts: event::Timestamps) -> Vec<event::Event> {
+        let sequence = u64::try_from(self.update_id).ok();
+        let levels = levels(
+            Exchange::ByBit,
+            self.symbol,
+            self.asks,
+            self.bids,
+            is_snapshot,
+            sequence,
+            ts,
+        );
+        if is_snapshot {
+            vec![event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+                exchange: Exchange::ByBit,
+                levels: levels.collect(),
+                symbol: self.symbol,
+                sequence,
+                ts,
+            })]
+        } else {
+            levels.map(event::Event::OrderbookUpdate).collect()
+        }
+    }
+}
+
+/// Data of a linear `tickers.{symbol}` push. Deltas only carry the fields that changed.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TickerRaw {
+    pub symbol: Symbol,
+    pub bid1_price: Option<Decimal>,
+    pub bid1_size: Option<Decimal>,
+    pub ask1_price: Option<Decimal>,
+    pub ask1_size: Option<Decimal>,
+}
+
+impl TickerRaw {
+    /// Apply a delta on top of the last known ticker. Returns whether the best bid or offer moved.
+    pub fn merge(&mut self, delta: TickerRaw) -> bool {
+        let before = self.clone();
+        self.symbol = delta.symbol;
+        self.bid1_price = delta.bid1_price.or(self.bid1_price);
+        self.bid1_size = delta.bid1_size.or(self.bid1_size);
+        self.ask1_price = delta.ask1_price.or(self.ask1_price);
+        self.ask1_size = delta.ask1_size.or(self.ask1_size);
+        *self != before
+    }
+
+    /// A two level [`event::OrderbookSnapshot`], once both sides are known. `ts` is that of the
+    /// push that moved it.
+    pub fn to_event(&self, ts: event::Timestamps) -> Option<event::Event> {
+        let ask = Level {
+            price: self.ask1_price?,
+            quantity: self.ask1_size?,
+        };
+        let bid = Level {
+            price: self.bid1_price?,
+            quantity: self.bid1_size?,
+        };
+        Some(event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::ByBit,
+            levels: levels(
+                Exchange::ByBit,
+                self.symbol,
+                vec![ask],
+                vec![bid],
+                true,
+                None,
+                ts,
+            )
+            .collect(),
+            symbol: self.symbol,
+            sequence: None,
+            ts,
+        }))
+    }
+}
+
+#[test]
+fn test_orderbook() {
+    let push: BybitPush = serde_json::from_str(RAW_ORDERBOOK).unwrap();
+    assert!(!push.is_snapshot());
+    let Some(raw) = push.data::<OrderbookRaw>() else {
+        panic!("Expected an orderbook");
+    };
+    assert_eq!(raw.update_id, 177400507);
+
+    let events = raw.into_events(false, push.timestamps());
+    assert_eq!(events.len(), 3);
+    let event::Event::OrderbookUpdate(removed) = &events[0] else {
+        panic!("Expected an orderbook update, got {:?}", events[0]);
+    };
+    assert!(matches!(removed.side, Side::SELL));
+    assert!(removed.quantity.is_zero());
+    assert_eq!(removed.sequence, Some(177400507));
+    assert_eq!(removed.ts.exchange, Some(1687940967466000));
+}
+
+#[test]
+fn test_trade() {
+    let push: BybitPush = serde_json::from_str(RAW_TRADE).unwrap();
+    let Some(mut trades) = push.data::<Vec<TradeRaw>>() else {
+        panic!("Expected trades");
+    };
+    let trade: event::Trade = trades.remove(0).into();
+    assert_eq!(trade.symbol, "BTCUSDT");
+    assert!(matches!(trade.side, Side::BUY));
+    assert_eq!(trade.price, "16578.5".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "20f43950-d8dd-5b31-9112-a178eb6023af");
+    assert_eq!(trade.ts.exchange, Some(1672304486865000));
+}
+
+#[test]
+fn test_ticker_delta() {
+    let mut ticker: TickerRaw = serde_json::from_str(
+        r#"{"symbol":"BTCUSDT","bid1Price":"17215.50","bid1Size":"84.489","ask1Price":"17216.00","ask1Size":"83.020","lastPrice":"17216.00"}"#,
+    )
+    .unwrap();
+    let delta = serde_json::from_str(r#"{"symbol":"BTCUSDT","bid1Size":"80.1"}"#).unwrap();
+    assert!(ticker.merge(delta));
+    assert_eq!(ticker.bid1_size, Some("80.1".parse::<Decimal>().unwrap()));
+    assert_eq!(
+        ticker.ask1_price,
+        Some("17216.0".parse::<Decimal>().unwrap())
+    );
+
+    let unrelated = serde_json::from_str(r#"{"symbol":"BTCUSDT","lastPrice":"17217.00"}"#).unwrap();
+    assert!(!ticker.merge(unrelated));
+}
@@ -0,0 +1,274 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::{de, Deserialize, Deserializer};
+
+#[cfg(test)]
+const RAW_MATCH: &str = r#"
+        {
+            "type": "match",
+            "trade_id": 10,
+            "sequence": 50,
+            "maker_order_id": "ac928c66-ca53-498f-9c13-a110027a60e8",
+            "taker_order_id": "132fb6ae-456b-4654-b4e0-d681ac05cea1",
+            "time": "2014-11-07T08:19:27.028459Z",
+            "product_id": "BTC-USD",
+            "size": "5.23512",
+            "price": "400.23",
+            "side": "sell"
+        }"#;
+
+#[cfg(test)]
+const RAW_L2UPDATE: &str = r#"
+        {
+            "type": "l2update",
+            "product_id": "BTC-USD",
+            "time": "2019-08-14T20:42:27.265Z",
+            "changes": [
+                ["buy", "10101.80000000", "0.162567"],
+                ["sell", "10102.55000000", "0"]
+            ]
+        }"#;
+
+/// Every message of the Coinbase Exchange websocket feed we decode, tagged by `type`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CoinbaseMessage {
+    Match(MatchRaw),
+    /// Full `level2_batch` book, sent once after subscribing
+    Snapshot(SnapshotRaw),
+    L2update(L2UpdateRaw),
+    Ticker(TickerRaw),
+    /// Answer to a `subscribe` or `unsubscribe`, listing every channel still subscribed
+    Subscriptions,
+    Error(ErrorRaw),
+    /// `last_match`, `heartbeat` and everything else we don't stream
+    #[serde(other)]
+    Other,
+}
+
+/// `{"type":"error","message":"Failed to subscribe","reason":"BTC-XXX is not a valid product"}`.
+/// Errors don't say which request they answer.
+#[derive(Deserialize, Debug)]
+pub struct ErrorRaw {
+    pub message: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Deserialize)]
+pub struct MatchRaw {
+    pub product_id: Symbol,
+    pub price: Decimal,
+    pub size: Decimal,
+    /// Side of the maker order, the taker traded the other way
+    pub side: String,
+    pub trade_id: u64,
+    pub time: String,
+}
+
+impl From<MatchRaw> for event::Trade {
+    fn from(value: MatchRaw) -> Self {
+        Self {
+            exchange: Exchange::Coinbase,
+            symbol: value.product_id,
+            side: if value.side == "sell" {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: value.price,
+            quantity: value.size,
+            trade_id: value.trade_id.into(),
+            ts: event::Timestamps::rfc3339(&value.time),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SnapshotRaw {
+    pub product_id: Symbol,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    /// Only sent by the newer feeds
+    pub time: Option<String>,
+}
+
+impl SnapshotRaw {
+    pub fn into_event(self) -> event::Event {
+        let ts = timestamps(self.time.as_deref());
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Coinbase,
+            levels: levels(
+                Exchange::Coinbase,
+                self.product_id,
+                self.asks,
+                self.bids,
+                true,
+                None,
+                ts,
+            )
+            .collect(),
+            symbol: self.product_id,
+            sequence: None,
+            ts,
+        })
+    }
+}
+
+/// One `[side, price, size]` entry of an `l2update`. A size of `0` removes the level.
+#[derive(Clone, Copy, Debug)]
+pub struct ChangeRaw {
+    pub side: Side,
+    pub level: Level,
+}
+
+impl<'de> Deserialize<'de> for ChangeRaw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let [side, price, size] = <[String; 3]>::deserialize(deserializer)?;
+        Ok(Self {
+            side: match side.as_str() {
+                "buy" => Side::BUY,
+                "sell" => Side::SELL,
+                other => return Err(de::Error::custom(format!("Unknown side {other}"))),
+            },
+            level: Level {
+                price: price.parse().map_err(de::Error::custom)?,
+                quantity: size.parse().map_err(de::Error::custom)?,
+            },
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct L2UpdateRaw {
+    pub product_id: Symbol,
+    pub changes: Vec<ChangeRaw>,
+    pub time: String,
+}
+
+impl L2UpdateRaw {
+    /// One [`event::OrderbookUpdate`] per change. `level2_batch` doesn't number its updates.
+    pub fn into_events(self) -> Vec<event::Event> {
+        let symbol = self.product_id;
+        let ts = event::Timestamps::rfc3339(&self.time);
+        self.changes
+            .into_iter()
+            .map(|change| {
+                event::Event::OrderbookUpdate(event::OrderbookUpdate {
+                    exchange: Exchange::Coinbase,
+                    symbol,
+                    side: change.side,
+                    price: change.level.price,
+                    quantity: change.level.quantity,
+                    is_snapshot: false,
+                    sequence: None,
+                    ts,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Last trade and best bid and ask, pushed on every match
+#[derive(Deserialize)]
+pub struct TickerRaw {
+    pub product_id: Symbol,
+    pub best_bid: Decimal,
+    #[serde(default)]
+    pub best_bid_size: Decimal,
+    pub best_ask: Decimal,
+    #[serde(default)]
+    pub best_ask_size: Decimal,
+    pub sequence: Option<u64>,
+    pub time: Option<String>,
+}
+
+impl TickerRaw {
+    /// A two level [`event::OrderbookSnapshot`]
+    pub fn into_event(self) -> event::Event {
+        let ts = timestamps(self.time.as_deref());
+        let ask = Level {
+            price: self.best_ask,
+            quantity: self.best_ask_size,
+        };
+        let bid = Level {
+            price: self.best_bid,
+            quantity: self.best_bid_size,
+        };
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Coinbase,
+            levels: levels(
+                Exchange::Coinbase,
+                self.product_id,
+                vec![ask],
+                vec![bid],
+                true,
+                self.sequence,
+                ts,
+            )
+            .collect(),
+            symbol: self.product_id,
+            sequence: self.sequence,
+            ts,
+        })
+    }
+}
+
+/// Of a `time` that may be missing
+fn timestamps(time: Option<&str>) -> event::Timestamps {
+    time.map_or_else(event::Timestamps::default, event::Timestamps::rfc3339)
+}
+
+#[test]
+fn test_match() {
+    let Ok(CoinbaseMessage::Match(raw)) = serde_json::from_str(RAW_MATCH) else {
+        panic!("Expected a match");
+    };
+    let trade: event::Trade = raw.into();
+    assert_eq!(trade.symbol, "BTC-USD");
+    // The maker sold, so the taker bought
+    assert!(matches!(trade.side, Side::BUY));
+    assert_eq!(trade.price, "400.23".parse::<Decimal>().unwrap());
+    assert_eq!(trade.quantity, "5.23512".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "10");
+    assert_eq!(trade.ts.exchange, Some(1415348367028459));
+}
+
+#[test]
+fn test_l2update() {
+    let Ok(CoinbaseMessage::L2update(raw)) = serde_json::from_str(RAW_L2UPDATE) else {
+        panic!("Expected an l2update");
+    };
+    let events = raw.into_events();
+    assert_eq!(events.len(), 2);
+    let event::Event::OrderbookUpdate(removed) = &events[1] else {
+        panic!("Expected an orderbook update, got {:?}", events[1]);
+    };
+    assert!(matches!(removed.side, Side::SELL));
+    assert_eq!(removed.price, "10102.55".parse::<Decimal>().unwrap());
+    assert!(removed.quantity.is_zero());
+    assert_eq!(removed.ts.exchange, Some(1565815347265000));
+}
+
+#[test]
+fn test_control_messages() {
+    let subscriptions =
+        r#"{"type":"subscriptions","channels":[{"name":"matches","product_ids":["BTC-USD"]}]}"#;
+    assert!(matches!(
+        serde_json::from_str(subscriptions),
+        Ok(CoinbaseMessage::Subscriptions)
+    ));
+
+    let error = r#"{"type":"error","message":"Failed to subscribe","reason":"BTC-XXX is not a valid product"}"#;
+    let Ok(CoinbaseMessage::Error(error)) = serde_json::from_str(error) else {
+        panic!("Expected an error");
+    };
+    assert_eq!(error.reason, "BTC-XXX is not a valid product");
+
+    let heartbeat = r#"{"type":"heartbeat","sequence":90,"last_trade_id":20,"product_id":"BTC-USD","time":"2014-11-07T08:19:28.464459Z"}"#;
+    assert!(matches!(
+        serde_json::from_str(heartbeat),
+        Ok(CoinbaseMessage::Other)
+    ));
+}
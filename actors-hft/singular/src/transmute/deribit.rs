@@ -0,0 +1,269 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+
+#[cfg(test)]
+const RAW_BOOK_CHANGE: &str = r#"
+        {
+            "jsonrpc": "2.0",
+            "method": "subscription",
+            "params": {
+                "channel": "book.BTC-29SEP23-30000-C.100ms",
+                "data": {
+                    "type": "change",
+                    "timestamp": 1554375447971,
+                    "instrument_name": "BTC-29SEP23-30000-C",
+                    "prev_change_id": 297217,
+                    "change_id": 297218,
+                    "bids": [["delete", 0.0245, 0.0]],
+                    "asks": [["new", 0.026, 12.5]]
+                }
+            }
+        }"#;
+
+/// Any JSON-RPC 2.0 frame: the answer to a call, which carries its `id`, or a notification, which
+/// carries a `method`. `params` and `result` are decoded once it is known which it is.
+#[derive(Deserialize, Debug)]
+pub struct DeribitFrame {
+    pub id: Option<u64>,
+    pub method: Option<String>,
+    pub params: Option<Box<RawValue>>,
+    pub result: Option<Box<RawValue>>,
+    pub error: Option<RpcError>,
+}
+
+impl DeribitFrame {
+    /// Decode `params` as `T`
+    pub fn params<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.params.as_ref()?.get()).ok()
+    }
+
+    /// Decode `result` as `T`
+    pub fn result<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.result.as_ref()?.get()).ok()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+/// `params` of a `subscription` notification. `data` depends on the channel.
+#[derive(Deserialize, Debug)]
+pub struct SubscriptionParams {
+    pub channel: String,
+    pub data: Box<RawValue>,
+}
+
+impl SubscriptionParams {
+    /// Decode `data` as `T`
+    pub fn data<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.data.get()).ok()
+    }
+}
+
+/// `params` of a `heartbeat` notification. A `test_request` has to be answered with a call to
+/// `public/test`, anything else is informational.
+#[derive(Deserialize, Debug)]
+pub struct HeartbeatParams {
+    #[serde(rename = "type")]
+    pub kind: String,
+}
+
+impl HeartbeatParams {
+    pub fn is_test_request(&self) -> bool {
+        self.kind == "test_request"
+    }
+}
+
+/// One entry of `trades.{instrument}.100ms`
+#[derive(Deserialize, Debug)]
+pub struct TradeRaw {
+    pub trade_id: event::TradeId,
+    pub instrument_name: Symbol,
+    pub price: Decimal,
+    /// Contracts for futures and options, base currency for spot
+    pub amount: Decimal,
+    /// Side of the taker, `buy` or `sell`
+    pub direction: String,
+    /// In milliseconds
+    pub timestamp: u64,
+}
+
+impl From<TradeRaw> for event::Trade {
+    fn from(value: TradeRaw) -> Self {
+        Self {
+            exchange: Exchange::Deribit,
+            symbol: value.instrument_name,
+            side: if value.direction == "buy" {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: value.price,
+            quantity: value.amount,
+            trade_id: value.trade_id,
+            ts: event::Timestamps::millis(value.timestamp),
+        }
+    }
+}
+
+/// `["new" | "change" | "delete", price, amount]`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct LevelRaw(pub String, pub Decimal, pub Decimal);
+
+impl From<LevelRaw> for Level {
+    fn from(LevelRaw(action, price, amount): LevelRaw) -> Self {
+        Level {
+            price,
+            quantity: if action == "delete" {
+                Decimal::ZERO
+            } else {
+                amount
+            },
+        }
+    }
+}
+
+/// Data of `book.{instrument}.100ms`. Each `change` names the `change_id` it follows in
+/// `prev_change_id`, the `snapshot` sent on subscribe has none.
+#[derive(Deserialize, Debug)]
+pub struct BookRaw {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub instrument_name: Symbol,
+    pub change_id: i64,
+    pub prev_change_id: Option<i64>,
+    pub bids: Vec<LevelRaw>,
+    pub asks: Vec<LevelRaw>,
+    /// In milliseconds
+    pub timestamp: u64,
+}
+
+impl BookRaw {
+    pub fn is_snapshot(&self) -> bool {
+        self.kind == "snapshot"
+    }
+
+    /// A snapshot becomes a single [`event::OrderbookSnapshot`], a change one
+    /// [`event::OrderbookUpdate`] per level, numbered by `change_id`
+    pub fn into_events(self) -> Vec<event::Event> {
+        let is_snapshot = self.is_snapshot();
+        let sequence = u64::try_from(self.change_id).ok();
+        let ts = event::Timestamps::millis(self.timestamp);
+        let side =
+            |levels: Vec<LevelRaw>| -> Vec<Level> { levels.into_iter().map(Level::from).collect() };
+        let levels = levels(
+            Exchange::Deribit,
+            self.instrument_name,
+            side(self.asks),
+            side(self.bids),
+            is_snapshot,
+            sequence,
+            ts,
+        );
+        if is_snapshot {
+            vec![event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+                exchange: Exchange::Deribit,
+                levels: levels.collect(),
+                symbol: self.instrument_name,
+                sequence,
+                ts,
+            })]
+        } else {
+            levels.map(event::Event::OrderbookUpdate).collect()
+        }
+    }
+}
+
+/// Data of `quote.{instrument}`, the best bid and offer
+#[derive(Deserialize, Debug)]
+pub struct QuoteRaw {
+    pub instrument_name: Symbol,
+    pub best_bid_price: Decimal,
+    pub best_bid_amount: Decimal,
+    pub best_ask_price: Decimal,
+    pub best_ask_amount: Decimal,
+    /// In milliseconds
+    pub timestamp: u64,
+}
+
+impl QuoteRaw {
+    /// A two level [`event::OrderbookSnapshot`]
+    pub fn into_event(self) -> event::Event {
+        let ts = event::Timestamps::millis(self.timestamp);
+        let ask = Level {
+            price: self.best_ask_price,
+            quantity: self.best_ask_amount,
+        };
+        let bid = Level {
+            price: self.best_bid_price,
+            quantity: self.best_bid_amount,
+        };
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Deribit,
+            levels: levels(
+                Exchange::Deribit,
+                self.instrument_name,
+                vec![ask],
+                vec![bid],
+                true,
+                None,
+                ts,
+            )
+            .collect(),
+            symbol: self.instrument_name,
+            sequence: None,
+            ts,
+        })
+    }
+}
+
+#[test]
+fn test_book_change() {
+    let frame: DeribitFrame = serde_json::from_str(RAW_BOOK_CHANGE).unwrap();
+    assert_eq!(frame.method.as_deref(), Some("subscription"));
+    let Some(params) = frame.params::<SubscriptionParams>() else {
+        panic!("Expected subscription params");
+    };
+    let Some(book) = params.data::<BookRaw>() else {
+        panic!("Expected a book change");
+    };
+    assert!(!book.is_snapshot());
+    assert_eq!(book.prev_change_id, Some(297217));
+
+    let events = book.into_events();
+    let [event::Event::OrderbookUpdate(ask), event::Event::OrderbookUpdate(bid)] = &events[..]
+    else {
+        panic!("Expected two orderbook updates, got {events:?}");
+    };
+    assert_eq!(ask.symbol, "BTC-29SEP23-30000-C");
+    assert_eq!(ask.quantity, "12.5".parse::<Decimal>().unwrap());
+    assert!(matches!(bid.side, Side::BUY));
+    assert!(bid.quantity.is_zero());
+    assert_eq!(bid.sequence, Some(297218));
+    assert_eq!(bid.ts.exchange, Some(1554375447971000));
+}
+
+#[test]
+fn test_rpc_answer() {
+    let frame: DeribitFrame = serde_json::from_str(
+        r#"{"jsonrpc":"2.0","id":7,"error":{"message":"Invalid params","code":-32602}}"#,
+    )
+    .unwrap();
+    assert_eq!(frame.id, Some(7));
+    assert_eq!(frame.error.map(|e| e.code), Some(-32602));
+
+    let frame: DeribitFrame = serde_json::from_str(
+        r#"{"jsonrpc":"2.0","id":8,"result":["trades.BTC-PERPETUAL.100ms"],"usIn":1,"usOut":2}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        frame.result::<Vec<String>>(),
+        Some(vec!["trades.BTC-PERPETUAL.100ms".to_string()])
+    );
+}
@@ -0,0 +1,271 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::io::Read;
+
+#[cfg(test)]
+const RAW_TRADE_DETAIL: &str = r#"
+        {
+            "ch": "market.btcusdt.trade.detail",
+            "ts": 1630994963175,
+            "tick": {
+                "id": 137005445109,
+                "ts": 1630994963173,
+                "data": [
+                    {
+                        "id": 1.3700544510935929e26,
+                        "ts": 1630994963173,
+                        "tradeId": 102523573486,
+                        "amount": 0.006754,
+                        "price": 52648.62,
+                        "direction": "sell"
+                    }
+                ]
+            }
+        }"#;
+
+#[cfg(test)]
+const RAW_MBP: &str = r#"
+        {
+            "ch": "market.btcusdt.mbp.150",
+            "ts": 1573199608679,
+            "tick": {
+                "seqNum": 100020146795,
+                "prevSeqNum": 100020146794,
+                "bids": [],
+                "asks": [
+                    [645.140000000000000000, 26.755973959140651643]
+                ]
+            }
+        }"#;
+
+/// Text of a gzip-compressed frame, which is every frame Huobi sends
+pub fn inflate(frame: &[u8]) -> Option<String> {
+    let mut text = String::new();
+    GzDecoder::new(frame).read_to_string(&mut text).ok()?;
+    Some(text)
+}
+
+/// A topic push, `{"ch":"market.btcusdt.mbp.150","ts":..,"tick":{..}}`. The shape of `tick`
+/// depends on the topic, it is decoded once the topic is known.
+#[derive(Deserialize)]
+pub struct HuobiPush {
+    pub ch: String,
+    /// When Huobi sent the push, in milliseconds
+    pub ts: Option<u64>,
+    pub tick: Box<RawValue>,
+}
+
+impl HuobiPush {
+    /// Decode `tick` as `T`
+    pub fn tick<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.tick.get()).ok()
+    }
+}
+
+/// `{"ping":1492420473027}`, to be echoed back as `{"pong":1492420473027}`
+#[derive(Deserialize, Debug)]
+pub struct HuobiPing {
+    pub ping: i64,
+}
+
+/// Answer to a `sub`, `unsub` or `req`, matched to it by `id`.
+/// `{"id":"1","status":"ok","subbed":"market.btcusdt.trade.detail","ts":1489474081631}`. A `req`
+/// answer names its topic in `rep` and carries the result in `data`.
+#[derive(Deserialize, Debug)]
+pub struct HuobiReply {
+    pub id: Option<String>,
+    pub status: String,
+    pub rep: Option<String>,
+    pub data: Option<Box<RawValue>>,
+    pub ts: Option<u64>,
+    #[serde(rename = "err-code", default)]
+    pub err_code: String,
+    #[serde(rename = "err-msg", default)]
+    pub err_msg: String,
+}
+
+impl HuobiReply {
+    pub fn is_ok(&self) -> bool {
+        self.status == "ok"
+    }
+}
+
+/// Tick of `market.$symbol.trade.detail`, every trade of one taker order
+#[derive(Deserialize, Debug)]
+pub struct TradeDetailRaw {
+    pub data: Vec<TradeRaw>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TradeRaw {
+    pub trade_id: u64,
+    pub amount: Decimal,
+    pub price: Decimal,
+    /// Side of the taker, `buy` or `sell`
+    pub direction: String,
+    /// In milliseconds
+    pub ts: u64,
+}
+
+impl TradeRaw {
+    pub fn into_trade(self, symbol: Symbol) -> event::Trade {
+        event::Trade {
+            exchange: Exchange::Huobi,
+            symbol,
+            side: if self.direction == "buy" {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: self.price,
+            quantity: self.amount,
+            trade_id: self.trade_id.into(),
+            ts: event::Timestamps::millis(self.ts),
+        }
+    }
+}
+
+/// Tick of `market.$symbol.bbo`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BboRaw {
+    pub bid: Decimal,
+    pub bid_size: Decimal,
+    pub ask: Decimal,
+    pub ask_size: Decimal,
+    pub seq_id: u64,
+    /// In milliseconds
+    pub quote_time: u64,
+}
+
+impl BboRaw {
+    /// A two level [`event::OrderbookSnapshot`]
+    pub fn into_event(self, symbol: Symbol) -> event::Event {
+        let sequence = Some(self.seq_id);
+        let ts = event::Timestamps::millis(self.quote_time);
+        let ask = Level {
+            price: self.ask,
+            quantity: self.ask_size,
+        };
+        let bid = Level {
+            price: self.bid,
+            quantity: self.bid_size,
+        };
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Huobi,
+            levels: levels(
+                Exchange::Huobi,
+                symbol,
+                vec![ask],
+                vec![bid],
+                true,
+                sequence,
+                ts,
+            )
+            .collect(),
+            symbol,
+            sequence,
+            ts,
+        })
+    }
+}
+
+/// Tick of `market.$symbol.mbp.$levels` and `data` of the snapshot `req` of the same topic.
+/// Updates chain by `prevSeqNum`, the snapshot has none. A size of `0` removes the level.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MbpRaw {
+    pub seq_num: i64,
+    pub prev_seq_num: Option<i64>,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    /// `ts` of the push or `req` answer it came in, the tick itself doesn't say
+    #[serde(skip)]
+    pub ts: Option<u64>,
+}
+
+impl MbpRaw {
+    /// A snapshot becomes a single [`event::OrderbookSnapshot`], an update one
+    /// [`event::OrderbookUpdate`] per level
+    pub fn into_events(self, symbol: Symbol, is_snapshot: bool) -> Vec<event::Event> {
+        let sequence = u64::try_from(self.seq_num).ok();
+        let ts = self
+            .ts
+            .map_or_else(event::Timestamps::default, event::Timestamps::millis);
+        let levels = levels(
+            Exchange::Huobi,
+            symbol,
+            self.asks,
+            self.bids,
+            is_snapshot,
+            sequence,
+            ts,
+        );
+        if is_snapshot {
+            vec![event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+                exchange: Exchange::Huobi,
+                levels: levels.collect(),
+                symbol,
+                sequence,
+                ts,
+            })]
+        } else {
+            levels.map(event::Event::OrderbookUpdate).collect()
+        }
+    }
+}
+
+#[test]
+fn test_inflate() {
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(br#"{"ping":1492420473027}"#).unwrap();
+    let frame = encoder.finish().unwrap();
+
+    let text = inflate(&frame).unwrap();
+    let ping: HuobiPing = serde_json::from_str(&text).unwrap();
+    assert_eq!(ping.ping, 1492420473027);
+    assert!(inflate(b"not gzip").is_none());
+}
+
+#[test]
+fn test_trade_detail() {
+    let push: HuobiPush = serde_json::from_str(RAW_TRADE_DETAIL).unwrap();
+    let Some(mut detail) = push.tick::<TradeDetailRaw>() else {
+        panic!("Expected a trade detail");
+    };
+    let trade = detail.data.remove(0);
+    assert_eq!(trade.trade_id, 102523573486);
+
+    let trade = trade.into_trade("btcusdt".into());
+    assert!(matches!(trade.side, Side::SELL));
+    assert_eq!(trade.price, "52648.62".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "102523573486");
+    assert_eq!(trade.ts.exchange, Some(1630994963173000));
+}
+
+#[test]
+fn test_mbp() {
+    let push: HuobiPush = serde_json::from_str(RAW_MBP).unwrap();
+    let Some(mut mbp) = push.tick::<MbpRaw>() else {
+        panic!("Expected an mbp update");
+    };
+    mbp.ts = push.ts;
+    assert_eq!(mbp.prev_seq_num, Some(100020146794));
+
+    let events = mbp.into_events("btcusdt".into(), false);
+    let [event::Event::OrderbookUpdate(ask)] = &events[..] else {
+        panic!("Expected one orderbook update, got {events:?}");
+    };
+    assert!(matches!(ask.side, Side::SELL));
+    assert_eq!(ask.price, "645.14".parse::<Decimal>().unwrap());
+    assert_eq!(ask.sequence, Some(100020146795));
+    assert_eq!(ask.ts.exchange, Some(1573199608679000));
+}
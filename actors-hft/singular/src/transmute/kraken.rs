@@ -0,0 +1,400 @@
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::{de, Deserialize, Deserializer};
+use serde_json::value::RawValue;
+use std::collections::BTreeMap;
+
+#[cfg(test)]
+const RAW_BOOK: &str = r#"
+        {
+            "channel": "book",
+            "type": "snapshot",
+            "data": [
+                {
+                    "symbol": "MATIC/USD",
+                    "bids": [
+                        {"price": 0.5666, "qty": 4831.75496356},
+                        {"price": 0.5665, "qty": 6658.22734739}
+                    ],
+                    "asks": [
+                        {"price": 0.5668, "qty": 4410.79769741},
+                        {"price": 0.5669, "qty": 4655.40412487}
+                    ],
+                    "checksum": 3588693387
+                }
+            ]
+        }"#;
+
+#[cfg(test)]
+const RAW_TRADE: &str = r#"
+        {
+            "channel": "trade",
+            "type": "update",
+            "data": [
+                {
+                    "symbol": "MATIC/USD",
+                    "side": "sell",
+                    "price": 0.5117,
+                    "qty": 40.0,
+                    "ord_type": "market",
+                    "trade_id": 4665906,
+                    "timestamp": "2023-09-25T07:49:37.708706Z"
+                }
+            ]
+        }"#;
+
+/// Kraken's own names for assets that go by another name everywhere else
+const ALIASES: [(&str, &str); 2] = [("XBT", "BTC"), ("XDG", "DOGE")];
+
+/// Canonical `BASE/QUOTE` form of a Kraken pair, `XBT/USD` and `xbt-usd` become `BTC/USD`.
+/// The v2 websocket takes and sends pairs in this form, the REST `wsname`s still use `XBT`.
+pub fn canonical(symbol: &str) -> String {
+    symbol
+        .to_uppercase()
+        .split(['/', '-', '_'])
+        .map(|asset| {
+            ALIASES
+                .iter()
+                .find(|(kraken, _)| *kraken == asset)
+                .map_or(asset, |(_, canonical)| canonical)
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// [`Symbol`] of a Kraken pair, by its [`canonical`] form
+pub fn symbol(pair: &str) -> Symbol {
+    Symbol::canonical(Exchange::Kraken, pair, canonical)
+}
+
+/// A channel push, `{"channel":"book","type":"update","data":[..]}`. `data` is decoded once the
+/// channel is known since book levels have to be read from the raw text.
+#[derive(Deserialize)]
+pub struct KrakenPush {
+    pub channel: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub data: Option<Box<RawValue>>,
+}
+
+impl KrakenPush {
+    pub fn is_snapshot(&self) -> bool {
+        self.kind.as_deref() == Some("snapshot")
+    }
+
+    /// Decode `data` as a list of `T`
+    pub fn data<'a, T: Deserialize<'a>>(&'a self) -> Vec<T> {
+        self.data
+            .as_ref()
+            .and_then(|data| serde_json::from_str(data.get()).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Answer to a `subscribe` or `unsubscribe` request, matched to it by `req_id`.
+/// `{"method":"subscribe","result":{..},"success":true,"req_id":1}` or
+/// `{"method":"subscribe","error":"Currency pair not supported","success":false,"req_id":1}`.
+#[derive(Deserialize, Debug)]
+pub struct KrakenReply {
+    #[serde(default)]
+    pub method: String,
+    pub success: Option<bool>,
+    pub error: Option<String>,
+    pub req_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct TradeRaw {
+    pub symbol: String,
+    /// Side of the taker
+    pub side: String,
+    pub price: Decimal,
+    pub qty: Decimal,
+    pub trade_id: u64,
+    pub timestamp: String,
+}
+
+impl From<TradeRaw> for event::Trade {
+    fn from(value: TradeRaw) -> Self {
+        Self {
+            exchange: Exchange::Kraken,
+            symbol: symbol(&value.symbol),
+            side: if value.side == "buy" {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: value.price,
+            quantity: value.qty,
+            trade_id: value.trade_id.into(),
+            ts: event::Timestamps::rfc3339(&value.timestamp),
+        }
+    }
+}
+
+/// Data of the `ticker` channel, subscribed with `event_trigger: bbo`
+#[derive(Deserialize)]
+pub struct TickerRaw {
+    pub symbol: String,
+    pub bid: Decimal,
+    pub bid_qty: Decimal,
+    pub ask: Decimal,
+    pub ask_qty: Decimal,
+    pub timestamp: Option<String>,
+}
+
+impl TickerRaw {
+    /// A two level [`event::OrderbookSnapshot`]
+    pub fn into_event(self) -> event::Event {
+        let symbol = symbol(&self.symbol);
+        let ts = timestamps(self.timestamp.as_deref());
+        let level = |side, price, quantity| event::OrderbookUpdate {
+            exchange: Exchange::Kraken,
+            symbol,
+            side,
+            price,
+            quantity,
+            is_snapshot: true,
+            sequence: None,
+            ts,
+        };
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Kraken,
+            levels: vec![
+                level(Side::SELL, self.ask, self.ask_qty),
+                level(Side::BUY, self.bid, self.bid_qty),
+            ],
+            symbol,
+            sequence: None,
+            ts,
+        })
+    }
+}
+
+/// Of a `timestamp` Kraken only sends on some pushes
+fn timestamps(timestamp: Option<&str>) -> event::Timestamps {
+    timestamp.map_or_else(event::Timestamps::default, event::Timestamps::rfc3339)
+}
+
+/// Data of the `book` channel. `checksum` covers the top ten levels of each side after the push
+/// is applied. Only updates carry a `timestamp`, and nothing numbers them.
+#[derive(Deserialize)]
+pub struct BookRaw {
+    pub symbol: String,
+    #[serde(default)]
+    pub bids: Vec<LevelRaw>,
+    #[serde(default)]
+    pub asks: Vec<LevelRaw>,
+    pub checksum: u32,
+    pub timestamp: Option<String>,
+}
+
+/// One `{"price":0.5666,"qty":4831.75496356}` entry of a Kraken book.
+///
+/// Kraken prints both numbers with the precision of the pair. The text is kept next to the
+/// parsed numbers since the checksum is computed over it, trailing zeros included.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LevelRaw {
+    pub price: String,
+    pub qty: String,
+    pub px: Decimal,
+    pub sz: Decimal,
+}
+
+impl<'de> Deserialize<'de> for LevelRaw {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Text {
+            price: Box<RawValue>,
+            qty: Box<RawValue>,
+        }
+
+        let text = Text::deserialize(deserializer)?;
+        let price = text.price.get().trim_matches('"').to_string();
+        let qty = text.qty.get().trim_matches('"').to_string();
+        Ok(Self {
+            px: price.parse().map_err(de::Error::custom)?,
+            sz: qty.parse().map_err(de::Error::custom)?,
+            price,
+            qty,
+        })
+    }
+}
+
+impl BookRaw {
+    /// A snapshot becomes a single [`event::OrderbookSnapshot`], an update becomes one
+    /// [`event::OrderbookUpdate`] per level
+    pub fn into_events(self, is_snapshot: bool) -> Vec<event::Event> {
+        let symbol = symbol(&self.symbol);
+        let ts = timestamps(self.timestamp.as_deref());
+        let asks = self.asks.into_iter().map(|l| (Side::SELL, l));
+        let bids = self.bids.into_iter().map(|l| (Side::BUY, l));
+        let levels = asks
+            .chain(bids)
+            .map(|(side, level)| event::OrderbookUpdate {
+                exchange: Exchange::Kraken,
+                symbol,
+                side,
+                price: level.px,
+                quantity: level.sz,
+                is_snapshot,
+                sequence: None,
+                ts,
+            });
+
+        if is_snapshot {
+            let levels = levels.collect();
+            vec![event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+                exchange: Exchange::Kraken,
+                symbol,
+                levels,
+                sequence: None,
+                ts,
+            })]
+        } else {
+            levels.map(event::Event::OrderbookUpdate).collect()
+        }
+    }
+}
+
+/// Number of levels per side Kraken includes in the book checksum
+const CHECKSUM_DEPTH: usize = 10;
+
+/// Deepest book Kraken offers
+pub const DEPTH_LIMIT: usize = 1000;
+
+/// Local copy of a Kraken book, kept in the exchange's text so every push can be verified against
+/// its `checksum`
+#[derive(Debug)]
+pub struct KrakenBook {
+    /// Levels per side of the subscription. Kraken never deletes levels that fall out of it, so
+    /// the book is cut back to this after every push.
+    depth: usize,
+    asks: BTreeMap<Decimal, LevelRaw>,
+    bids: BTreeMap<Decimal, LevelRaw>,
+    /// Cleared on a checksum mismatch, set again by the next snapshot
+    pub valid: bool,
+}
+
+impl KrakenBook {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            asks: BTreeMap::new(),
+            bids: BTreeMap::new(),
+            valid: false,
+        }
+    }
+
+    /// Apply a push. A snapshot replaces the book, an update replaces or removes levels.
+    pub fn apply(&mut self, data: &BookRaw, is_snapshot: bool) {
+        if is_snapshot {
+            self.asks.clear();
+            self.bids.clear();
+            self.valid = true;
+        }
+
+        for (book, levels) in [(&mut self.asks, &data.asks), (&mut self.bids, &data.bids)] {
+            for level in levels {
+                if level.sz.is_zero() {
+                    book.remove(&level.px);
+                } else {
+                    book.insert(level.px, level.clone());
+                }
+            }
+        }
+
+        while self.asks.len() > self.depth {
+            self.asks.pop_last();
+        }
+        while self.bids.len() > self.depth {
+            self.bids.pop_first();
+        }
+    }
+
+    /// CRC32 over the best 10 asks, lowest first, then the best 10 bids, highest first. Every
+    /// price and quantity goes in without its decimal point and leading zeros.
+    pub fn checksum(&self) -> u32 {
+        let asks = self.asks.values().take(CHECKSUM_DEPTH);
+        let bids = self.bids.values().rev().take(CHECKSUM_DEPTH);
+        let mut text = String::new();
+        for level in asks.chain(bids) {
+            for number in [&level.price, &level.qty] {
+                let digits: String = number.chars().filter(|c| *c != '.').collect();
+                text.push_str(digits.trim_start_matches('0'));
+            }
+        }
+        crc32fast::hash(text.as_bytes())
+    }
+
+    /// Apply a push and compare against its checksum. A mismatch marks the book invalid.
+    pub fn verify(&mut self, data: &BookRaw, is_snapshot: bool) -> bool {
+        self.apply(data, is_snapshot);
+        self.valid &= self.checksum() == data.checksum;
+        self.valid
+    }
+}
+
+#[test]
+fn test_canonical() {
+    assert_eq!(canonical("XBT/USD"), "BTC/USD");
+    assert_eq!(canonical("xdg-usd"), "DOGE/USD");
+    assert_eq!(canonical("ETH/XBT"), "ETH/BTC");
+    assert_eq!(canonical("MATIC/USD"), "MATIC/USD");
+}
+
+#[test]
+fn test_trade() {
+    let push: KrakenPush = serde_json::from_str(RAW_TRADE).unwrap();
+    assert_eq!(push.channel, "trade");
+    let Some(raw) = push.data::<TradeRaw>().pop() else {
+        panic!("Expected a trade");
+    };
+
+    let trade: event::Trade = raw.into();
+    assert_eq!(trade.symbol, "MATIC/USD");
+    assert!(matches!(trade.side, Side::SELL));
+    assert_eq!(trade.price, "0.5117".parse::<Decimal>().unwrap());
+    assert_eq!(trade.quantity, "40.0".parse::<Decimal>().unwrap());
+    assert_eq!(trade.trade_id, "4665906");
+    assert_eq!(trade.ts.exchange, Some(1695628177708706));
+}
+
+#[test]
+fn test_book_checksum() {
+    let push: KrakenPush = serde_json::from_str(RAW_BOOK).unwrap();
+    assert!(push.is_snapshot());
+    let Some(raw) = push.data::<BookRaw>().pop() else {
+        panic!("Expected a book");
+    };
+    assert_eq!(raw.bids[0].qty, "4831.75496356");
+
+    let mut book = KrakenBook::new(10);
+    let asks = concat!("5668", "441079769741", "5669", "465540412487");
+    let bids = concat!("5666", "483175496356", "5665", "665822734739");
+    assert_eq!(
+        raw.checksum,
+        crc32fast::hash(format!("{asks}{bids}").as_bytes())
+    );
+    assert!(book.verify(&raw, true));
+
+    // Trailing zeros count, the parsed number alone can't reproduce the checksum
+    let update: BookRaw = serde_json::from_str(
+        r#"{"symbol":"MATIC/USD","bids":[{"price":0.5667,"qty":10.00}],"asks":[],"checksum":0}"#,
+    )
+    .unwrap();
+    assert_eq!(update.bids[0].qty, "10.00");
+    book.apply(&update, false);
+    let checksum = crc32fast::hash(format!("{asks}56671000{bids}").as_bytes());
+    assert_eq!(book.checksum(), checksum);
+
+    // Levels beyond the subscribed depth are cut, Kraken doesn't delete them
+    let mut book = KrakenBook::new(1);
+    book.apply(&update, true);
+    let raw: BookRaw = serde_json::from_str(
+        r#"{"symbol":"MATIC/USD","bids":[{"price":0.5666,"qty":1.0}],"asks":[],"checksum":0}"#,
+    )
+    .unwrap();
+    book.apply(&raw, false);
+    assert_eq!(book.checksum(), crc32fast::hash(b"56671000"));
+}
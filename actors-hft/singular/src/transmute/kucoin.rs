@@ -0,0 +1,316 @@
+use super::{levels, Level};
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::Deserialize;
+use serde_aux::prelude::*;
+use serde_json::value::RawValue;
+
+#[cfg(test)]
+const RAW_BULLET: &str = r#"
+        {
+            "code": "200000",
+            "data": {
+                "token": "2neAiuYvAU61ZDXANAGAsiL4-iAExhsBXZxftpOeh_55i3Ysy2q2LEsEWU64mdzUOPusi34M_wGoSf7iNyEWJ4aBZXpWhrmY9jKtqkdWoFa75w3istPvPtiYB9J6i9GjsxUuhPw3BlrzazF6ghq4L_9yZB6-8N6BtR3Ju1d1JEQ=.MqpEUyqQjh8nwTo7fFKnNA==",
+                "instanceServers": [
+                    {
+                        "endpoint": "wss://ws-api-spot.kucoin.com/",
+                        "encrypt": true,
+                        "protocol": "websocket",
+                        "pingInterval": 18000,
+                        "pingTimeout": 10000
+                    }
+                ]
+            }
+        }"#;
+
+#[cfg(test)]
+const RAW_LEVEL2: &str = r#"
+        {
+            "type": "message",
+            "topic": "/market/level2:BTC-USDT",
+            "subject": "trade.l2update",
+            "data": {
+                "changes": {
+                    "asks": [["18906", "0.00331", "14103845"], ["18907.3", "0.58751503", "14103844"]],
+                    "bids": [["18891.9", "0.15688", "14103847"]]
+                },
+                "sequenceEnd": 14103847,
+                "sequenceStart": 14103844,
+                "symbol": "BTC-USDT",
+                "time": 1663747970273
+            }
+        }"#;
+
+/// Answer of the REST API, `{"code":"200000","data":{..}}` on success
+#[derive(Deserialize, Debug)]
+pub struct KucoinRest<T> {
+    pub code: String,
+    #[serde(default)]
+    pub msg: String,
+    pub data: Option<T>,
+}
+
+impl<T> KucoinRest<T> {
+    /// `data` of a successful answer, the code and message otherwise
+    pub fn into_result(self) -> Result<T, String> {
+        match self.data {
+            Some(data) if self.code == "200000" => Ok(data),
+            _ => Err(format!("{}: {}", self.code, self.msg)),
+        }
+    }
+}
+
+/// `data` of `bullet-public`, the token and the servers it is good for
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct BulletRaw {
+    pub token: String,
+    pub instance_servers: Vec<InstanceServerRaw>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceServerRaw {
+    pub endpoint: String,
+    /// Milliseconds between the pings the server expects
+    pub ping_interval: u64,
+    /// Milliseconds the server waits past a missed ping before closing the connection
+    pub ping_timeout: u64,
+}
+
+/// Every websocket frame. `welcome`, `ack`, `pong` and `error` answer the connection or a request
+/// by `id`, a `message` carries `data` for a `topic`.
+#[derive(Deserialize, Debug)]
+pub struct KucoinFrame {
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub topic: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+    pub code: Option<i64>,
+    pub data: Option<Box<RawValue>>,
+}
+
+impl KucoinFrame {
+    /// Decode `data` as `T`
+    pub fn data<'a, T: Deserialize<'a>>(&'a self) -> Option<T> {
+        serde_json::from_str(self.data.as_ref()?.get()).ok()
+    }
+}
+
+/// Data of `/market/match`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MatchRaw {
+    pub symbol: Symbol,
+    /// Side of the taker, `buy` or `sell`
+    pub side: String,
+    pub price: Decimal,
+    pub size: Decimal,
+    pub trade_id: event::TradeId,
+    /// In nanoseconds
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub time: u64,
+}
+
+impl From<MatchRaw> for event::Trade {
+    fn from(value: MatchRaw) -> Self {
+        Self {
+            exchange: Exchange::KuCoin,
+            symbol: value.symbol,
+            side: if value.side == "buy" {
+                Side::BUY
+            } else {
+                Side::SELL
+            },
+            price: value.price,
+            quantity: value.size,
+            trade_id: value.trade_id,
+            ts: event::Timestamps::nanos(value.time),
+        }
+    }
+}
+
+/// Data of `/market/ticker`, which only names the symbol in its topic
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TickerRaw {
+    pub best_bid: Decimal,
+    pub best_bid_size: Decimal,
+    pub best_ask: Decimal,
+    pub best_ask_size: Decimal,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub sequence: u64,
+    /// In milliseconds
+    pub time: u64,
+}
+
+impl TickerRaw {
+    /// A two level [`event::OrderbookSnapshot`]
+    pub fn into_event(self, symbol: Symbol) -> event::Event {
+        let sequence = Some(self.sequence);
+        let ts = event::Timestamps::millis(self.time);
+        let ask = Level {
+            price: self.best_ask,
+            quantity: self.best_ask_size,
+        };
+        let bid = Level {
+            price: self.best_bid,
+            quantity: self.best_bid_size,
+        };
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::KuCoin,
+            symbol,
+            levels: levels(
+                Exchange::KuCoin,
+                symbol,
+                vec![ask],
+                vec![bid],
+                true,
+                sequence,
+                ts,
+            )
+            .collect(),
+            sequence,
+            ts,
+        })
+    }
+}
+
+/// `[price, size, sequence]` of a `/market/level2` change, a size of `0` removes the level
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ChangeRaw(pub String, pub String, pub String);
+
+impl ChangeRaw {
+    fn sequence(&self) -> i64 {
+        self.2.parse().unwrap_or_default()
+    }
+
+    fn level(&self) -> Option<Level> {
+        Some(Level {
+            price: self.0.parse().ok()?,
+            quantity: self.1.parse().ok()?,
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ChangesRaw {
+    pub asks: Vec<ChangeRaw>,
+    pub bids: Vec<ChangeRaw>,
+}
+
+/// Data of `/market/level2`. Each update covers `sequenceStart..=sequenceEnd` and every change
+/// carries its own sequence.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Level2Raw {
+    pub symbol: Symbol,
+    pub sequence_start: i64,
+    pub sequence_end: i64,
+    pub changes: ChangesRaw,
+    /// In milliseconds
+    pub time: u64,
+}
+
+impl Level2Raw {
+    /// One [`event::OrderbookUpdate`] per change newer than `sequence`, numbered by the
+    /// `sequenceEnd` of the update
+    pub fn into_events(self, sequence: i64) -> Vec<event::Event> {
+        let side = |changes: Vec<ChangeRaw>| -> Vec<Level> {
+            changes
+                .iter()
+                .filter(|change| change.sequence() > sequence)
+                .filter_map(ChangeRaw::level)
+                .collect()
+        };
+        let asks = side(self.changes.asks);
+        let bids = side(self.changes.bids);
+        let sequence = u64::try_from(self.sequence_end).ok();
+        let ts = event::Timestamps::millis(self.time);
+        levels(
+            Exchange::KuCoin,
+            self.symbol,
+            asks,
+            bids,
+            false,
+            sequence,
+            ts,
+        )
+        .map(event::Event::OrderbookUpdate)
+        .collect()
+    }
+}
+
+/// `data` of the REST `level2_100` order book, at `sequence`
+#[derive(Deserialize, Debug)]
+pub struct OrderBookRaw {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub sequence: i64,
+    pub bids: Vec<Level>,
+    pub asks: Vec<Level>,
+    /// In milliseconds
+    pub time: u64,
+}
+
+impl OrderBookRaw {
+    pub fn into_event(self, symbol: Symbol) -> event::Event {
+        let sequence = u64::try_from(self.sequence).ok();
+        let ts = event::Timestamps::millis(self.time);
+        event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::KuCoin,
+            symbol,
+            levels: levels(
+                Exchange::KuCoin,
+                symbol,
+                self.asks,
+                self.bids,
+                true,
+                sequence,
+                ts,
+            )
+            .collect(),
+            sequence,
+            ts,
+        })
+    }
+}
+
+#[test]
+fn test_bullet() {
+    let bullet: KucoinRest<BulletRaw> = serde_json::from_str(RAW_BULLET).unwrap();
+    let bullet = bullet.into_result().unwrap();
+    assert!(bullet.token.ends_with("=="));
+    assert_eq!(bullet.instance_servers[0].ping_interval, 18000);
+
+    let error: KucoinRest<BulletRaw> =
+        serde_json::from_str(r#"{"code":"429000","msg":"Too Many Requests"}"#).unwrap();
+    assert_eq!(
+        error.into_result().unwrap_err(),
+        "429000: Too Many Requests"
+    );
+}
+
+#[test]
+fn test_level2() {
+    let frame: KucoinFrame = serde_json::from_str(RAW_LEVEL2).unwrap();
+    assert_eq!(frame.kind, "message");
+    let Some(update) = frame.data::<Level2Raw>() else {
+        panic!("Expected a level2 update");
+    };
+    assert_eq!(
+        (update.sequence_start, update.sequence_end),
+        (14103844, 14103847)
+    );
+
+    // The snapshot at 14103844 already has the second ask
+    let events = update.into_events(14103844);
+    let [event::Event::OrderbookUpdate(ask), event::Event::OrderbookUpdate(bid)] = &events[..]
+    else {
+        panic!("Expected two orderbook updates, got {events:?}");
+    };
+    assert_eq!(ask.price, "18906.0".parse::<Decimal>().unwrap());
+    assert!(matches!(bid.side, Side::BUY));
+    assert_eq!(bid.sequence, Some(14103847));
+    assert_eq!(bid.ts.exchange, Some(1663747970273000));
+}
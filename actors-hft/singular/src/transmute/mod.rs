@@ -1 +1,54 @@
+pub mod binance;
+pub mod bitfinex;
+pub mod bitstamp;
+pub mod bybit;
+pub mod coinbase;
+pub mod deribit;
+pub mod huobi;
+pub mod kraken;
+pub mod kucoin;
 pub mod okx;
+
+use crate::event;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::{Deserialize, Deserializer};
+
+/// One `[price, quantity]` book entry, as Binance and Coinbase send them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Level {
+    pub price: Decimal,
+    pub quantity: Decimal,
+}
+
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let [price, quantity] = <[Decimal; 2]>::deserialize(deserializer)?;
+        Ok(Self { price, quantity })
+    }
+}
+
+/// One [`event::OrderbookUpdate`] per level, asks first, each carrying the `sequence` and `ts` of
+/// the push
+pub(crate) fn levels(
+    exchange: Exchange,
+    symbol: Symbol,
+    asks: Vec<Level>,
+    bids: Vec<Level>,
+    is_snapshot: bool,
+    sequence: Option<u64>,
+    ts: event::Timestamps,
+) -> impl Iterator<Item = event::OrderbookUpdate> {
+    let asks = asks.into_iter().map(|l| (Side::SELL, l));
+    let bids = bids.into_iter().map(|l| (Side::BUY, l));
+    asks.chain(bids)
+        .map(move |(side, level)| event::OrderbookUpdate {
+            exchange,
+            symbol,
+            side,
+            price: level.price,
+            quantity: level.quantity,
+            is_snapshot,
+            sequence,
+            ts,
+        })
+}
@@ -1,115 +1,580 @@
 use crate::event;
-use crate::models::{Exchange, Side};
-use serde::{Deserialize, Serialize};
-use serde_aux::prelude::*;
-use std::mem;
-
-const RAW_BOOK: &'static str = r#"
-        {
-            "arg": {
-                "channel": "books",
-                "instId": "BTC-USDT"
-            },
-            "action": "update",
-            "data": [
-                {
-                    "asks": [
-                        ["30557.3", "0", "0", "0"],
-                        ["30557.6", "0.51065898", "0", "1"]
-                    ],
-                    "bids": [
-                        ["30545", "0.51069492", "0", "2"],
-                        ["30544.9", "0.17474", "0", "1"]
-                    ],
-                    "ts": "1688060541909",
-                    "checksum": -1316686072,
-                    "seqId": 12815993309,
-                    "prevSeqId": 12815993303
-                }
-            ]
-        }
-    "#;
+use crate::models::{Decimal, Exchange, Side, Symbol};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use serde_json::value::RawValue;
+use std::collections::{btree_map::Entry, BTreeMap};
+use std::{fmt, marker::PhantomData, str::FromStr};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Arg {
+    pub channel: String,
+    pub inst_id: String,
+}
 
-#[derive(Serialize, Deserialize)]
-pub struct OkxRaw<Data> {
-    arg: Arg,
-    action: Option<String>,
-    data: Vec<Data>,
+/// Answer to an `op` request. Either `{"event":"subscribe","arg":{..}}` or
+/// `{"event":"error","code":"60012","msg":".."}`, errors only say which request they answer by
+/// the `id` it was sent with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OkxEvent {
+    pub event: String,
+    pub arg: Option<Arg>,
+    pub code: Option<String>,
+    pub msg: Option<String>,
+    pub id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// [`Arg`] of a push, borrowed from the frame
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
-pub struct Arg {
-    channel: String,
-    inst_id: String,
+pub struct ArgRef<'a> {
+    pub channel: &'a str,
+    pub inst_id: Symbol,
+}
+
+/// Parse a number OKX sends as a string straight from the frame
+fn number<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    <&str>::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+struct Each<F, T>(F, PhantomData<fn(T)>);
+
+impl<'de, F, T> de::Visitor<'de> for Each<F, T>
+where
+    F: FnMut(T) -> serde_json::Result<()>,
+    T: Deserialize<'de>,
+{
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array")
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(mut self, mut seq: A) -> Result<(), A::Error> {
+        while let Some(item) = seq.next_element()? {
+            (self.0)(item).map_err(de::Error::custom)?;
+        }
+        Ok(())
+    }
+}
+
+/// Call `f` with every element of the array `raw` as it is decoded, without collecting them
+pub fn each<'a, T: Deserialize<'a>>(
+    raw: &'a RawValue,
+    f: impl FnMut(T) -> serde_json::Result<()>,
+) -> serde_json::Result<()> {
+    let mut deserializer = serde_json::Deserializer::from_str(raw.get());
+    de::Deserializer::deserialize_seq(&mut deserializer, Each(f, PhantomData))
+}
+
+/// A data push, `{"arg":{..},"action":"update","data":[..]}`, borrowed from the frame. `data` is
+/// decoded once the channel is known.
+#[derive(Deserialize, Debug)]
+pub struct OkxPush<'a> {
+    #[serde(borrow)]
+    pub arg: ArgRef<'a>,
+    #[serde(borrow)]
+    pub action: Option<&'a str>,
+    #[serde(borrow)]
+    pub data: &'a RawValue,
+}
+
+impl<'a> OkxPush<'a> {
+    /// Decode a text frame. Answers to `op` requests carry no `data` and fail, see [`OkxEvent`].
+    pub fn parse(frame: &'a str) -> serde_json::Result<Self> {
+        serde_json::from_str(frame)
+    }
+
+    /// `books` and `books50-l2-tbt` say whether a push is a `snapshot` or an `update`. `books5`
+    /// and `bbo-tbt` have no action and always push the full top of book.
+    pub fn is_snapshot(&self) -> bool {
+        self.action != Some("update")
+    }
+
+    /// Whether the push is from a depth channel
+    pub fn is_book(&self) -> bool {
+        self.arg.channel.starts_with("books") || self.arg.channel == "bbo-tbt"
+    }
+
+    /// Call `f` with every book of a depth push
+    pub fn books(
+        &self,
+        f: impl FnMut(BookRef<'a>) -> serde_json::Result<()>,
+    ) -> serde_json::Result<()> {
+        each(self.data, f)
+    }
+
+    /// Append the events of the push to `out`. Books are taken as they come, validating them is
+    /// up to the adapter.
+    pub fn events(&self, out: &mut Vec<event::Event>) -> serde_json::Result<()> {
+        let inst_id = self.arg.inst_id;
+        if self.is_book() {
+            let is_snapshot = self.is_snapshot();
+            return self.books(|book| book.events(inst_id, is_snapshot, out));
+        }
+        if self.arg.channel == "trades" {
+            return each(self.data, |trade: TradeRef| {
+                out.push(event::Event::Trade(trade.into_trade(inst_id)));
+                Ok(())
+            });
+        }
+        Ok(())
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// Data of every OKX depth channel: `books`, `books5`, `bbo-tbt` and `books50-l2-tbt`. The
+/// levels are decoded one at a time by [`BookRef::levels`].
+///
+/// Only `books` and `books50-l2-tbt` carry `checksum` and `prevSeqId`. `prevSeqId` is `-1` on a
+/// snapshot.
+#[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-struct BookUpdateRaw {
-    asks: Vec<[String; 4]>,
-    bids: Vec<[String; 4]>,
-    ts: String,
-    checksum: i64,
-    seq_id: u64,
-    prev_seq_id: u64,
-}
-
-const RAW_TRADE: &'static str = r#"
-        {
-            "arg": {
-                "channel": "trades",
-                "instId": "BTC-USDT"
-            },
-            "data": [
-                {
-                    "instId": "BTC-USDT",
-                    "tradeId": "426790906",
-                    "px": "30460.1",
-                    "sz": "0.0010244",
-                    "side": "sell",
-                    "ts": "1688085963425"
+pub struct BookRef<'a> {
+    #[serde(borrow)]
+    pub asks: &'a RawValue,
+    #[serde(borrow)]
+    pub bids: &'a RawValue,
+    #[serde(deserialize_with = "number")]
+    pub ts: u64,
+    pub checksum: Option<i64>,
+    pub seq_id: Option<i64>,
+    pub prev_seq_id: Option<i64>,
+}
+
+impl<'a> BookRef<'a> {
+    /// Call `f` with every level, asks first
+    pub fn levels(&self, mut f: impl FnMut(Side, LevelRef<'a>)) -> serde_json::Result<()> {
+        each(self.asks, |level| {
+            f(Side::SELL, level);
+            Ok(())
+        })?;
+        each(self.bids, |level| {
+            f(Side::BUY, level);
+            Ok(())
+        })
+    }
+
+    /// Append the events of the book to `out`. A snapshot becomes a single
+    /// [`event::OrderbookSnapshot`], an update becomes one [`event::OrderbookUpdate`] per level.
+    pub fn events(
+        &self,
+        inst_id: Symbol,
+        is_snapshot: bool,
+        out: &mut Vec<event::Event>,
+    ) -> serde_json::Result<()> {
+        let sequence = self.seq_id.and_then(|seq_id| u64::try_from(seq_id).ok());
+        let ts = event::Timestamps::millis(self.ts);
+        let update = |side, level: LevelRef| event::OrderbookUpdate {
+            exchange: Exchange::Okx,
+            symbol: inst_id,
+            side,
+            price: level.px,
+            quantity: level.sz,
+            is_snapshot,
+            sequence,
+            ts,
+        };
+        if !is_snapshot {
+            return self.levels(|side, level| {
+                out.push(event::Event::OrderbookUpdate(update(side, level)));
+            });
+        }
+
+        let mut levels = Vec::new();
+        self.levels(|side, level| levels.push(update(side, level)))?;
+        out.push(event::Event::OrderbookSnapshot(event::OrderbookSnapshot {
+            exchange: Exchange::Okx,
+            symbol: inst_id,
+            levels,
+            sequence,
+            ts,
+        }));
+        Ok(())
+    }
+}
+
+/// One `[price, size, deprecated, orders]` entry of an OKX book, borrowed from the frame.
+///
+/// The exchange strings are kept next to the parsed numbers since the book checksum is computed
+/// over the strings as they were sent.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LevelRef<'a> {
+    pub price: &'a str,
+    pub size: &'a str,
+    pub px: Decimal,
+    pub sz: Decimal,
+}
+
+impl<'de> Deserialize<'de> for LevelRef<'de> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (price, size, _, _) = <(&str, &str, &str, &str)>::deserialize(deserializer)?;
+        Ok(Self {
+            px: price.parse().map_err(de::Error::custom)?,
+            sz: size.parse().map_err(de::Error::custom)?,
+            price,
+            size,
+        })
+    }
+}
+
+/// Number of levels per side OKX includes in the book checksum
+const CHECKSUM_DEPTH: usize = 25;
+
+/// A level of an [`OkxBook`] in the exchange's strings
+#[derive(Default, Debug)]
+struct BookLevel {
+    price: String,
+    size: String,
+}
+
+impl BookLevel {
+    /// Overwrite the level, reusing its buffers
+    fn set(&mut self, level: &LevelRef) {
+        self.price.clear();
+        self.price.push_str(level.price);
+        self.size.clear();
+        self.size.push_str(level.size);
+    }
+}
+
+/// Local copy of an OKX book, kept in the exchange's strings so every push can be verified
+/// against its `checksum`.
+#[derive(Default, Debug)]
+pub struct OkxBook {
+    asks: BTreeMap<Decimal, BookLevel>,
+    bids: BTreeMap<Decimal, BookLevel>,
+    /// Levels taken out of the book, their buffers are reused by the next ones added
+    spare: Vec<BookLevel>,
+    /// Cleared on a checksum mismatch, set again by the next snapshot
+    pub valid: bool,
+}
+
+impl OkxBook {
+    /// Apply a push. A snapshot replaces the book, an update replaces or removes levels.
+    pub fn apply(&mut self, data: &BookRef, is_snapshot: bool) -> serde_json::Result<()> {
+        if is_snapshot {
+            // `retain` frees the map nodes, only the string buffers of the levels are kept for reuse
+            for book in [&mut self.asks, &mut self.bids] {
+                book.retain(|_, level| {
+                    self.spare.push(std::mem::take(level));
+                    false
+                });
+            }
+            self.valid = true;
+        }
+
+        data.levels(|side, level| {
+            let book = match side {
+                Side::SELL => &mut self.asks,
+                Side::BUY => &mut self.bids,
+            };
+            let price = level.px;
+            if level.sz.is_zero() {
+                self.spare.extend(book.remove(&price));
+                return;
+            }
+            match book.entry(price) {
+                Entry::Occupied(mut stored) => stored.get_mut().set(&level),
+                Entry::Vacant(vacant) => {
+                    let mut stored = self.spare.pop().unwrap_or_default();
+                    stored.set(&level);
+                    vacant.insert(stored);
+                }
+            }
+        })
+    }
+
+    /// CRC32 over the best 25 bids and asks interleaved as `bid:size:ask:size:...`. When one side
+    /// runs out the remaining levels of the other side are appended.
+    pub fn checksum(&self) -> i32 {
+        let mut bids = self.bids.values().rev().take(CHECKSUM_DEPTH);
+        let mut asks = self.asks.values().take(CHECKSUM_DEPTH);
+        let mut hasher = crc32fast::Hasher::new();
+        let mut first = true;
+
+        loop {
+            let (bid, ask) = (bids.next(), asks.next());
+            if bid.is_none() && ask.is_none() {
+                break;
+            }
+            for level in [bid, ask].into_iter().flatten() {
+                for part in [&level.price, &level.size] {
+                    if !first {
+                        hasher.update(b":");
+                    }
+                    first = false;
+                    hasher.update(part.as_bytes());
                 }
-            ]
-        }"#;
+            }
+        }
+
+        hasher.finalize() as i32
+    }
 
-#[derive(Serialize, Deserialize)]
+    /// Apply a push and compare against its checksum. Pushes without a checksum (`books5`,
+    /// `bbo-tbt`) always pass. A mismatch, or a push that can't be decoded, marks the book
+    /// invalid.
+    pub fn verify(&mut self, data: &BookRef, is_snapshot: bool) -> bool {
+        if self.apply(data, is_snapshot).is_err() {
+            self.valid = false;
+        }
+        if let Some(expected) = data.checksum {
+            self.valid &= self.checksum() == expected as i32;
+        }
+        self.valid
+    }
+}
+
+/// One trade of a `trades` push, borrowed from the frame
+#[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct TradeRaw {
-    inst_id: String,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    trade_id: u128,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    px: f64,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    sz: f64,
-    side: String,
-    #[serde(deserialize_with = "deserialize_number_from_string")]
-    ts: u128,
-}
-
-impl From<OkxRaw<TradeRaw>> for event::Trade {
-    fn from(mut value: OkxRaw<TradeRaw>) -> Self {
-        Self {
-            symbol: mem::take(&mut value.data[0].inst_id),
+pub struct TradeRef<'a> {
+    #[serde(deserialize_with = "number")]
+    pub trade_id: u64,
+    #[serde(deserialize_with = "number")]
+    pub px: Decimal,
+    #[serde(deserialize_with = "number")]
+    pub sz: Decimal,
+    pub side: &'a str,
+    #[serde(deserialize_with = "number")]
+    pub ts: u64,
+}
+
+impl TradeRef<'_> {
+    pub fn into_trade(self, inst_id: Symbol) -> event::Trade {
+        event::Trade {
+            symbol: inst_id,
             exchange: Exchange::Okx,
-            side: if value.data[0].side == "sell" {
+            side: if self.side == "sell" {
                 Side::SELL
             } else {
                 Side::BUY
             },
-            price: value.data[0].px,
-            quantity: value.data[0].sz,
+            price: self.px,
+            quantity: self.sz,
+            trade_id: self.trade_id.into(),
+            ts: event::Timestamps::millis(self.ts),
         }
     }
 }
-#[test]
-fn test_book_transform() {
-    let valid = serde_json::from_str::<OkxRaw<BookUpdateRaw>>(RAW_BOOK).is_ok();
-    assert!(valid);
-    let valid = serde_json::from_str::<OkxRaw<TradeRaw>>(RAW_TRADE);
-    let trade: event::Trade = valid.unwrap().into();
 
-    dbg!(trade);
-    // assert!(&valid.is_ok());
+/// Decode a text frame from the OKX public websocket, appending its events to `out`
+pub fn parse_into(raw_str: &str, out: &mut Vec<event::Event>) -> serde_json::Result<()> {
+    OkxPush::parse(raw_str)?.events(out)
+}
+
+/// Decode a text frame from the OKX public websocket into events
+pub fn parse(raw_str: &str) -> serde_json::Result<Vec<event::Event>> {
+    let mut events = Vec::new();
+    parse_into(raw_str, &mut events)?;
+    Ok(events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RAW_BOOK: &str = r#"
+            {
+                "arg": {
+                    "channel": "books",
+                    "instId": "BTC-USDT"
+                },
+                "action": "update",
+                "data": [
+                    {
+                        "asks": [
+                            ["30557.3", "0", "0", "0"],
+                            ["30557.6", "0.51065898", "0", "1"]
+                        ],
+                        "bids": [
+                            ["30545", "0.51069492", "0", "2"],
+                            ["30544.9", "0.17474", "0", "1"]
+                        ],
+                        "ts": "1688060541909",
+                        "checksum": -1316686072,
+                        "seqId": 12815993309,
+                        "prevSeqId": 12815993303
+                    }
+                ]
+            }
+        "#;
+
+    const RAW_BOOK5: &str = r#"
+            {
+                "arg": {
+                    "channel": "books5",
+                    "instId": "BTC-USDT"
+                },
+                "data": [
+                    {
+                        "asks": [
+                            ["30557.6", "0.51065898", "0", "1"],
+                            ["30558", "1.2", "0", "3"]
+                        ],
+                        "bids": [
+                            ["30545", "0.51069492", "0", "2"]
+                        ],
+                        "instId": "BTC-USDT",
+                        "ts": "1688060541909",
+                        "seqId": 12815993309
+                    }
+                ]
+            }
+        "#;
+
+    const RAW_TRADE: &str = r#"
+            {
+                "arg": {
+                    "channel": "trades",
+                    "instId": "BTC-USDT"
+                },
+                "data": [
+                    {
+                        "instId": "BTC-USDT",
+                        "tradeId": "426790906",
+                        "px": "30460.1",
+                        "sz": "0.0010244",
+                        "side": "sell",
+                        "ts": "1688085963425"
+                    }
+                ]
+            }"#;
+
+    #[test]
+    fn test_book_transform() {
+        let push = OkxPush::parse(RAW_BOOK).unwrap();
+        assert_eq!(push.arg.inst_id, "BTC-USDT");
+        let mut seq_ids = Vec::new();
+        push.books(|book| {
+            seq_ids.push(book.seq_id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seq_ids, [Some(12815993309)]);
+
+        let push = OkxPush::parse(RAW_TRADE).unwrap();
+        each(push.data, |trade: TradeRef| {
+            assert_eq!(trade.trade_id, 426790906);
+            assert_eq!(trade.ts, 1688085963425);
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_book_update_events() {
+        let events = parse(RAW_BOOK).unwrap();
+        assert_eq!(events.len(), 4);
+
+        let event::Event::OrderbookUpdate(first) = &events[0] else {
+            panic!("Expected an orderbook update, got {:?}", events[0]);
+        };
+        assert_eq!(first.symbol, "BTC-USDT");
+        assert!(matches!(first.side, Side::SELL));
+        assert_eq!(first.price, "30557.3".parse::<Decimal>().unwrap());
+        assert!(first.quantity.is_zero());
+        assert!(!first.is_snapshot);
+        assert_eq!(first.sequence, Some(12815993309));
+        assert_eq!(first.ts.exchange, Some(1688060541909000));
+
+        let event::Event::OrderbookUpdate(last) = &events[3] else {
+            panic!("Expected an orderbook update, got {:?}", events[3]);
+        };
+        assert!(matches!(last.side, Side::BUY));
+        assert_eq!(last.price, "30544.9".parse::<Decimal>().unwrap());
+        assert_eq!(last.quantity, "0.17474".parse::<Decimal>().unwrap());
+    }
+
+    #[test]
+    fn test_book_snapshot_events() {
+        let raw = RAW_BOOK.replace(r#""action": "update""#, r#""action": "snapshot""#);
+        let events = parse(&raw).unwrap();
+        assert_eq!(events.len(), 1);
+
+        let event::Event::OrderbookSnapshot(snapshot) = &events[0] else {
+            panic!("Expected an orderbook snapshot, got {:?}", events[0]);
+        };
+        assert_eq!(snapshot.levels.len(), 4);
+        assert!(snapshot.levels.iter().all(|l| l.is_snapshot));
+
+        // books5 has no action and always pushes the full top of book
+        let events = parse(RAW_BOOK5).unwrap();
+        let event::Event::OrderbookSnapshot(snapshot) = &events[0] else {
+            panic!("Expected an orderbook snapshot, got {:?}", events[0]);
+        };
+        assert_eq!(snapshot.levels.len(), 3);
+    }
+
+    #[test]
+    fn test_trade_events() {
+        let mut events = Vec::new();
+        parse_into(RAW_TRADE, &mut events).unwrap();
+        parse_into(RAW_TRADE, &mut events).unwrap();
+        assert!(
+            matches!(&events[..], [event::Event::Trade(t), _] if t.price == "30460.1".parse::<Decimal>().unwrap())
+        );
+        let event::Event::Trade(trade) = &events[0] else {
+            panic!("Expected a trade, got {:?}", events[0]);
+        };
+        assert_eq!(trade.trade_id, "426790906");
+        assert_eq!(trade.ts.exchange, Some(1688085963425000));
+    }
+
+    #[test]
+    fn test_level() {
+        let level: LevelRef = serde_json::from_str(r#"["30545", "0.51069492", "0", "2"]"#).unwrap();
+        assert_eq!(
+            (level.price, level.px.to_string().as_str()),
+            ("30545", "30545")
+        );
+        assert_eq!(
+            (level.size, level.sz.to_string().as_str()),
+            ("0.51069492", "0.51069492")
+        );
+
+        let bad = r#"["not a price", "1", "0", "1"]"#;
+        assert!(serde_json::from_str::<LevelRef>(bad).is_err());
+    }
+
+    #[test]
+    fn test_book_checksum() {
+        let expected =
+            crc32fast::hash(b"30545:0.51069492:30557.6:0.51065898:30544.9:0.17474") as i32;
+        let raw = RAW_BOOK.replace(
+            r#""checksum": -1316686072"#,
+            &format!(r#""checksum": {expected}"#),
+        );
+        let push = OkxPush::parse(&raw).unwrap();
+        let mut pushed = None;
+        push.books(|book| {
+            pushed = Some(book);
+            Ok(())
+        })
+        .unwrap();
+        let data = pushed.unwrap();
+
+        // The zero sized ask only deletes, so the book has two bids and one ask
+        let mut book = OkxBook::default();
+        assert!(book.verify(&data, true));
+        assert_eq!(book.checksum(), expected);
+
+        // A push whose checksum doesn't match invalidates the book until the next snapshot
+        let bad = raw.replace(&expected.to_string(), &(expected as i64 + 1).to_string());
+        let push = OkxPush::parse(&bad).unwrap();
+        push.books(|data| {
+            assert!(!book.verify(&data, false));
+            Ok(())
+        })
+        .unwrap();
+        assert!(!book.verify(&data, false));
+        assert!(book.verify(&data, true));
+    }
 }
@@ -4,7 +4,7 @@ use actix_cors::Cors;
 use actix_web::{http::header, middleware::Logger, web, App, HttpServer};
 use tokio::sync;
 
-use crate::state::client::StreamRequest;
+use crate::state::dispatch::{DispatchRequest, Dispatcher};
 mod routes;
 mod state;
 
@@ -17,13 +17,9 @@ static CLIENT_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 8)]
 async fn main() -> std::io::Result<()> {
-    let (dis_tx, mut dis_rx) = sync::mpsc::channel::<StreamRequest>(500);
+    let (dis_tx, dis_rx) = sync::mpsc::channel::<DispatchRequest>(500);
 
-    tokio::spawn(async move {
-        while let Some(cmd) = dis_rx.recv().await {
-            dbg!(cmd);
-        }
-    });
+    tokio::spawn(Dispatcher::new().run(dis_rx));
 
     let port = std::env::var("PORT")
         .unwrap_or("5050".into())
@@ -36,6 +32,13 @@ async fn main() -> std::io::Result<()> {
 
     #[cfg(debug_assertions)]
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("debug"));
+    match routes::symbols::intern_listed_symbols().await {
+        Ok(listed) => log::info!("Loaded {listed} listed symbols"),
+        Err(e) => log::error!(
+            "Couldn't load the symbol listings ({e}), only symbols already streaming can be subscribed"
+        ),
+    }
+
     log::info!("Starting HTTP server at http:/localhost:{port}");
     log::info!("GraphiQL playground: http://localhost:{port}/graphiql");
 
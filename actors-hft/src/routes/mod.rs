@@ -1,11 +1,10 @@
-use actix_web::{get, post, web, Error, HttpRequest, HttpResponse, Responder};
-use std::collections::HashMap;
+use actix_web::{get, web, Error, HttpResponse, Responder};
 use std::sync::atomic::Ordering;
 use tokio::sync::mpsc::Sender;
 
 use crate::{
     routes::symbols::retrieve_symbols,
-    state::client::{StreamRequest, WsState},
+    state::{client::WsState, dispatch::DispatchRequest},
     CLIENT_COUNTER,
 };
 pub mod symbols;
@@ -14,7 +13,7 @@ mod ws;
 pub async fn ws_route(
     req: actix_web::HttpRequest,
     stream: web::Payload,
-    dis_tx: web::Data<Sender<StreamRequest>>,
+    dis_tx: web::Data<Sender<DispatchRequest>>,
 ) -> Result<HttpResponse, Error> {
     CLIENT_COUNTER.fetch_add(1, Ordering::SeqCst);
     let client = WsState::new(CLIENT_COUNTER.load(Ordering::SeqCst));
@@ -48,11 +47,11 @@ pub async fn exchange_symbols(
     if let Some(value) = map.get(&exchange.into_inner()) {
         return web::Json(value.clone());
     }
-    return web::Json(serde_json::json!({
+    web::Json(serde_json::json!({
         "error":
             format!(
                 "Invalid or unsupported exchange name. Supported Exchanges: ",
                 // map.keys().collect::<Vec<String>>()
             )
-    }));
+    }))
 }
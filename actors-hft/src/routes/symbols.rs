@@ -6,12 +6,23 @@ const SYMBOLS_URLS: [&str; 8] = [
     "https://api.bitfinex.com/v1/symbols_details",
     "https://api.exchange.coinbase.com/products",
     "https://api.kraken.com/0/public/AssetPairs",
-    "https://api.bybit.com/spot/v3/public/symbols",
+    "https://api.bybit.com/v5/market/instruments-info?category=spot",
     "https://www.bitstamp.net/api/v2/trading-pairs-info/",
     "https://api.huobi.com/v1/common/symbols",
     "https://www.okx.com/api/v5/public/instruments?instType=SPOT",
 ];
 
+/// Bybit lists every category separately, linear perpetuals and futures come from here
+const BYBIT_LINEAR_SYMBOLS_URL: &str =
+    "https://api.bybit.com/v5/market/instruments-info?category=linear&limit=1000";
+
+/// Every active Deribit future, option and spot pair
+const DERIBIT_SYMBOLS_URL: &str =
+    "https://www.deribit.com/api/v2/public/get_instruments?currency=any&expired=false";
+
+/// Every KuCoin spot pair
+const KUCOIN_SYMBOLS_URL: &str = "https://api.kucoin.com/api/v2/symbols";
+
 pub async fn get_all_symbols(client: &awc::Client) -> serde_json::Map<String, Value> {
     let mut map = serde_json::Map::new();
     let bin = get_bin_symbols(client).await;
@@ -22,8 +33,10 @@ pub async fn get_all_symbols(client: &awc::Client) -> serde_json::Map<String, Va
     let bitstamp = get_bitstamp_symbols(client).await;
     let huobi = get_huobi_symbols(client).await;
     let okx = get_okx_symbols(client).await;
-    // let deribit = get_deribit_symbols().await;
-    // map.insert("deribit".to_string(), deribit.into());
+    let deribit = get_deribit_symbols(client).await;
+    let kucoin = get_kucoin_symbols(client).await;
+    map.insert("deribit".to_string(), deribit.into());
+    map.insert("kucoin".to_string(), kucoin.into());
     map.insert("binance".to_string(), bin.into());
     map.insert("coinbase".to_string(), coin.into());
     map.insert("bitfinex".to_string(), bitfinex.into());
@@ -116,27 +129,72 @@ async fn get_kraken_symbols(client: &awc::Client) -> Vec<String> {
     symbols
 }
 async fn get_bybit_symbols(client: &awc::Client) -> Vec<String> {
-    let mut bybit_raw = client
-        .get(SYMBOLS_URLS[4])
+    let mut symbols = Vec::new();
+    for url in [SYMBOLS_URLS[4], BYBIT_LINEAR_SYMBOLS_URL] {
+        let mut bybit_raw = client
+            .get(url)
+            .send()
+            .await
+            .unwrap()
+            .json::<serde_json::Value>()
+            .await
+            .unwrap();
+        let list = bybit_raw
+            .get_mut("result")
+            .unwrap()
+            .get_mut("list")
+            .unwrap()
+            .as_array_mut()
+            .unwrap();
+        symbols.extend(
+            list.iter()
+                .map(|v| v.get("symbol").unwrap().as_str().unwrap().to_string()),
+        );
+    }
+    // Most spot pairs are listed as perpetuals too
+    symbols.sort();
+    symbols.dedup();
+    symbols
+}
+
+async fn get_deribit_symbols(client: &awc::Client) -> Vec<String> {
+    let deribit_raw = client
+        .get(DERIBIT_SYMBOLS_URL)
         .send()
         .await
         .unwrap()
         .json::<serde_json::Value>()
+        // Options alone run to several megabytes
+        .limit(32 * 1024 * 1024)
         .await
         .unwrap();
-    let map = bybit_raw
-        .get_mut("result")
-        .unwrap()
-        .get_mut("list")
+    let list = deribit_raw.get("result").unwrap().as_array().unwrap();
+    list.iter()
+        .map(|v| {
+            v.get("instrument_name")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        })
+        .collect()
+}
+
+async fn get_kucoin_symbols(client: &awc::Client) -> Vec<String> {
+    let kucoin_raw = client
+        .get(KUCOIN_SYMBOLS_URL)
+        .send()
+        .await
         .unwrap()
-        .as_array_mut()
+        .json::<serde_json::Value>()
+        // Over a thousand pairs with their trading rules
+        .limit(8 * 1024 * 1024)
+        .await
         .unwrap();
-    // dbg!(&map);
-    let symbols: Vec<String> = map
-        .iter()
-        .map(|v| v.get("name").unwrap().as_str().unwrap().to_string())
-        .collect();
-    symbols
+    let list = kucoin_raw.get("data").unwrap().as_array().unwrap();
+    list.iter()
+        .map(|v| v.get("symbol").unwrap().as_str().unwrap().to_string())
+        .collect()
 }
 
 async fn get_bitstamp_symbols(client: &awc::Client) -> Vec<String> {
@@ -194,6 +252,9 @@ async fn get_okx_symbols(client: &awc::Client) -> Vec<String> {
     symbols
 }
 
+/// Fetch every exchange's listings into `./resources/symbols.json`, the file
+/// [`retrieve_symbols`] reads. Run by hand, no route refreshes the listings yet.
+#[allow(dead_code)]
 pub async fn store_symbols(
     path: Option<&std::path::Path>,
     name: Option<&std::path::Path>,
@@ -220,7 +281,7 @@ pub async fn store_symbols(
 
     let json = serde_json::to_value(map).expect("Literally a json object, WTF");
 
-    let ref contents = serde_json::to_string_pretty(&json).expect("Is should already be json");
+    let contents = &serde_json::to_string_pretty(&json).expect("Is should already be json");
 
     fs::write(dir_path.join(file_name), contents).await?;
 
@@ -231,7 +292,6 @@ pub async fn retrieve_symbols(
     path: Option<&std::path::Path>,
     name: Option<&std::path::Path>,
 ) -> std::io::Result<serde_json::Map<String, Value>> {
-    use std::io::ErrorKind;
     use std::path::Path;
     use tokio::fs;
 
@@ -252,13 +312,29 @@ pub async fn retrieve_symbols(
     }
 
     if !Path::new(dir_path).exists() {
-        return Err(std::io::Error::new(
-            ErrorKind::Other,
-            "Provided path doesn't exist",
-        ));
+        return Err(std::io::Error::other("Provided path doesn't exist"));
     }
 
     let symbols_file = fs::read_to_string(dir_path.join(file_name)).await?;
     let map = serde_json::from_str::<serde_json::Map<String, Value>>(&symbols_file).unwrap();
     Ok(map)
 }
+
+/// Intern every symbol the stored listings name, so clients can subscribe to them. Client input is
+/// only ever looked up, never interned, the listings bound what the interner holds. Returns how
+/// many symbols were listed.
+pub async fn intern_listed_symbols() -> std::io::Result<usize> {
+    let map = retrieve_symbols(None, None).await?;
+    let symbols = map
+        .values()
+        .filter_map(Value::as_array)
+        .flatten()
+        .filter_map(Value::as_str);
+
+    let mut listed = 0;
+    for symbol in symbols {
+        singular::models::Symbol::intern(symbol);
+        listed += 1;
+    }
+    Ok(listed)
+}
@@ -1,19 +1,31 @@
 use std::{
-    sync::atomic::Ordering,
+    sync::{atomic::Ordering, Arc},
     time::{Duration, Instant},
 };
 
-use actix_ws::{Closed, Message};
+use actix_ws::{CloseCode, CloseReason, Message};
 use futures_util::{
-    future::{self, Either},
-    StreamExt as _,
+    future::{self, BoxFuture},
+    stream::FuturesOrdered,
+    FutureExt as _, StreamExt as _,
+};
+use singular::{
+    event::{now_micros, Event},
+    models::normal::Stats,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, Sender},
+        oneshot,
+    },
+    time::interval,
 };
-use tokio::{pin, sync::mpsc::Sender, time::interval};
 
 use crate::{
     state::{
-        client::{ClientEvent, ClientRequest, StreamRequest},
-        server::ServerResponse,
+        client::{ClientEvent, StreamRequest},
+        dispatch::{Delivery, DispatchOp, DispatchRequest},
+        server::{Meta, ServerResponse},
     },
     CLIENT_COUNTER,
 };
@@ -26,41 +38,41 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
 /// How long before lack of client response causes a timeout.
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// How many events a client can fall behind before the adapters drop its streams.
+const EVENT_BUFFER: usize = 1024;
+
 /// Echo text & binary messages received from the client, respond to ping messages, and monitor
 /// connection health to detect network issues and free up resources.
 pub async fn ws_client(
     mut session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
-    dispatch_tx: Sender<StreamRequest>,
+    dispatch_tx: Sender<DispatchRequest>,
 ) {
     log::info!("connected");
     let mut last_heartbeat = Instant::now();
     let mut interval = interval(HEARTBEAT_INTERVAL);
+    // Events of every stream this client subscribed. Dropping the receiver on disconnect releases
+    // the client's subscriptions in the adapters.
+    let (events_tx, mut events_rx) = mpsc::channel::<Delivery>(EVENT_BUFFER);
+    // Answers to the client's requests, sent back in the order it asked. Events keep flowing
+    // while the exchanges take their time, the adapters never wait on a client that waits on them.
+    let mut responses = FuturesOrdered::new();
 
     let reason = loop {
-        // create "next client timeout check" future
-        let tick = interval.tick();
-        // required for select()
-        pin!(tick);
-
-        // waits for either `msg_stream` to receive a message from the client or the heartbeat
-        // interval timer to tick, yielding the value of whichever one is ready first
-        match future::select(msg_stream.next(), tick).await {
+        // waits for either `msg_stream` to receive a message from the client, an event from a
+        // subscribed stream or the heartbeat interval timer to tick
+        tokio::select! {
             // received message from WebSocket client
-            Either::Left((Some(Ok(msg)), _)) => {
+            Some(Ok(msg)) = msg_stream.next() => {
                 log::debug!("msg: {msg:?}");
                 let session = &mut session;
                 match msg {
                     Message::Text(text) => {
-                        let response: ServerResponse =
-                            match serde_json::from_str::<ClientEvent>(&text) {
-                                Ok(event) => handle_message(event, session, &dispatch_tx).await,
-                                Err(e) => handle_serde(&e, session).await,
-                            };
-
-                        let text = serde_json::to_string(&response).expect("No user input");
-
-                        session.text(text).await.unwrap();
+                        let response = match serde_json::from_str::<ClientEvent>(&text) {
+                            Ok(event) => handle_message(event, &dispatch_tx, &events_tx).await,
+                            Err(e) => future::ready(handle_serde(&e)).boxed(),
+                        };
+                        responses.push_back(response);
                     }
 
                     Message::Close(reason) => {
@@ -85,17 +97,39 @@ pub async fn ws_client(
                 };
             }
 
-            // client WebSocket stream error
-            Either::Left((Some(Err(err)), _)) => {
-                log::error!("{}", err);
-                break None;
+            // the exchange answered the oldest outstanding request
+            Some(response) = responses.next(), if !responses.is_empty() => {
+                let text = serde_json::to_string(&response).expect("No user input");
+                if session.text(text).await.is_err() {
+                    break None;
+                }
             }
 
-            // client WebSocket stream ended
-            Either::Left((None, _)) => break None,
+            // event from a subscribed stream
+            Some((subscription, event)) = events_rx.recv() => {
+                // The adapters released every stream this client fell behind on, it can't tell
+                // that from a quiet market unless told
+                if let Event::Lagged(_) = &event {
+                    let response = ServerResponse::Error {
+                        message: format!(
+                            "{subscription}: more than {EVENT_BUFFER} events behind, stream released"
+                        ),
+                    };
+                    let text = serde_json::to_string(&response).expect("No user input");
+                    let _ = session.text(text).await;
+                    break Some(CloseReason {
+                        code: CloseCode::Policy,
+                        description: Some("Fell behind its streams".into()),
+                    });
+                }
+                let text = serde_json::to_string(&data_response(subscription, event)).expect("No user input");
+                if session.text(text).await.is_err() {
+                    break None;
+                }
+            }
 
             // heartbeat interval ticked
-            Either::Right((_inst, _)) => {
+            _ = interval.tick() => {
                 // if no heartbeat ping/pong received recently, close the connection
                 if Instant::now().duration_since(last_heartbeat) > CLIENT_TIMEOUT {
                     log::info!(
@@ -109,6 +143,9 @@ pub async fn ws_client(
                 // send heartbeat ping
                 let _ = session.ping(b"").await;
             }
+
+            // client WebSocket stream errored or ended
+            else => break None,
         }
     };
 
@@ -119,46 +156,81 @@ pub async fn ws_client(
     log::info!("disconnected");
 }
 
-async fn handle_serde(e: &serde_json::Error, session: &mut actix_ws::Session) -> ServerResponse {
-    log::error!("Error parsing client json: {:?}", &e);
+/// Wrap a stream event for the client, tagged with the channel it subscribed and how long it took
+/// to get here since it happened on the exchange
+fn data_response(subscription: Arc<StreamRequest>, event: Event) -> ServerResponse {
+    let meta = Meta::from(subscription);
 
-    let mut message = "".to_string();
+    let stats = event.ts().latency_ms(now_micros()).map(|latency_ms| Stats {
+        latency_ms,
+        exchange_status: !matches!(&event, Event::Connection(c) if !c.connected),
+    });
 
-    message = match e.classify() {
+    ServerResponse::Data {
+        payload: serde_json::to_value(event).expect("Events always serialize"),
+        meta: Some(meta),
+        stats,
+    }
+}
+
+fn handle_serde(e: &serde_json::Error) -> ServerResponse {
+    log::error!("Error parsing client json: {:?}", &e);
+
+    let message = match e.classify() {
         serde_json::error::Category::Io => "Io error, failed to read or write bytes.".to_string(),
         serde_json::error::Category::Syntax => format!(
             "Syntax error on line, {} and column, {}",
             e.line(),
             e.column()
         ),
-        serde_json::error::Category::Data => format!(
-            "Type error, an unexpected type/kind of data was provided. Details, {}",
-            e.to_string()
-        ),
+        serde_json::error::Category::Data => {
+            format!("Type error, an unexpected type/kind of data was provided. Details, {e}")
+        }
         serde_json::error::Category::Eof => "Empty messages are not valid inputs".to_string(),
     };
 
     ServerResponse::Error { message }
 }
 
+/// Hand the request to the dispatcher. Resolves to the answer once the exchange confirmed or
+/// rejected it.
 async fn handle_message(
     event: ClientEvent,
-    session: &mut actix_ws::Session,
-    dispatch_tx: &Sender<StreamRequest>,
-) -> ServerResponse {
-    match event {
-        ClientEvent::Subscribe(s) => {
-            if let Ok(req) = s.to_request() {
-                dbg!(req);
-                // dispatch_tx.send(req).await;
-            }
-        }
-        ClientEvent::Unsubscribe(_) => todo!(),
-        ClientEvent::Auth { key } => todo!(),
+    dispatch_tx: &Sender<DispatchRequest>,
+    events_tx: &mpsc::Sender<Delivery>,
+) -> BoxFuture<'static, ServerResponse> {
+    let (op, request) = match event {
+        ClientEvent::Subscribe(s) => (DispatchOp::Subscribe, s),
+        ClientEvent::Unsubscribe(s) => (DispatchOp::Unsubscribe, s),
+        ClientEvent::Auth { .. } => todo!(),
         ClientEvent::Status => todo!(),
     };
 
-    ServerResponse::Info {
-        message: "Filler".into(),
+    let request = match request.to_request() {
+        Ok(request) => request,
+        Err(response) => return future::ready(response).boxed(),
+    };
+
+    // Answered once the exchange confirmed or rejected the request
+    let (respond_to, response) = oneshot::channel();
+    let dispatch = DispatchRequest {
+        op,
+        request,
+        client: events_tx.clone(),
+        respond_to,
+    };
+    if dispatch_tx.send(dispatch).await.is_err() {
+        return future::ready(ServerResponse::Error {
+            message: "The dispatch system is not running".into(),
+        })
+        .boxed();
     }
+
+    response
+        .map(|response| {
+            response.unwrap_or(ServerResponse::Error {
+                message: "The dispatch system dropped the request".into(),
+            })
+        })
+        .boxed()
 }
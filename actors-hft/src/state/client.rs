@@ -1,10 +1,14 @@
 use super::server::ServerResponse;
-use crate::routes::symbols::retrieve_symbols;
 // use crate::{api::authentication::Credentials, routes::retrieve_symbols, CONFIG};
 use serde::{Deserialize, Serialize};
-use singular::models::{normal::DataTypes, Exchange};
+use singular::{
+    adapters::capabilities::Capabilities,
+    models::{normal::DataTypes, Exchange, Symbol},
+};
 use std::{collections::HashMap, fmt::Display, time::Instant};
 
+/// Per-client state, only logged on connect until auth and rate limits are enforced
+#[allow(dead_code)]
 #[derive(Debug)]
 pub struct WsState {
     pub client_id: usize,
@@ -81,6 +85,8 @@ pub struct ClientRequest {
     pub options: Option<Extra>,
 }
 
+// The error is the response sent back as is, once per request
+#[allow(clippy::result_large_err)]
 fn str_to_request(channel_str: &str) -> Result<StreamRequest, ServerResponse> {
     use std::str::FromStr;
     let mut stream_request = StreamRequest::default();
@@ -99,55 +105,42 @@ fn str_to_request(channel_str: &str) -> Result<StreamRequest, ServerResponse> {
 
     match DataTypes::from_str(broken[2]) {
         Ok(rename) => stream_request.data_type = rename,
-        Err(_) => {
-            return Err(ServerResponse::Error {
-                message: "Incorrect DataType provided. Only Trade (trade), Book (book) supported."
+        Err(_) => return Err(ServerResponse::Error {
+            message:
+                "Incorrect DataType provided. Only Trade (trade), Book (book), Bbo (bbo) supported."
                     .into(),
-            })
-        }
+        }),
     };
 
-    futures::executor::block_on(validate_symbols(
-        broken[0].to_string(),
-        broken[3].to_string(),
-    ))?;
+    stream_request.asset_class = broken[1].to_string();
 
-    stream_request.symbol = broken[3].to_string();
+    // Rejected before reaching the dispatcher when the exchange doesn't offer it
+    Capabilities::for_exchange(stream_request.exchange)
+        .check(stream_request.data_type, &stream_request.asset_class)
+        .map_err(|e| ServerResponse::Error {
+            message: format!("{channel_str}: {e}"),
+        })?;
 
-    Ok(stream_request)
-}
+    // Looked up rather than interned, a client can't grow the interner with made up names
+    stream_request.symbol = Symbol::get(broken[3]).ok_or_else(|| ServerResponse::Error {
+        message: format!(
+            "The provided symbol: {} is not listed on the exchange, {}",
+            broken[3], broken[0]
+        ),
+    })?;
 
-async fn validate_symbols(exc_str: String, sym: String) -> Result<(), ServerResponse> {
-    // let map = retrieve_symbols(None, None).await.unwrap();
-    // if let Some(symbols) = map.get(&exc_str.to_lowercase()) {
-    //     let search = symbols
-    //         .as_array()
-    //         .expect("The symbols json has changed from a Map<String, Vec<String>>")
-    //         .iter()
-    //         .find(|s| match s.as_str() {
-    //             Some(t) => sym == t,
-    //             None => false,
-    //         });
-    //
-    //     if search.is_none() {
-    //         return Err(ServerResponse::Error {
-    //             message: format!(
-    //                 "The provided symbol: {sym} is not provided in the exchange, {exc_str}"
-    //             ),
-    //         });
-    //     }
-    // }
-    Ok(())
+    Ok(stream_request)
 }
 
 impl ClientRequest {
+    #[allow(clippy::result_large_err)]
     pub fn to_request(&self) -> Result<StreamRequest, ServerResponse> {
         if let Some(c) = self.channel.as_ref() {
             return str_to_request(c);
         }
 
         Err(ServerResponse::Error {
-            message: format!("No request provided"),
+            message: "No request provided".into(),
         })
     }
 
@@ -164,7 +157,7 @@ impl ClientRequest {
 pub struct StreamRequest {
     pub exchange: Exchange,
     pub data_type: DataTypes,
-    pub symbol: String,
+    pub symbol: Symbol,
     pub asset_class: String,
     pub options: Option<Extra>,
 }
@@ -173,10 +166,15 @@ impl Display for StreamRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let exc_str = self.exchange.to_string();
         let pipe_str = self.data_type.to_string();
-        write!(f, "{exc_str}.spot.{pipe_str}.{}", self.symbol)
+        let asset_class = match self.asset_class.as_str() {
+            "" => "spot",
+            asset_class => asset_class,
+        };
+        write!(f, "{exc_str}.{asset_class}.{pipe_str}.{}", self.symbol)
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default, Clone)]
 pub enum RequestState {
     Book {
@@ -199,6 +197,8 @@ pub struct Extra {
 mod tests {
     use serde_json::json;
 
+    use crate::routes::symbols::retrieve_symbols;
+
     use super::*;
     #[cfg(feature = "interval")]
     #[test]
@@ -268,6 +268,32 @@ mod tests {
         dbg!(&search);
     }
 
+    #[test]
+    fn unoffered_channel() {
+        let req: ClientRequest =
+            serde_json::from_value(json!({"channel": "bybit.option.trade.BTCUSDT"})).unwrap();
+        let Err(ServerResponse::Error { message }) = req.to_request() else {
+            panic!("Bybit doesn't stream options");
+        };
+        assert!(message.ends_with("choose from: spot, linear"), "{message}");
+
+        Symbol::intern("BTCUSDT");
+        let req: ClientRequest =
+            serde_json::from_value(json!({"channel": "bybit.linear.bbo.BTCUSDT"})).unwrap();
+        assert!(req.to_request().is_ok());
+    }
+
+    #[test]
+    fn unknown_symbol() {
+        let req: ClientRequest =
+            serde_json::from_value(json!({"channel": "okx.spot.trade.NOT-LISTED"})).unwrap();
+        let Err(ServerResponse::Error { message }) = req.to_request() else {
+            panic!("Symbols nobody listed are rejected");
+        };
+        assert!(message.contains("NOT-LISTED is not listed"), "{message}");
+        assert!(Symbol::get("NOT-LISTED").is_none());
+    }
+
     #[test]
     fn interval_syntax() {
         let ex_json = json!({"interval": 1}).to_string();
@@ -1,11 +1,11 @@
 use serde::{Deserialize, Serialize};
 
+/// Server settings, not loaded yet
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Serialize, Eq, Hash, PartialEq, Clone, Default)]
 pub struct Config {
     #[serde(skip_serializing)]
     pub resources: Resources,
-    #[serde(skip_serializing)]
-    pub channels: Available,
 }
 
 #[derive(Debug, Deserialize, Eq, Hash, PartialEq, Clone, Default)]
@@ -14,11 +14,3 @@ pub struct Resources {
     pub dispatch_url: String,
     pub dispatch_zmq: String,
 }
-
-#[derive(Debug, Deserialize, Eq, Hash, PartialEq, Clone, Default)]
-pub struct Available {
-    pub diff_book: bool,
-    pub snap_book: bool,
-    pub trade: bool,
-    pub candles: bool,
-}
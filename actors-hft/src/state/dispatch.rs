@@ -0,0 +1,328 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+
+use futures_util::StreamExt as _;
+use singular::{
+    adapters::{
+        actor::MyActorHandle,
+        binance::BinanceProtocol,
+        bitfinex::{BitfinexProtocol, BITFINEX_URL},
+        bitstamp::{BitstampProtocol, BITSTAMP_REST, BITSTAMP_URL},
+        bybit::BybitProtocol,
+        capabilities::Capabilities,
+        coinbase::{CoinbaseProtocol, COINBASE_URL},
+        deribit::{DeribitProtocol, DERIBIT_URL},
+        huobi::{HuobiProtocol, HUOBI_URL},
+        keepalive::KeepaliveConfig,
+        kraken::{KrakenProtocol, KRAKEN_URL},
+        kucoin::{KucoinProtocol, KUCOIN_REST},
+        okx::{OkxProtocol, OKX_PUBLIC_URL},
+        protocol::Protocol,
+        subscriptions::SubscribeError,
+    },
+    event::Event,
+    interfaces::{Adapter, SUBSCRIBE_TIMEOUT},
+    models::{normal::DataTypes, Exchange, Symbol},
+    system::adapter::AdapterSystem,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Mutex},
+    task::JoinHandle,
+    time,
+};
+
+use super::{client::StreamRequest, server::ServerResponse};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchOp {
+    Subscribe,
+    Unsubscribe,
+}
+
+/// An event, tagged with the request of the stream it belongs to
+pub type Delivery = (Arc<StreamRequest>, Event);
+
+/// A websocket client (un)subscribing a stream. `client` receives the stream's events and
+/// `respond_to` the [`ServerResponse`] to send back once the exchange answered.
+#[derive(Debug)]
+pub struct DispatchRequest {
+    pub op: DispatchOp,
+    pub request: StreamRequest,
+    pub client: mpsc::Sender<Delivery>,
+    pub respond_to: oneshot::Sender<ServerResponse>,
+}
+
+/// A data type streamed for a symbol
+type Channel = (DataTypes, Symbol);
+
+/// The connections to one exchange, sharded by an [`AdapterSystem`], and the clients of every
+/// channel they carry
+struct Route {
+    system: AdapterSystem,
+    clients: HashMap<Channel, Vec<Forward>>,
+    /// Subscriptions of every channel waiting on the exchange's answer
+    pending: HashMap<Channel, usize>,
+}
+
+/// A client of a channel. Each client takes its own stream from the [`AdapterSystem`], a task
+/// forwards it until the client unsubscribes or goes away.
+struct Forward {
+    client: mpsc::Sender<Delivery>,
+    task: JoinHandle<()>,
+}
+
+type SharedRoute = Arc<Mutex<Route>>;
+
+impl Route {
+    /// Shard the streams of `exchange` over connections speaking the protocol `protocol` makes
+    fn shared<P: Protocol>(
+        exchange: Exchange,
+        protocol: impl Fn() -> P + Send + 'static,
+    ) -> SharedRoute {
+        let system = AdapterSystem::for_exchange(exchange, move || {
+            let keepalive = KeepaliveConfig::for_exchange(exchange);
+            Box::new(MyActorHandle::with_protocol(protocol(), keepalive)) as Box<dyn Adapter>
+        });
+        Arc::new(Mutex::new(Self {
+            system,
+            clients: HashMap::new(),
+            pending: HashMap::new(),
+        }))
+    }
+
+    fn subscribed(&self, channel: Channel, client: &mpsc::Sender<Delivery>) -> bool {
+        self.clients
+            .get(&channel)
+            .is_some_and(|clients| clients.iter().any(|f| f.client.same_channel(client)))
+    }
+
+    /// Forwarding task of `client` for `channel`, no longer counted as one of its clients
+    fn remove(
+        &mut self,
+        channel: Channel,
+        client: &mpsc::Sender<Delivery>,
+    ) -> Option<JoinHandle<()>> {
+        let clients = self.clients.get_mut(&channel)?;
+        let index = clients
+            .iter()
+            .position(|forward| forward.client.same_channel(client))?;
+        Some(clients.swap_remove(index).task)
+    }
+
+    /// Release `channel` upstream once it has no client left and none waiting on it, which closes
+    /// its connection if it was the last channel there
+    async fn release_if_unused(&mut self, channel: Channel) -> Result<(), SubscribeError> {
+        let used = self
+            .clients
+            .get(&channel)
+            .is_some_and(|clients| !clients.is_empty());
+        if used || self.pending.contains_key(&channel) {
+            return Ok(());
+        }
+        self.clients.remove(&channel);
+        self.system.unsubscribe(channel.0, channel.1).await
+    }
+}
+
+/// Stream `channel` to `client` through the connections of `route`, every event tagged with the
+/// client's `subscription`. The route is only locked to
+/// send the request and to account for the answer, other clients of the exchange carry on while
+/// it waits.
+async fn subscribe(
+    route: &SharedRoute,
+    channel: Channel,
+    subscription: Arc<StreamRequest>,
+    client: mpsc::Sender<Delivery>,
+) -> Result<(), SubscribeError> {
+    let (mut guard, mut stream) = loop {
+        let (request, subscribing) = {
+            let mut guard = route.lock().await;
+            if guard.subscribed(channel, &client) {
+                return Ok(());
+            }
+            let requested = guard.system.request(channel.0, channel.1).await?;
+            *guard.pending.entry(channel).or_default() += 1;
+            requested
+        };
+
+        let answer = time::timeout(SUBSCRIBE_TIMEOUT, subscribing)
+            .await
+            .unwrap_or(Err(SubscribeError::Timeout(SUBSCRIBE_TIMEOUT)));
+        let mut guard = route.lock().await;
+        if let Entry::Occupied(mut pending) = guard.pending.entry(channel) {
+            *pending.get_mut() -= 1;
+            if *pending.get() == 0 {
+                pending.remove();
+            }
+        }
+        if guard.system.settle(request, answer.as_ref().err()).await {
+            continue;
+        }
+        match answer {
+            Ok(stream) => break (guard, stream),
+            Err(e) => {
+                // A stream joined while this one waited may still hold the channel
+                if let Err(released) = guard.release_if_unused(channel).await {
+                    log::warn!("Releasing {} {}: {released}", channel.0, channel.1);
+                }
+                return Err(e);
+            }
+        }
+    };
+    // Subscribed twice at once, the stream taken first serves it
+    if guard.subscribed(channel, &client) {
+        return Ok(());
+    }
+
+    let forwarding = client.clone();
+    let shared = route.clone();
+    let task = tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                event = stream.next() => {
+                    // The adapter ended the stream, or the client is gone
+                    let Some(event) = event else { break };
+                    if forwarding.send((subscription.clone(), event)).await.is_err() {
+                        break;
+                    }
+                }
+                _ = forwarding.closed() => break,
+            }
+        }
+
+        let mut route = shared.lock().await;
+        if route.remove(channel, &forwarding).is_some() {
+            if let Err(e) = route.release_if_unused(channel).await {
+                log::warn!("Releasing {} {}: {e}", channel.0, channel.1);
+            }
+        }
+    });
+    guard
+        .clients
+        .entry(channel)
+        .or_default()
+        .push(Forward { client, task });
+    Ok(())
+}
+
+/// Stop streaming `channel` to `client`
+async fn unsubscribe(
+    route: &SharedRoute,
+    channel: Channel,
+    client: &mpsc::Sender<Delivery>,
+) -> Result<(), SubscribeError> {
+    let mut route = route.lock().await;
+    let Some(task) = route.remove(channel, client) else {
+        return Ok(());
+    };
+    task.abort();
+    route.release_if_unused(channel).await
+}
+
+/// Routes client requests to the connections of their exchange
+#[derive(Clone)]
+pub struct Dispatcher {
+    okx: SharedRoute,
+    binance_usdm: SharedRoute,
+    binance_coinm: SharedRoute,
+    coinbase: SharedRoute,
+    kraken: SharedRoute,
+    bybit_spot: SharedRoute,
+    bybit_linear: SharedRoute,
+    bitfinex: SharedRoute,
+    huobi: SharedRoute,
+    deribit: SharedRoute,
+    bitstamp: SharedRoute,
+    kucoin: SharedRoute,
+}
+
+impl Dispatcher {
+    /// Nothing connects until the first subscription to an exchange
+    pub fn new() -> Self {
+        Self {
+            okx: Route::shared(Exchange::Okx, || OkxProtocol::new(OKX_PUBLIC_URL)),
+            binance_usdm: Route::shared(Exchange::BinanceUsdm, BinanceProtocol::usdm),
+            binance_coinm: Route::shared(Exchange::BinanceCoinm, BinanceProtocol::coinm),
+            coinbase: Route::shared(Exchange::Coinbase, || CoinbaseProtocol::new(COINBASE_URL)),
+            kraken: Route::shared(Exchange::Kraken, || KrakenProtocol::new(KRAKEN_URL)),
+            bybit_spot: Route::shared(Exchange::ByBit, BybitProtocol::spot),
+            bybit_linear: Route::shared(Exchange::ByBit, BybitProtocol::linear),
+            bitfinex: Route::shared(Exchange::Bitfinex, || BitfinexProtocol::new(BITFINEX_URL)),
+            huobi: Route::shared(Exchange::Huobi, || HuobiProtocol::new(HUOBI_URL)),
+            deribit: Route::shared(Exchange::Deribit, || DeribitProtocol::new(DERIBIT_URL)),
+            bitstamp: Route::shared(Exchange::BitStamp, || {
+                BitstampProtocol::new(BITSTAMP_URL, BITSTAMP_REST)
+            }),
+            kucoin: Route::shared(Exchange::KuCoin, || KucoinProtocol::new(KUCOIN_REST)),
+        }
+    }
+
+    /// Connections streaming `request`, if there are any. Bybit streams each asset class on its
+    /// own connections, `bybit.linear.trade.BTCUSDT` goes to linear and anything else to spot.
+    fn route(&self, request: &StreamRequest) -> Option<&SharedRoute> {
+        match request.exchange {
+            Exchange::Okx => Some(&self.okx),
+            Exchange::BinanceUsdm => Some(&self.binance_usdm),
+            Exchange::BinanceCoinm => Some(&self.binance_coinm),
+            Exchange::Coinbase => Some(&self.coinbase),
+            Exchange::Kraken => Some(&self.kraken),
+            Exchange::ByBit if request.asset_class == "linear" => Some(&self.bybit_linear),
+            Exchange::ByBit => Some(&self.bybit_spot),
+            Exchange::Bitfinex => Some(&self.bitfinex),
+            Exchange::Huobi => Some(&self.huobi),
+            Exchange::Deribit => Some(&self.deribit),
+            Exchange::BitStamp => Some(&self.bitstamp),
+            Exchange::KuCoin => Some(&self.kucoin),
+        }
+    }
+
+    /// Serve requests until every sender is gone. Each request waits on its exchange in its own
+    /// task so a slow answer doesn't hold up other clients.
+    pub async fn run(self, mut requests: mpsc::Receiver<DispatchRequest>) {
+        while let Some(request) = requests.recv().await {
+            let dispatcher = self.clone();
+            tokio::spawn(async move { dispatcher.handle(request).await });
+        }
+    }
+
+    async fn handle(&self, dispatch: DispatchRequest) {
+        let DispatchRequest {
+            op,
+            request,
+            client,
+            respond_to,
+        } = dispatch;
+        let channel = request.to_string();
+
+        let result = match self.route(&request) {
+            Some(route) => {
+                let stream = (request.data_type, request.symbol);
+                match op {
+                    // Rejected up front when the exchange doesn't offer it
+                    DispatchOp::Subscribe => match Capabilities::for_exchange(request.exchange)
+                        .check(request.data_type, &request.asset_class)
+                    {
+                        Ok(()) => subscribe(route, stream, Arc::new(request), client).await,
+                        Err(e) => Err(e),
+                    },
+                    DispatchOp::Unsubscribe => unsubscribe(route, stream, &client).await,
+                }
+            }
+            None => Err(SubscribeError::Unsupported(format!(
+                "Streaming from {}",
+                request.exchange
+            ))),
+        };
+
+        let response = match (result, op) {
+            (Ok(()), DispatchOp::Subscribe) => ServerResponse::Subscribed { channel },
+            (Ok(()), DispatchOp::Unsubscribe) => ServerResponse::Unsubscribed { channel },
+            (Err(e), _) => ServerResponse::Error {
+                message: format!("{channel}: {e}"),
+            },
+        };
+        let _ = respond_to.send(response);
+    }
+}
@@ -1,3 +1,4 @@
 pub mod client;
 pub mod config;
+pub mod dispatch;
 pub mod server;
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use serde::{Deserialize, Serialize};
 use singular::models::{normal, Exchange};
 
-use super::client::{Extra, StreamRequest};
+use super::client::StreamRequest;
 
 /// Possible events a this server can respond with
 ///
@@ -62,7 +62,7 @@ impl From<StreamRequest> for Meta {
             exchange: value.exchange,
             data_type: value.data_type,
             asset_class: value.asset_class,
-            symbol: value.symbol,
+            symbol: value.symbol.to_string(),
             // options: value.options,
         }
     }
@@ -73,9 +73,9 @@ impl From<Arc<StreamRequest>> for Meta {
         Self {
             channel: value.to_string(),
             exchange: value.exchange,
-            data_type: value.data_type.clone(),
+            data_type: value.data_type,
             asset_class: value.asset_class.clone(),
-            symbol: value.symbol.clone(),
+            symbol: value.symbol.to_string(),
             // options: value.options.clone(),
         }
     }